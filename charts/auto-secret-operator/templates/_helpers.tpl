@@ -60,3 +60,129 @@ Create the name of the service account to use
 {{- default "default" .Values.serviceAccount.name }}
 {{- end }}
 {{- end }}
+
+{{/*
+RBAC rules for the namespaced objects the operator reads and writes
+*/}}
+{{- define "auto-secret-operator.rules" -}}
+- apiGroups:
+  - ""
+  resources:
+  - secrets
+  verbs:
+    - get
+    - list
+    - watch
+    - patch
+    - create
+    - delete
+{{- if .Values.application.jobHooks }}
+- apiGroups:
+  - batch
+  resources:
+  - jobs
+  verbs:
+    - get
+    - create
+    - delete
+{{- end }}
+- apiGroups:
+  - events.k8s.io
+  resources:
+  - events
+  verbs:
+    - create
+    - patch
+{{- if or .Values.application.leaderElection (gt (int .Values.replicaCount) 1) }}
+- apiGroups:
+  - coordination.k8s.io
+  resources:
+  - leases
+  verbs:
+    - get
+    - create
+    - update
+{{- end }}
+{{- if .Values.application.configMaps }}
+- apiGroups:
+  - ""
+  resources:
+  - configmaps
+  verbs:
+    - get
+    - list
+    - watch
+    - patch
+{{- end }}
+{{- if .Values.application.workloads }}
+- apiGroups:
+  - apps
+  resources:
+  - deployments
+  - statefulsets
+  verbs:
+    - get
+    - list
+    - watch
+{{- end }}
+{{- if .Values.application.restartWorkloads }}
+- apiGroups:
+  - apps
+  resources:
+  - deployments
+  - statefulsets
+  - daemonsets
+  verbs:
+    - list
+    - patch
+{{- end }}
+{{- if .Values.application.sealedSecrets }}
+- apiGroups:
+  - bitnami.com
+  resources:
+  - sealedsecrets
+  verbs:
+    - get
+    - create
+    - patch
+{{- if not .Values.application.sealedSecretsCert }}
+- apiGroups:
+  - ""
+  resources:
+  - services/proxy
+  verbs:
+    - get
+{{- end }}
+{{- end }}
+{{- if .Values.application.reportResources }}
+- apiGroups:
+  - auto-secret.k8s.eboland.de
+  resources:
+  - autosecretreports
+  verbs:
+    - list
+    - create
+    - patch
+    - delete
+{{- end }}
+{{- if .Values.application.pushSecrets }}
+- apiGroups:
+  - external-secrets.io
+  resources:
+  - pushsecrets
+  verbs:
+    - get
+    - create
+    - patch
+{{- end }}
+{{- if or .Values.application.certManager .Values.application.certManagerIssuer }}
+- apiGroups:
+  - cert-manager.io
+  resources:
+  - certificates
+  verbs:
+    - get
+    - create
+    - patch
+{{- end }}
+{{- end }}
@@ -0,0 +1,254 @@
+// Audit records of generated keys, written to `--audit-sink` and sent to the
+// `--notify` targets. Records name keys and settings, never values.
+
+use crate::{
+    controller::Context,
+    error::Error,
+    generate::SecretData,
+    opts::Opts,
+    settings::{Policy, Settings},
+};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap, fs::OpenOptions, io::Write,
+    os::unix::net::UnixDatagram, str::FromStr,
+};
+
+// Where audit records go, set with `--audit-sink`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum AuditSink {
+    // `file:<path>`, appended to as JSON lines.
+    File(String),
+    // `syslog`, sent to the local syslog socket with the authpriv facility.
+    Syslog,
+    // `https://...`, each record POSTed as JSON.
+    Https(String),
+}
+
+impl TryFrom<String> for AuditSink {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, String> {
+        src.parse()
+    }
+}
+
+impl FromStr for AuditSink {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        if let Some(path) = src.strip_prefix("file:") {
+            Ok(AuditSink::File(path.to_string()))
+        } else if src == "syslog" {
+            Ok(AuditSink::Syslog)
+        } else if src.starts_with("https://") {
+            Ok(AuditSink::Https(src.to_string()))
+        } else {
+            Err(format!(
+                "invalid audit sink {:?}, expected file:<path>, syslog or an \
+                 https:// URL",
+                src
+            ))
+        }
+    }
+}
+
+// A record of generated or rotated keys. It never contains values.
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    actor: &'a str,
+    action: &'static str,
+    namespace: &'a str,
+    secret: &'a str,
+    keys: &'a [String],
+    policy: Option<Policy>,
+}
+
+struct Audit<'a> {
+    sink: &'a AuditSink,
+    http: &'a reqwest::Client,
+}
+
+impl Audit<'_> {
+    // Failing to write a record is logged, but does not fail the reconcile.
+    async fn record(&self, record: &AuditRecord<'_>) {
+        if let Err(e) = self.write(record).await {
+            warn!("failed to write audit record {:?}: {}", record, e);
+        }
+    }
+
+    async fn write(&self, record: &AuditRecord<'_>) -> Result<(), Error> {
+        let line = serde_json::to_string(record)?;
+        match self.sink {
+            AuditSink::File(path) => {
+                let mut file =
+                    OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)?;
+            }
+            AuditSink::Syslog => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect("/dev/log")?;
+                // authpriv.info
+                socket.send(
+                    format!("<86>auto-secret-operator: {}", line).as_bytes(),
+                )?;
+            }
+            AuditSink::Https(url) => {
+                self.http
+                    .post(url)
+                    .json(record)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Receivers of a notification whenever keys are generated or rotated, set
+// with `--notify`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum Notifier {
+    // `webhook=<url>`, POSTed the audit record as JSON.
+    Webhook(String),
+    // `slack=<url>`, a Slack incoming webhook POSTed a message.
+    Slack(String),
+}
+
+impl TryFrom<String> for Notifier {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, String> {
+        src.parse()
+    }
+}
+
+impl FromStr for Notifier {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src.split_once('=') {
+            Some(("webhook", url)) => Ok(Notifier::Webhook(url.to_string())),
+            Some(("slack", url)) => Ok(Notifier::Slack(url.to_string())),
+            _ => Err(format!(
+                "invalid notifier {:?}, expected webhook=<url> or slack=<url>",
+                src
+            )),
+        }
+    }
+}
+
+impl Notifier {
+    fn payload(
+        &self,
+        record: &AuditRecord,
+    ) -> Result<serde_json::Value, Error> {
+        match self {
+            Notifier::Webhook(_) => Ok(serde_json::to_value(record)?),
+            Notifier::Slack(_) => {
+                let action = match record.action {
+                    "rotate" => "Rotated",
+                    _ => "Generated",
+                };
+                let keys: Vec<String> =
+                    record.keys.iter().map(|k| format!("`{}`", k)).collect();
+                Ok(serde_json::json!({
+                    "text": format!(
+                        "{} {} in secret `{}/{}`",
+                        action,
+                        keys.join(", "),
+                        record.namespace,
+                        record.secret
+                    ),
+                }))
+            }
+        }
+    }
+
+    // Like audit records, failed notifications are only logged.
+    async fn notify(&self, http: &reqwest::Client, record: &AuditRecord<'_>) {
+        let (kind, url) = match self {
+            Notifier::Webhook(url) => ("webhook", url),
+            Notifier::Slack(url) => ("slack", url),
+        };
+        let result = match self.payload(record) {
+            Ok(payload) => http
+                .post(url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map(|_| ())
+                // Slack webhook URLs contain a token.
+                .map_err(|e| Error::from(e.without_url())),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("failed to send {} notification: {}", kind, e);
+        }
+    }
+}
+
+// Writes audit records and sends notifications for the keys written to a
+// secret.
+pub(crate) async fn publish_records(
+    ctx: &Context,
+    opts: &Opts,
+    namespace: &str,
+    secret: &str,
+    records: Vec<(&'static str, Option<Policy>, Vec<String>)>,
+) {
+    for (action, policy, keys) in records {
+        let record = AuditRecord {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            actor: app_id!(),
+            action,
+            namespace,
+            secret,
+            keys: &keys,
+            policy,
+        };
+        if let Some(sink) = &opts.audit_sink {
+            let audit = Audit {
+                sink,
+                http: &ctx.http,
+            };
+            audit.record(&record).await;
+        }
+        for notifier in &opts.notify {
+            notifier.notify(&ctx.http, &record).await;
+        }
+    }
+}
+
+// Groups the keys written to a secret by action and the policy they were
+// generated under.
+pub(crate) fn audit_records(
+    opts: &Opts,
+    settings: &BTreeMap<String, Settings>,
+    old_data: &SecretData,
+    data: &SecretData,
+) -> Vec<(&'static str, Option<Policy>, Vec<String>)> {
+    let mut records: BTreeMap<_, Vec<String>> = BTreeMap::new();
+    for key in data.keys() {
+        let action = match old_data.contains_key(key) {
+            true => "rotate",
+            false => "generate",
+        };
+        let policy = settings.get(key).and_then(|s| s.defaults(opts).policy);
+        records
+            .entry((action, policy))
+            .or_default()
+            .push(key.clone());
+    }
+    records
+        .into_iter()
+        .map(|((action, policy), keys)| (action, policy, keys))
+        .collect()
+}
@@ -0,0 +1,496 @@
+// Mirrors generated values to AWS Secrets Manager, for secrets annotated
+// with `aws-secret`. The operator assumes the IAM role of its service account
+// (IRSA) with the projected token and signs its requests with Signature
+// Version 4.
+
+use crate::{
+    controller::wipe,
+    crypto::{hmac_sha256, sha256},
+    error::Error,
+    generate::{hex, url_query_escape, SecretData},
+    opts::Opts,
+    store::{from_strings, string_values, SecretStore, StoreWrite},
+};
+use futures_util::future::BoxFuture;
+use k8s_openapi::chrono::Utc;
+use std::{collections::BTreeMap, str::FromStr, sync::Mutex};
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+const AWS_TIMEOUT: Duration = Duration::from_secs(10);
+// Lifetime of the assumed role's credentials, renewed after two thirds.
+const SESSION_DURATION: Duration = Duration::from_secs(3600);
+const NOT_FOUND: &str = "ResourceNotFoundException";
+
+// A tag put on the secrets in Secrets Manager, given as `<key>=<value>`.
+#[derive(Debug, Clone)]
+pub(crate) struct Tag {
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Tag {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!("invalid tag {:?}, expected <key>=<value>", src)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key: String,
+    secret_key: Zeroizing<String>,
+    session_token: Zeroizing<String>,
+}
+
+pub(crate) struct SecretsManager {
+    region: String,
+    role_arn: String,
+    token_file: String,
+    tags: Vec<Tag>,
+    client: reqwest::Client,
+    // The assumed role's credentials with the time they are renewed at.
+    credentials: Mutex<Option<(Credentials, Instant)>>,
+}
+
+impl SecretsManager {
+    pub(crate) fn new(
+        region: &str,
+        opts: &Opts,
+    ) -> Result<SecretsManager, Error> {
+        // The EKS pod identity webhook sets these for service accounts
+        // annotated with a role.
+        let irsa = |flag: &Option<String>, var: &str| {
+            flag.clone()
+                .or_else(|| std::env::var(var).ok())
+                .ok_or_else(|| {
+                    Error::Aws(format!(
+                        "{} is not set, is IRSA configured?",
+                        var
+                    ))
+                })
+        };
+        let client = reqwest::Client::builder()
+            .timeout(AWS_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Aws(e.to_string()))?;
+        Ok(SecretsManager {
+            region: region.to_string(),
+            role_arn: irsa(&opts.aws_role_arn, "AWS_ROLE_ARN")?,
+            token_file: irsa(
+                &opts.aws_web_identity_token_file,
+                "AWS_WEB_IDENTITY_TOKEN_FILE",
+            )?,
+            tags: opts
+                .aws_tags
+                .iter()
+                .map(|tag| tag.parse().map_err(Error::Aws))
+                .collect::<Result<_, _>>()?,
+            client,
+            credentials: Mutex::new(None),
+        })
+    }
+
+    // Stores `data` as the current version of the secret `secret_id`, a name
+    // or ARN, creating it if it doesn't exist. `version` makes retries of the
+    // same write idempotent. Returns the id of the stored version.
+    async fn put(
+        &self,
+        secret_id: &str,
+        tags: &[Tag],
+        data: &SecretData,
+        version: &str,
+    ) -> Result<String, Error> {
+        let tags: Vec<_> = self
+            .tags
+            .iter()
+            .chain(tags)
+            .map(|t| serde_json::json!({"Key": t.key, "Value": t.value}))
+            .collect();
+        let mut values = serde_json::Value::Object(string_values(data));
+        let secret_string = Zeroizing::new(values.to_string());
+        wipe(&mut values);
+        let put = self
+            .call(
+                "PutSecretValue",
+                serde_json::json!({
+                    "SecretId": secret_id,
+                    "SecretString": secret_string.as_str(),
+                    "ClientRequestToken": version,
+                }),
+            )
+            .await;
+        let stored = match put {
+            Err(AwsError::Api(type_, _)) if type_ == NOT_FOUND => {
+                return self
+                    .call(
+                        "CreateSecret",
+                        serde_json::json!({
+                            "Name": secret_id,
+                            "SecretString": secret_string.as_str(),
+                            "ClientRequestToken": version,
+                            "Tags": tags,
+                        }),
+                    )
+                    .await
+                    .map(version_id)
+                    .map_err(Error::from);
+            }
+            put => put?,
+        };
+        // Existing secrets get tags added since they were created.
+        self.call(
+            "TagResource",
+            serde_json::json!({"SecretId": secret_id, "Tags": tags}),
+        )
+        .await?;
+        Ok(version_id(stored))
+    }
+
+    async fn get(&self, secret_id: &str) -> Result<SecretData, Error> {
+        let request = serde_json::json!({"SecretId": secret_id});
+        let mut value = match self.call("GetSecretValue", request).await {
+            Err(AwsError::Api(type_, _)) if type_ == NOT_FOUND => {
+                return Ok(SecretData::default())
+            }
+            value => value?,
+        };
+        let secret_string =
+            Zeroizing::new(value["SecretString"].as_str().map(str::to_string));
+        wipe(&mut value);
+        let Some(secret_string) = &*secret_string else {
+            return Ok(SecretData::default());
+        };
+        let mut values: serde_json::Value =
+            serde_json::from_str(secret_string)?;
+        let data = values.as_object().map(from_strings).unwrap_or_default();
+        wipe(&mut values);
+        Ok(data)
+    }
+
+    // The version in the `AWSCURRENT` stage.
+    async fn current_version(
+        &self,
+        secret_id: &str,
+    ) -> Result<Option<String>, Error> {
+        let request = serde_json::json!({"SecretId": secret_id});
+        let described = match self.call("DescribeSecret", request).await {
+            Err(AwsError::Api(type_, _)) if type_ == NOT_FOUND => {
+                return Ok(None)
+            }
+            described => described?,
+        };
+        let versions = described["VersionIdsToStages"].as_object();
+        Ok(versions
+            .into_iter()
+            .flatten()
+            .find_map(|(version, stages)| {
+                let stages = stages.as_array()?;
+                stages
+                    .iter()
+                    .any(|stage| stage == "AWSCURRENT")
+                    .then(|| version.clone())
+            }))
+    }
+
+    // Calls the Secrets Manager `action` and returns its response.
+    async fn call(
+        &self,
+        action: &str,
+        mut request: serde_json::Value,
+    ) -> Result<serde_json::Value, AwsError> {
+        let credentials = self.credentials().await?;
+        let host = format!("secretsmanager.{}.amazonaws.com", self.region);
+        let body = Zeroizing::new(request.to_string().into_bytes());
+        wipe(&mut request);
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let target = format!("secretsmanager.{}", action);
+        let headers = BTreeMap::from([
+            ("content-type", "application/x-amz-json-1.1"),
+            ("host", host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+            ("x-amz-security-token", credentials.session_token.as_str()),
+            ("x-amz-target", target.as_str()),
+        ]);
+        let authorization = authorization(
+            &credentials,
+            &self.region,
+            "secretsmanager",
+            &headers,
+            &body,
+        );
+        let mut builder = self.client.post(format!("https://{}/", host));
+        for (name, value) in headers.iter().filter(|(n, _)| **n != "host") {
+            builder = builder.header(*name, *value);
+        }
+        let response = builder
+            .header("authorization", authorization)
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(AwsError::Http)?;
+        let status = response.status();
+        let response: serde_json::Value =
+            response.json().await.map_err(AwsError::Http)?;
+        if status.is_success() {
+            return Ok(response);
+        }
+        // Errors name their type like `ResourceNotFoundException`, with the
+        // namespace of the service sometimes prefixed.
+        let type_ = response["__type"].as_str().unwrap_or_default();
+        let type_ = type_.rsplit('#').next().unwrap_or_default();
+        let message = response["message"]
+            .as_str()
+            .or(response["Message"].as_str())
+            .unwrap_or_default();
+        Err(AwsError::Api(type_.to_string(), message.to_string()))
+    }
+
+    // The assumed role's credentials, assuming it again once they are about
+    // to expire.
+    async fn credentials(&self) -> Result<Credentials, Error> {
+        if let Some((credentials, renew)) = &*self.credentials.lock().unwrap() {
+            if Instant::now() < *renew {
+                return Ok(credentials.clone());
+            }
+        }
+        let token = Zeroizing::new(std::fs::read_to_string(&self.token_file)?);
+        let params = [
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("Version", "2011-06-15"),
+            ("RoleArn", self.role_arn.as_str()),
+            ("RoleSessionName", "auto-secret-operator"),
+            ("DurationSeconds", &SESSION_DURATION.as_secs().to_string()),
+            ("WebIdentityToken", token.trim()),
+        ];
+        let form: Vec<_> = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_query_escape(v.as_bytes())))
+            .collect();
+        let form = Zeroizing::new(form.join("&"));
+        // STS answers with JSON instead of XML when asked to.
+        let response = self
+            .client
+            .post(format!("https://sts.{}.amazonaws.com/", self.region))
+            .header("accept", "application/json")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form.to_string())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| Error::Aws(e.without_url().to_string()))?;
+        let mut response: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| Error::Aws(e.without_url().to_string()))?;
+        let assumed = &response["AssumeRoleWithWebIdentityResponse"]
+            ["AssumeRoleWithWebIdentityResult"]["Credentials"];
+        let field = |name: &str| assumed[name].as_str().map(str::to_string);
+        let credentials = match (
+            field("AccessKeyId"),
+            field("SecretAccessKey"),
+            field("SessionToken"),
+        ) {
+            (Some(access_key), Some(secret_key), Some(session_token)) => {
+                Some(Credentials {
+                    access_key,
+                    secret_key: Zeroizing::new(secret_key),
+                    session_token: Zeroizing::new(session_token),
+                })
+            }
+            _ => None,
+        };
+        wipe(&mut response);
+        let credentials = credentials.ok_or_else(|| {
+            Error::Aws("STS answered without credentials".to_string())
+        })?;
+        let renew = Instant::now() + SESSION_DURATION * 2 / 3;
+        *self.credentials.lock().unwrap() = Some((credentials.clone(), renew));
+        Ok(credentials)
+    }
+}
+
+enum AwsError {
+    Api(String, String),
+    Http(reqwest::Error),
+    Other(Error),
+}
+
+impl From<Error> for AwsError {
+    fn from(e: Error) -> Self {
+        AwsError::Other(e)
+    }
+}
+
+impl From<AwsError> for Error {
+    fn from(e: AwsError) -> Self {
+        match e {
+            AwsError::Api(type_, message) => {
+                Error::Aws(format!("{}: {}", type_, message))
+            }
+            AwsError::Http(e) => Error::Aws(e.without_url().to_string()),
+            AwsError::Other(e) => e,
+        }
+    }
+}
+
+impl SecretStore for SecretsManager {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    fn annotation(&self) -> &'static str {
+        "aws-secret"
+    }
+
+    // Secrets Manager holds all managed keys in one version, identified by
+    // their checksum, so that retried writes don't add versions.
+    fn write<'a>(
+        &'a self,
+        write: &'a StoreWrite<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move {
+            let tags = [
+                Tag {
+                    key: app_id!("namespace").to_string(),
+                    value: write.namespace.to_string(),
+                },
+                Tag {
+                    key: app_id!("name").to_string(),
+                    value: write.name.to_string(),
+                },
+            ];
+            let version = self
+                .put(write.location, &tags, write.managed, write.checksum)
+                .await?;
+            Ok(Some(version))
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<SecretData, Error>> {
+        Box::pin(self.get(location))
+    }
+
+    // Schedules the deletion after the default recovery window.
+    fn delete<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let request = serde_json::json!({"SecretId": location});
+            match self.call("DeleteSecret", request).await {
+                Err(AwsError::Api(type_, _)) if type_ == NOT_FOUND => Ok(()),
+                deleted => deleted.map(|_| ()).map_err(Error::from),
+            }
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(self.current_version(location))
+    }
+}
+
+fn version_id(response: serde_json::Value) -> String {
+    response["VersionId"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The Signature Version 4 authorization header of a POST request to `/`,
+// signing all `headers`, which include `x-amz-date`.
+fn authorization(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    headers: &BTreeMap<&str, &str>,
+    body: &[u8],
+) -> String {
+    let amz_date = headers["x-amz-date"];
+    let scope =
+        format!("{}/{}/{}/aws4_request", &amz_date[..8], region, service);
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers: Vec<_> = headers.keys().copied().collect();
+    let signed_headers = signed_headers.join(";");
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex(&sha256(body))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&sha256(canonical_request.as_bytes()))
+    );
+    let secret = Zeroizing::new(format!("AWS4{}", *credentials.secret_key));
+    let key = [&amz_date[..8], region, service, "aws4_request"]
+        .iter()
+        .fold(Zeroizing::new(secret.as_bytes().to_vec()), |key, part| {
+            Zeroizing::new(hmac_sha256(&key, part.as_bytes()))
+        });
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key,
+        scope,
+        signed_headers,
+        hex(&hmac_sha256(&key, string_to_sign.as_bytes()))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // The post-vanilla case of the AWS Signature Version 4 test suite.
+        let credentials = Credentials {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: Zeroizing::new(
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            ),
+            session_token: Zeroizing::default(),
+        };
+        let headers = BTreeMap::from([
+            ("host", "example.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ]);
+        assert_eq!(
+            authorization(&credentials, "us-east-1", "service", &headers, b""),
+            "AWS4-HMAC-SHA256 \
+             Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=\
+             5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    #[test]
+    fn tags() {
+        let tag: Tag = "team=payments".parse().unwrap();
+        assert_eq!(
+            (tag.key.as_str(), tag.value.as_str()),
+            ("team", "payments")
+        );
+        assert!("=payments".parse::<Tag>().is_err());
+        assert!("team".parse::<Tag>().is_err());
+    }
+}
@@ -0,0 +1,370 @@
+// Writes generated values to Azure Key Vault secrets, one per key, as mapped
+// in the `azure-key-vault` annotation. The operator authenticates with
+// Azure AD workload identity when its pod is set up for it, and with the
+// managed identity of the node otherwise.
+
+use crate::{
+    controller::wipe,
+    error::Error,
+    generate::{url_query_escape, SecretData},
+    settings::parse_value,
+    store::{location_annotation, string_values, SecretStore, StoreWrite},
+};
+use futures_util::future::BoxFuture;
+use k8s_openapi::ByteString;
+use reqwest::{Method, StatusCode};
+use std::{collections::BTreeMap, sync::Mutex};
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+const AZURE_TIMEOUT: Duration = Duration::from_secs(10);
+const API_VERSION: &str = "7.4";
+const SCOPE: &str = "https://vault.azure.net";
+const IMDS_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/\
+                        token?api-version=2018-02-01";
+
+// The Azure AD application and token set up by the workload identity
+// webhook.
+struct WorkloadIdentity {
+    authority: String,
+    tenant: String,
+    client_id: String,
+    token_file: String,
+}
+
+pub(crate) struct KeyVault {
+    url: String,
+    workload_identity: Option<WorkloadIdentity>,
+    // The client of a user assigned managed identity.
+    client_id: Option<String>,
+    client: reqwest::Client,
+    // The access token with the time it is renewed at.
+    token: Mutex<Option<(Zeroizing<String>, Instant)>>,
+}
+
+impl KeyVault {
+    // The Key Vault `vault`, given by name or URL.
+    pub(crate) fn new(vault: &str) -> Result<KeyVault, Error> {
+        let url = match vault.starts_with("https://") {
+            true => vault.trim_end_matches('/').to_string(),
+            false => format!("https://{}.vault.azure.net", vault),
+        };
+        let env = |var: &str| std::env::var(var).ok();
+        let workload_identity =
+            env("AZURE_FEDERATED_TOKEN_FILE").and_then(|token_file| {
+                Some(WorkloadIdentity {
+                    authority: env("AZURE_AUTHORITY_HOST").unwrap_or_else(
+                        || "https://login.microsoftonline.com/".to_string(),
+                    ),
+                    tenant: env("AZURE_TENANT_ID")?,
+                    client_id: env("AZURE_CLIENT_ID")?,
+                    token_file,
+                })
+            });
+        let client = reqwest::Client::builder()
+            .timeout(AZURE_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Azure(e.to_string()))?;
+        Ok(KeyVault {
+            url,
+            workload_identity,
+            client_id: env("AZURE_CLIENT_ID"),
+            client,
+            token: Mutex::new(None),
+        })
+    }
+
+    // Sets each key of `data` that `mapping` names a Key Vault secret for,
+    // tagged with `tags`. Returns the versions as `<secret>/<version>`.
+    async fn put(
+        &self,
+        mapping: &BTreeMap<String, String>,
+        tags: &BTreeMap<String, String>,
+        data: &SecretData,
+    ) -> Result<Vec<String>, Error> {
+        let mut values = string_values(data);
+        let mut versions = vec![];
+        let mut written = Ok(());
+        for (key, name) in mapping {
+            let Some(value) = values.get(key) else {
+                continue;
+            };
+            let mut body = serde_json::json!({"value": value, "tags": tags});
+            let set = self.request(Method::PUT, name, Some(&body)).await;
+            wipe(&mut body);
+            match set {
+                Ok(Some(mut set)) => {
+                    versions.push(secret_version(name, &set));
+                    wipe(&mut set);
+                }
+                Ok(None) => {
+                    written =
+                        Err(Error::Azure(format!("{} not found", self.url)));
+                    break;
+                }
+                Err(e) => {
+                    written = Err(e);
+                    break;
+                }
+            }
+        }
+        values.values_mut().for_each(wipe);
+        written.map(|_| versions)
+    }
+
+    async fn get(
+        &self,
+        mapping: &BTreeMap<String, String>,
+    ) -> Result<SecretData, Error> {
+        let mut data = SecretData::default();
+        for (key, name) in mapping {
+            let Some(mut secret) =
+                self.request(Method::GET, name, None).await?
+            else {
+                continue;
+            };
+            if let Some(value) = secret["value"].as_str() {
+                let value = ByteString(value.as_bytes().to_vec());
+                data.insert(key.clone(), value);
+            }
+            wipe(&mut secret);
+        }
+        Ok(data)
+    }
+
+    async fn current_versions(
+        &self,
+        mapping: &BTreeMap<String, String>,
+    ) -> Result<Option<String>, Error> {
+        let mut versions = vec![];
+        for name in mapping.values() {
+            if let Some(mut secret) =
+                self.request(Method::GET, name, None).await?
+            {
+                versions.push(secret_version(name, &secret));
+                wipe(&mut secret);
+            }
+        }
+        Ok((!versions.is_empty()).then(|| versions.join(",")))
+    }
+
+    fn mapping(
+        &self,
+        location: &str,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        key_vault_mapping(&location_annotation(self), location)
+    }
+
+    // Sends `body` to the Key Vault secret `name`, returning nothing if it
+    // doesn't exist.
+    async fn request(
+        &self,
+        method: Method,
+        name: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>, Error> {
+        let token = self.token().await?;
+        let url = format!(
+            "{}/secrets/{}?api-version={}",
+            self.url, name, API_VERSION
+        );
+        let mut request =
+            self.client.request(method, url).bearer_auth(token.as_str());
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let failed = |e: reqwest::Error| {
+            Error::Azure(format!("secret {}: {}", name, e.without_url()))
+        };
+        let response = request.send().await.map_err(failed)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(failed)?;
+        Ok(Some(response.json().await.map_err(failed)?))
+    }
+
+    // The access token, fetched again once it is about to expire.
+    async fn token(&self) -> Result<Zeroizing<String>, Error> {
+        if let Some((token, renew)) = &*self.token.lock().unwrap() {
+            if Instant::now() < *renew {
+                return Ok(token.clone());
+            }
+        }
+        let request = match &self.workload_identity {
+            Some(identity) => {
+                let assertion = Zeroizing::new(std::fs::read_to_string(
+                    &identity.token_file,
+                )?);
+                let params = [
+                    ("grant_type", "client_credentials"),
+                    ("client_id", identity.client_id.as_str()),
+                    (
+                        "client_assertion_type",
+                        "urn:ietf:params:oauth:client-assertion-type:\
+                         jwt-bearer",
+                    ),
+                    ("client_assertion", assertion.trim()),
+                    ("scope", &format!("{}/.default", SCOPE)),
+                ];
+                let form: Vec<_> = params
+                    .iter()
+                    .map(|(k, v)| {
+                        format!("{}={}", k, url_query_escape(v.as_bytes()))
+                    })
+                    .collect();
+                self.client
+                    .post(format!(
+                        "{}/{}/oauth2/v2.0/token",
+                        identity.authority.trim_end_matches('/'),
+                        identity.tenant
+                    ))
+                    .header("content-type", "application/x-www-form-urlencoded")
+                    .body(Zeroizing::new(form.join("&")).to_string())
+            }
+            None => {
+                let mut url = format!(
+                    "{}&resource={}",
+                    IMDS_URL,
+                    url_query_escape(SCOPE.as_bytes())
+                );
+                if let Some(client_id) = &self.client_id {
+                    url.push_str("&client_id=");
+                    url.push_str(&url_query_escape(client_id.as_bytes()));
+                }
+                self.client.get(url).header("Metadata", "true")
+            }
+        };
+        let mut response: serde_json::Value = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| Error::Azure(e.without_url().to_string()))?
+            .json()
+            .await
+            .map_err(|e| Error::Azure(e.without_url().to_string()))?;
+        let token = response["access_token"]
+            .as_str()
+            .map(|token| Zeroizing::new(token.to_string()));
+        // The managed identity endpoint answers with a string.
+        let expires_in = match &response["expires_in"] {
+            serde_json::Value::String(s) => s.parse().unwrap_or(0),
+            expires_in => expires_in.as_u64().unwrap_or(0),
+        };
+        wipe(&mut response);
+        let token = token.ok_or_else(|| {
+            Error::Azure("answered without an access token".to_string())
+        })?;
+        // Tokens are renewed after two thirds of their lifetime.
+        let renew = Instant::now() + Duration::from_secs(expires_in * 2 / 3);
+        *self.token.lock().unwrap() = Some((token.clone(), renew));
+        Ok(token)
+    }
+}
+
+impl SecretStore for KeyVault {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    fn annotation(&self) -> &'static str {
+        "azure-key-vault"
+    }
+
+    fn validate(&self, location: &str) -> Result<(), Error> {
+        self.mapping(location).map(|_| ())
+    }
+
+    // Each key is a Key Vault secret of its own, with versions of its own.
+    fn write<'a>(
+        &'a self,
+        write: &'a StoreWrite<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move {
+            let tags = BTreeMap::from([
+                ("managed-by".to_string(), "auto-secret-operator".to_string()),
+                ("namespace".to_string(), write.namespace.to_string()),
+                ("name".to_string(), write.name.to_string()),
+            ]);
+            let mapping = self.mapping(write.location)?;
+            let versions = self.put(&mapping, &tags, write.generated).await?;
+            Ok((!versions.is_empty()).then(|| versions.join(",")))
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<SecretData, Error>> {
+        Box::pin(async move { self.get(&self.mapping(location)?).await })
+    }
+
+    // Deletes the mapped secrets, which are kept as deleted secrets for the
+    // retention period of the Key Vault.
+    fn delete<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            for name in self.mapping(location)?.values() {
+                self.request(Method::DELETE, name, None).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move {
+            self.current_versions(&self.mapping(location)?).await
+        })
+    }
+}
+
+// `<name>/<version>` of the Key Vault secret `name`, from the id in `secret`.
+fn secret_version(name: &str, secret: &serde_json::Value) -> String {
+    let id = secret["id"].as_str().unwrap_or_default();
+    format!("{}/{}", name, id.rsplit('/').next().unwrap_or_default())
+}
+
+// The Key Vault secret names of keys in the `azure-key-vault` annotation,
+// which may only hold letters, digits and `-`.
+fn key_vault_mapping(
+    annotation: &str,
+    value: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    let mapping: BTreeMap<String, String> = parse_value(annotation, value)?;
+    let invalid = mapping.values().find(|name| {
+        name.is_empty()
+            || name.len() > 127
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    match invalid {
+        Some(name) => Err(Error::InvalidAnnotation(
+            annotation.to_string(),
+            format!("{:?} is not a valid Key Vault secret name", name),
+        )),
+        None => Ok(mapping),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping() {
+        let mapping = key_vault_mapping(
+            "azure-key-vault",
+            "password: app-db-password\nusername: app-db-user",
+        )
+        .unwrap();
+        assert_eq!(mapping["password"], "app-db-password");
+        assert!(
+            key_vault_mapping("azure-key-vault", "password: app.db").is_err()
+        );
+        assert!(key_vault_mapping("azure-key-vault", "[password]").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+// cert-manager Certificates for keys of `kind: tls`. With an issuer and
+// cert-manager installed, the operator maintains a Certificate for the
+// generated secret and leaves the certificate and its key to cert-manager,
+// while it keeps generating the other keys of the secret.
+
+use crate::{
+    controller::Context,
+    error::Error,
+    opts::Opts,
+    settings::{KeyErrors, Settings},
+};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// The keys cert-manager writes into the secret of a Certificate.
+const ISSUED_KEYS: [&str; 3] = ["tls.crt", "tls.key", "ca.crt"];
+
+// Only a positive answer is kept, so that installing cert-manager later is
+// picked up.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// The `issuerRef` of a Certificate, from an issuer given as
+// `[<kind>/]<name>`. The kind defaults to a namespaced `Issuer`.
+pub(crate) fn issuer_ref(issuer: &str) -> serde_json::Value {
+    let (kind, name) = issuer.split_once('/').unwrap_or(("Issuer", issuer));
+    serde_json::json!({
+        "group": "cert-manager.io",
+        "kind": kind,
+        "name": name,
+    })
+}
+
+// Whether the cluster serves cert-manager's Certificates.
+async fn installed(client: &Client) -> Result<bool, Error> {
+    if INSTALLED.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+    let installed =
+        match client.list_api_group_resources("cert-manager.io/v1").await {
+            Ok(list) => list.resources.iter().any(|r| r.name == "certificates"),
+            Err(kube::Error::Api(e)) if e.code == 404 => false,
+            Err(e) => return Err(e.into()),
+        };
+    INSTALLED.store(installed, Ordering::Relaxed);
+    Ok(installed)
+}
+
+// The Certificate specs of the keys in `settings` that have an issuer, from
+// the key or `--cert-manager-issuer`.
+fn certificates(
+    opts: &Opts,
+    settings: &BTreeMap<String, Settings>,
+) -> BTreeMap<String, Result<serde_json::Value, Error>> {
+    let mut certificates = BTreeMap::new();
+    for (key, s) in settings {
+        let Settings::Kind(kind) = s else {
+            continue;
+        };
+        let spec = match kind.generator.certificate(key) {
+            Some(Ok(mut spec)) => {
+                if spec.get("issuerRef").is_none() {
+                    let Some(issuer) = &opts.cert_manager_issuer else {
+                        continue;
+                    };
+                    spec["issuerRef"] = issuer_ref(issuer);
+                }
+                Ok(spec)
+            }
+            Some(Err(e)) => Err(e),
+            None => continue,
+        };
+        certificates.insert(key.clone(), spec);
+    }
+    certificates
+}
+
+fn certificate_resource() -> ApiResource {
+    let gvk = GroupVersionKind::gvk("cert-manager.io", "v1", "Certificate");
+    ApiResource::from_gvk_with_plural(&gvk, "certificates")
+}
+
+// Applies the Certificate of the secret `dest` for the keys of `settings`
+// with an issuer and takes them out of `settings`. Keys that can't be issued
+// fail. Without cert-manager, the keys are self-signed. Returns the keys
+// cert-manager writes.
+pub(crate) async fn issue_certificates(
+    ctx: &Context,
+    opts: &Opts,
+    source: &Secret,
+    dest: &Secret,
+    settings: &mut BTreeMap<String, Settings>,
+    failed: &mut KeyErrors,
+) -> Result<BTreeSet<String>, Error> {
+    let certificates = certificates(opts, settings);
+    if certificates.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    if !installed(&ctx.client).await? {
+        debug!("cert-manager is not installed, self-signing certificates");
+        return Ok(BTreeSet::new());
+    }
+    let name = dest.name_any();
+    let namespace = dest.namespace().unwrap_or_default();
+    let mut issued = None;
+    for (key, spec) in certificates {
+        settings.remove(&key);
+        // A secret is written by a single Certificate.
+        let spec = match (spec, &issued) {
+            (Ok(_), Some(other)) => Err(Error::Conflict(format!(
+                "{:?} is issued by cert-manager already",
+                other
+            ))),
+            (spec, _) => spec,
+        };
+        match spec {
+            Ok(spec) => {
+                issued = Some(key);
+                apply_certificate(ctx, source, &namespace, &name, spec).await?;
+            }
+            Err(e) => {
+                failed.insert(key, e);
+            }
+        }
+    }
+    Ok(match issued {
+        Some(_) => ISSUED_KEYS.iter().map(|key| key.to_string()).collect(),
+        None => BTreeSet::new(),
+    })
+}
+
+// Applies the Certificate `name` in `namespace` writing the secret of the
+// same name. It is garbage collected with `source` if they share the
+// namespace.
+async fn apply_certificate(
+    ctx: &Context,
+    source: &Secret,
+    namespace: &str,
+    name: &str,
+    mut spec: serde_json::Value,
+) -> Result<(), Error> {
+    let resource = certificate_resource();
+    let api = Api::<DynamicObject>::namespaced_with(
+        ctx.client.clone(),
+        namespace,
+        &resource,
+    );
+    spec["secretName"] = name.into();
+    let mut metadata = serde_json::json!({
+        "name": name,
+        "namespace": namespace,
+    });
+    if source.namespace().as_deref() == Some(namespace) {
+        if let Some(owner) = source.controller_owner_ref(&()) {
+            metadata["ownerReferences"] = serde_json::json!([owner]);
+        }
+    }
+    let patch = serde_json::json!({
+        "apiVersion": resource.api_version,
+        "kind": resource.kind,
+        "metadata": metadata,
+        "spec": spec,
+    });
+    let params = PatchParams::apply(app_id!()).force();
+    api.patch(name, &params, &Patch::Apply(&patch)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::parse_value;
+    use clap::Parser;
+
+    #[test]
+    fn issuers() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--cert-manager-issuer",
+            "ClusterIssuer/letsencrypt",
+        ]);
+        let settings: BTreeMap<String, Settings> = parse_value(
+            "gen",
+            "{tls.crt: {kind: tls, dns_names: [app.example.com]}, \
+             password: all}",
+        )
+        .unwrap();
+        let specs = certificates(&opts, &settings);
+        assert_eq!(specs.len(), 1);
+        let spec = specs["tls.crt"].as_ref().unwrap();
+        assert_eq!(spec["dnsNames"], serde_json::json!(["app.example.com"]));
+        assert_eq!(spec["issuerRef"]["kind"], "ClusterIssuer");
+        assert_eq!(spec["issuerRef"]["name"], "letsencrypt");
+
+        // Keys name their own issuer, and are self-signed without any.
+        let settings: BTreeMap<String, Settings> = parse_value(
+            "gen",
+            "{tls.crt: {kind: tls, common_name: app, issuer: internal}}",
+        )
+        .unwrap();
+        let spec = &certificates(&opts, &settings)["tls.crt"];
+        let spec = spec.as_ref().unwrap();
+        assert_eq!(spec["issuerRef"], issuer_ref("Issuer/internal"));
+        assert_eq!(spec["dnsNames"], serde_json::json!(["app"]));
+        let opts = Opts::parse_from(["auto-secret"]);
+        let settings: BTreeMap<String, Settings> =
+            parse_value("gen", "{tls.crt: {kind: tls, common_name: app}}")
+                .unwrap();
+        assert!(certificates(&opts, &settings).is_empty());
+    }
+
+    #[test]
+    fn issued_keys_only() {
+        let opts =
+            Opts::parse_from(["auto-secret", "--cert-manager-issuer", "ca"]);
+        let settings: BTreeMap<String, Settings> =
+            parse_value("gen", "{cert.pem: {kind: tls, common_name: app}}")
+                .unwrap();
+        assert!(certificates(&opts, &settings)["cert.pem"].is_err());
+    }
+}
@@ -0,0 +1,1119 @@
+// Subcommands that run once from the command line instead of starting the
+// controllers.
+
+use crate::{
+    controller::{
+        applied_checksums, applied_keys, check_settings, data_checksum,
+        data_elsewhere, list_params, reconcile_secret, watched_api, wipe,
+        Context, Rotation,
+    },
+    error::Error,
+    export::{decrypt, identities},
+    generate::{gen_standalone, SecretData},
+    opts::{GenerateOutput, Opts, ReportFormat},
+    settings::{
+        declared_keys, declared_settings, parse_annotation, parse_value,
+        Defaults, KeyCase, Policy, Settings,
+    },
+    store::annotated_stores,
+    template::split_secret_ref,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Namespace, Secret},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{DateTime, SecondsFormat, Utc},
+};
+use kube::{
+    api::{Api, ListParams, Patch, PatchParams, PostParams},
+    Client, Resource, ResourceExt,
+};
+use log::{info, warn};
+use serde::Serialize;
+use serde_yaml::{
+    value::{Tag, TaggedValue},
+    Mapping, Value,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+// Generates the keys of the gen annotation `settings` with the same code the
+// operator uses and renders them as `output`.
+pub(crate) fn generate(
+    opts: &Opts,
+    settings: &str,
+    defaults_file: Option<&str>,
+    output: GenerateOutput,
+    name: &str,
+    namespace: Option<&str>,
+) -> Result<String, Error> {
+    let opts = with_defaults(opts, defaults_file)?;
+    let keys = Settings::parse(settings)?;
+    let data = gen_standalone(&opts, &keys)?;
+    let rendered = match output {
+        GenerateOutput::Values => serde_yaml::to_string(&values(&data))?,
+        GenerateOutput::Secret => serde_yaml::to_string(&Secret {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                namespace: namespace.map(Into::into),
+                annotations: Some(BTreeMap::from([(
+                    app_id!("gen").to_string(),
+                    settings.into(),
+                )])),
+                ..ObjectMeta::default()
+            },
+            data: Some(data.0.clone()),
+            ..Secret::default()
+        })?,
+    };
+    Ok(rendered)
+}
+
+// Creates a secret declaring `keys` (`<key>[=<settings>]`) in per-key
+// annotations, and waits up to `timeout` for the operator to generate them.
+pub(crate) async fn create(
+    client: Client,
+    opts: &Opts,
+    name: &str,
+    namespace: Option<&str>,
+    keys: &[String],
+    timeout: Duration,
+) -> Result<(), Error> {
+    let namespace = namespace.unwrap_or(client.default_namespace()).to_string();
+    let annotations = keys
+        .iter()
+        .map(|key| {
+            let (key, settings) =
+                key.split_once('=').unwrap_or((key, "default"));
+            (format!("{}{}", app_id!("gen-"), key), settings.to_string())
+        })
+        .collect();
+    let meta = ObjectMeta {
+        name: Some(name.into()),
+        namespace: Some(namespace.clone()),
+        annotations: Some(annotations),
+        ..ObjectMeta::default()
+    };
+    // Refuse what the webhook would refuse before creating anything.
+    let settings = declared_settings(&meta)?.unwrap_or_default();
+    if let Some((_, e)) = check_settings(opts, &meta, &settings)?.pop_first() {
+        return Err(e);
+    }
+    let api = Api::<Secret>::namespaced(client, &namespace);
+    let secret = Secret {
+        metadata: meta,
+        ..Secret::default()
+    };
+    api.create(&PostParams::default(), &secret).await?;
+    println!("secret/{} created", name);
+    if timeout.is_zero() {
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let secret = api.get(name).await?;
+        let annotations = secret.annotations();
+        let mut errors: BTreeMap<String, String> = annotations
+            .get(app_id!("errors"))
+            .map(|errors| serde_json::from_str(errors))
+            .transpose()?
+            .unwrap_or_default();
+        if let Some(error) = annotations.get(app_id!("error")) {
+            errors.insert(name.into(), error.clone());
+        }
+        for (key, error) in &errors {
+            eprintln!("{}: {}", key, error);
+        }
+        if !errors.is_empty() {
+            return Err(Error::Problems(errors.len()));
+        }
+        let data = secret.data.unwrap_or_default();
+        if settings.keys().all(|key| data.contains_key(key)) {
+            println!("secret/{} generated", name);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::Timeout(format!(
+                "waiting for the operator to generate secret/{}",
+                name
+            )));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Lists the secrets in `namespace`, or in all namespaces, that declare keys.
+pub(crate) async fn list(
+    client: Client,
+    opts: &Opts,
+    namespace: Option<&str>,
+    all_namespaces: bool,
+) -> Result<(), Error> {
+    let api = match all_namespaces {
+        true => Api::<Secret>::all(client.clone()),
+        false => Api::namespaced(
+            client.clone(),
+            namespace.unwrap_or(client.default_namespace()),
+        ),
+    };
+    let defaults = cluster_defaults(&client, opts).await?;
+    let mut namespace_opts = BTreeMap::new();
+    let mut rows = vec![vec![
+        "NAMESPACE".to_string(),
+        "NAME".into(),
+        "GENERATED".into(),
+        "LAST GENERATED".into(),
+        "NEXT ROTATION".into(),
+    ]];
+    for secret in api.list(&ListParams::default()).await? {
+        let ns = secret.namespace().unwrap_or_default();
+        if !namespace_opts.contains_key(&ns) {
+            let opts = with_namespace(&client, opts, &defaults, &ns).await;
+            namespace_opts.insert(ns.clone(), opts);
+        }
+        let Some(keys) = key_states(&namespace_opts[&ns], &secret) else {
+            continue;
+        };
+        let generated = keys.iter().filter(|k| k.generated).count();
+        let last = keys.iter().filter_map(|k| k.generated_at.clone()).max();
+        let next = keys.iter().filter_map(|k| k.next_rotation.clone()).min();
+        rows.push(vec![
+            ns,
+            secret.name_any(),
+            format!("{}/{}", generated, keys.len()),
+            last.unwrap_or_else(|| "-".into()),
+            next.unwrap_or_else(|| "-".into()),
+        ]);
+    }
+    if !all_namespaces {
+        rows.iter_mut().for_each(|row| drop(row.remove(0)));
+    }
+    print!("{}", table(&rows));
+    Ok(())
+}
+
+// Shows the declared keys of a secret and the errors of those that could not
+// be generated.
+pub(crate) async fn inspect(
+    client: Client,
+    opts: &Opts,
+    name: &str,
+    namespace: Option<&str>,
+) -> Result<(), Error> {
+    let namespace = namespace.unwrap_or(client.default_namespace());
+    let secret = Api::<Secret>::namespaced(client.clone(), namespace)
+        .get(name)
+        .await?;
+    let defaults = cluster_defaults(&client, opts).await?;
+    let opts = with_namespace(&client, opts, &defaults, namespace).await;
+    let Some(keys) = key_states(&opts, &secret) else {
+        println!("secret/{} declares no keys", name);
+        return Ok(());
+    };
+    let mut rows = vec![vec![
+        "KEY".to_string(),
+        "GENERATED".into(),
+        "GENERATED AT".into(),
+        "NEXT ROTATION".into(),
+        "SETTINGS".into(),
+    ]];
+    for key in &keys {
+        rows.push(vec![
+            key.key.clone(),
+            if key.generated { "yes" } else { "no" }.into(),
+            key.generated_at.clone().unwrap_or_else(|| "-".into()),
+            key.next_rotation.clone().unwrap_or_else(|| "-".into()),
+            key.settings.clone(),
+        ]);
+    }
+    print!("{}", table(&rows));
+    if let Some(error) = secret.annotations().get(app_id!("error")) {
+        println!("\nerror: {}", error);
+    }
+    let errors: BTreeMap<String, String> = secret
+        .annotations()
+        .get(app_id!("errors"))
+        .and_then(|errors| serde_json::from_str(errors).ok())
+        .unwrap_or_default();
+    if !errors.is_empty() {
+        println!("\nerrors:");
+        for (key, error) in errors {
+            println!("  {}: {}", key, error);
+        }
+    }
+    let stores = annotated_stores(secret.meta());
+    if !stores.is_empty() {
+        println!("\nstores:");
+    }
+    for (store, location) in stores {
+        let version = store.version(&location).await?;
+        let stored = store.read(&location).await?;
+        // Stores hold binary values base64 encoded.
+        let held = |key: &String| secret.data.as_ref()?.get(key);
+        let in_sync = stored.iter().all(|(key, value)| {
+            held(key).is_some_and(|held| {
+                held.0 == value.0
+                    || BASE64_STANDARD.encode(&held.0).as_bytes() == value.0
+            })
+        });
+        println!(
+            "  {} {}: version {}, {}",
+            store.name(),
+            location.replace('\n', " "),
+            version.as_deref().unwrap_or("-"),
+            if in_sync { "in sync" } else { "out of sync" }
+        );
+    }
+    Ok(())
+}
+
+// Reports the annotated secrets of all namespaces that are missing values
+// and, with `apply`, generates them by reconciling the secrets one by one at
+// `rate` secrets per second.
+pub(crate) async fn bootstrap(
+    ctx: &Arc<Context>,
+    apply: bool,
+    rate: u32,
+) -> Result<(), Error> {
+    let api = watched_api::<Secret>(&ctx.client, &ctx.opts);
+    let incomplete: Vec<(Secret, Vec<String>)> = api
+        .list(&list_params::<Secret>(&ctx.opts))
+        .await?
+        .into_iter()
+        .filter(|secret| {
+            ctx.opts
+                .watches_namespace(&secret.namespace().unwrap_or_default())
+        })
+        .filter_map(|secret| {
+            let missing = missing_keys(&ctx.opts, &secret)?;
+            Some((secret, missing))
+        })
+        .collect();
+    let mut rows = vec![vec![
+        "NAMESPACE".to_string(),
+        "NAME".into(),
+        "MISSING".into(),
+    ]];
+    for (secret, missing) in &incomplete {
+        rows.push(vec![
+            secret.namespace().unwrap_or_default(),
+            secret.name_any(),
+            missing.join(","),
+        ]);
+    }
+    print!("{}", table(&rows));
+    if !apply {
+        println!(
+            "\n{} secrets are missing values, run with --apply to generate them",
+            incomplete.len()
+        );
+        return Ok(());
+    }
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / rate);
+    let (mut completed, mut failed) = (0, 0);
+    for (secret, _) in &incomplete {
+        ticks.tick().await;
+        let ns = secret.namespace().unwrap_or_default();
+        let name = secret.name_any();
+        let secret = Arc::new(secret.clone());
+        if let Err(e) = reconcile_secret(secret, ctx.clone()).await {
+            eprintln!("{}/{}: {}", ns, name, e);
+            failed += 1;
+            continue;
+        }
+        let api = Api::<Secret>::namespaced(ctx.client.clone(), &ns);
+        match missing_keys(&ctx.opts, &api.get(&name).await?) {
+            Some(missing) => {
+                eprintln!(
+                    "{}/{}: still missing {}",
+                    ns,
+                    name,
+                    missing.join(",")
+                );
+                failed += 1;
+            }
+            None => completed += 1,
+        }
+    }
+    println!(
+        "\ngenerated the values of {} of {} secrets, {} failed",
+        completed,
+        incomplete.len(),
+        failed
+    );
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::Unreconciled(failed)),
+    }
+}
+
+// The declared keys of `secret` without a value, or `None` if there are
+// none or the values are kept in other secrets.
+fn missing_keys(opts: &Opts, secret: &Secret) -> Option<Vec<String>> {
+    if data_elsewhere(secret.meta()) {
+        return None;
+    }
+    let missing: Vec<String> = key_states(opts, secret)?
+        .into_iter()
+        .filter(|key| !key.generated)
+        .map(|key| key.key)
+        .collect();
+    (!missing.is_empty()).then_some(missing)
+}
+
+// Prints the inventory of all managed secrets as `format`.
+pub(crate) async fn report(
+    client: Client,
+    opts: &Opts,
+    format: ReportFormat,
+) -> Result<(), Error> {
+    print!("{}", render(&inventory(&client, opts).await?, format)?);
+    Ok(())
+}
+
+// Writes the inventory to `--report-file` every `--report-interval`, for as
+// long as the controllers run.
+pub(crate) async fn write_reports(ctx: Arc<Context>, path: String) {
+    let mut ticks = tokio::time::interval(ctx.opts.report_interval.0);
+    loop {
+        ticks.tick().await;
+        let opts = ctx.configured();
+        let report = match inventory(&ctx.client, &opts).await {
+            Ok(inventory) => render(&inventory, opts.report_format),
+            Err(e) => Err(e),
+        };
+        // Readers never see a partially written report.
+        let partial = format!("{}.tmp", path);
+        let written = report.and_then(|report| {
+            std::fs::write(&partial, report)?;
+            Ok(std::fs::rename(&partial, &path)?)
+        });
+        match written {
+            Ok(()) => info!("wrote inventory report to {}", path),
+            Err(e) => warn!("failed to write inventory report: {}", e),
+        }
+    }
+}
+
+// A managed secret in the inventory report.
+#[derive(Serialize)]
+pub(crate) struct SecretReport {
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+    pub(crate) keys: Vec<KeyState>,
+}
+
+// The managed secrets of all watched namespaces.
+pub(crate) async fn inventory(
+    client: &Client,
+    opts: &Opts,
+) -> Result<Vec<SecretReport>, Error> {
+    let defaults = cluster_defaults(client, opts).await?;
+    let mut namespace_opts = BTreeMap::new();
+    let mut inventory = vec![];
+    let secrets = watched_api::<Secret>(client, opts);
+    for secret in secrets.list(&ListParams::default()).await? {
+        let ns = secret.namespace().unwrap_or_default();
+        if !namespace_opts.contains_key(&ns) {
+            let opts = with_namespace(client, opts, &defaults, &ns).await;
+            namespace_opts.insert(ns.clone(), opts);
+        }
+        let Some(keys) = key_states(&namespace_opts[&ns], &secret) else {
+            continue;
+        };
+        inventory.push(SecretReport {
+            namespace: ns,
+            name: secret.name_any(),
+            keys,
+        });
+    }
+    Ok(inventory)
+}
+
+// Renders the inventory as a JSON array of secrets, or as CSV with a row per
+// key.
+fn render(
+    inventory: &[SecretReport],
+    format: ReportFormat,
+) -> Result<String, Error> {
+    if format == ReportFormat::Json {
+        return Ok(serde_json::to_string_pretty(inventory)? + "\n");
+    }
+    let mut csv = String::from(
+        "namespace,name,key,kind,generated,generated_at,age_seconds,\
+         next_rotation\n",
+    );
+    for secret in inventory {
+        for key in &secret.keys {
+            let fields = [
+                secret.namespace.clone(),
+                secret.name.clone(),
+                key.key.clone(),
+                key.kind.clone(),
+                key.generated.to_string(),
+                key.generated_at.clone().unwrap_or_default(),
+                key.age_seconds.map(|a| a.to_string()).unwrap_or_default(),
+                key.next_rotation.clone().unwrap_or_default(),
+            ];
+            let fields: Vec<String> =
+                fields.iter().map(|f| csv_field(f)).collect();
+            csv += &fields.join(",");
+            csv.push('\n');
+        }
+    }
+    Ok(csv)
+}
+
+// Quotes a CSV field if it has to be (RFC 4180).
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+// What `list`, `inspect` and `report` show about a declared key. The
+// settings are left out of reports, they may hold literal values.
+#[derive(Serialize)]
+pub(crate) struct KeyState {
+    pub(crate) key: String,
+    pub(crate) kind: String,
+    pub(crate) generated: bool,
+    pub(crate) generated_at: Option<String>,
+    pub(crate) age_seconds: Option<i64>,
+    pub(crate) next_rotation: Option<String>,
+    pub(crate) policy: Option<Policy>,
+    #[serde(skip)]
+    pub(crate) settings: String,
+}
+
+// The states of the keys `secret` declares, or `None` if it declares none.
+// Keys whose settings are invalid are listed as well.
+fn key_states(opts: &Opts, secret: &Secret) -> Option<Vec<KeyState>> {
+    let meta = secret.meta();
+    let (settings, failed) = declared_keys(meta).ok()??;
+    let rotation = Rotation::new(opts, meta, &settings).ok();
+    let case = parse_annotation::<KeyCase>(meta, app_id!("key-case"))
+        .ok()
+        .flatten();
+    let data = secret.data.clone().unwrap_or_default();
+    let written = written_settings(meta);
+    let keys = settings
+        .keys()
+        .chain(failed.keys())
+        .collect::<BTreeSet<_>>();
+    let generated_at = |key: &str| rotation.as_ref()?.generated_at(key);
+    Some(
+        keys.into_iter()
+            .map(|key| KeyState {
+                key: key.clone(),
+                kind: settings
+                    .get(key)
+                    .map_or("invalid", Settings::kind)
+                    .to_string(),
+                generated: data.contains_key(key)
+                    || case.is_some_and(|c| data.contains_key(&c.apply(key))),
+                generated_at: generated_at(key).map(Into::into),
+                age_seconds: generated_at(key)
+                    .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+                    .map(|at| {
+                        Utc::now().signed_duration_since(at).num_seconds()
+                    }),
+                next_rotation: rotation
+                    .as_ref()
+                    .and_then(|r| r.next(key))
+                    .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                policy: settings.get(key).and_then(|s| s.defaults(opts).policy),
+                settings: written.get(key).cloned().unwrap_or_default(),
+            })
+            .collect(),
+    )
+}
+
+// The settings of each declared key as written in the annotations, on a
+// single line.
+fn written_settings(meta: &ObjectMeta) -> BTreeMap<String, String> {
+    let gen = parse_annotation::<Value>(meta, app_id!("gen"))
+        .ok()
+        .flatten();
+    let keys = match gen {
+        Some(gen) if gen.get("apiVersion").is_some() => {
+            gen.get("keys").cloned()
+        }
+        gen => gen,
+    };
+    let mut written = BTreeMap::new();
+    for (key, value) in keys
+        .as_ref()
+        .and_then(Value::as_mapping)
+        .into_iter()
+        .flatten()
+    {
+        let Some(key) = key.as_str() else {
+            continue;
+        };
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => serde_json::to_string(value).unwrap_or_default(),
+        };
+        written.insert(key.to_string(), value);
+    }
+    for (name, value) in meta.annotations.iter().flatten() {
+        if let Some(key) = name.strip_prefix(app_id!("gen-")) {
+            written.insert(key.to_string(), value.trim().to_string());
+        }
+    }
+    written
+}
+
+// The operator-wide defaults, with the ones of `--defaults-config-map`
+// layered over them if it is given.
+pub(crate) async fn cluster_defaults(
+    client: &Client,
+    opts: &Opts,
+) -> Result<Defaults, Error> {
+    let Some(reference) = &opts.defaults_config_map else {
+        return Ok(opts.defaults.clone());
+    };
+    let (ns, name) = split_secret_ref(reference, &opts.namespace);
+    let config_map = Api::<ConfigMap>::namespaced(client.clone(), ns)
+        .get_opt(name)
+        .await?;
+    let over = match config_map.and_then(|c| c.data?.remove("defaults.yaml")) {
+        Some(defaults) => parse_value("defaults.yaml", &defaults)?,
+        None => Defaults::default(),
+    };
+    Ok(opts.defaults.clone().layer(over))
+}
+
+// The options for secrets in `namespace`, with the defaults of the
+// namespace from `--config` and from its annotation layered over `defaults`.
+// Namespaces that can't be read don't add any.
+async fn with_namespace(
+    client: &Client,
+    opts: &Opts,
+    defaults: &Defaults,
+    namespace: &str,
+) -> Opts {
+    let object = Api::<Namespace>::all(client.clone())
+        .get_opt(namespace)
+        .await
+        .ok()
+        .flatten();
+    let own = object
+        .and_then(|ns| parse_annotation(ns.meta(), app_id!("defaults")).ok())
+        .flatten()
+        .unwrap_or_default();
+    let configured = opts.namespace_defaults.get(namespace);
+    Opts {
+        defaults: defaults
+            .clone()
+            .layer(configured.cloned().unwrap_or_default())
+            .layer(own),
+        ..opts.clone()
+    }
+}
+
+// Aligns rows into columns three spaces apart, like kubectl does.
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths = vec![];
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            line += &format!("{:width$}   ", cell, width = width);
+        }
+        table += line.trim_end();
+        table.push('\n');
+    }
+    table
+}
+
+// Checks the gen annotations of the Secret and ConfigMap manifests in
+// `files`, or in stdin for `-` or if there are none, and prints where their
+// problems are.
+pub(crate) fn validate(
+    opts: &Opts,
+    files: &[String],
+    defaults_file: Option<&str>,
+) -> Result<(), Error> {
+    let opts = with_defaults(opts, defaults_file)?;
+    let stdin = ["-".to_string()];
+    let files = match files.is_empty() {
+        true => &stdin[..],
+        false => files,
+    };
+    let mut count = 0;
+    for file in files {
+        let (name, input) = match file.as_str() {
+            "-" => ("<stdin>", std::io::read_to_string(std::io::stdin())?),
+            path => (path, std::fs::read_to_string(path)?),
+        };
+        for problem in problems(&opts, name, &input) {
+            println!("{}", problem);
+            count += 1;
+        }
+    }
+    match count {
+        0 => Ok(()),
+        count => Err(Error::Problems(count)),
+    }
+}
+
+// The problems of the manifests in `input`, each prefixed with the file and
+// the line of the annotation or key it is about.
+fn problems(opts: &Opts, file: &str, input: &str) -> Vec<String> {
+    let mut problems = vec![];
+    for (n, (start, document)) in documents(input).into_iter().enumerate() {
+        let value: Value = match serde_yaml::from_str(&document) {
+            Ok(value) => value,
+            Err(e) => {
+                let line = start + e.location().map_or(0, |l| l.line() - 1);
+                problems.push(format!(
+                    "{}:{}: document {}: {}",
+                    file,
+                    line,
+                    n + 1,
+                    e
+                ));
+                continue;
+            }
+        };
+        let kind = match value.get("kind").and_then(Value::as_str) {
+            Some(kind @ ("Secret" | "ConfigMap")) => kind,
+            _ => continue,
+        };
+        let meta = value
+            .get("metadata")
+            .cloned()
+            .map(serde_yaml::from_value::<ObjectMeta>)
+            .transpose();
+        let meta = match meta {
+            Ok(meta) => meta.unwrap_or_default(),
+            Err(e) => {
+                problems.push(format!("{}:{}: {}: {}", file, start, kind, e));
+                continue;
+            }
+        };
+        let object = match &meta.namespace {
+            Some(ns) => format!("{} {}/", kind, ns),
+            None => format!("{} ", kind),
+        } + meta.name.as_deref().unwrap_or_default();
+        let mut report = |offset: usize, e: &Error| {
+            let line = start + offset;
+            problems.push(format!("{}:{}: {}: {}", file, line, object, e));
+        };
+        // Errors of the whole declaration point at the annotation they are
+        // about, key errors at the key.
+        let whole = |e: &Error| match e {
+            Error::InvalidAnnotation(name, _) => locate(&document, name, None),
+            _ => locate(&document, app_id!("gen"), None),
+        };
+        let (settings, mut failed) = match declared_keys(&meta) {
+            Ok(Some(declared)) => declared,
+            Ok(None) => continue,
+            Err(e) => {
+                report(whole(&e), &e);
+                continue;
+            }
+        };
+        match check_settings(opts, &meta, &settings) {
+            Ok(refused) => failed.extend(refused),
+            Err(e) => report(whole(&e), &e),
+        }
+        for (key, e) in &failed {
+            let own = format!("{}{}", app_id!("gen-"), key);
+            let offset =
+                match meta.annotations.iter().flatten().any(|(a, _)| *a == own)
+                {
+                    true => locate(&document, &own, None),
+                    false => locate(&document, app_id!("gen"), Some(key)),
+                };
+            report(offset, e);
+        }
+    }
+    problems
+}
+
+// Splits a YAML stream into its documents and the lines they start on.
+// Documents holding nothing but comments are left out.
+fn documents(input: &str) -> Vec<(usize, String)> {
+    let mut documents = vec![(1, String::new())];
+    for (n, line) in input.lines().enumerate() {
+        if line.trim_end() == "---" || line.starts_with("--- ") {
+            documents.push((n + 2, String::new()));
+            continue;
+        }
+        let document = &mut documents.last_mut().unwrap().1;
+        document.push_str(line);
+        document.push('\n');
+    }
+    documents.retain(|(_, document)| {
+        document
+            .lines()
+            .map(str::trim)
+            .any(|line| !line.is_empty() && !line.starts_with('#'))
+    });
+    documents
+}
+
+// The line of `document` that declares `annotation`, or `key` within its
+// value, counted from the start of the document. Falls back to the
+// annotation's line if the key isn't on a line of its own, and to the start
+// if the annotation isn't found.
+fn locate(document: &str, annotation: &str, key: Option<&str>) -> usize {
+    let lines: Vec<&str> = document.lines().collect();
+    let declares = |line: &str, name: &str| {
+        let line = line.trim_start();
+        [
+            format!("{}:", name),
+            format!("\"{}\":", name),
+            format!("'{}':", name),
+        ]
+        .iter()
+        .any(|prefix| line.starts_with(prefix.as_str()))
+    };
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let Some(at) = lines.iter().position(|line| declares(line, annotation))
+    else {
+        return 0;
+    };
+    let value = lines[at + 1..].iter().take_while(|line| {
+        line.trim().is_empty() || indent(line) > indent(lines[at])
+    });
+    key.and_then(|key| value.into_iter().position(|line| declares(line, key)))
+        .map_or(at, |n| at + 1 + n)
+}
+
+// The options with the defaults of `defaults_file`, if given.
+fn with_defaults(
+    opts: &Opts,
+    defaults_file: Option<&str>,
+) -> Result<Opts, Error> {
+    let mut opts = opts.clone();
+    if let Some(path) = defaults_file {
+        let defaults = std::fs::read_to_string(path)?;
+        opts.defaults = parse_value::<Defaults>(path, &defaults)?;
+    }
+    Ok(opts)
+}
+
+// Applies the values of the encrypted snapshot or backup `from` to its
+// secret again, decrypted with the identities in the file `identity`. The
+// keys are applied as the operator's own, so that it keeps managing them,
+// and get back the generation times recorded with them so that their
+// rotation schedule resumes.
+pub(crate) async fn restore(
+    client: Client,
+    from: &str,
+    identity: &str,
+) -> Result<(), Error> {
+    let identity_file = Zeroizing::new(std::fs::read_to_string(identity)?);
+    let manifest =
+        decrypt(&identities(&identity_file)?, &std::fs::read(from)?)?;
+    let restored: Secret = serde_yaml::from_slice(&manifest)?;
+    let name = restored.name_any();
+    let namespace = restored
+        .namespace()
+        .unwrap_or_else(|| client.default_namespace().to_string());
+    let api = Api::<Secret>::namespaced(client.clone(), &namespace);
+    let current = api.get_opt(&name).await?;
+    let mut data = SecretData(restored.data.clone().unwrap_or_default());
+    let restored_keys: Vec<String> = data.keys().cloned().collect();
+    // Server-side apply drops the keys left out, so the other keys the
+    // operator manages are applied again with their current values.
+    let revision = match &current {
+        Some(current) => {
+            let values = current.data.as_ref();
+            for key in applied_keys(current) {
+                if let Some(value) = values.and_then(|v| v.get(&key)) {
+                    data.entry(key).or_insert_with(|| value.clone());
+                }
+            }
+            parse_annotation::<u64>(current.meta(), app_id!("revision"))
+                .ok()
+                .flatten()
+                .unwrap_or(0)
+        }
+        None => 0,
+    };
+    // The restored values are recorded like generated ones, so that they
+    // don't show as drift.
+    let checksums = applied_checksums(
+        current.as_ref().unwrap_or(&Secret::default()),
+        &data,
+        &SecretData(restored.data.clone().unwrap_or_default()),
+    );
+    let mut patch = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+            "annotations": {
+                app_id!("revision"): (revision + 1).to_string(),
+                app_id!("checksum"): data_checksum(&data),
+                app_id!("checksums"): serde_json::to_string(&checksums)?,
+            },
+        },
+        "type": restored.type_,
+        "data": data,
+    });
+    let patched = api
+        .patch(
+            &name,
+            &PatchParams::apply(app_id!()).force(),
+            &Patch::Apply(&patch),
+        )
+        .await;
+    wipe(&mut patch);
+    patched?;
+
+    // Generation times live on the secret declaring the keys, which is
+    // another one for targets.
+    let generated_at: Option<BTreeMap<String, String>> =
+        parse_annotation(restored.meta(), app_id!("generated-at"))?;
+    if let Some(mut generated_at) = generated_at {
+        generated_at.retain(|key, _| restored_keys.contains(key));
+        let target_of = current
+            .as_ref()
+            .and_then(|c| c.annotations().get(app_id!("target-of")).cloned());
+        let (source_ns, source_name) = match &target_of {
+            Some(source) => split_secret_ref(source, &namespace),
+            None => (namespace.as_str(), name.as_str()),
+        };
+        let source_api = Api::<Secret>::namespaced(client, source_ns);
+        let source = source_api.get(source_name).await?;
+        let mut merged: BTreeMap<String, String> =
+            parse_annotation(source.meta(), app_id!("generated-at"))?
+                .unwrap_or_default();
+        merged.extend(generated_at);
+        source_api
+            .patch(
+                source_name,
+                &PatchParams::apply(app_id!()),
+                &Patch::Merge(serde_json::json!({
+                    "metadata": {"annotations": {
+                        app_id!("generated-at"): serde_json::to_string(&merged)?,
+                    }}
+                })),
+            )
+            .await?;
+    }
+    println!(
+        "secret/{} in {}: restored {}",
+        name,
+        namespace,
+        restored_keys.join(", ")
+    );
+    Ok(())
+}
+
+// The values as YAML strings. Values that aren't UTF-8 are base64 encoded and
+// tagged `!binary`.
+fn values(data: &SecretData) -> Mapping {
+    data.iter()
+        .map(|(key, value)| {
+            let value = match std::str::from_utf8(&value.0) {
+                Ok(value) => Value::String(value.into()),
+                Err(_) => Value::Tagged(Box::new(TaggedValue {
+                    tag: Tag::new("binary"),
+                    value: Value::String(BASE64_STANDARD.encode(&value.0)),
+                })),
+            };
+            (Value::String(key.clone()), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use k8s_openapi::ByteString;
+
+    #[test]
+    fn values_yaml() {
+        let data = SecretData(BTreeMap::from([
+            ("password".into(), ByteString(b"s3cret".to_vec())),
+            ("raw".into(), ByteString(vec![0xff, 0x00])),
+        ]));
+        assert_eq!(
+            serde_yaml::to_string(&values(&data)).unwrap(),
+            "password: s3cret\nraw: !binary /wA=\n"
+        );
+    }
+
+    #[test]
+    fn validate_manifests() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--min-length=16",
+            "--floor-action=reject",
+        ]);
+        let input = "\
+# leading comment
+---
+apiVersion: v1
+kind: Secret
+metadata:
+  name: db
+  namespace: dev
+  annotations:
+    auto-secret.k8s.eboland.de/gen: |
+      password: default
+      pin:
+        digit: true
+        length: 4
+    auto-secret.k8s.eboland.de/gen-token: '{colour: red}'
+---
+kind: ConfigMap
+metadata:
+  name: ok
+  annotations:
+    auto-secret.k8s.eboland.de/gen: '{id: {length: 20, letter: true}}'
+---
+kind: Deployment
+metadata:
+  annotations:
+    auto-secret.k8s.eboland.de/gen: '{'
+---
+kind: Secret
+metadata: [
+";
+        let problems = problems(&opts, "m.yaml", input);
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(
+            problems[0].starts_with("m.yaml:11: Secret dev/db: key \"pin\"")
+        );
+        assert!(problems[1].starts_with("m.yaml:14: Secret dev/db: "));
+        assert!(problems[2].starts_with("m.yaml:29: document 4: "));
+    }
+
+    #[test]
+    fn states_of_keys() {
+        let secret: Secret = serde_yaml::from_str(
+            "
+metadata:
+  annotations:
+    auto-secret.k8s.eboland.de/gen: |
+      password: {length: 20, letter: true, rotate: 30d}
+      url: {alias_of: password}
+      token: {colour: red}
+    auto-secret.k8s.eboland.de/gen-pin: digit,length=4
+    auto-secret.k8s.eboland.de/generated-at: '{\"password\":\"2026-01-01T00:00:00Z\"}'
+data:
+  password: cGFzc3dvcmQ=
+",
+        )
+        .unwrap();
+        let keys = key_states(&Opts::default(), &secret).unwrap();
+        let rows: Vec<_> = keys
+            .iter()
+            .map(|k| {
+                (
+                    k.key.as_str(),
+                    k.generated,
+                    k.generated_at.as_deref(),
+                    k.next_rotation.as_deref(),
+                    k.settings.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "password",
+                    true,
+                    Some("2026-01-01T00:00:00Z"),
+                    Some("2026-01-31T00:00:00Z"),
+                    r#"{"length":20,"letter":true,"rotate":"30d"}"#
+                ),
+                ("pin", false, None, None, "digit,length=4"),
+                ("token", false, None, None, r#"{"colour":"red"}"#),
+                ("url", false, None, None, r#"{"alias_of":"password"}"#),
+            ]
+        );
+        assert!(key_states(&Opts::default(), &Secret::default()).is_none());
+        let kinds: Vec<_> = keys.iter().map(|k| k.kind.as_str()).collect();
+        assert_eq!(kinds, ["random", "random", "invalid", "alias"]);
+        assert_eq!(
+            missing_keys(&Opts::default(), &secret).unwrap(),
+            ["pin", "token", "url"]
+        );
+    }
+
+    #[test]
+    fn inventory_formats() {
+        let keys = |kind: &str| KeyState {
+            key: "password".into(),
+            kind: kind.into(),
+            generated: true,
+            generated_at: Some("2026-01-01T00:00:00Z".into()),
+            age_seconds: Some(86400),
+            next_rotation: None,
+            policy: Some(Policy::PciDss),
+            settings: "{value: hunter2}".into(),
+        };
+        let inventory = [SecretReport {
+            namespace: "dev".into(),
+            name: "db".into(),
+            keys: vec![keys("random"), keys("a,\"b\"")],
+        }];
+        assert_eq!(
+            render(&inventory, ReportFormat::Csv).unwrap(),
+            "namespace,name,key,kind,generated,generated_at,age_seconds,\
+             next_rotation\n\
+             dev,db,password,random,true,2026-01-01T00:00:00Z,86400,\n\
+             dev,db,password,\"a,\"\"b\"\"\",true,2026-01-01T00:00:00Z,86400,\n"
+        );
+        let json = render(&inventory, ReportFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json[0]["keys"][0]["kind"], "random");
+        assert_eq!(json[0]["keys"][0]["age_seconds"], 86400);
+        assert_eq!(json[0]["keys"][0]["policy"], "pci-dss");
+        assert!(!json.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn aligned_table() {
+        let rows = [
+            vec!["NAME".to_string(), "GENERATED".into()],
+            vec!["database".into(), "1/2".into()],
+        ];
+        assert_eq!(table(&rows), "NAME       GENERATED\ndatabase   1/2\n");
+    }
+
+    #[test]
+    fn generate_secret() {
+        let opts = Opts::default();
+        let rendered = generate(
+            &opts,
+            "{pin: {digit: true, length: 6}}",
+            None,
+            GenerateOutput::Secret,
+            "db",
+            Some("dev"),
+        )
+        .unwrap();
+        let secret: Secret = serde_yaml::from_str(&rendered).unwrap();
+        assert_eq!(secret.metadata.name.as_deref(), Some("db"));
+        assert_eq!(secret.metadata.namespace.as_deref(), Some("dev"));
+        let data = secret.data.unwrap();
+        assert_eq!(data["pin"].0.len(), 6);
+        assert!(data["pin"].0.iter().all(u8::is_ascii_digit));
+    }
+}
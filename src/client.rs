@@ -0,0 +1,188 @@
+// The Kubernetes client, with requests limited to `--kube-qps` per second
+// and bursts of `--kube-burst`, so that bulk rotations don't flood shared
+// API servers.
+
+use crate::{error::Error, opts::Opts};
+use futures_util::future::BoxFuture;
+use kube::{client::ClientBuilder, config::KubeConfigOptions, Client, Config};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::time::{sleep, Duration, Instant};
+use tower::{Layer, Service};
+
+// A cluster given with `--cluster`, reached through a kubeconfig context.
+#[derive(Debug, Clone)]
+pub(crate) struct Cluster {
+    pub(crate) name: String,
+    pub(crate) context: String,
+}
+
+impl FromStr for Cluster {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src.split_once('=') {
+            Some((name, context))
+                if !name.is_empty() && !context.is_empty() =>
+            {
+                Ok(Cluster {
+                    name: name.to_string(),
+                    context: context.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "invalid cluster {:?}, expected <name>=<context>",
+                src
+            )),
+        }
+    }
+}
+
+// The client of the kubeconfig `context`, or of the inferred configuration.
+pub(crate) async fn client(
+    opts: &Opts,
+    context: Option<&str>,
+) -> Result<Client, Error> {
+    let mut config = match context {
+        Some(context) => {
+            let options = KubeConfigOptions {
+                context: Some(context.to_string()),
+                ..Default::default()
+            };
+            Config::from_kubeconfig(&options).await?
+        }
+        None => Config::infer().await.map_err(kube::Error::InferConfig)?,
+    };
+    // Every request, and so every write, is made as the impersonated
+    // identity.
+    if let Some(user) = &opts.impersonate {
+        config.auth_info.impersonate = Some(user.clone());
+    }
+    if !opts.impersonate_groups.is_empty() {
+        config.auth_info.impersonate_groups =
+            Some(opts.impersonate_groups.clone());
+    }
+    let builder = ClientBuilder::try_from(config)?;
+    Ok(match opts.kube_qps {
+        Some(qps) => {
+            let limit = RateLimitLayer {
+                bucket: Arc::new(Bucket::new(qps, opts.kube_burst)),
+            };
+            builder.with_layer(&limit).build()
+        }
+        None => builder.build(),
+    })
+}
+
+// A token bucket holding up to `burst` tokens, refilled by `qps` tokens per
+// second. Every request takes a token, waiting for it if there is none.
+pub(crate) struct Bucket {
+    qps: f64,
+    burst: f64,
+    // The tokens left at the instant, negative while requests are waiting.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub(crate) fn new(qps: f64, burst: u32) -> Bucket {
+        let burst = f64::from(burst.max(1));
+        Bucket {
+            qps,
+            burst,
+            tokens: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    // Takes a token and returns how long to wait for it.
+    pub(crate) fn reserve(&self, now: Instant) -> Duration {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (left, then) = *tokens;
+        let refilled = now.saturating_duration_since(then).as_secs_f64();
+        let left = (left + refilled * self.qps).min(self.burst) - 1.0;
+        *tokens = (left, now);
+        match left < 0.0 {
+            true => Duration::from_secs_f64(-left / self.qps),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RateLimitLayer {
+    bucket: Arc<Bucket>,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> RateLimit<S> {
+        RateLimit {
+            inner,
+            bucket: self.bucket.clone(),
+        }
+    }
+}
+
+struct RateLimit<S> {
+    inner: S,
+    bucket: Arc<Bucket>,
+}
+
+impl<S, R> Service<R> for RateLimit<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    // The request is only sent once the returned future is polled, after
+    // waiting for its token.
+    fn call(&mut self, request: R) -> Self::Future {
+        let wait = self.bucket.reserve(Instant::now());
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            sleep(wait).await;
+            response.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clusters() {
+        let cluster: Cluster = "prod=admin@prod".parse().unwrap();
+        assert_eq!(
+            (cluster.name.as_str(), cluster.context.as_str()),
+            ("prod", "admin@prod")
+        );
+        assert!("prod".parse::<Cluster>().is_err());
+        assert!("=admin@prod".parse::<Cluster>().is_err());
+    }
+
+    #[test]
+    fn bucket() {
+        let bucket = Bucket::new(2.0, 3);
+        let start = Instant::now();
+        let waits: Vec<_> = (0..5).map(|_| bucket.reserve(start)).collect();
+        let ms = |ms| Duration::from_millis(ms);
+        assert_eq!(waits, [ms(0), ms(0), ms(0), ms(500), ms(1000)]);
+        // A second later, the waiting requests used up the new tokens.
+        assert_eq!(bucket.reserve(start + ms(1000)), ms(500));
+        assert_eq!(bucket.reserve(start + ms(10000)), ms(0));
+    }
+}
@@ -0,0 +1,124 @@
+// The operator configuration file given with `--config`. Settings in the
+// file take precedence over the corresponding flags, and the file is reloaded
+// whenever it changes.
+
+use crate::{
+    audit::{AuditSink, Notifier},
+    error::Error,
+    opts::{FloorAction, Opts},
+    quota::Quota,
+    settings::{parse_value, CharClass, Defaults},
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub(crate) struct ConfigFile {
+    // Operator-wide defaults, which the defaults config map is layered over.
+    defaults: Option<Defaults>,
+    // Defaults of single namespaces, layered over the operator-wide ones.
+    #[serde(default)]
+    namespaces: BTreeMap<String, Defaults>,
+    // Quotas of single namespaces, over `--max-secrets-per-namespace` and
+    // `--max-keys-per-namespace`.
+    #[serde(default)]
+    quotas: BTreeMap<String, Quota>,
+    #[serde(default)]
+    policy: PolicyConfig,
+    audit_sink: Option<AuditSink>,
+    #[serde(default)]
+    notify: Vec<Notifier>,
+}
+
+// The strength floor and minimum score, like `--min-length`,
+// `--require-classes`, `--min-score` and `--floor-action`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct PolicyConfig {
+    min_length: Option<usize>,
+    #[serde(default)]
+    require_classes: Vec<CharClass>,
+    min_score: Option<u8>,
+    floor_action: Option<FloorAction>,
+}
+
+impl ConfigFile {
+    pub(crate) fn load(path: &str) -> Result<ConfigFile, Error> {
+        ConfigFile::parse(path, &std::fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn parse(
+        path: &str,
+        content: &str,
+    ) -> Result<ConfigFile, Error> {
+        let config: ConfigFile = parse_value(path, content)?;
+        if config.policy.min_score.is_some_and(|score| score > 4) {
+            return Err(Error::InvalidAnnotation(
+                path.to_string(),
+                "minScore has to be between 0 and 4".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    // Overrides the options set in the file.
+    pub(crate) fn apply(&self, opts: &mut Opts) {
+        if let Some(defaults) = &self.defaults {
+            opts.defaults = defaults.clone();
+        }
+        opts.namespace_defaults.extend(self.namespaces.clone());
+        opts.namespace_quotas.extend(self.quotas.clone());
+        let policy = &self.policy;
+        opts.min_length = policy.min_length.or(opts.min_length);
+        if !policy.require_classes.is_empty() {
+            opts.require_classes = policy.require_classes.clone();
+        }
+        opts.min_score = policy.min_score.or(opts.min_score);
+        opts.floor_action = policy.floor_action.unwrap_or(opts.floor_action);
+        if self.audit_sink.is_some() {
+            opts.audit_sink = self.audit_sink.clone();
+        }
+        if !self.notify.is_empty() {
+            opts.notify = self.notify.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn overrides_flags() {
+        let config = ConfigFile::parse(
+            "config.yaml",
+            "
+defaults: {length: 40}
+namespaces:
+  team-a: {charset: [digit]}
+quotas:
+  team-a: {keys: 100}
+policy:
+  minLength: 16
+  floorAction: reject
+auditSink: syslog
+",
+        )
+        .unwrap();
+        let mut opts = Opts::parse_from(["auto-secret", "--min-score=2"]);
+        config.apply(&mut opts);
+        assert_eq!(opts.min_length, Some(16));
+        assert_eq!(opts.min_score, Some(2));
+        assert_eq!(opts.floor_action, FloorAction::Reject);
+        assert!(matches!(opts.audit_sink, Some(AuditSink::Syslog)));
+        assert!(opts.namespace_defaults.contains_key("team-a"));
+        assert_eq!(opts.quota("team-a").keys, Some(100));
+        assert_eq!(opts.quota("team-b"), Quota::default());
+
+        assert!(ConfigFile::parse("config.yaml", "{polcy: {}}").is_err());
+        assert!(ConfigFile::parse("config.yaml", "{policy: {minScore: 5}}")
+            .is_err());
+    }
+}
@@ -0,0 +1,4140 @@
+// The controllers reconciling secrets, config maps and workloads, and the
+// Kubernetes plumbing around generation: references, owned secrets, status
+// annotations and events.
+
+use crate::{
+    audit::{audit_records, publish_records},
+    certmanager::issue_certificates,
+    cli, client,
+    config::ConfigFile,
+    crypto::{enable_fips, init_rng, sha256, RngKind},
+    debug::{self, DebugServer},
+    error::Error,
+    eso::{apply_push_secret, PushTarget},
+    export::{self, backup_secret, export_secret, snapshot_path, Snapshot},
+    generate::{
+        blocking, dependency_order, drop_empty, gen_data, hex, SecretData,
+    },
+    git,
+    health::{self, HealthServer, Reconciling, WarmUpList, Watch},
+    hooks::{self, Hook, Outcome},
+    kinds::{
+        advance_counters, load_http_providers, recorded_counters,
+        register_generator, PluginKind,
+    },
+    leader::Leader,
+    metrics,
+    mittwald::{
+        migrate_cluster, migrate_manifests, mittwald_mark, mittwald_settings,
+    },
+    opts::{ApplyConflicts, Command, Opts, WatchList},
+    overflow::{check_size, overflow_keys, read_companion, write_companion},
+    plugins::Plugins,
+    quota::check_quota,
+    reports,
+    restart::restart_workloads,
+    seal::seal_secret,
+    settings::{
+        declared_keys, declared_settings, parse_annotation, parse_value,
+        Defaults, KeyCase, KeyErrors, Period, Settings,
+    },
+    store::{
+        annotated_stores, is_version_annotation, register_built_in_stores,
+        version_annotation, StoreWrite,
+    },
+    telemetry::Telemetry,
+    template::{split_secret_ref, Scope},
+    throttle::Throttle,
+    webhook, ANNOTATION_DOMAIN,
+};
+use futures_util::{
+    future::{self, BoxFuture, Either},
+    stream, FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, StatefulSet},
+        core::v1::{ConfigMap, Namespace, Secret},
+    },
+    apimachinery::pkg::apis::meta::v1::{ManagedFieldsEntry, ObjectMeta},
+    chrono::{DateTime, SecondsFormat, TimeDelta, Utc},
+    ByteString, NamespaceResourceScope,
+};
+use kube::{
+    api::{
+        Api, DeleteParams, DynamicObject, ListParams, PartialObjectMeta, Patch,
+        PatchParams, PostParams, Preconditions, Resource,
+    },
+    runtime::{
+        controller::{Action, Config, Controller},
+        events::{Event, EventType, Recorder},
+        metadata_watcher, predicates,
+        reflector::{self, reflector, ObjectRef, Store},
+        watcher::{self, watcher, InitialListStrategy, ListSemantic},
+        WatchStreamExt,
+    },
+    Client, ResourceExt,
+};
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    task::Poll,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::{info_span, Instrument};
+use zeroize::{Zeroize, Zeroizing};
+
+// A reference to the secret `object` is the metadata of.
+fn secret_ref<K: Resource<DynamicType = ()>>(object: &K) -> ObjectRef<Secret> {
+    ObjectRef::new(&object.name_any())
+        .within(object.meta().namespace.as_deref().unwrap_or_default())
+}
+
+// Tracks which secrets are referenced from templates of other secrets, so
+// that a change to a referenced secret re-renders the templates using it.
+#[derive(Default)]
+struct Source {
+    version: Option<String>,
+    dependents: HashSet<ObjectRef<Secret>>,
+}
+
+#[derive(Default)]
+struct References {
+    sources: HashMap<ObjectRef<Secret>, Source>,
+}
+
+impl References {
+    fn set(
+        &mut self,
+        dependent: &ObjectRef<Secret>,
+        sources: &[ObjectRef<Secret>],
+    ) {
+        for source in self.sources.values_mut() {
+            source.dependents.remove(dependent);
+        }
+        self.sources
+            .retain(|_, source| !source.dependents.is_empty());
+        for source in sources {
+            self.sources
+                .entry(source.clone())
+                .or_default()
+                .dependents
+                .insert(dependent.clone());
+        }
+    }
+
+    // Returns the dependents of `secret` if it changed since it was last
+    // seen. The metadata of the secret is enough.
+    fn observe<K>(&mut self, secret: &K) -> Vec<ObjectRef<Secret>>
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        let Some(source) = self.sources.get_mut(&secret_ref(secret)) else {
+            return vec![];
+        };
+        if source.version == secret.resource_version() {
+            return vec![];
+        }
+        source.version = secret.resource_version();
+        source.dependents.iter().cloned().collect()
+    }
+}
+
+pub(crate) struct Context {
+    pub(crate) client: Client,
+    // The name of the cluster with `--cluster`.
+    cluster: Option<String>,
+    pub(crate) opts: Opts,
+    references: Mutex<References>,
+    // Secrets carrying `stamp-to-selector`, reconciled whenever a namespace
+    // changes.
+    stamps: Mutex<HashSet<ObjectRef<Secret>>>,
+    trigger: mpsc::UnboundedSender<ObjectRef<Secret>>,
+    recorder: Recorder,
+    pub(crate) http: reqwest::Client,
+    // `None` until the defaults config map has been read.
+    defaults: watch::Sender<Option<Defaults>>,
+    // The current contents of `--config`.
+    config: watch::Sender<ConfigFile>,
+    namespaces: Store<Namespace>,
+    // Resource versions of secrets the operator wrote, whose watch events do
+    // not need another reconcile.
+    written: Mutex<HashMap<ObjectRef<Secret>, HashSet<String>>>,
+    // Consecutive failed reconciles of each object, to back off from.
+    failures: Mutex<HashMap<ObjectRef<DynamicObject>, u32>>,
+    // Objects being reconciled, to report those cut off by a shutdown.
+    in_flight: Mutex<HashSet<ObjectRef<DynamicObject>>>,
+    // The limit of reconciles per namespace with `--namespace-qps`.
+    throttle: Option<Throttle>,
+}
+
+impl Context {
+    // Remembers that the operator wrote `secret` in this version.
+    pub(crate) fn wrote(&self, secret: &Secret) {
+        let Some(version) = secret.resource_version() else {
+            return;
+        };
+        let mut written = self.written.lock().unwrap();
+        written
+            .entry(secret_ref(secret))
+            .or_default()
+            .insert(version);
+    }
+
+    // Logs the reconciles still in flight when the shutdown grace period is
+    // over, and fails if there were any.
+    fn abandon(&self) -> Result<(), Error> {
+        let in_flight = self.in_flight.lock().unwrap();
+        for object in in_flight.iter() {
+            warn!(
+                "shutdown cut off the reconcile of {}{}",
+                self.cluster_prefix(),
+                object
+            );
+        }
+        match in_flight.len() {
+            0 => Ok(()),
+            n => Err(Error::Timeout(format!(
+                "waiting for {} reconciles to finish",
+                n
+            ))),
+        }
+    }
+
+    // Whether `object` carries annotations of the operator, which the secrets
+    // controller reconciles.
+    fn annotated(&self, object: &impl ResourceExt) -> bool {
+        object.annotations().keys().any(|a| {
+            a.starts_with(app_id!(""))
+                || self.opts.mittwald_compat && a.starts_with(mittwald!(""))
+        })
+    }
+
+    // Prefixes log lines about objects with the name of their cluster.
+    fn cluster_prefix(&self) -> String {
+        match &self.cluster {
+            Some(cluster) => format!("{}:", cluster),
+            None => String::new(),
+        }
+    }
+
+    // Counts a failed reconcile of `object` and returns the time to wait
+    // before retrying it.
+    fn failed<K: Resource<DynamicType = ()>>(&self, object: &K) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let attempts = failures
+            .entry(ObjectRef::from_obj(object).erase())
+            .or_default();
+        *attempts += 1;
+        backoff(&self.opts, *attempts)
+    }
+
+    // Whether `secret` is in a version the operator wrote itself. Versions
+    // are forgotten once seen, and all of them once somebody else changed the
+    // secret.
+    fn own_write<K: Resource<DynamicType = ()>>(&self, secret: &K) -> bool {
+        let mut written = self.written.lock().unwrap();
+        let key = secret_ref(secret);
+        let Some(versions) = written.get_mut(&key) else {
+            return false;
+        };
+        let own = secret
+            .resource_version()
+            .is_some_and(|version| versions.remove(&version));
+        if !own || versions.is_empty() {
+            written.remove(&key);
+        }
+        own
+    }
+
+    // The options with the current `--config` applied.
+    pub(crate) fn configured(&self) -> Opts {
+        let mut opts = self.opts.clone();
+        self.config.borrow().apply(&mut opts);
+        opts
+    }
+
+    // The options for objects in `namespace`, with the current `--config`
+    // applied and the defaults of the namespace layered over the
+    // operator-wide ones.
+    pub(crate) async fn opts(&self, namespace: &str) -> Result<Opts, Error> {
+        let mut opts = self.configured();
+        let mut defaults = self.defaults.subscribe();
+        let defaults = defaults
+            .wait_for(Option::is_some)
+            .await
+            .map(|d| d.clone().unwrap_or_default())
+            .unwrap_or_default();
+        let defaults = opts.defaults.clone().layer(defaults).layer(
+            opts.namespace_defaults
+                .get(namespace)
+                .cloned()
+                .unwrap_or_default(),
+        );
+        // Namespaces are not watched with `--watch-namespace`.
+        let namespace = match self.opts.watch_namespace {
+            Some(_) => None,
+            None => {
+                self.namespaces.wait_until_ready().await.ok();
+                self.namespaces.get(&ObjectRef::new(namespace))
+            }
+        };
+        opts.defaults = match namespace {
+            Some(ns) => defaults.layer(
+                parse_annotation(ns.meta(), app_id!("defaults"))?
+                    .unwrap_or_default(),
+            ),
+            None => defaults,
+        };
+        Ok(opts)
+    }
+}
+
+// The master seed, fetched only when a key is derived from it.
+pub(crate) async fn master_seed(
+    ctx: &Context,
+    settings: &BTreeMap<String, Settings>,
+) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+    if !settings.values().any(Settings::is_derived) {
+        return Ok(None);
+    }
+    let Some(reference) = &ctx.opts.master_seed_secret else {
+        return Err(Error::Derive(
+            "derive requires --master-seed-secret".to_string(),
+        ));
+    };
+    let (ns, name) = split_secret_ref(reference, &ctx.opts.namespace);
+    let secret = Api::<Secret>::namespaced(ctx.client.clone(), ns)
+        .get(name)
+        .await?;
+    secret
+        .data
+        .and_then(|mut data| data.remove("seed"))
+        .filter(|seed| !seed.0.is_empty())
+        .map(|seed| Some(Zeroizing::new(seed.0)))
+        .ok_or_else(|| {
+            Error::Derive(format!("secret {}/{} has no seed key", ns, name))
+        })
+}
+
+// The secrets referenced from templates, as `(namespace, name)`.
+pub(crate) fn secret_references(
+    settings: &BTreeMap<String, Settings>,
+    namespace: &str,
+) -> Vec<(String, String)> {
+    settings
+        .values()
+        .flat_map(Settings::secret_refs)
+        .map(|r| split_secret_ref(r, namespace))
+        .map(|(ns, name)| (ns.to_string(), name.to_string()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+pub(crate) async fn fetch_references(
+    client: &Client,
+    namespace: &str,
+    references: &[(String, String)],
+) -> Result<BTreeMap<String, SecretData>, Error> {
+    let mut secrets = BTreeMap::new();
+    for (ns, name) in references {
+        let api = Api::<Secret>::namespaced(client.clone(), ns);
+        let source = match api.get_opt(name).await {
+            Ok(Some(source)) => source,
+            Ok(None) => {
+                return Err(Error::Reference(format!(
+                    "secret {}/{} does not exist",
+                    ns, name
+                )))
+            }
+            Err(kube::Error::Api(e)) if e.code == 403 => {
+                return Err(Error::Reference(format!(
+                    "not allowed to read secret {}/{}: {}",
+                    ns, name, e.message
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if ns != namespace {
+            let allowed = source
+                .annotations()
+                .get(app_id!("allow-reference-from"))
+                .is_some_and(|allowed| {
+                    allowed
+                        .split(',')
+                        .map(str::trim)
+                        .any(|a| a == "*" || a == namespace)
+                });
+            if !allowed {
+                return Err(Error::Reference(format!(
+                    "secret {}/{} does not allow references from namespace {}",
+                    ns, name, namespace
+                )));
+            }
+        }
+        secrets.insert(
+            format!("{}/{}", ns, name),
+            SecretData(source.data.unwrap_or_default()),
+        );
+    }
+    Ok(secrets)
+}
+
+// Overwrites the strings of a patch once it has been sent.
+pub(crate) fn wipe(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => s.zeroize(),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(wipe),
+        serde_json::Value::Object(map) => map.values_mut().for_each(wipe),
+        _ => {}
+    }
+}
+
+// The data keys a managedFields entry claims.
+fn managed_keys(fields: &ManagedFieldsEntry) -> impl Iterator<Item = &str> {
+    let fields = fields.fields_v1.as_ref().and_then(|f| f.0.as_object());
+    ["f:data", "f:stringData"]
+        .into_iter()
+        .filter_map(move |f| fields?.get(f)?.as_object())
+        .flat_map(|data| data.keys())
+        .filter_map(|key| key.strip_prefix("f:"))
+}
+
+// The data keys the operator's field manager applied to `secret`.
+pub(crate) fn applied_keys(secret: &Secret) -> BTreeSet<String> {
+    let applied = secret.managed_fields().iter().filter(|fields| {
+        fields.manager.as_deref() == Some(app_id!())
+            && fields.operation.as_deref() == Some("Apply")
+    });
+    applied.flat_map(managed_keys).map(str::to_string).collect()
+}
+
+// The data keys of `secret` other field managers own, with the first of
+// their managers.
+fn foreign_owners(secret: &Secret) -> BTreeMap<String, String> {
+    let mut owners = BTreeMap::new();
+    for fields in secret.managed_fields() {
+        let Some(manager) = fields.manager.as_deref() else {
+            continue;
+        };
+        if manager == app_id!() {
+            continue;
+        }
+        for key in managed_keys(fields) {
+            owners
+                .entry(key.to_string())
+                .or_insert_with(|| manager.to_string());
+        }
+    }
+    owners
+}
+
+// A SHA-256 checksum over the keys of `data` and their values.
+pub(crate) fn data_checksum(data: &SecretData) -> String {
+    let mut content = Zeroizing::new(vec![]);
+    for (key, value) in data.iter() {
+        content.extend_from_slice(key.as_bytes());
+        content.push(0);
+        content.extend_from_slice(&value.0);
+        content.push(0);
+    }
+    hex(&sha256(&content))
+}
+
+// A SHA-256 checksum over `key` and its value.
+fn key_checksum(key: &str, value: &ByteString) -> String {
+    let mut content = Zeroizing::new(key.as_bytes().to_vec());
+    content.push(0);
+    content.extend_from_slice(&value.0);
+    hex(&sha256(&content))
+}
+
+// The checksums of the values of keys recorded on `secret` when they were
+// generated.
+fn recorded_checksums(secret: &Secret) -> BTreeMap<String, String> {
+    parse_annotation(secret.meta(), app_id!("checksums"))
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+// The checksums to record for the `applied` keys, of which `generated` were
+// generated now. The other keys keep the checksum they were generated with,
+// so that changes made by others in the meantime still show as drift.
+pub(crate) fn applied_checksums(
+    dest: &Secret,
+    applied: &SecretData,
+    generated: &SecretData,
+) -> BTreeMap<String, String> {
+    let mut recorded = recorded_checksums(dest);
+    applied
+        .iter()
+        .map(|(key, value)| {
+            let checksum = match generated.contains_key(key) {
+                true => None,
+                false => recorded.remove(key),
+            };
+            let checksum = checksum.unwrap_or_else(|| key_checksum(key, value));
+            (key.clone(), checksum)
+        })
+        .collect()
+}
+
+// The keys of `data` whose values no longer match the checksums recorded on
+// `dest`. Keys that were removed are generated again instead.
+fn drifted_keys(dest: &Secret, data: &SecretData) -> BTreeSet<String> {
+    recorded_checksums(dest)
+        .into_iter()
+        .filter(|(key, checksum)| {
+            data.get(key)
+                .is_some_and(|v| key_checksum(key, v) != *checksum)
+        })
+        .map(|(key, _)| key)
+        .collect()
+}
+
+// Whether the annotation `name` declares keys, also with `--mittwald-compat`.
+fn declares_keys(name: &str) -> bool {
+    let mittwald = [
+        mittwald!("autogenerate"),
+        mittwald!("type"),
+        mittwald!("length"),
+        mittwald!("encoding"),
+    ];
+    name == app_id!("gen")
+        || name.starts_with(app_id!("gen-"))
+        || mittwald.contains(&name)
+}
+
+// A SHA-256 hash over the annotations declaring the keys of `meta`, which
+// changes whenever their declaration does.
+pub(crate) fn settings_hash(meta: &ObjectMeta) -> String {
+    let mut content = vec![];
+    for (name, value) in meta.annotations.iter().flatten() {
+        if declares_keys(name) {
+            content.extend_from_slice(name.as_bytes());
+            content.push(0);
+            content.extend_from_slice(value.as_bytes());
+            content.push(0);
+        }
+    }
+    hex(&sha256(&content))
+}
+
+// With `string_data`, values that are valid UTF-8 are sent as stringData.
+// The apiserver merges them into data.
+fn data_patch(data: SecretData, string_data: bool) -> serde_json::Value {
+    if !string_data {
+        return serde_json::json!({ "data": data });
+    }
+    let mut binary = BTreeMap::new();
+    let mut strings = BTreeMap::new();
+    for (key, value) in data {
+        match String::from_utf8(value.0) {
+            Ok(s) => {
+                strings.insert(key, s);
+            }
+            Err(e) => {
+                binary.insert(key, ByteString(e.into_bytes()));
+            }
+        }
+    }
+    serde_json::json!({ "data": binary, "stringData": strings })
+}
+
+// How long recreating a secret waits for the old one to be gone, which
+// takes until other controllers removed their finalizers.
+const DELETE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The type of a secret is immutable, so changing it means deleting and
+// creating the secret again, with `annotations` added. Finalizers belong to
+// the deleted object and are left to their controllers to add again. If the
+// new secret can't be created, the old one is restored.
+async fn recreate_secret(
+    api: &Api<Secret>,
+    secret: Secret,
+    type_: Option<String>,
+    data: SecretData,
+    annotations: BTreeMap<String, String>,
+) -> Result<(), Error> {
+    let name = secret.name_any();
+    let meta = &secret.metadata;
+    let metadata = ObjectMeta {
+        name: meta.name.clone(),
+        namespace: meta.namespace.clone(),
+        labels: meta.labels.clone(),
+        annotations: meta.annotations.clone(),
+        owner_references: meta.owner_references.clone(),
+        ..Default::default()
+    };
+    let mut recreated = Secret {
+        metadata: metadata.clone(),
+        type_,
+        data: Some(data.into_iter().collect()),
+        immutable: secret.immutable,
+        ..Default::default()
+    };
+    recreated.annotations_mut().extend(annotations);
+
+    info!("recreating secret {} to change its type", name);
+    let params = DeleteParams {
+        preconditions: Some(Preconditions {
+            uid: meta.uid.clone(),
+            resource_version: meta.resource_version.clone(),
+        }),
+        ..Default::default()
+    };
+    api.delete(&name, &params).await?;
+    let deadline = Instant::now() + DELETE_TIMEOUT;
+    while api
+        .get_opt(&name)
+        .await?
+        .is_some_and(|s| s.uid() == meta.uid)
+    {
+        if Instant::now() >= deadline {
+            return Err(Error::Conflict(format!(
+                "secret {} is still being deleted after {}s, check its \
+                 finalizers",
+                name,
+                DELETE_TIMEOUT.as_secs()
+            )));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let Err(e) = api.create(&PostParams::default(), &recreated).await else {
+        return Ok(());
+    };
+    warn!("recreating secret {} failed, restoring it: {}", name, e);
+    let original = Secret {
+        metadata,
+        type_: secret.type_,
+        data: secret.data,
+        immutable: secret.immutable,
+        ..Default::default()
+    };
+    if let Err(e) = api.create(&PostParams::default(), &original).await {
+        warn!("restoring secret {} failed: {}", name, e);
+    }
+    Err(e.into())
+}
+
+// Creates the secret described by `secret` with `owner` as its controller,
+// or updates the operator annotations of an existing one. Secrets that are
+// not owned by `owner` are left alone.
+async fn apply_owned_secret<K: Resource<DynamicType = ()>>(
+    api: &Api<Secret>,
+    owner: &K,
+    mut secret: Secret,
+) -> Result<(), Error> {
+    let name = secret.name_any();
+    let owner_ref = owner.controller_owner_ref(&()).ok_or_else(|| {
+        Error::Conflict(format!("owner of secret {} has no uid", name))
+    })?;
+    let Some(existing) = api.get_opt(&name).await? else {
+        secret.metadata.owner_references = Some(vec![owner_ref]);
+        api.create(&PostParams::default(), &secret).await?;
+        return Ok(());
+    };
+
+    if !existing
+        .owner_references()
+        .iter()
+        .any(|o| o.uid == owner_ref.uid)
+    {
+        return Err(Error::Conflict(format!(
+            "secret {} exists but is not owned by {} {}",
+            name, owner_ref.kind, owner_ref.name
+        )));
+    }
+    patch_annotations(api, &existing, secret.annotations()).await
+}
+
+// The annotations the operator records on a secret about the secret itself:
+// its generation state, status and origin. Secrets stamped from it don't
+// carry them, and setting its operator annotations keeps them.
+const STATUS_ANNOTATIONS: &[&str] = &[
+    "canary-status",
+    "checksum",
+    "checksums",
+    "counters",
+    "drifted",
+    "error",
+    "errors",
+    "generated-at",
+    "last-generated",
+    "managed-keys",
+    "next-rotation",
+    "post-generate-status",
+    "revision",
+    "settings-hash",
+    "stale",
+    "target-of",
+];
+
+fn is_status_annotation(annotation: &str) -> bool {
+    annotation
+        .strip_prefix(app_id!(""))
+        .is_some_and(|name| STATUS_ANNOTATIONS.contains(&name))
+        || is_version_annotation(annotation)
+}
+
+// Sets the operator annotations of `existing` to `annotations`, removing
+// operator annotations that are no longer declared. The generation state the
+// operator records on `existing` itself is kept.
+async fn patch_annotations(
+    api: &Api<Secret>,
+    existing: &Secret,
+    annotations: &BTreeMap<String, String>,
+) -> Result<(), Error> {
+    let mut patch: BTreeMap<&String, Option<&String>> = existing
+        .annotations()
+        .keys()
+        .filter(|k| k.starts_with(app_id!("")) && !is_status_annotation(k))
+        .map(|k| (k, None))
+        .collect();
+    patch.extend(annotations.iter().map(|(k, v)| (k, Some(v))));
+    if patch
+        .iter()
+        .all(|(k, v)| existing.annotations().get(*k) == *v)
+    {
+        return Ok(());
+    }
+    api.patch(
+        &existing.name_any(),
+        &PatchParams::apply(app_id!()),
+        &Patch::Merge(serde_json::json!({
+            "metadata": { "annotations": patch }
+        })),
+    )
+    .await?;
+    Ok(())
+}
+
+// The operator annotations of `secret` except `except`, to be passed on to
+// the secrets it declares.
+fn operator_annotations(
+    secret: &Secret,
+    except: &str,
+) -> BTreeMap<String, String> {
+    secret
+        .annotations()
+        .iter()
+        .filter(|(k, _)| {
+            k.starts_with(app_id!(""))
+                && *k != except
+                && !is_status_annotation(k)
+        })
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateSecret {
+    name: String,
+    keys: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    #[serde(rename = "type")]
+    type_: Option<String>,
+}
+
+impl CreateSecret {
+    // The secret carries the declared keys in its own gen annotation, so
+    // generation happens through the regular secret reconciler.
+    fn to_secret(&self) -> Result<Secret, Error> {
+        for settings in self.keys.values() {
+            serde_yaml::from_value::<Settings>(settings.clone())?;
+        }
+        let mut annotations = self.annotations.clone();
+        annotations.insert(
+            app_id!("gen").to_string(),
+            serde_yaml::to_string(&self.keys)?,
+        );
+        Ok(Secret {
+            metadata: ObjectMeta {
+                name: Some(self.name.clone()),
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            type_: self.type_.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+// Tracks when random keys were generated, so that keys with a rotation
+// interval are generated again once it has passed. A key's own `rotate`
+// takes precedence over the secret's rotate annotation and the operator
+// default. The schedule is kept in annotations only, so it survives
+// restarts and failovers.
+pub(crate) struct Rotation<'a> {
+    intervals: BTreeMap<&'a str, Duration>,
+    // The times recorded in the generated-at annotation.
+    recorded: BTreeMap<String, String>,
+    // The recorded times, and for keys that got an interval since they were
+    // generated the time of their last generation.
+    generated_at: BTreeMap<String, String>,
+    now: DateTime<Utc>,
+}
+
+impl<'a> Rotation<'a> {
+    pub(crate) fn new(
+        opts: &Opts,
+        meta: &ObjectMeta,
+        settings: &'a BTreeMap<String, Settings>,
+    ) -> Result<Self, Error> {
+        let secret_rotate =
+            parse_annotation::<Period>(meta, app_id!("rotate"))?;
+        let intervals = settings
+            .iter()
+            .filter(|(_, s)| s.is_random())
+            .filter_map(|(key, s)| {
+                let own = match s {
+                    Settings::Options(o) => o.rotate,
+                    _ => None,
+                };
+                let interval = own
+                    .or(secret_rotate)
+                    .or_else(|| s.defaults(opts).rotate)?;
+                Some((key.as_str(), interval.0))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let recorded: BTreeMap<String, String> =
+            parse_annotation(meta, app_id!("generated-at"))?
+                .unwrap_or_default();
+        // Unreadable times are replaced rather than failing the secret.
+        let last_generated: BTreeMap<String, String> =
+            parse_annotation(meta, app_id!("last-generated"))
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+        let mut generated_at = recorded.clone();
+        for key in intervals.keys() {
+            if let Some(at) = last_generated.get(*key) {
+                generated_at
+                    .entry(key.to_string())
+                    .or_insert_with(|| at.clone());
+            }
+        }
+        Ok(Rotation {
+            intervals,
+            recorded,
+            generated_at,
+            now: Utc::now(),
+        })
+    }
+
+    fn due(&self, key: &str, generated_at: &str) -> Option<DateTime<Utc>> {
+        let at = DateTime::parse_from_rfc3339(generated_at).ok()?;
+        let interval = TimeDelta::from_std(self.intervals[key]).ok()?;
+        at.with_timezone(&Utc).checked_add_signed(interval)
+    }
+
+    pub(crate) fn rotated(&self, key: &str) -> bool {
+        self.intervals.contains_key(key)
+    }
+
+    // When `key` was last generated, if it is rotated.
+    pub(crate) fn generated_at(&self, key: &str) -> Option<&str> {
+        self.intervals.get(key)?;
+        self.generated_at.get(key).map(String::as_str)
+    }
+
+    // When `key` is rotated next, if it is rotated.
+    pub(crate) fn next(&self, key: &str) -> Option<DateTime<Utc>> {
+        self.due(key, self.generated_at(key)?)
+    }
+
+    // When each key is rotated next, with the generation times of `update`.
+    fn schedule(
+        &self,
+        generated_at: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        generated_at
+            .iter()
+            .filter(|(key, _)| self.intervals.contains_key(key.as_str()))
+            .filter_map(|(key, at)| {
+                let due = self.due(key, at)?;
+                Some((
+                    key.clone(),
+                    due.to_rfc3339_opts(SecondsFormat::Secs, true),
+                ))
+            })
+            .collect()
+    }
+
+    fn expired(&self) -> BTreeSet<String> {
+        self.intervals
+            .keys()
+            .filter(|key| {
+                self.generated_at
+                    .get(**key)
+                    .and_then(|at| self.due(key, at))
+                    .is_some_and(|due| due <= self.now)
+            })
+            .map(|key| key.to_string())
+            .collect()
+    }
+
+    // The generation timestamps after this run, and the time until the next
+    // rotation is due. Keys without a timestamp start their interval now.
+    pub(crate) fn update(
+        &self,
+        written: impl Fn(&str) -> bool,
+    ) -> (BTreeMap<String, String>, Option<Duration>) {
+        let now = self.now.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut generated_at = BTreeMap::new();
+        let mut next = None;
+        for key in self.intervals.keys() {
+            let at = match self.generated_at.get(*key) {
+                Some(at) if !written(key) => at.clone(),
+                _ => now.clone(),
+            };
+            if let Some(due) = self.due(key, &at) {
+                let remaining = (due - self.now).to_std().unwrap_or_default();
+                next = Some(
+                    next.map_or(remaining, |n: Duration| n.min(remaining)),
+                );
+            }
+            generated_at.insert(key.to_string(), at);
+        }
+        (generated_at, next)
+    }
+}
+
+// Whether the values generated for `meta` end up in other secrets than the
+// declaring one.
+pub(crate) fn data_elsewhere(meta: &ObjectMeta) -> bool {
+    let elsewhere = [
+        app_id!("target"),
+        app_id!("fan-out"),
+        app_id!("stamp-to-selector"),
+    ];
+    let annotations = meta.annotations.iter().flatten();
+    annotations
+        .into_iter()
+        .any(|(a, _)| elsewhere.contains(&a.as_str()))
+}
+
+// Checks the declared keys of an object the way the reconciler does before
+// generating anything, for the webhook and `validate`. Problems of the whole
+// declaration are returned as errors, keys whose generation would be refused
+// as key errors.
+pub(crate) fn check_settings(
+    opts: &Opts,
+    meta: &ObjectMeta,
+    settings: &BTreeMap<String, Settings>,
+) -> Result<KeyErrors, Error> {
+    dependency_order(settings)?;
+    Rotation::new(opts, meta, settings)?;
+    parse_annotation::<Period>(meta, app_id!("max-age"))?;
+    parse_annotation::<bool>(meta, app_id!("overwrite-empty"))?;
+    Ok(settings
+        .iter()
+        .filter_map(|(key, s)| Some((key.clone(), s.refused(key, opts)?)))
+        .collect())
+}
+
+async fn mark_generated_at(
+    ctx: &Context,
+    api: &Api<Secret>,
+    secret: &Secret,
+    generated_at: &BTreeMap<String, String>,
+) -> Result<(), Error> {
+    let value = match generated_at.is_empty() {
+        true => serde_json::Value::Null,
+        false => serde_json::to_string(generated_at)?.into(),
+    };
+    let patched = api
+        .patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": { "annotations": { app_id!("generated-at"): value } }
+            })),
+        )
+        .await?;
+    ctx.wrote(&patched);
+    Ok(())
+}
+
+// When each of the `managed` keys of a declaring secret was last generated,
+// after a reconcile that wrote `written` at `now`. Keys generated before the
+// times were recorded have the time of their last rotation, if any.
+fn last_generated<'a>(
+    meta: &'a ObjectMeta,
+    managed: &'a BTreeSet<String>,
+    written: &BTreeSet<String>,
+    now: &str,
+) -> BTreeMap<&'a String, String> {
+    // Unreadable times are replaced rather than failing the secret.
+    let times = |name| {
+        parse_annotation::<BTreeMap<String, String>>(meta, name)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    };
+    let (previous, rotated) = (
+        times(app_id!("last-generated")),
+        times(app_id!("generated-at")),
+    );
+    managed
+        .iter()
+        .filter_map(|key| match written.contains(key) {
+            true => Some((key, now.to_string())),
+            false => {
+                let at = previous.get(key).or_else(|| rotated.get(key))?;
+                Some((key, at.clone()))
+            }
+        })
+        .collect()
+}
+
+// The declared keys older than their maximum age that aren't rotated, and
+// the time until the next one gets too old. A key's own `max_age` takes
+// precedence over the secret's max-age annotation and the operator default.
+fn stale_keys(
+    opts: &Opts,
+    meta: &ObjectMeta,
+    settings: &BTreeMap<String, Settings>,
+    rotation: &Rotation,
+    last_generated: &BTreeMap<&String, String>,
+    now: DateTime<Utc>,
+) -> Result<(BTreeSet<String>, Option<Duration>), Error> {
+    let secret_max_age = parse_annotation::<Period>(meta, app_id!("max-age"))?;
+    let mut stale = BTreeSet::new();
+    let mut next: Option<Duration> = None;
+    for (key, s) in settings.iter().filter(|(key, _)| !rotation.rotated(key)) {
+        let own = match s {
+            Settings::Options(o) => o.max_age,
+            _ => None,
+        };
+        let Some(max_age) =
+            own.or(secret_max_age).or_else(|| s.defaults(opts).max_age)
+        else {
+            continue;
+        };
+        let Some(at) = last_generated
+            .get(key)
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        else {
+            continue;
+        };
+        let Some(expires) = TimeDelta::from_std(max_age.0)
+            .ok()
+            .and_then(|age| at.with_timezone(&Utc).checked_add_signed(age))
+        else {
+            continue;
+        };
+        match (expires - now).to_std() {
+            Ok(remaining) if !remaining.is_zero() => {
+                next = Some(next.map_or(remaining, |n| n.min(remaining)));
+            }
+            _ => {
+                stale.insert(key.clone());
+            }
+        }
+    }
+    Ok((stale, next))
+}
+
+// The status annotations of a declaring secret: when each of the `managed`
+// keys was last generated and is rotated next, the managed keys, the hash of
+// their declaration, the keys that drifted and the keys older than their
+// maximum age. Annotations without a value are removed.
+fn status_annotations(
+    meta: &ObjectMeta,
+    managed: &BTreeSet<String>,
+    last_generated: &BTreeMap<&String, String>,
+    next_rotation: &BTreeMap<String, String>,
+    drifted: &BTreeSet<String>,
+    stale: &BTreeSet<String>,
+) -> Result<BTreeMap<&'static str, Option<String>>, Error> {
+    let list = |keys: &BTreeSet<String>| {
+        keys.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let last_generated = serde_json::to_string(last_generated)?;
+    let next_rotation = match next_rotation.is_empty() {
+        true => None,
+        false => Some(serde_json::to_string(next_rotation)?),
+    };
+    Ok(BTreeMap::from([
+        (app_id!("last-generated"), Some(last_generated)),
+        (app_id!("next-rotation"), next_rotation),
+        (app_id!("managed-keys"), Some(list(managed))),
+        (app_id!("settings-hash"), Some(settings_hash(meta))),
+        (
+            app_id!("drifted"),
+            Some(list(drifted)).filter(|d| !d.is_empty()),
+        ),
+        (
+            app_id!("stale"),
+            Some(list(stale)).filter(|s| !s.is_empty()),
+        ),
+    ]))
+}
+
+// The keys listed in the annotation `name` of `meta`.
+fn listed_keys(meta: &ObjectMeta, name: &str) -> BTreeSet<String> {
+    let annotations = meta.annotations.iter().flatten();
+    let listed = annotations.filter(|(n, _)| *n == name).map(|(_, v)| v);
+    listed
+        .flat_map(|v| v.split(','))
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+async fn mark_status(
+    ctx: &Context,
+    api: &Api<Secret>,
+    secret: &Secret,
+    status: &BTreeMap<&'static str, Option<String>>,
+) -> Result<(), Error> {
+    if status
+        .iter()
+        .all(|(k, v)| secret.annotations().get(*k) == v.as_ref())
+    {
+        return Ok(());
+    }
+    let patched = api
+        .patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": { "annotations": status }
+            })),
+        )
+        .await?;
+    ctx.wrote(&patched);
+    Ok(())
+}
+
+// Records the keys that could not be generated in the errors annotation, and
+// removes it once all keys were generated.
+async fn mark_errors(
+    ctx: &Context,
+    api: &Api<Secret>,
+    secret: &Secret,
+    failed: &KeyErrors,
+) -> Result<(), Error> {
+    let errors: BTreeMap<&String, String> =
+        failed.iter().map(|(key, e)| (key, e.to_string())).collect();
+    let value = match errors.is_empty() {
+        true => None,
+        false => Some(serde_json::to_string(&errors)?),
+    };
+    if secret.annotations().get(app_id!("errors")) == value.as_ref() {
+        return Ok(());
+    }
+    let patched = api
+        .patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": { "annotations": { app_id!("errors"): value } }
+            })),
+        )
+        .await?;
+    ctx.wrote(&patched);
+    Ok(())
+}
+
+// Sets or removes the error annotation, unless it is up to date.
+async fn mark_error(
+    ctx: &Context,
+    secret: &Secret,
+    error: Option<String>,
+) -> Result<(), Error> {
+    if secret.annotations().get(app_id!("error")) == error.as_ref() {
+        return Ok(());
+    }
+    let ns = secret.namespace().unwrap_or_default();
+    let patched = Api::<Secret>::namespaced(ctx.client.clone(), &ns)
+        .patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": { "annotations": { app_id!("error"): error } }
+            })),
+        )
+        .await?;
+    ctx.wrote(&patched);
+    Ok(())
+}
+
+// Publishes a warning event on `object`. Failing to publish is only logged,
+// so it doesn't hide the original problem.
+async fn report<K: Resource<DynamicType = ()>>(
+    ctx: &Context,
+    object: &K,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) {
+    let event = Event {
+        type_,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: "Generate".to_string(),
+        secondary: None,
+    };
+    if let Err(e) = ctx.recorder.publish(&event, &object.object_ref(&())).await
+    {
+        warn!("failed to publish event: {}", e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Target {
+    name: String,
+    namespace: Option<String>,
+}
+
+// Looks up the secret generated data of `source` is redirected to, creating
+// it if necessary. Targets are marked with the secret they belong to, so
+// neither unrelated secrets nor targets of other sources are overwritten.
+async fn target_secret(
+    ctx: &Context,
+    source: &Secret,
+    target: &Target,
+) -> Result<(Api<Secret>, Secret), Error> {
+    let source_ns = source.namespace().unwrap();
+    let ns = target.namespace.as_deref().unwrap_or(&source_ns);
+    if ns != source_ns && !ctx.opts.cross_namespace {
+        return Err(Error::Conflict(format!(
+            "target {}/{} is in another namespace, which requires \
+             --cross-namespace",
+            ns, target.name
+        )));
+    }
+    if !ctx.opts.namespace_allowed(ns) {
+        return Err(Error::Conflict(format!(
+            "target {}/{} is in an excluded namespace",
+            ns, target.name
+        )));
+    }
+    if ns != source_ns && !replication_allowed(ctx, ns, &source_ns).await? {
+        return Err(Error::Conflict(format!(
+            "namespace {} does not allow replication from namespace {}",
+            ns, source_ns
+        )));
+    }
+    let api = Api::<Secret>::namespaced(ctx.client.clone(), ns);
+    let source_ref = format!("{}/{}", source_ns, source.name_any());
+
+    if let Some(existing) = api.get_opt(&target.name).await? {
+        if existing.annotations().get(app_id!("target-of")) != Some(&source_ref)
+        {
+            return Err(Error::Conflict(format!(
+                "secret {}/{} is not a target of {}",
+                ns, target.name, source_ref
+            )));
+        }
+        return Ok((api, existing));
+    }
+
+    // Owner references can't cross namespaces, so only targets in the same
+    // namespace are garbage collected together with their source.
+    let owner_references = (ns == source_ns)
+        .then(|| source.controller_owner_ref(&()))
+        .flatten()
+        .map(|owner| vec![owner]);
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(target.name.clone()),
+            annotations: Some(
+                [(app_id!("target-of").to_string(), source_ref)]
+                    .into_iter()
+                    .collect(),
+            ),
+            owner_references,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let created = api.create(&PostParams::default(), &secret).await?;
+    Ok((api, created))
+}
+
+// Whether `namespace` lets secrets of the namespace `from` write into it, by
+// listing it (or `*`) in its `allow-replication-from` annotation.
+fn allows_replication(namespace: &Namespace, from: &str) -> bool {
+    namespace
+        .annotations()
+        .get(app_id!("allow-replication-from"))
+        .is_some_and(|allowed| {
+            allowed
+                .split(',')
+                .map(str::trim)
+                .any(|a| a == "*" || a == from)
+        })
+}
+
+async fn replication_allowed(
+    ctx: &Context,
+    ns: &str,
+    from: &str,
+) -> Result<bool, Error> {
+    let namespace = Api::<Namespace>::all(ctx.client.clone())
+        .get_opt(ns)
+        .await?;
+    Ok(namespace.is_some_and(|namespace| allows_replication(&namespace, from)))
+}
+
+// Collects the namespaces listed in `replicate-to` and those matching the
+// label selector in `replicate-to-selector` that allow replication from the
+// namespace of `source`. Listed namespaces that don't are an error.
+async fn replica_namespaces(
+    ctx: &Context,
+    source: &Secret,
+) -> Result<BTreeSet<String>, Error> {
+    let source_ns = source.namespace().unwrap();
+    let mut namespaces: BTreeSet<String> = source
+        .annotations()
+        .get(app_id!("replicate-to"))
+        .into_iter()
+        .flat_map(|l| l.split(','))
+        .map(str::trim)
+        .filter(|ns| !ns.is_empty())
+        .map(str::to_string)
+        .collect();
+    namespaces.remove(&source_ns);
+    namespaces.retain(|ns| ctx.opts.namespace_allowed(ns));
+    for ns in &namespaces {
+        if !replication_allowed(ctx, ns, &source_ns).await? {
+            return Err(Error::Conflict(format!(
+                "namespace {} does not allow replication from namespace {}",
+                ns, source_ns
+            )));
+        }
+    }
+    if let Some(selector) =
+        source.annotations().get(app_id!("replicate-to-selector"))
+    {
+        let api = Api::<Namespace>::all(ctx.client.clone());
+        for ns in api.list(&ListParams::default().labels(selector)).await? {
+            let name = ns.name_any();
+            if allows_replication(&ns, &source_ns)
+                && name != source_ns
+                && ctx.opts.namespace_allowed(&name)
+            {
+                namespaces.insert(name);
+            }
+        }
+    }
+    Ok(namespaces)
+}
+
+// Copies `data` into secrets called `name` in each of `namespaces`, and
+// deletes replicas of `source` in namespaces no longer listed. Replicas are
+// labeled with the uid of their source, so unrelated secrets are never
+// overwritten.
+async fn replicate(
+    ctx: &Context,
+    source: &Secret,
+    name: &str,
+    type_: Option<String>,
+    data: SecretData,
+    namespaces: &BTreeSet<String>,
+) -> Result<(), Error> {
+    let uid = source.uid().unwrap();
+    let source_ns = source.namespace().unwrap();
+    let selector = format!("{}={}", app_id!("replica-of"), uid);
+
+    for ns in namespaces {
+        let api = Api::<Secret>::namespaced(ctx.client.clone(), ns);
+        let Some(replica) = api.get_opt(name).await? else {
+            let replica = Secret {
+                metadata: ObjectMeta {
+                    name: Some(name.to_string()),
+                    labels: Some(
+                        [(app_id!("replica-of").to_string(), uid.clone())]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..Default::default()
+                },
+                type_: type_.clone(),
+                data: Some(data.0.clone()),
+                ..Default::default()
+            };
+            api.create(&PostParams::default(), &replica).await?;
+            continue;
+        };
+
+        if replica.labels().get(app_id!("replica-of")) != Some(&uid) {
+            return Err(Error::Conflict(format!(
+                "secret {}/{} exists but is not a replica of {}/{}",
+                ns,
+                name,
+                source_ns,
+                source.name_any()
+            )));
+        }
+        if replica.type_ != type_ {
+            let (type_, data) = (type_.clone(), data.clone());
+            recreate_secret(&api, replica, type_, data, BTreeMap::new())
+                .await?;
+        } else if replica.data.as_ref() != Some(&data) {
+            let mut patch: BTreeMap<&String, Option<&ByteString>> = replica
+                .data
+                .iter()
+                .flatten()
+                .map(|(k, _)| (k, None))
+                .collect();
+            patch.extend(data.iter().map(|(k, v)| (k, Some(v))));
+            let mut patch = serde_json::json!({ "data": patch });
+            let patched = api
+                .patch(
+                    name,
+                    &PatchParams::apply(app_id!()),
+                    &Patch::Merge(&patch),
+                )
+                .await;
+            wipe(&mut patch);
+            patched?;
+        }
+    }
+
+    let api = Api::<Secret>::all(ctx.client.clone());
+    for replica in api.list(&ListParams::default().labels(&selector)).await? {
+        let ns = replica.namespace().unwrap();
+        // Namespaces that withdrew their consent keep their replicas.
+        if !namespaces.contains(&ns)
+            && ctx.opts.namespace_allowed(&ns)
+            && replication_allowed(ctx, &ns, &source_ns).await?
+        {
+            Api::<Secret>::namespaced(ctx.client.clone(), &ns)
+                .delete(&replica.name_any(), &DeleteParams::default())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// Maintains the secret declared in the create annotation of `owner`.
+async fn reconcile_creator<K>(
+    owner: Arc<K>,
+    ctx: Arc<Context>,
+) -> Result<Action, Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let Some(create) = owner.annotations().get(app_id!("create")) else {
+        return Ok(Action::await_change());
+    };
+    let create: CreateSecret = parse_value(app_id!("create"), create)?;
+    let ns = owner.namespace().unwrap();
+    if !ctx.opts.watches_namespace(&ns) {
+        return Ok(Action::await_change());
+    }
+    let api = Api::<Secret>::namespaced(ctx.client.clone(), &ns);
+
+    let mut secret = create.to_secret()?;
+    secret.labels_mut().extend(ctx.opts.selector_labels());
+    apply_owned_secret(&api, owner.as_ref(), secret).await?;
+    Ok(requeue(&ctx.opts, None, true))
+}
+
+// Stamps out `<name>-0` … `<name>-<count - 1>` carrying the operator
+// annotations of `parent`. The children are generated like any other
+// annotated secret and are garbage collected together with the parent.
+async fn fan_out(
+    ctx: &Context,
+    api: &Api<Secret>,
+    parent: &Secret,
+    count: usize,
+) -> Result<(), Error> {
+    let uid = parent.uid().unwrap_or_default();
+    let prefix = format!("{}-", parent.name_any());
+    let annotations = operator_annotations(parent, app_id!("fan-out"));
+
+    for i in 0..count {
+        let child = Secret {
+            metadata: ObjectMeta {
+                name: Some(format!("{}{}", prefix, i)),
+                annotations: Some(annotations.clone()),
+                labels: Some(
+                    [(app_id!("fan-out-of").to_string(), uid.clone())]
+                        .into_iter()
+                        .chain(ctx.opts.selector_labels())
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            type_: parent.type_.clone(),
+            ..Default::default()
+        };
+        apply_owned_secret(api, parent, child).await?;
+    }
+
+    let selector = format!("{}={}", app_id!("fan-out-of"), uid);
+    for child in api.list(&ListParams::default().labels(&selector)).await? {
+        let name = child.name_any();
+        let index = name.strip_prefix(&prefix).and_then(|i| i.parse().ok());
+        if index.is_none_or(|i: usize| i >= count) {
+            api.delete(&name, &DeleteParams::default()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Stamps out a secret of the same name carrying the operator annotations of
+// `template` into every namespace matching `selector`. Unlike replicas, the
+// stamped secrets are generated independently. Stamped secrets in namespaces
+// that no longer match are deleted. Hooks act for the namespace of the
+// template and aren't stamped.
+pub(crate) async fn stamp(
+    ctx: &Context,
+    template: &Secret,
+    selector: &str,
+) -> Result<(), Error> {
+    let uid = template.uid().unwrap_or_default();
+    let name = template.name_any();
+    let mut annotations =
+        operator_annotations(template, app_id!("stamp-to-selector"));
+    for hook in [
+        app_id!("pre-rotate"),
+        app_id!("post-generate"),
+        app_id!("canary"),
+    ] {
+        annotations.remove(hook);
+    }
+    let namespaces: BTreeSet<String> =
+        Api::<Namespace>::all(ctx.client.clone())
+            .list(&ListParams::default().labels(selector))
+            .await?
+            .into_iter()
+            .map(|ns| ns.name_any())
+            .filter(|ns| Some(ns) != template.namespace().as_ref())
+            .filter(|ns| ctx.opts.namespace_allowed(ns))
+            .collect();
+
+    for ns in &namespaces {
+        let api = Api::<Secret>::namespaced(ctx.client.clone(), ns);
+        let Some(existing) = api.get_opt(&name).await? else {
+            let stamped = Secret {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    annotations: Some(annotations.clone()),
+                    labels: Some(
+                        [(app_id!("stamped-from").to_string(), uid.clone())]
+                            .into_iter()
+                            .chain(ctx.opts.selector_labels())
+                            .collect(),
+                    ),
+                    ..Default::default()
+                },
+                type_: template.type_.clone(),
+                ..Default::default()
+            };
+            api.create(&PostParams::default(), &stamped).await?;
+            continue;
+        };
+        if existing.labels().get(app_id!("stamped-from")) != Some(&uid) {
+            return Err(Error::Conflict(format!(
+                "secret {}/{} exists but was not stamped from {}/{}",
+                ns,
+                name,
+                template.namespace().unwrap(),
+                name
+            )));
+        }
+        patch_annotations(&api, &existing, &annotations).await?;
+    }
+
+    let selector = format!("{}={}", app_id!("stamped-from"), uid);
+    let api = Api::<Secret>::all(ctx.client.clone());
+    for stamped in api.list(&ListParams::default().labels(&selector)).await? {
+        let ns = stamped.namespace().unwrap();
+        if !namespaces.contains(&ns) && ctx.opts.namespace_allowed(&ns) {
+            Api::<Secret>::namespaced(ctx.client.clone(), &ns)
+                .delete(&stamped.name_any(), &DeleteParams::default())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// Reconciles a secret that is watched by its metadata only. Only secrets
+// carrying operator annotations are fetched, so the data of the others is
+// never held in memory.
+async fn reconcile_metadata(
+    meta: Arc<PartialObjectMeta<Secret>>,
+    ctx: Arc<Context>,
+) -> Result<Action, Error> {
+    for dependent in ctx.references.lock().unwrap().observe(meta.as_ref()) {
+        ctx.trigger.send(dependent).ok();
+    }
+    if !ctx.annotated(meta.as_ref()) {
+        return Ok(Action::await_change());
+    }
+    let api = Api::<Secret>::namespaced(
+        ctx.client.clone(),
+        meta.namespace().as_deref().unwrap_or_default(),
+    );
+    match api.get_opt(&meta.name_any()).await? {
+        Some(secret) => reconcile_secret(Arc::new(secret), ctx).await,
+        None => Ok(Action::await_change()),
+    }
+}
+
+// Reconciles a secret and records why that failed in its error annotation,
+// so that users can see it with `kubectl get secret -o yaml`. API errors are
+// usually transient and not recorded.
+pub(crate) async fn reconcile_secret(
+    secret: Arc<Secret>,
+    ctx: Arc<Context>,
+) -> Result<Action, Error> {
+    // The spans of the steps of the reconcile belong to this one.
+    let span = info_span!(
+        "reconcile",
+        namespace = %secret.namespace().unwrap_or_default(),
+        name = %secret.name_any(),
+    );
+    let started = Instant::now();
+    let result = reconcile(secret.clone(), ctx.clone())
+        .instrument(span)
+        .await;
+    let ns = secret.namespace().unwrap_or_default();
+    metrics::reconciled(&ns, &result, started.elapsed());
+    let error = match &result {
+        Err(Error::Kube(_)) => return result,
+        Err(e) => Some(e.to_string()),
+        Ok(_) => None,
+    };
+    if let Err(e) = mark_error(&ctx, &secret, error).await {
+        warn!("failed to record error on {}: {}", secret.name_any(), e);
+    }
+    result
+}
+
+async fn reconcile(
+    secret: Arc<Secret>,
+    ctx: Arc<Context>,
+) -> Result<Action, Error> {
+    let client = ctx.client.clone();
+    let ns = secret.namespace().unwrap();
+    // Objects of other namespaces are filtered from the watch, but may still
+    // be triggered through references from templates.
+    if !ctx.opts.watches_namespace(&ns) {
+        return Ok(Action::await_change());
+    }
+    let api = Api::<Secret>::namespaced(client.clone(), &ns);
+    let secret = Arc::unwrap_or_clone(secret);
+
+    for dependent in ctx.references.lock().unwrap().observe(&secret) {
+        ctx.trigger.send(dependent).ok();
+    }
+
+    let mut regenerate = None;
+    let declared =
+        info_span!("parse").in_scope(|| declared_keys(secret.meta()))?;
+    let (mut settings, mut failed) = match declared {
+        Some(declared) => declared,
+        None if ctx.opts.mittwald_compat => {
+            match mittwald_settings(secret.meta())? {
+                Some(mittwald) => {
+                    regenerate = Some(mittwald.regenerate);
+                    (mittwald.settings, KeyErrors::new())
+                }
+                None => return Ok(Action::await_change()),
+            }
+        }
+        None => return Ok(Action::await_change()),
+    };
+
+    let selector = secret.annotations().get(app_id!("stamp-to-selector"));
+    // Templates write into namespaces of their choosing, so only the
+    // operator's own namespace may hold them.
+    let template = selector.is_some() && ns == ctx.opts.namespace;
+    if ctx.opts.cross_namespace {
+        let mut stamps = ctx.stamps.lock().unwrap();
+        match template {
+            true => stamps.insert(ObjectRef::from_obj(&secret)),
+            false => stamps.remove(&ObjectRef::from_obj(&secret)),
+        };
+    }
+    if let Some(selector) = selector {
+        if !ctx.opts.cross_namespace {
+            return Err(Error::Conflict(format!(
+                "{} requires --cross-namespace",
+                app_id!("stamp-to-selector")
+            )));
+        }
+        if !template {
+            return Err(Error::Conflict(format!(
+                "{} is only honoured in namespace {}",
+                app_id!("stamp-to-selector"),
+                ctx.opts.namespace
+            )));
+        }
+        stamp(&ctx, &secret, selector).await?;
+        return Ok(requeue(&ctx.opts, None, true));
+    }
+
+    if let Some(count) =
+        parse_annotation::<usize>(secret.meta(), app_id!("fan-out"))?
+    {
+        fan_out(&ctx, &api, &secret, count).await?;
+        return Ok(requeue(&ctx.opts, None, true));
+    }
+
+    // With a target, the annotated secret only holds the declaration and the
+    // generated data goes into the target secret.
+    let source_api = api.clone();
+    let target = parse_annotation::<Target>(secret.meta(), app_id!("target"))?;
+    let targeted = target.is_some();
+    let (api, mut dest) = match target {
+        Some(target) => target_secret(&ctx, &secret, &target).await?,
+        None => (api, secret.clone()),
+    };
+    // `dest` keeps its data to be restored if recreating it fails.
+    let mut old_data = SecretData(dest.data.clone().unwrap_or_default());
+    // Keys that don't fit live in the companion of the secret.
+    let overflow = overflow_keys(&secret);
+    let companion = match overflow.is_empty() {
+        true => SecretData::default(),
+        false => read_companion(&api, &dest.name_any()).await?,
+    };
+    for key in &overflow {
+        if let Some(value) = companion.get(key) {
+            old_data.insert(key.clone(), value.clone());
+        }
+    }
+    let empty = drop_empty(secret.meta(), &settings, &mut old_data)?;
+    if !empty.is_empty() {
+        let name = secret.name_any();
+        info!("overwriting empty keys {} of {}", empty.join(", "), name);
+    }
+    // Values others changed since they were generated are only reported.
+    let mut drifted = drifted_keys(&dest, &old_data);
+    let drift_owners = match drifted.is_empty() {
+        true => BTreeMap::new(),
+        false => foreign_owners(&dest),
+    };
+
+    let opts = ctx.opts(&ns).await?;
+    check_quota(&client, &opts, &secret).await?;
+    // The keys issued by cert-manager are left to it.
+    let issued = issue_certificates(
+        &ctx,
+        &opts,
+        &secret,
+        &dest,
+        &mut settings,
+        &mut failed,
+    )
+    .await?;
+    let rotation = Rotation::new(&opts, secret.meta(), &settings)?;
+    let mut expired = rotation.expired();
+    // A rotation whose canary runs passed the pre-rotate hook already.
+    let canary_pending = secret
+        .annotations()
+        .get(app_id!("canary-status"))
+        .is_some_and(|s| s == "pending");
+    let retry = match expired.is_empty() || canary_pending {
+        true => None,
+        false => pre_rotate(&ctx, &secret, &mut expired).await?,
+    };
+    let rotating = !expired.is_empty();
+    expired.extend(regenerate.iter().flatten().cloned());
+
+    let references = secret_references(&settings, &ns);
+    ctx.references.lock().unwrap().set(
+        &ObjectRef::from_obj(&secret),
+        &references
+            .iter()
+            .map(|(ns, name)| ObjectRef::new(name).within(ns))
+            .collect::<Vec<_>>(),
+    );
+    let secrets = fetch_references(&client, &ns, &references).await?;
+    let seed = master_seed(&ctx, &settings).await?;
+
+    // Keys that cannot reach the minimum score or a rejecting strength floor
+    // are refused before anything is generated, and so are keys other field
+    // managers own with `--apply-conflicts=refuse`.
+    let case = parse_annotation::<KeyCase>(secret.meta(), app_id!("key-case"))?;
+    let exists = |data: &SecretData, key: &str| {
+        data.contains_key(key)
+            || case.is_some_and(|c| data.contains_key(&c.apply(key)))
+    };
+    let adopt = parse_annotation::<bool>(secret.meta(), app_id!("adopt"))?
+        .unwrap_or(false);
+    let owners = match opts.apply_conflicts {
+        ApplyConflicts::Refuse if !adopt => foreign_owners(&dest),
+        _ => BTreeMap::new(),
+    };
+    let owner = |key: &str| {
+        let cased = case.map(|c| c.apply(key));
+        owners.get(key).or_else(|| owners.get(cased.as_deref()?))
+    };
+    for (key, s) in settings
+        .iter()
+        .filter(|(key, _)| !exists(&old_data, key) || expired.contains(*key))
+    {
+        if let Some(e) = s.refused(key, &opts) {
+            failed.insert(key.clone(), e);
+        } else if let Some(manager) = owner(key) {
+            let e = Error::Conflict(format!(
+                "owned by field manager {:?}, set {} to adopt it",
+                manager,
+                app_id!("adopt")
+            ));
+            failed.insert(key.clone(), e);
+        }
+    }
+
+    let scope = Scope {
+        meta: secret.meta(),
+        secrets: &secrets,
+        seed: seed.as_deref().map(Vec::as_slice),
+    };
+    let mut data = info_span!("generate").in_scope(|| {
+        blocking(|| {
+            gen_data(&opts, &settings, &scope, &old_data, &expired, &mut failed)
+        })
+    })?;
+    // Rotations of secrets with a canary hook are staged in a copy of the
+    // secret and only written once the hook passed.
+    let hook = parse_annotation::<Hook>(secret.meta(), app_id!("canary"))?;
+    let mut source_version = secret.resource_version();
+    if let Some(hook) = hook.filter(|_| rotating) {
+        let staging = Staging {
+            source_api: &source_api,
+            secret: &secret,
+            dest: &dest,
+            old_data: &old_data,
+        };
+        match canary(&ctx, &staging, &hook, &data).await? {
+            Canary::Promote(staged, version) => {
+                data = staged;
+                // Recording the promotion changed the declaring secret, which
+                // holds the values without a target.
+                if version.is_some() {
+                    if !targeted {
+                        dest.metadata.resource_version = version.clone();
+                    }
+                    source_version = version;
+                }
+            }
+            Canary::Hold(retry) => return Ok(Action::requeue(retry)),
+        }
+    }
+    let written = |key: &str| exists(&data, key);
+    let (generated_at, next_rotation) = rotation.update(written);
+
+    // Values were generated at the floor already.
+    for (key, s) in settings.iter().filter(|(key, _)| written(key)) {
+        let Some(problem) = s.below_floor(&opts) else {
+            continue;
+        };
+        let note =
+            format!("raised {:?} to the strength floor: {}", key, problem);
+        report(&ctx, &secret, EventType::Warning, "Clamped", note).await;
+    }
+
+    let type_ = secret.annotations().get(app_id!("type")).cloned();
+    if type_.is_some() && type_ != dest.type_ {
+        let recreate =
+            parse_annotation::<bool>(secret.meta(), app_id!("recreate"))?
+                .unwrap_or(false);
+        if !recreate {
+            return Err(Error::Conflict(format!(
+                "secret has type {:?} instead of {:?}, set {} to allow \
+                 recreating it",
+                dest.type_.as_deref().unwrap_or("Opaque"),
+                type_.as_deref().unwrap_or_default(),
+                app_id!("recreate")
+            )));
+        }
+    }
+
+    let dest_name = dest.name_any();
+    let dest_namespace = dest.namespace().unwrap_or_default();
+    let generated = !data.is_empty();
+    let written_keys: BTreeSet<String> = data.keys().cloned().collect();
+    let records = audit_records(&opts, &settings, &old_data, &data);
+    let type_ = type_.or_else(|| dest.type_.clone());
+    // Values about to be overwritten, by a rotation or by recreating the
+    // secret, are backed up first so that they can be rolled back. Backups
+    // hold the keys the operator manages.
+    let overwrites = data.keys().any(|key| old_data.contains_key(key));
+    if let Some(dir) = &ctx.opts.backup_dir {
+        let managed = applied_keys(&dest);
+        let previous: SecretData = old_data
+            .iter()
+            .filter(|(key, _)| {
+                managed.contains(*key) || data.contains_key(*key)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if !previous.is_empty() && (overwrites || type_ != dest.type_) {
+            let snapshot = Snapshot {
+                namespace: &dest_namespace,
+                name: &dest_name,
+                type_: dest.type_.as_deref(),
+                data: &previous,
+                generated_at: &rotation.generated_at,
+            };
+            let path = backup_secret(&ctx.opts, dir, &snapshot)?;
+            info!("backed up secret {} to {}", dest_name, path.display());
+        }
+    }
+    let mut merged = old_data;
+    merged.extend(data.clone());
+    // Values that expire, like self-signed certificates, are generated again
+    // before they do.
+    let next_renewal = settings
+        .iter()
+        .filter_map(|(key, s)| s.renew_after(key, &merged))
+        .min();
+    // The keys applied before are applied again, as server-side apply
+    // removes the ones left out. Keys cert-manager issues now are left to
+    // it.
+    let dest_applied = applied_keys(&dest);
+    let mut applied: SecretData = dest_applied
+        .iter()
+        .chain(&overflow)
+        .filter(|key| !issued.contains(*key))
+        .filter_map(|key| Some((key.clone(), merged.get(key)?.clone())))
+        .collect();
+    applied.extend(data.clone());
+    // From here on, `merged` and `in_secret` hold what the secret itself
+    // holds, and `overflowed` what goes into its companion.
+    let mut overflowed = SecretData::default();
+    for key in &overflow {
+        if let Some(value) = merged.remove(key) {
+            overflowed.insert(key.clone(), value);
+        }
+    }
+    let mut in_secret = applied.clone();
+    in_secret.retain(|key, _| !overflow.contains(key));
+    // Keys moved into the companion are removed from the secret.
+    let moved = dest_applied.iter().any(|key| overflow.contains(key));
+    if let Some(warning) = check_size(&dest_name, &merged)? {
+        report(&ctx, &secret, EventType::Warning, "LargeSecret", warning).await;
+    }
+    // Consumers like Reloader or pipeline checks watch these to react to
+    // changed keys.
+    let revision = parse_annotation::<u64>(dest.meta(), app_id!("revision"))
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    let checksum = data_checksum(&applied);
+    let mut revision = BTreeMap::from([
+        (app_id!("revision").to_string(), (revision + 1).to_string()),
+        (app_id!("checksum").to_string(), checksum.clone()),
+        (
+            app_id!("checksums").to_string(),
+            serde_json::to_string(&applied_checksums(&dest, &applied, &data))?,
+        ),
+    ]);
+    // Counters record what they generated in the same write as the values.
+    // Secrets with a target reserve the values first instead, a failed write
+    // skips them.
+    let recorded = recorded_counters(secret.meta())?;
+    let counters = advance_counters(&recorded, &settings, |key| {
+        data.get(key).or_else(|| data.get(&case?.apply(key)))
+    });
+    if targeted && counters != recorded {
+        let patched = source_api
+            .patch(
+                &secret.name_any(),
+                &PatchParams::apply(app_id!()),
+                &Patch::Merge(serde_json::json!({
+                    "metadata": {
+                        "resourceVersion": source_version,
+                        "annotations": {
+                            app_id!("counters"):
+                                serde_json::to_string(&counters)?,
+                        },
+                    }
+                })),
+            )
+            .await;
+        match patched {
+            Ok(patched) => ctx.wrote(&patched),
+            Err(e) if stale(&e) => {
+                let name = secret.name_any();
+                info!("secret {} changed meanwhile, retrying", name);
+                return Ok(Action::requeue(STALE_RETRY));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    } else if !targeted && !counters.is_empty() {
+        revision.insert(
+            app_id!("counters").to_string(),
+            serde_json::to_string(&counters)?,
+        );
+    }
+    // Stores get the values first, a failed write is retried before the
+    // secret holds values a store is missing. Broken locations are reported
+    // before any value leaves the cluster.
+    let stores = annotated_stores(secret.meta());
+    for (store, location) in &stores {
+        store.validate(location)?;
+    }
+    for (store, location) in stores.iter().filter(|_| generated) {
+        let write = StoreWrite {
+            location,
+            namespace: &dest_namespace,
+            name: &dest_name,
+            generated: &data,
+            managed: &applied,
+            checksum: &checksum,
+        };
+        if let Some(version) = store.write(&write).await? {
+            revision.insert(version_annotation(store.as_ref()), version);
+        }
+    }
+    // The companion gets the values first, like stores.
+    if overflowed != companion {
+        write_companion(&api, &dest_name, overflowed).await?;
+    }
+    // Nothing is written when all keys exist already.
+    if type_ != dest.type_ {
+        let recreated = recreate_secret(
+            &api,
+            dest,
+            type_.clone(),
+            merged.clone(),
+            revision,
+        )
+        .await;
+        match recreated {
+            Err(Error::Kube(e)) if stale(&e) => {
+                info!("secret {} changed meanwhile, retrying", dest_name);
+                return Ok(Action::requeue(STALE_RETRY));
+            }
+            recreated => recreated?,
+        }
+    } else if generated || moved {
+        let string_data =
+            parse_annotation::<bool>(secret.meta(), app_id!("string-data"))?
+                .unwrap_or(false);
+
+        debug!("generated data: {:?}", data);
+        let mut patch = data_patch(in_secret, string_data);
+        patch["apiVersion"] = "v1".into();
+        patch["kind"] = "Secret".into();
+        // The resource version makes the apply fail if the secret changed
+        // since it was read, instead of overwriting what was written then.
+        patch["metadata"] = serde_json::json!({
+            "name": dest_name,
+            "resourceVersion": dest.resource_version(),
+            "annotations": revision,
+        });
+        let mut params = PatchParams::apply(app_id!());
+        params.force = adopt || opts.apply_conflicts == ApplyConflicts::Force;
+        let patched = api
+            .patch(&dest_name, &params, &Patch::Apply(&patch))
+            .instrument(info_span!("patch"))
+            .await;
+        wipe(&mut patch);
+        match patched {
+            Ok(patched) => ctx.wrote(&patched),
+            Err(e) if stale(&e) => {
+                info!("secret {} changed meanwhile, retrying", dest_name);
+                return Ok(Action::requeue(STALE_RETRY));
+            }
+            Err(kube::Error::Api(e)) if e.code == 409 => {
+                return Err(Error::Conflict(e.message));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    for (action, _, keys) in &records {
+        metrics::keys_written(&dest_namespace, action, keys.len());
+        let (reason, note) = match *action {
+            "rotate" => ("Rotated", format!("Rotated {}", keys.join(", "))),
+            _ => (
+                "Generated",
+                format!(
+                    "Generated {} key{}",
+                    keys.len(),
+                    if keys.len() == 1 { "" } else { "s" }
+                ),
+            ),
+        };
+        report(&ctx, &secret, EventType::Normal, reason, note).await;
+    }
+    let rotated = records.iter().any(|(action, ..)| *action == "rotate");
+    publish_records(&ctx, &opts, &dest_namespace, &dest_name, records).await;
+    // The rotation is done already, so a failed restart is only reported.
+    if rotated && ctx.opts.restart_workloads {
+        match restart_workloads(&ctx, &dest_namespace, &dest_name).await {
+            Ok(restarted) if restarted.is_empty() => {}
+            Ok(restarted) => {
+                let note = format!("Restarted {}", restarted.join(", "));
+                report(&ctx, &secret, EventType::Normal, "Restarted", note)
+                    .await;
+            }
+            Err(e) => {
+                let note = format!("Failed to restart workloads: {}", e);
+                report(
+                    &ctx,
+                    &secret,
+                    EventType::Warning,
+                    "RestartFailed",
+                    note,
+                )
+                .await;
+            }
+        }
+    }
+    for (key, e) in &failed {
+        let note = format!("{}: {}", key, e);
+        report(&ctx, &secret, EventType::Warning, e.reason(), note).await;
+    }
+    mark_errors(&ctx, &source_api, &secret, &failed).await?;
+    // Hook Jobs run next to the annotated secret and can only mount the
+    // generated secret if it is in the same namespace.
+    let mount = (dest_namespace == ns).then_some(dest_name.as_str());
+    let hook_retry =
+        post_generate(&ctx, &source_api, &secret, &written_keys, mount).await?;
+
+    if regenerate.is_some() {
+        mittwald_mark(&ctx, &source_api, &secret, generated).await?;
+    }
+    if generated_at != rotation.recorded {
+        mark_generated_at(&ctx, &source_api, &secret, &generated_at).await?;
+    }
+    let managed = applied.keys().cloned().collect();
+    let now = Utc::now();
+    let last_generated = last_generated(
+        secret.meta(),
+        &managed,
+        &written_keys,
+        &now.to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    // Keys generated now have a new checksum.
+    drifted.retain(|key| !written_keys.contains(key));
+    let reported = listed_keys(secret.meta(), app_id!("drifted"));
+    let new_drift: Vec<String> = drifted
+        .difference(&reported)
+        .map(|key| match drift_owners.get(key) {
+            Some(manager) => format!("{} (by {})", key, manager),
+            None => key.clone(),
+        })
+        .collect();
+    if !new_drift.is_empty() {
+        metrics::keys_drifted(&dest_namespace, new_drift.len());
+        let note = format!(
+            "Values changed since they were generated: {}",
+            new_drift.join(", ")
+        );
+        report(&ctx, &secret, EventType::Warning, "Drifted", note).await;
+    }
+    // Keys that aren't rotated are reported once they are too old.
+    let (stale, next_stale) = stale_keys(
+        &opts,
+        secret.meta(),
+        &settings,
+        &rotation,
+        &last_generated,
+        now,
+    )?;
+    let reported = listed_keys(secret.meta(), app_id!("stale"));
+    let new_stale: Vec<String> = stale
+        .difference(&reported)
+        .map(|key| format!("{} (generated {})", key, last_generated[key]))
+        .collect();
+    if !new_stale.is_empty() {
+        metrics::secret_stale(&dest_namespace);
+        let note = format!(
+            "Values are older than their maximum age and aren't rotated: {}",
+            new_stale.join(", ")
+        );
+        report(&ctx, &secret, EventType::Warning, "Stale", note).await;
+    }
+    let status = status_annotations(
+        secret.meta(),
+        &managed,
+        &last_generated,
+        &rotation.schedule(&generated_at),
+        &drifted,
+        &stale,
+    )?;
+    mark_status(&ctx, &source_api, &secret, &status).await?;
+    let seal = parse_annotation::<bool>(secret.meta(), app_id!("seal"))?
+        .unwrap_or(false);
+    let mut output_failed = false;
+    if ctx.opts.sealed_secrets && seal {
+        let sealed = seal_secret(
+            &ctx,
+            &dest_namespace,
+            &dest_name,
+            type_.as_deref(),
+            &merged,
+        )
+        .await;
+        match sealed {
+            Ok(true) => {
+                let note = format!("Sealed {}", dest_name);
+                report(&ctx, &secret, EventType::Normal, "Sealed", note).await;
+            }
+            Ok(false) => {}
+            // The secret is written already, so a failed seal is only
+            // reported and tried again periodically.
+            Err(e) => {
+                output_failed = true;
+                let note = format!("Failed to write SealedSecret: {}", e);
+                report(&ctx, &secret, EventType::Warning, "SealFailed", note)
+                    .await;
+            }
+        }
+    }
+    let push =
+        parse_annotation::<PushTarget>(secret.meta(), app_id!("push-secret"))?;
+    if let Some(push) = push.filter(|_| ctx.opts.push_secrets) {
+        let keys = applied.keys().cloned().collect();
+        let pushed = apply_push_secret(
+            &ctx,
+            &secret,
+            &dest_namespace,
+            &dest_name,
+            &keys,
+            &push,
+        )
+        .await;
+        if let Err(e) = pushed {
+            output_failed = true;
+            let note = format!("Failed to write PushSecret: {}", e);
+            report(&ctx, &secret, EventType::Warning, "PushFailed", note).await;
+        }
+    }
+    if let Some(dir) = &ctx.opts.export_dir {
+        let snapshot = Snapshot {
+            namespace: &dest_namespace,
+            name: &dest_name,
+            type_: type_.as_deref(),
+            data: &applied,
+            generated_at: &generated_at,
+        };
+        let exported = export_secret(&ctx.opts, dir, &snapshot, generated);
+        // Snapshots that were written or failed to push before are pushed.
+        let snapshot = snapshot_path(&dest_namespace, &dest_name);
+        let pushed = match exported {
+            Ok(written) if written || git::pending(&snapshot) => {
+                if written {
+                    info!("exported secret {}/{}", dest_namespace, dest_name);
+                }
+                match &ctx.opts.export_git_repo {
+                    Some(_) => {
+                        let message = format!("Update {}", snapshot);
+                        git::push(&ctx.opts, &snapshot, message).await
+                    }
+                    None => Ok(()),
+                }
+            }
+            exported => exported.map(|_| ()),
+        };
+        if let Err(e) = pushed {
+            output_failed = true;
+            let note = format!("Failed to export: {}", e);
+            report(&ctx, &secret, EventType::Warning, "ExportFailed", note)
+                .await;
+        }
+    }
+    let mut replicated = false;
+    if ctx.opts.cross_namespace {
+        let namespaces = replica_namespaces(&ctx, &secret).await?;
+        replicated = !namespaces.is_empty();
+        replicate(&ctx, &secret, &dest_name, type_, merged, &namespaces)
+            .await?;
+    }
+    if let Some(retry) = retry.into_iter().chain(hook_retry).min() {
+        return Ok(Action::requeue(retry));
+    }
+    // Changes to the secret itself trigger a reconcile anyway. Copies in other
+    // secrets, failed SealedSecrets and exports, and keys that failed are
+    // still checked periodically.
+    let target = (dest_namespace.as_str(), dest_name.as_str())
+        != (ns.as_str(), secret.name_any().as_str());
+    let periodic = target || replicated || output_failed || !failed.is_empty();
+    let due = next_rotation
+        .into_iter()
+        .chain(next_stale)
+        .chain(next_renewal)
+        .min();
+    Ok(requeue(&ctx.opts, due, periodic))
+}
+
+// Time after which a secret that changed while it was reconciled is read and
+// reconciled again.
+const STALE_RETRY: Duration = Duration::from_secs(1);
+
+// Whether a write failed because the object changed since it was read, or was
+// created meanwhile. Conflicts with other field managers are not stale.
+fn stale(e: &kube::Error) -> bool {
+    matches!(e, kube::Error::Api(e)
+        if e.code == 409 && !e.message.starts_with("Apply failed"))
+}
+
+// Reconciles an object again once `due` has passed, and with `periodic` also
+// after `--requeue-interval` plus up to `--requeue-jitter`, whichever comes
+// first.
+fn requeue(opts: &Opts, due: Option<Duration>, periodic: bool) -> Action {
+    let interval = opts.requeue_interval.0;
+    let interval = (periodic && !interval.is_zero()).then(|| {
+        let jitter = opts.requeue_jitter.0.as_millis() as u64;
+        interval
+            + Duration::from_millis(rand::thread_rng().gen_range(0..=jitter))
+    });
+    let due = due.map(|due| due.max(Duration::from_secs(1)));
+    match due.into_iter().chain(interval).min() {
+        Some(after) => Action::requeue(after),
+        None => Action::await_change(),
+    }
+}
+
+// Runs the post-generate hook once keys were written and follows it until it
+// finished, recording its state in the post-generate-status annotation.
+// Returns the time to check again while it runs.
+async fn post_generate(
+    ctx: &Context,
+    api: &Api<Secret>,
+    secret: &Secret,
+    keys: &BTreeSet<String>,
+    mount: Option<&str>,
+) -> Result<Option<Duration>, Error> {
+    let Some(hook) =
+        parse_annotation::<Hook>(secret.meta(), app_id!("post-generate"))?
+    else {
+        return Ok(None);
+    };
+    let current = secret.annotations().get(app_id!("post-generate-status"));
+    if keys.is_empty() && current.map(String::as_str) != Some("pending") {
+        return Ok(None);
+    }
+    let outcome = hook.run(ctx, secret, "post-generate", keys, mount).await?;
+    let (status, retry) = match outcome {
+        Outcome::Passed => ("succeeded".to_string(), None),
+        Outcome::Pending => ("pending".to_string(), Some(hooks::HOOK_POLL)),
+        Outcome::Failed(reason) => {
+            let note = format!("post-generate hook failed: {}", reason);
+            report(ctx, secret, EventType::Warning, "HookFailed", note).await;
+            (format!("failed: {}", reason), None)
+        }
+    };
+    if current != Some(&status) {
+        api.patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": {
+                    "annotations": { app_id!("post-generate-status"): status }
+                }
+            })),
+        )
+        .await?;
+    }
+    Ok(retry)
+}
+
+// Runs the pre-rotate hook of a secret whose keys are due for rotation.
+// Unless it passed, the rotation is deferred and the time to check again is
+// returned.
+async fn pre_rotate(
+    ctx: &Context,
+    secret: &Secret,
+    expired: &mut BTreeSet<String>,
+) -> Result<Option<Duration>, Error> {
+    let Some(hook) =
+        parse_annotation::<Hook>(secret.meta(), app_id!("pre-rotate"))?
+    else {
+        return Ok(None);
+    };
+    let outcome = hook.run(ctx, secret, "pre-rotate", expired, None).await?;
+    let retry = match outcome {
+        Outcome::Passed => return Ok(None),
+        Outcome::Pending => hooks::HOOK_POLL,
+        Outcome::Failed(reason) => {
+            let keys = expired.iter().cloned().collect::<Vec<_>>().join(", ");
+            let note = format!("Deferred rotating {}: {}", keys, reason);
+            report(ctx, secret, EventType::Warning, "RotationDeferred", note)
+                .await;
+            hooks::HOOK_RETRY
+        }
+    };
+    expired.clear();
+    Ok(Some(retry))
+}
+
+// A rotation staged for its canary.
+struct Staging<'a> {
+    source_api: &'a Api<Secret>,
+    secret: &'a Secret,
+    dest: &'a Secret,
+    old_data: &'a SecretData,
+}
+
+enum Canary {
+    // Write these values, which passed the canary, with the version of the
+    // declaring secret after recording that, if it changed.
+    Promote(SecretData, Option<String>),
+    // Keep the live values for now and check again after this time.
+    Hold(Duration),
+}
+
+// Stages the rotated `data` in a copy of the generated secret named
+// `<name>-canary` and runs the canary hook against it, recording its state in
+// the canary-status annotation. The staged values are promoted once the hook
+// passed, and dropped if it failed so that the rotation is tried again with
+// new ones.
+async fn canary(
+    ctx: &Context,
+    staging: &Staging<'_>,
+    hook: &Hook,
+    data: &SecretData,
+) -> Result<Canary, Error> {
+    let Staging {
+        source_api,
+        secret,
+        dest,
+        old_data,
+    } = *staging;
+    let ns = dest.namespace().unwrap_or_default();
+    let name = format!("{}-canary", dest.name_any());
+    let api = Api::<Secret>::namespaced(ctx.client.clone(), &ns);
+    let same_namespace = secret.namespace().as_deref() == Some(ns.as_str());
+    let staged = match api.get_opt(&name).await? {
+        Some(staged) => staged,
+        None => {
+            let mut merged = old_data.clone();
+            merged.extend(data.clone());
+            let keys = data.keys().cloned().collect::<Vec<_>>().join(",");
+            let staged = Secret {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    namespace: Some(ns.clone()),
+                    annotations: Some(BTreeMap::from([(
+                        app_id!("canary-keys").to_string(),
+                        keys,
+                    )])),
+                    // Owners can't be in other namespaces.
+                    owner_references: secret
+                        .controller_owner_ref(&())
+                        .filter(|_| same_namespace)
+                        .map(|owner| vec![owner]),
+                    ..Default::default()
+                },
+                type_: dest.type_.clone(),
+                data: Some(std::mem::take(&mut merged.0)),
+                ..Default::default()
+            };
+            let staged = api.create(&PostParams::default(), &staged).await?;
+            info!("staged rotation of {}/{} in {}", ns, dest.name_any(), name);
+            staged
+        }
+    };
+    let keys = listed_keys(staged.meta(), app_id!("canary-keys"));
+    let listed = keys.iter().cloned().collect::<Vec<_>>().join(", ");
+    // Hook Jobs can only mount the staged secret in their own namespace.
+    let mount = same_namespace.then_some(name.as_str());
+    let outcome = hook.run(ctx, secret, "canary", &keys, mount).await?;
+    let (status, mut result) = match outcome {
+        Outcome::Passed => {
+            let values = staged.data.unwrap_or_default();
+            let promoted = keys
+                .iter()
+                .filter_map(|key| Some((key.clone(), values.get(key)?.clone())))
+                .collect();
+            api.delete(&name, &DeleteParams::default()).await?;
+            let note = format!("Promoted {} after the canary passed", listed);
+            report(ctx, secret, EventType::Normal, "Promoted", note).await;
+            (
+                "promoted".to_string(),
+                Canary::Promote(SecretData(promoted), None),
+            )
+        }
+        Outcome::Pending => {
+            ("pending".to_string(), Canary::Hold(hooks::HOOK_POLL))
+        }
+        Outcome::Failed(reason) => {
+            api.delete(&name, &DeleteParams::default()).await?;
+            let note = format!(
+                "Canary of {} failed, keeping the live values: {}",
+                listed, reason
+            );
+            report(ctx, secret, EventType::Warning, "CanaryFailed", note).await;
+            (
+                format!("failed: {}", reason),
+                Canary::Hold(hooks::HOOK_RETRY),
+            )
+        }
+    };
+    if secret.annotations().get(app_id!("canary-status")) != Some(&status) {
+        let patched = source_api
+            .patch(
+                &secret.name_any(),
+                &PatchParams::apply(app_id!()),
+                &Patch::Merge(serde_json::json!({
+                    "metadata": {
+                        "annotations": { app_id!("canary-status"): status }
+                    }
+                })),
+            )
+            .await?;
+        ctx.wrote(&patched);
+        if let Canary::Promote(_, version) = &mut result {
+            *version = patched.resource_version();
+        }
+    }
+    Ok(result)
+}
+
+async fn reconcile_config_map(
+    config_map: Arc<ConfigMap>,
+    ctx: Arc<Context>,
+) -> Result<Action, Error> {
+    let name = config_map.name_any();
+    let ns = config_map.namespace().unwrap();
+    if !ctx.opts.watches_namespace(&ns) {
+        return Ok(Action::await_change());
+    }
+    let api = Api::<ConfigMap>::namespaced(ctx.client.clone(), &ns);
+
+    // Config maps may declare a secret as well, for charts that can't
+    // annotate secrets they don't own.
+    let action = reconcile_creator(config_map.clone(), ctx.clone()).await?;
+    let Some(settings) = declared_settings(config_map.meta())? else {
+        return Ok(action);
+    };
+    if settings.values().any(|s| !s.secret_refs().is_empty()) {
+        return Err(Error::Reference(
+            "config maps can't reference secrets".to_string(),
+        ));
+    }
+
+    let mut old_data =
+        SecretData(config_map.binary_data.clone().unwrap_or_default());
+    for (key, value) in config_map.data.iter().flatten() {
+        old_data.insert(key.clone(), ByteString(value.clone().into_bytes()));
+    }
+
+    let seed = master_seed(&ctx, &settings).await?;
+    let scope = Scope {
+        meta: config_map.meta(),
+        secrets: &BTreeMap::new(),
+        seed: seed.as_deref().map(Vec::as_slice),
+    };
+    let mut data = BTreeMap::new();
+    let mut binary_data = BTreeMap::new();
+    let opts = ctx.opts(&ns).await?;
+    let mut failed = KeyErrors::new();
+    let generated = blocking(|| {
+        gen_data(
+            &opts,
+            &settings,
+            &scope,
+            &old_data,
+            &BTreeSet::new(),
+            &mut failed,
+        )
+    })?;
+    if let Some((_, e)) = failed.pop_first() {
+        return Err(e);
+    }
+    for (key, value) in generated {
+        match String::from_utf8(value.0) {
+            Ok(s) => {
+                data.insert(key, s);
+            }
+            Err(e) => {
+                binary_data.insert(key, ByteString(e.into_bytes()));
+            }
+        }
+    }
+    if data.is_empty() && binary_data.is_empty() {
+        return Ok(requeue(&ctx.opts, None, true));
+    }
+
+    api.patch(
+        &name,
+        &PatchParams::apply(app_id!()),
+        &Patch::Merge(serde_json::json!({
+            "data": data,
+            "binaryData": binary_data,
+        })),
+    )
+    .await?;
+    Ok(requeue(&ctx.opts, None, true))
+}
+
+// Replaces the operator-wide defaults with the ones in the defaults config
+// map. Invalid defaults are logged and the previous ones are kept.
+fn load_defaults(
+    ctx: &Context,
+    event: Result<Option<ConfigMap>, watcher::Error>,
+) {
+    let config_map = match event {
+        Ok(config_map) => config_map,
+        Err(e) => {
+            warn!("failed to watch defaults: {}", e);
+            return;
+        }
+    };
+    let defaults = config_map
+        .and_then(|c| c.data)
+        .and_then(|mut data| data.remove("defaults.yaml"))
+        .map(|d| parse_value::<Defaults>("defaults.yaml", &d))
+        .transpose();
+    match defaults {
+        Ok(defaults) => {
+            let defaults = defaults.unwrap_or_default();
+            info!("using defaults {:?}", defaults);
+            ctx.defaults.send_replace(Some(defaults));
+        }
+        // Don't hold up generation if the defaults are invalid from the start.
+        Err(e) => {
+            warn!("ignoring invalid defaults: {}", e);
+            ctx.defaults.send_if_modified(|defaults| {
+                defaults.get_or_insert_with(Defaults::default);
+                true
+            });
+        }
+    }
+}
+
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Applies changes of the configuration file, which is checked every
+// CONFIG_CHECK_INTERVAL. Invalid configurations are logged and the previous one is kept.
+async fn reload_config(ctx: Arc<Context>, path: String) {
+    let mut last = std::fs::read_to_string(&path).ok();
+    let mut ticks = tokio::time::interval(CONFIG_CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("failed to read {}: {}", path, e);
+                continue;
+            }
+        };
+        if last.as_ref() == Some(&content) {
+            continue;
+        }
+        match ConfigFile::parse(&path, &content) {
+            Ok(config) => {
+                info!("reloaded {}", path);
+                ctx.config.send_replace(config);
+            }
+            Err(e) => warn!("ignoring invalid configuration: {}", e),
+        }
+        last = Some(content);
+    }
+}
+
+// Errors other than API errors, which are usually transient and retried
+// shortly, are reported as events on the object.
+fn error_policy<K>(object: Arc<K>, error: &Error, ctx: Arc<Context>) -> Action
+where
+    K: Resource<DynamicType = ()> + Send + Sync + 'static,
+{
+    // Errors of the API server are not worth an event on the object.
+    if let Error::Kube(_) = error {
+        return Action::requeue(ctx.failed(object.as_ref()));
+    }
+    let retry = error.transient().then(|| ctx.failed(object.as_ref()));
+    let (reason, note) = (error.reason(), error.to_string());
+    tokio::spawn(async move {
+        report(&ctx, object.as_ref(), EventType::Warning, reason, note).await
+    });
+    match retry {
+        Some(retry) => Action::requeue(retry),
+        None => Action::await_change(),
+    }
+}
+
+// The time to wait before retrying an object that failed `attempts` times in
+// a row: `--retry-backoff` doubled with every attempt up to
+// `--retry-backoff-max`, of which a random half is waited less to spread
+// retries of objects that failed together.
+fn backoff(opts: &Opts, attempts: u32) -> Duration {
+    let base = opts.retry_backoff.0;
+    let doubled = 2u32.saturating_pow(attempts.saturating_sub(1));
+    let delay = base
+        .saturating_mul(doubled)
+        .min(opts.retry_backoff_max.0.max(base));
+    let half = (delay / 2).as_millis() as u64;
+    delay - Duration::from_millis(rand::thread_rng().gen_range(0..=half))
+}
+
+// Reconciles every object of `api` not in `seen` once, including the ones
+// created along the way, e.g. by fan-out, and returns how many failed.
+// Failures are reported like the controllers do.
+async fn reconcile_each<K, F, Fut>(
+    api: Api<K>,
+    reconcile: F,
+    ctx: &Arc<Context>,
+    seen: &mut HashSet<Option<String>>,
+) -> Result<usize, Error>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + fmt::Debug,
+    F: Fn(Arc<K>, Arc<Context>) -> Fut,
+    Fut: Future<Output = Result<Action, Error>>,
+{
+    let mut failed = 0;
+    loop {
+        let objects: Vec<K> = api
+            .list(&list_params::<K>(&ctx.opts))
+            .await?
+            .into_iter()
+            .filter(|object| {
+                ctx.opts.watches_namespace(&object.namespace().unwrap())
+            })
+            .filter(|object| seen.insert(object.uid()))
+            .collect();
+        if objects.is_empty() {
+            return Ok(failed);
+        }
+        for object in objects {
+            let object = Arc::new(object);
+            let Err(e) = reconcile(object.clone(), ctx.clone()).await else {
+                continue;
+            };
+            warn!(
+                "failed to reconcile {}/{}: {}",
+                object.namespace().unwrap_or_default(),
+                object.name_any(),
+                e
+            );
+            failed += 1;
+            if !matches!(e, Error::Kube(_)) {
+                let note = e.to_string();
+                report(
+                    ctx,
+                    object.as_ref(),
+                    EventType::Warning,
+                    e.reason(),
+                    note,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+// Reads the defaults and starts watching the namespaces, which the
+// reconcilers need, for runs that don't start the controllers. The
+// namespaces are watched until the returned task is aborted.
+async fn prepare_run(
+    client: &Client,
+    ctx: &Context,
+    namespaces: reflector::store::Writer<Namespace>,
+) -> Result<JoinHandle<()>, Error> {
+    let defaults = cli::cluster_defaults(client, &ctx.opts).await?;
+    ctx.defaults.send_replace(Some(defaults));
+    let namespaces = watch_namespaces(client, &ctx.opts, namespaces);
+    Ok(tokio::spawn(namespaces))
+}
+
+// Caches the namespaces for their annotations, unless only
+// `--watch-namespace` is watched.
+fn watch_namespaces(
+    client: &Client,
+    opts: &Opts,
+    namespaces: reflector::store::Writer<Namespace>,
+) -> BoxFuture<'static, ()> {
+    if opts.watch_namespace.is_some() {
+        return future::pending().boxed();
+    }
+    let watch = Watch::new("namespaces");
+    reflector(
+        namespaces,
+        watcher(Api::<Namespace>::all(client.clone()), watcher_config(opts))
+            .default_backoff()
+            .inspect(move |event| watch.observe(event)),
+    )
+    .for_each(|_| future::ready(()))
+    .boxed()
+}
+
+// The objects of kind `K` in `--watch-namespace`, or in all namespaces.
+pub(crate) fn watched_api<K>(client: &Client, opts: &Opts) -> Api<K>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>,
+{
+    match &opts.watch_namespace {
+        Some(ns) => Api::namespaced(client.clone(), ns),
+        None => Api::all(client.clone()),
+    }
+}
+
+// Reconciles everything the controllers would reconcile once and fails if
+// anything failed, for `--once`.
+async fn reconcile_once(
+    client: Client,
+    ctx: Arc<Context>,
+    namespaces: reflector::store::Writer<Namespace>,
+) -> Result<(), Error> {
+    let namespaces = prepare_run(&client, &ctx, namespaces).await?;
+    let mut seen = HashSet::new();
+    let secrets = watched_api::<Secret>(&client, &ctx.opts);
+    let mut failed =
+        reconcile_each(secrets.clone(), reconcile_secret, &ctx, &mut seen)
+            .await?;
+    if ctx.opts.config_maps {
+        let api = watched_api::<ConfigMap>(&client, &ctx.opts);
+        failed +=
+            reconcile_each(api, reconcile_config_map, &ctx, &mut seen).await?;
+    }
+    if ctx.opts.workloads {
+        let api = watched_api::<Deployment>(&client, &ctx.opts);
+        failed +=
+            reconcile_each(api, reconcile_creator, &ctx, &mut seen).await?;
+        let api = watched_api::<StatefulSet>(&client, &ctx.opts);
+        failed +=
+            reconcile_each(api, reconcile_creator, &ctx, &mut seen).await?;
+    }
+    // The secrets declared by config maps and workloads.
+    failed +=
+        reconcile_each(secrets, reconcile_secret, &ctx, &mut seen).await?;
+    namespaces.abort();
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::Unreconciled(failed)),
+    }
+}
+
+/// Runs the operator, or the command given in `opts`, until it is stopped.
+/// Expects a logger to be set up and may be called once.
+pub async fn run(mut opts: Opts) -> Result<(), Error> {
+    ANNOTATION_DOMAIN
+        .set(opts.annotation_domain.clone())
+        .unwrap();
+
+    if opts.fips {
+        enable_fips()?;
+        info!("FIPS mode enabled");
+    } else {
+        info!("FIPS mode disabled");
+    }
+    init_rng(opts.rng, opts.rng_seed)?;
+    if opts.rng == RngKind::UnsafeSeeded {
+        warn!("using a seeded random source, generated values are predictable");
+    }
+    if let Some(Command::Migrate { stdin: true, .. }) = opts.command {
+        let input = std::io::read_to_string(std::io::stdin())?;
+        print!("{}", migrate_manifests(&input)?);
+        return Ok(());
+    }
+    if let Some(path) = &opts.http_providers_file {
+        opts.http_providers = load_http_providers(path)?;
+    }
+    if let Some(path) = &opts.job_hooks_file {
+        opts.job_hooks = Arc::new(hooks::load_job_hooks(path)?);
+    }
+    if let Some(dir) = &opts.plugin_dir {
+        let plugins = Plugins::load(dir)?;
+        // Plugins can also be used by name, with the other fields as
+        // settings.
+        for name in plugins.names() {
+            let plugin = name.clone();
+            let registered = register_generator(
+                &name,
+                Box::new(move |settings| {
+                    Ok(Box::new(PluginKind {
+                        plugin: plugin.clone(),
+                        settings: serde_yaml::from_value(settings)?,
+                    }))
+                }),
+            );
+            if !registered {
+                warn!("plugin {} is shadowed by the built-in kind", name);
+            }
+        }
+        opts.plugins = Some(Arc::new(plugins));
+    }
+    register_built_in_stores(&opts)?;
+    export::recipients(&opts)?;
+    // Subcommands run with the configuration file applied once, the
+    // controllers apply its current contents whenever they need options.
+    let config_file = match &opts.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    let flags = opts.clone();
+    config_file.apply(&mut opts);
+    if let Some(Command::Generate {
+        settings,
+        defaults_file,
+        output,
+        name,
+        namespace,
+    }) = &opts.command
+    {
+        print!(
+            "{}",
+            cli::generate(
+                &opts,
+                settings,
+                defaults_file.as_deref(),
+                *output,
+                name,
+                namespace.as_deref(),
+            )?
+        );
+        return Ok(());
+    }
+    if let Some(Command::Validate {
+        files,
+        defaults_file,
+    }) = &opts.command
+    {
+        return cli::validate(&opts, files, defaults_file.as_deref());
+    }
+    let client = client::client(&opts, None).await?;
+    if let Some(Command::Create {
+        name,
+        namespace,
+        keys,
+        timeout,
+    }) = &opts.command
+    {
+        let namespace = namespace.as_deref();
+        return cli::create(client, &opts, name, namespace, keys, timeout.0)
+            .await;
+    }
+    if let Some(Command::List {
+        namespace,
+        all_namespaces,
+    }) = &opts.command
+    {
+        let namespace = namespace.as_deref();
+        return cli::list(client, &opts, namespace, *all_namespaces).await;
+    }
+    if let Some(Command::Inspect { name, namespace }) = &opts.command {
+        return cli::inspect(client, &opts, name, namespace.as_deref()).await;
+    }
+    if let Some(Command::Report { format }) = opts.command {
+        return cli::report(client, &opts, format).await;
+    }
+    if let Some(Command::Migrate { apply, .. }) = opts.command {
+        return migrate_cluster(client, apply).await;
+    }
+    if let Some(Command::Restore { from, identity }) = &opts.command {
+        return cli::restore(client, from, identity).await;
+    }
+
+    if opts.shard_index >= opts.shard_count {
+        return Err(Error::Conflict(format!(
+            "--shard-index {} is not below --shard-count {}",
+            opts.shard_index, opts.shard_count
+        )));
+    }
+    if opts.cross_namespace && opts.watch_namespace.is_some() {
+        return Err(Error::Conflict(
+            "--cross-namespace cannot be used with --watch-namespace"
+                .to_string(),
+        ));
+    }
+    if !opts.clusters.is_empty() && opts.webhook.is_some() {
+        return Err(Error::Conflict(
+            "--webhook cannot be used with --cluster".to_string(),
+        ));
+    }
+
+    eprintln!(
+        "Starting auto-secret-operator version {}",
+        env!("CARGO_PKG_VERSION")
+    );
+    // Sends the spans and metrics that are left when the controllers
+    // stopped.
+    let _telemetry = Telemetry::init(&opts)?;
+    if let Some(addr) = opts.debug_addr {
+        let server = DebugServer::bind(addr).await?;
+        tokio::spawn(async move {
+            if let Err(e) = server.serve().await {
+                warn!("diagnostics stopped: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = opts.health_addr {
+        let server = HealthServer::bind(addr).await?;
+        tokio::spawn(async move {
+            if let Err(e) = server.serve().await {
+                warn!("probes stopped: {}", e);
+            }
+        });
+    }
+    if opts.clusters.is_empty() {
+        return run_cluster(client, None, opts, flags, config_file).await;
+    }
+    // Every cluster gets its own client, watches and context.
+    let mut runs = vec![];
+    for cluster in &opts.clusters {
+        let client = client::client(&opts, Some(&cluster.context)).await?;
+        let name = Some(cluster.name.clone());
+        let (opts, flags) = (opts.clone(), flags.clone());
+        runs.push(run_cluster(client, name, opts, flags, config_file.clone()));
+    }
+    future::try_join_all(runs).await?;
+    Ok(())
+}
+
+// Runs the controllers against the cluster of `client`, which is called
+// `cluster` with `--cluster`.
+async fn run_cluster(
+    client: Client,
+    cluster: Option<String>,
+    opts: Opts,
+    flags: Opts,
+    config_file: ConfigFile,
+) -> Result<(), Error> {
+    let api = watched_api::<Secret>(&client, &opts);
+    let config = Config::default().concurrency(2);
+    let config_maps = opts.config_maps;
+    let workloads = opts.workloads;
+    let (trigger, mut triggered) = mpsc::unbounded_channel();
+    let (namespaces, namespaces_writer) = reflector::store();
+    let defaults = match opts.defaults_config_map {
+        Some(_) => None,
+        None => Some(Defaults::default()),
+    };
+    let context = Arc::new(Context {
+        client: client.clone(),
+        cluster,
+        opts: flags,
+        references: Mutex::default(),
+        stamps: Mutex::default(),
+        trigger,
+        recorder: Recorder::new(client.clone(), "auto-secret-operator".into()),
+        http: reqwest::Client::new(),
+        defaults: watch::Sender::new(defaults),
+        config: watch::Sender::new(config_file),
+        namespaces,
+        written: Mutex::default(),
+        failures: Mutex::default(),
+        in_flight: Mutex::default(),
+        throttle: opts
+            .namespace_qps
+            .map(|qps| Throttle::new(qps, opts.namespace_burst)),
+    });
+    if context.opts.once {
+        return reconcile_once(client, context, namespaces_writer).await;
+    }
+    if let Some(Command::Bootstrap { apply, rate }) = context.opts.command {
+        let namespaces =
+            prepare_run(&client, &context, namespaces_writer).await?;
+        let result = cli::bootstrap(&context, apply, rate).await;
+        namespaces.abort();
+        return result;
+    }
+
+    let triggered = stream::poll_fn(move |cx| triggered.poll_recv(cx));
+    let mut secrets = watched_secrets(api, &context)
+        .with_config(config.clone())
+        .reconcile_on(triggered.map(|secret| secret.into_kind_unchecked(())));
+    if context.opts.cross_namespace {
+        let ctx = context.clone();
+        secrets = secrets.watches(
+            Api::<Namespace>::all(client.clone()),
+            watcher_config(&context.opts),
+            move |_| {
+                let stamps = ctx.stamps.lock().unwrap().clone();
+                stamps.into_iter().map(|s| s.into_kind_unchecked(()))
+            },
+        );
+    }
+    let mut controllers = vec![secrets
+        .shutdown_on_signal()
+        .run(tracked(reconcile_metadata), error_policy, context.clone())
+        .for_each(logged(context.clone()))
+        .boxed()];
+
+    if config_maps {
+        controllers.push(
+            watched_controller(
+                watched_api::<ConfigMap>(&client, &context.opts),
+                &context.opts,
+            )
+            .with_config(config.clone())
+            .shutdown_on_signal()
+            .run(tracked(reconcile_config_map), error_policy, context.clone())
+            .for_each(logged(context.clone()))
+            .boxed(),
+        );
+    }
+    if workloads {
+        controllers.push(
+            watched_workloads(
+                watched_api::<Deployment>(&client, &context.opts),
+                &context.opts,
+            )
+            .with_config(config.clone())
+            .shutdown_on_signal()
+            .run(tracked(reconcile_creator), error_policy, context.clone())
+            .for_each(logged(context.clone()))
+            .boxed(),
+        );
+        controllers.push(
+            watched_workloads(
+                watched_api::<StatefulSet>(&client, &context.opts),
+                &context.opts,
+            )
+            .with_config(config.clone())
+            .shutdown_on_signal()
+            .run(tracked(reconcile_creator), error_policy, context.clone())
+            .for_each(logged(context.clone()))
+            .boxed(),
+        );
+    }
+
+    // The defaults are watched for as long as the controllers run.
+    let defaults = match &context.opts.defaults_config_map {
+        Some(reference) => {
+            let (ns, name) =
+                split_secret_ref(reference, &context.opts.namespace);
+            let ctx = context.clone();
+            watcher::watch_object(
+                Api::<ConfigMap>::namespaced(client.clone(), ns),
+                name,
+            )
+            .default_backoff()
+            .for_each(move |event| {
+                load_defaults(&ctx, event);
+                future::ready(())
+            })
+            .boxed()
+        }
+        None => future::pending().boxed(),
+    };
+    let webhook = match context.opts.webhook {
+        Some(addr) => {
+            let opts = &context.opts;
+            let certificate = if opts.webhook_self_signed {
+                webhook::Certificate::SelfSigned {
+                    dns_name: format!(
+                        "{}.{}.svc",
+                        opts.webhook_service, opts.namespace
+                    ),
+                    configuration: opts.webhook_configuration.clone(),
+                }
+            } else {
+                webhook::Certificate::Files {
+                    cert: opts.webhook_cert.clone(),
+                    key: opts.webhook_key.clone(),
+                }
+            };
+            webhook::Webhook::bind(addr, certificate)
+                .await?
+                .serve(context.clone())
+                .map(|result| {
+                    if let Err(e) = result {
+                        warn!("webhook stopped: {}", e);
+                    }
+                })
+                .boxed()
+        }
+        None => future::pending().boxed(),
+    };
+    let namespaces =
+        watch_namespaces(&client, &context.opts, namespaces_writer);
+    let report = match context.opts.report_file.clone() {
+        Some(path) => {
+            let path = match &context.cluster {
+                Some(cluster) => format!("{}.{}", path, cluster),
+                None => path,
+            };
+            cli::write_reports(context.clone(), path).boxed()
+        }
+        None => future::pending().boxed(),
+    };
+    let report_resources = match context.opts.report_resources {
+        true => reports::write_report_resources(context.clone()).boxed(),
+        false => future::pending().boxed(),
+    };
+    let report = async move {
+        future::join(report, report_resources).await;
+    }
+    .boxed();
+    let config_file = match context.opts.config.clone() {
+        Some(path) => reload_config(context.clone(), path).boxed(),
+        None => future::pending().boxed(),
+    };
+    // With leader election, standby replicas keep serving the webhook but
+    // only the leader reconciles.
+    let controllers = future::select(future::join_all(controllers), report);
+    let controllers = match context.opts.leader_election {
+        true => {
+            let leader = Leader::new(client.clone(), &context.opts);
+            async move { leader.lead(controllers).await }.boxed()
+        }
+        false => controllers.map(|_| Ok(())).boxed(),
+    };
+    // On SIGTERM the controllers finish the reconciles in flight, but only
+    // within `--shutdown-grace-period`.
+    let grace = context.opts.shutdown_grace_period.0;
+    let deadline = async move {
+        shutdown_signal().await;
+        info!("shutting down, waiting up to {:?} for reconciles", grace);
+        tokio::time::sleep(grace).await;
+    };
+    let controllers = async {
+        match future::select(controllers, deadline.boxed()).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => context.abandon(),
+        }
+    };
+    let result = match future::select(
+        controllers.boxed(),
+        future::join_all([defaults, namespaces, webhook, config_file]),
+    )
+    .await
+    {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Ok(()),
+    };
+    info!("controller terminated");
+    result
+}
+
+// A controller for the objects of `api` in the namespaces this replica
+// watches. Objects not matching `list_params` are left out by the API server.
+fn watched_controller<K>(api: Api<K>, opts: &Opts) -> Controller<K>
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + DeserializeOwned
+        + fmt::Debug
+        + Send
+        + Sync
+        + 'static,
+{
+    let (objects, store) = watched(watcher(api, watch_config::<K>(opts)), opts);
+    Controller::for_stream(objects, store).reconcile_all_on(resyncs(opts))
+}
+
+// Like `watched_controller`, but only reconciles workloads when their
+// annotations changed rather than on every status update.
+fn watched_workloads<K>(api: Api<K>, opts: &Opts) -> Controller<K>
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + DeserializeOwned
+        + fmt::Debug
+        + Send
+        + Sync
+        + 'static,
+{
+    let (objects, store) = watched(watcher(api, watch_config::<K>(opts)), opts);
+    Controller::for_stream(
+        objects.predicate_filter(predicates::annotations),
+        store,
+    )
+    .reconcile_all_on(resyncs(opts))
+}
+
+// A controller for the secrets, of which only the metadata is watched and
+// cached. Changes the operator made itself are not reconciled again, but
+// still re-render the templates referencing the secret.
+fn watched_secrets(
+    api: Api<Secret>,
+    ctx: &Arc<Context>,
+) -> Controller<PartialObjectMeta<Secret>> {
+    let config = watch_config::<Secret>(&ctx.opts);
+    // The annotated secrets of the first list are reconciled before the
+    // operator turns ready.
+    let mut warm_up = WarmUpList::new(ctx.opts.warm_up_timeout.0);
+    let context = ctx.clone();
+    let events = metadata_watcher(api, config).inspect(move |event| {
+        warm_up.observe(event, |secret| {
+            let namespace = secret.namespace().unwrap_or_default();
+            let watched = context.opts.watches_namespace(&namespace);
+            let key = ObjectRef::from_obj(secret).erase();
+            (watched && context.annotated(secret))
+                .then(|| format!("{}{}", context.cluster_prefix(), key))
+        })
+    });
+    let (objects, store) = watched(events, &ctx.opts);
+    let resyncs = resyncs(&ctx.opts);
+    let ctx = ctx.clone();
+    let objects = objects.try_filter(move |secret| {
+        let own = ctx.own_write(secret);
+        if own {
+            for dependent in ctx.references.lock().unwrap().observe(secret) {
+                ctx.trigger.send(dependent).ok();
+            }
+        }
+        future::ready(!own)
+    });
+    Controller::for_stream(objects, store).reconcile_all_on(resyncs)
+}
+
+// The objects of `events` in the namespaces this replica watches, cached in
+// the returned store. The others are dropped before they are cached.
+fn watched<K>(
+    events: impl Stream<Item = Result<watcher::Event<K>, watcher::Error>>
+        + Send
+        + 'static,
+    opts: &Opts,
+) -> (
+    impl Stream<Item = Result<K, watcher::Error>> + Send + 'static,
+    Store<K>,
+)
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + DeserializeOwned
+        + fmt::Debug
+        + Send
+        + Sync
+        + 'static,
+{
+    let watch = Watch::new(&K::plural(&()));
+    let events = events.inspect(move |event| watch.observe(event));
+    let opts = opts.clone();
+    let watched = move |object: &K| {
+        let namespace = object.meta().namespace.as_deref().unwrap_or_default();
+        opts.watches_namespace(namespace)
+    };
+    let events = events.try_filter(move |event| {
+        future::ready(match event {
+            watcher::Event::Apply(object)
+            | watcher::Event::InitApply(object)
+            | watcher::Event::Delete(object) => watched(object),
+            watcher::Event::Init | watcher::Event::InitDone => true,
+        })
+    });
+    let (reader, writer) = reflector::store();
+    (reflector(writer, events).applied_objects(), reader)
+}
+
+fn watch_config<K: Resource<DynamicType = ()>>(opts: &Opts) -> watcher::Config {
+    let params = list_params::<K>(opts);
+    watcher::Config {
+        label_selector: params.label_selector,
+        field_selector: params.field_selector,
+        ..watcher_config(opts)
+    }
+}
+
+// How watchers list the objects, following `--watch-list` and
+// `--watch-page-size`.
+fn watcher_config(opts: &Opts) -> watcher::Config {
+    let (initial_list_strategy, list_semantic) = match opts.watch_list {
+        WatchList::Consistent => {
+            (InitialListStrategy::ListWatch, ListSemantic::MostRecent)
+        }
+        WatchList::Cached => {
+            (InitialListStrategy::ListWatch, ListSemantic::Any)
+        }
+        WatchList::Streaming => {
+            (InitialListStrategy::StreamingList, ListSemantic::MostRecent)
+        }
+    };
+    watcher::Config {
+        initial_list_strategy,
+        list_semantic,
+        page_size: Some(opts.watch_page_size),
+        ..Default::default()
+    }
+}
+
+// Ticks every `--resync-interval`, to reconcile all cached objects again.
+fn resyncs(opts: &Opts) -> impl Stream<Item = ()> + Send + Sync + 'static {
+    let period = opts.resync_interval.0;
+    let mut ticks = (!period.is_zero()).then(|| {
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    stream::poll_fn(move |cx| match &mut ticks {
+        Some(ticks) => ticks.poll_tick(cx).map(|_| Some(())),
+        None => Poll::Ready(None),
+    })
+}
+
+// Selects the objects of kind `K` the operator watches: secrets matching
+// `--label-selector` and `--field-selector`, in namespaces not excluded by
+// name.
+pub(crate) fn list_params<K: Resource<DynamicType = ()>>(
+    opts: &Opts,
+) -> ListParams {
+    let mut params = ListParams::default();
+    let mut fields = vec![];
+    if K::kind(&()) == "Secret" {
+        params.label_selector = opts.label_selector.clone();
+        fields.extend(opts.field_selector.clone());
+    }
+    fields.extend(opts.namespace_selector());
+    params.field_selector = (!fields.is_empty()).then(|| fields.join(","));
+    params
+}
+
+// Records the objects `reconcile` is working on in the context while it does.
+// Objects of namespaces over `--namespace-qps` are requeued for their turn
+// instead.
+fn tracked<K, F, Fut>(
+    reconcile: F,
+) -> impl FnMut(Arc<K>, Arc<Context>) -> BoxFuture<'static, Result<Action, Error>>
+where
+    K: Resource<DynamicType = ()>,
+    F: Fn(Arc<K>, Arc<Context>) -> Fut,
+    Fut: Future<Output = Result<Action, Error>> + Send + 'static,
+{
+    move |object, ctx| {
+        let key = ObjectRef::from_obj(object.as_ref()).erase();
+        let namespace = object.meta().namespace.as_deref();
+        let wait = ctx
+            .throttle
+            .as_ref()
+            .zip(namespace)
+            .and_then(|(throttle, ns)| throttle.wait(ns, &key, Instant::now()));
+        let name = format!("{}{}", ctx.cluster_prefix(), key);
+        if let Some(wait) = wait {
+            debug::throttled(&name, wait);
+            return future::ready(Ok(Action::requeue(wait))).boxed();
+        }
+        ctx.in_flight.lock().unwrap().insert(key.clone());
+        let reconciling = Reconciling::start();
+        debug::started(&name);
+        let reconciled = reconcile(object, ctx.clone());
+        async move {
+            let result = reconciled.await;
+            drop(reconciling);
+            health::warmed_up(&name);
+            debug::finished(&name, result.as_ref().err().map(Error::to_string));
+            ctx.in_flight.lock().unwrap().remove(&key);
+            result
+        }
+        .boxed()
+    }
+}
+
+// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// What a controller yields for every reconcile.
+type Reconciled<K> = Result<
+    (ObjectRef<K>, Action),
+    kube::runtime::controller::Error<Error, watcher::Error>,
+>;
+
+// Logs the results of a controller and forgets the failures of objects that
+// reconciled again.
+fn logged<K>(
+    ctx: Arc<Context>,
+) -> impl FnMut(Reconciled<K>) -> future::Ready<()>
+where
+    K: kube::runtime::reflector::Lookup,
+    K::DynamicType: Clone,
+{
+    move |res| {
+        if let Ok((o, _)) = &res {
+            ctx.failures.lock().unwrap().remove(&o.clone().erase());
+        }
+        log_result(&ctx.cluster_prefix(), res);
+        future::ready(())
+    }
+}
+
+fn log_result<K: kube::runtime::reflector::Lookup>(
+    prefix: &str,
+    res: Reconciled<K>,
+) {
+    match res {
+        Ok((o, _a)) => info!(
+            "reconciled {}{}/{}",
+            prefix,
+            o.namespace.as_deref().unwrap_or("<unknown>"),
+            o.name
+        ),
+        Err(kube::runtime::controller::Error::ReconcilerFailed(e, o)) => {
+            warn!("reconcile of {}{} failed: {}", prefix, o, e);
+            debug!("reconcile failed: {:?}", e);
+        }
+        Err(e) => {
+            warn!("{}reconcile failed: {}", prefix, e);
+            debug!("reconcile failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeApi;
+    use clap::Parser;
+
+    #[test]
+    fn applied() {
+        let secret: Secret = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "managedFields": [{
+                    "manager": app_id!(),
+                    "operation": "Apply",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": {"f:data": {"f:password": {}, "f:token": {}}},
+                }, {
+                    "manager": "kubectl-edit",
+                    "operation": "Update",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": {"f:data": {"f:username": {}}},
+                }],
+            },
+        }))
+        .unwrap();
+        let keys: Vec<_> = applied_keys(&secret).into_iter().collect();
+        assert_eq!(keys, ["password", "token"]);
+        let owners = foreign_owners(&secret);
+        assert_eq!(owners.keys().collect::<Vec<_>>(), ["username"]);
+        assert_eq!(owners["username"], "kubectl-edit");
+    }
+
+    #[test]
+    fn checksum() {
+        let data = |value: &str| {
+            SecretData(BTreeMap::from([(
+                "password".to_string(),
+                ByteString(value.as_bytes().to_vec()),
+            )]))
+        };
+        assert_eq!(data_checksum(&data("a")), data_checksum(&data("a")));
+        assert_ne!(data_checksum(&data("a")), data_checksum(&data("b")));
+        assert_eq!(data_checksum(&data("a")).len(), 64);
+    }
+
+    #[test]
+    fn status() {
+        let meta: ObjectMeta = serde_json::from_value(serde_json::json!({
+            "annotations": {
+                app_id!("gen"): "password: default",
+                app_id!("gen-token"): "default",
+                app_id!("rotate"): "90d",
+                app_id!("generated-at"): r#"{"token":"2026-01-01T00:00:00Z"}"#,
+                app_id!("last-generated"): r#"{"password":"2026-02-01T00:00:00Z","old":"2026-02-01T00:00:00Z"}"#,
+            },
+        }))
+        .unwrap();
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+        let managed = keys(&["api-key", "password", "token"]);
+        let last_generated = last_generated(
+            &meta,
+            &managed,
+            &keys(&["api-key"]),
+            "2026-03-01T00:00:00Z",
+        );
+        let next_rotation = BTreeMap::from([(
+            "token".to_string(),
+            "2026-04-01T00:00:00Z".to_string(),
+        )]);
+        let status = status_annotations(
+            &meta,
+            &managed,
+            &last_generated,
+            &next_rotation,
+            &keys(&[]),
+            &keys(&["password"]),
+        )
+        .unwrap();
+        assert_eq!(
+            status[app_id!("last-generated")].as_deref(),
+            Some(
+                r#"{"api-key":"2026-03-01T00:00:00Z","password":"2026-02-01T00:00:00Z","token":"2026-01-01T00:00:00Z"}"#
+            )
+        );
+        assert_eq!(
+            status[app_id!("managed-keys")].as_deref(),
+            Some("api-key,password,token")
+        );
+        assert_eq!(
+            status[app_id!("next-rotation")].as_deref(),
+            Some(r#"{"token":"2026-04-01T00:00:00Z"}"#)
+        );
+        assert_eq!(status[app_id!("drifted")], None);
+        assert_eq!(status[app_id!("stale")].as_deref(), Some("password"));
+        // Only the declaration counts towards the hash.
+        let changed = |name: &str, value: &str| {
+            let mut changed = meta.clone();
+            let annotations = changed.annotations.as_mut().unwrap();
+            annotations.insert(name.to_string(), value.to_string());
+            settings_hash(&changed)
+        };
+        assert_eq!(changed(app_id!("rotate"), "30d"), settings_hash(&meta));
+        assert_ne!(changed(app_id!("gen-token"), "hex"), settings_hash(&meta));
+    }
+
+    #[test]
+    fn drift() {
+        let data = |values: &[(&str, &str)]| {
+            SecretData(
+                values
+                    .iter()
+                    .map(|(k, v)| {
+                        (k.to_string(), ByteString(v.as_bytes().to_vec()))
+                    })
+                    .collect(),
+            )
+        };
+        let generated = data(&[("password", "a"), ("token", "b")]);
+        let mut dest = Secret::default();
+        let checksums = applied_checksums(&dest, &generated, &generated);
+        dest.annotations_mut().insert(
+            app_id!("checksums").into(),
+            serde_json::to_string(&checksums).unwrap(),
+        );
+        assert!(drifted_keys(&dest, &generated).is_empty());
+        // Removed keys are generated again rather than drifted.
+        let current = data(&[("password", "changed")]);
+        assert_eq!(
+            drifted_keys(&dest, &current),
+            BTreeSet::from(["password".into()])
+        );
+        // A drifted value keeps its checksum when other keys are generated.
+        let applied = data(&[("password", "changed"), ("token", "c")]);
+        let rotated = data(&[("token", "c")]);
+        let checksums = applied_checksums(&dest, &applied, &rotated);
+        assert_eq!(
+            checksums["password"],
+            key_checksum("password", &ByteString(b"a".to_vec()))
+        );
+        assert_ne!(
+            checksums["token"],
+            key_checksum("token", &ByteString(b"b".to_vec()))
+        );
+    }
+
+    #[test]
+    fn rotation_schedule() {
+        let meta: ObjectMeta = serde_json::from_value(serde_json::json!({
+            "annotations": {
+                app_id!("rotate"): "30d",
+                app_id!("generated-at"): r#"{"password":"2026-01-01T00:00:00Z"}"#,
+                app_id!("last-generated"): r#"{"password":"2025-12-01T00:00:00Z","token":"2026-02-01T00:00:00Z"}"#,
+            },
+        }))
+        .unwrap();
+        let settings =
+            Settings::parse("{password: default, token: default}").unwrap();
+        let opts = Opts::parse_from(["auto-secret"]);
+        let rotation = Rotation::new(&opts, &meta, &settings).unwrap();
+        // Keys that got an interval keep the time of their last generation.
+        assert_eq!(
+            rotation.generated_at("password"),
+            Some("2026-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            rotation.generated_at("token"),
+            Some("2026-02-01T00:00:00Z")
+        );
+        let (generated_at, _) = rotation.update(|_| false);
+        assert_ne!(generated_at, rotation.recorded);
+        assert_eq!(
+            rotation.schedule(&generated_at),
+            BTreeMap::from([
+                ("password".into(), "2026-01-31T00:00:00Z".into()),
+                ("token".into(), "2026-03-03T00:00:00Z".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn max_age() {
+        let meta: ObjectMeta = serde_json::from_value(serde_json::json!({
+            "annotations": { app_id!("max-age"): "30d" },
+        }))
+        .unwrap();
+        let settings = Settings::parse(
+            "{password: default, token: {rotate: 7d}, pin: {max_age: 90d}}",
+        )
+        .unwrap();
+        let opts = Opts::parse_from(["auto-secret"]);
+        let rotation = Rotation::new(&opts, &meta, &settings).unwrap();
+        let keys = ["password", "pin", "token"].map(String::from);
+        let at = "2026-01-01T00:00:00Z".to_string();
+        let last_generated = keys.iter().map(|k| (k, at.clone())).collect();
+        let now = |date: &str| DateTime::parse_from_rfc3339(date).unwrap();
+        let stale = |date| {
+            let now = now(date).with_timezone(&Utc);
+            stale_keys(&opts, &meta, &settings, &rotation, &last_generated, now)
+                .unwrap()
+        };
+        assert_eq!(
+            stale("2026-01-21T00:00:00Z"),
+            (
+                BTreeSet::new(),
+                Some(Duration::from_secs(10 * 24 * 60 * 60))
+            )
+        );
+        // Rotated keys are never stale.
+        assert_eq!(
+            stale("2026-03-01T00:00:00Z"),
+            (
+                BTreeSet::from(["password".into()]),
+                Some(Duration::from_secs(31 * 24 * 60 * 60))
+            )
+        );
+    }
+
+    #[test]
+    fn stale_writes() {
+        let conflict = |message: &str| {
+            kube::Error::Api(kube::error::ErrorResponse {
+                status: "Failure".to_string(),
+                message: message.to_string(),
+                reason: "Conflict".to_string(),
+                code: 409,
+            })
+        };
+        assert!(stale(&conflict(
+            "Operation cannot be fulfilled on secrets \"db\": the object \
+             has been modified; please apply your changes to the latest \
+             version and try again"
+        )));
+        assert!(!stale(&conflict(
+            "Apply failed with 1 conflict: conflict with \"kubectl-edit\" \
+             using v1: .data.password"
+        )));
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--retry-backoff=4s",
+            "--retry-backoff-max=1m",
+        ]);
+        let within = |attempts, secs| {
+            let delay = backoff(&opts, attempts);
+            let max = Duration::from_secs(secs);
+            assert!(max / 2 <= delay && delay <= max, "{:?}", delay);
+        };
+        within(1, 4);
+        within(2, 8);
+        within(4, 32);
+        within(5, 60);
+        within(u32::MAX, 60);
+    }
+
+    #[test]
+    fn requeue_after() {
+        let opts = Opts::parse_from(["auto-secret", "--requeue-interval=10m"]);
+        let minutes = |m: u64| Action::requeue(Duration::from_secs(m * 60));
+        assert_eq!(requeue(&opts, None, true), minutes(10));
+        assert_eq!(
+            requeue(&opts, Some(Duration::from_secs(60)), true),
+            minutes(1)
+        );
+        assert_eq!(requeue(&opts, None, false), Action::await_change());
+        assert_eq!(
+            requeue(&opts, Some(Duration::ZERO), false),
+            Action::requeue(Duration::from_secs(1))
+        );
+
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--requeue-interval=0s",
+            "--requeue-jitter=1m",
+        ]);
+        assert_eq!(requeue(&opts, None, true), Action::await_change());
+    }
+
+    // A context reconciling against `api`, with the flags `args`.
+    fn context(api: &FakeApi, args: &[&str]) -> Arc<Context> {
+        let client = api.client();
+        let opts = Opts::parse_from(
+            std::iter::once("auto-secret").chain(args.iter().copied()),
+        );
+        let (namespaces, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::InitDone);
+        let (trigger, _) = mpsc::unbounded_channel();
+        Arc::new(Context {
+            client: client.clone(),
+            cluster: None,
+            opts,
+            references: Mutex::default(),
+            stamps: Mutex::default(),
+            trigger,
+            recorder: Recorder::new(client, "auto-secret-operator".into()),
+            http: reqwest::Client::new(),
+            defaults: watch::Sender::new(Some(Defaults::default())),
+            config: watch::Sender::new(ConfigFile::default()),
+            namespaces,
+            written: Mutex::default(),
+            failures: Mutex::default(),
+            in_flight: Mutex::default(),
+            throttle: None,
+        })
+    }
+
+    // The secret `dev/db`, declaring keys with `annotations` and holding
+    // `data` written by `manager`.
+    fn declaring(
+        annotations: &[(&str, &str)],
+        data: &[(&str, &str)],
+        manager: &str,
+    ) -> Secret {
+        let data: BTreeMap<_, _> = data
+            .iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.as_bytes().to_vec())))
+            .collect();
+        let fields: serde_json::Map<_, _> = data
+            .keys()
+            .map(|k| (format!("f:{}", k), serde_json::json!({})))
+            .collect();
+        let operation = match manager == app_id!() {
+            true => "Apply",
+            false => "Update",
+        };
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "db",
+                "namespace": "dev",
+                "annotations": annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<BTreeMap<_, _>>(),
+                "managedFields": [{
+                    "manager": manager,
+                    "operation": operation,
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": {"f:data": fields},
+                }],
+            },
+            "data": data,
+        }))
+        .unwrap()
+    }
+
+    fn value(secret: &Secret, key: &str) -> String {
+        let value = &secret.data.as_ref().unwrap()[key];
+        String::from_utf8(value.0.clone()).unwrap()
+    }
+
+    fn reasons(api: &FakeApi) -> Vec<String> {
+        let events =
+            api.list::<k8s_openapi::api::events::v1::Event>(Some("dev"));
+        events.into_iter().filter_map(|e| e.reason).collect()
+    }
+
+    #[tokio::test]
+    async fn reconcile_generates() {
+        let api = FakeApi::default();
+        let gen = "{password: {letter: true, length: 12}, pin: digit}";
+        let secret =
+            api.insert(&declaring(&[(app_id!("gen"), gen)], &[], "helm"));
+        let ctx = context(&api, &[]);
+        reconcile(Arc::new(secret), ctx.clone()).await.unwrap();
+
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert_eq!(value(&secret, "password").len(), 12);
+        assert!(value(&secret, "pin").bytes().all(|b| b.is_ascii_digit()));
+        assert_eq!(
+            applied_keys(&secret).into_iter().collect::<Vec<_>>(),
+            ["password", "pin"]
+        );
+        let annotations = secret.annotations();
+        assert_eq!(annotations[app_id!("managed-keys")], "password,pin");
+        assert_eq!(annotations[app_id!("revision")], "1");
+        assert_eq!(reasons(&api), ["Generated"]);
+
+        // Nothing is written again once all keys exist.
+        let written = api.requests().len();
+        reconcile(Arc::new(secret), ctx).await.unwrap();
+        let requests = api.requests();
+        assert!(
+            requests[written..].iter().all(|r| r.starts_with("GET ")),
+            "{:?}",
+            &requests[written..]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_existing() {
+        let api = FakeApi::default();
+        let secret = api.insert(&declaring(
+            &[(app_id!("gen"), "{password: default, pin: digit}")],
+            &[("password", "chosen")],
+            "kubectl-edit",
+        ));
+        reconcile(Arc::new(secret), context(&api, &[]))
+            .await
+            .unwrap();
+
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert_eq!(value(&secret, "password"), "chosen");
+        assert!(!value(&secret, "pin").is_empty());
+        assert_eq!(foreign_owners(&secret)["password"], "kubectl-edit");
+        assert_eq!(
+            applied_keys(&secret).into_iter().collect::<Vec<_>>(),
+            ["pin"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_rotates() {
+        let api = FakeApi::default();
+        let secret = api.insert(&declaring(
+            &[
+                (app_id!("gen"), "{password: {letter: true, rotate: 1d}}"),
+                (
+                    app_id!("generated-at"),
+                    "{\"password\": \"2020-01-01T00:00:00Z\"}",
+                ),
+            ],
+            &[("password", "expired")],
+            app_id!(),
+        ));
+        let action = reconcile(Arc::new(secret), context(&api, &[]))
+            .await
+            .unwrap();
+
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert_ne!(value(&secret, "password"), "expired");
+        assert_eq!(reasons(&api), ["Rotated"]);
+        let annotations = secret.annotations();
+        assert!(!annotations[app_id!("generated-at")].contains("2020"));
+        assert!(annotations.contains_key(app_id!("next-rotation")));
+        // The next rotation is due in a day.
+        assert_ne!(action, Action::await_change());
+    }
+
+    #[tokio::test]
+    async fn reconcile_errors() {
+        let api = FakeApi::default();
+        // Keys that can't be generated are reported, the others written.
+        let gen = "{password: {length: 12}, pin: digit}";
+        let invalid = declaring(&[(app_id!("gen"), gen)], &[], "helm");
+        let invalid = api.insert(&invalid);
+        let ctx = context(&api, &[]);
+        let action = reconcile(Arc::new(invalid), ctx.clone()).await;
+        assert_eq!(action.unwrap(), requeue(&ctx.opts, None, true));
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert!(secret.annotations()[app_id!("errors")].contains("password"));
+        assert!(!value(&secret, "pin").is_empty());
+        assert_eq!(reasons(&api), ["Generated", "Failed"]);
+
+        // A secret that changed since it was read is read again.
+        let api = FakeApi::default();
+        let gen = [(app_id!("gen"), "{password: default}")];
+        let read = api.insert(&declaring(&gen, &[], "helm"));
+        let changed = Patch::Merge(serde_json::json!({
+            "metadata": {"labels": {"team": "a"}},
+        }));
+        let secrets = Api::<Secret>::namespaced(api.client(), "dev");
+        secrets
+            .patch("db", &PatchParams::default(), &changed)
+            .await
+            .unwrap();
+        let action = reconcile(Arc::new(read), context(&api, &[])).await;
+        assert_eq!(action.unwrap(), Action::requeue(STALE_RETRY));
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert!(secret.data.clone().unwrap_or_default().is_empty());
+
+        // Failed writes fail the reconcile.
+        api.fail(http::Method::PATCH, http::StatusCode::INTERNAL_SERVER_ERROR);
+        let result = reconcile(Arc::new(secret), context(&api, &[])).await;
+        assert!(matches!(result, Err(Error::Kube(_))));
+
+        // Keys other managers own are only taken over when allowed.
+        let api = FakeApi::default();
+        let annotations = [
+            (app_id!("gen"), "{password: default}"),
+            (app_id!("overwrite-empty"), "true"),
+        ];
+        let blank =
+            api.insert(&declaring(&annotations, &[("password", "")], "helm"));
+        let ctx = context(&api, &["--apply-conflicts=fail"]);
+        let result = reconcile(Arc::new(blank.clone()), ctx).await;
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        reconcile(Arc::new(blank), context(&api, &[]))
+            .await
+            .unwrap();
+        let secret: Secret = api.get(Some("dev"), "db").unwrap();
+        assert!(!value(&secret, "password").is_empty());
+        assert!(foreign_owners(&secret).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_recreates() {
+        let api = FakeApi::default();
+        let annotations = [
+            (app_id!("gen"), "{password: default}"),
+            (app_id!("type"), "kubernetes.io/basic-auth"),
+            (app_id!("recreate"), "true"),
+        ];
+        let secret = api.insert(&declaring(
+            &annotations,
+            &[("username", "app")],
+            "helm",
+        ));
+        let original: Secret = api.get(Some("dev"), "db").unwrap();
+
+        // The old secret is restored when the new one is rejected.
+        api.fail(http::Method::POST, http::StatusCode::FORBIDDEN);
+        let result = reconcile(Arc::new(secret), context(&api, &[])).await;
+        assert!(matches!(result, Err(Error::Kube(_))), "{:?}", result);
+        let restored: Secret = api.get(Some("dev"), "db").unwrap();
+        assert_ne!(restored.uid(), original.uid());
+        assert_eq!(restored.type_, original.type_);
+        assert_eq!(restored.data, original.data);
+        assert!(!restored.annotations().contains_key(app_id!("revision")));
+
+        reconcile(Arc::new(restored), context(&api, &[]))
+            .await
+            .unwrap();
+        let recreated: Secret = api.get(Some("dev"), "db").unwrap();
+        assert_eq!(
+            recreated.type_.as_deref(),
+            Some("kubernetes.io/basic-auth")
+        );
+        assert_eq!(value(&recreated, "username"), "app");
+        assert!(!value(&recreated, "password").is_empty());
+        assert_eq!(recreated.annotations()[app_id!("revision")], "1");
+    }
+
+    #[tokio::test]
+    async fn stamped_status() {
+        let api = FakeApi::default();
+        for name in ["dev", "app"] {
+            api.insert(&Namespace {
+                metadata: ObjectMeta {
+                    name: Some(name.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+        }
+        let template = api.insert(&declaring(
+            &[
+                (app_id!("gen"), "{password: default}"),
+                (app_id!("stamp-to-selector"), "team=a"),
+                (app_id!("rotate"), "30d"),
+                (app_id!("generated-at"), "{\"password\": \"2020-01-01\"}"),
+                (app_id!("target-of"), "ops/db"),
+                (app_id!("revision"), "3"),
+                (app_id!("post-generate"), "{job: register}"),
+            ],
+            &[("password", "secret")],
+            app_id!(),
+        ));
+        // Templates are only honoured in the operator's namespace.
+        let args = ["--cross-namespace"];
+        let result =
+            reconcile(Arc::new(template.clone()), context(&api, &args)).await;
+        assert!(matches!(result, Err(Error::Conflict(_))), "{result:?}");
+        assert!(api.get::<Secret>(Some("app"), "db").is_none());
+
+        stamp(&context(&api, &[]), &template, "team=a")
+            .await
+            .unwrap();
+
+        let stamped: Secret = api.get(Some("app"), "db").unwrap();
+        let annotations: Vec<_> = stamped.annotations().keys().collect();
+        assert_eq!(annotations, [app_id!("gen"), app_id!("rotate")]);
+        assert!(stamped.data.is_none());
+    }
+
+    #[tokio::test]
+    async fn replication_consent() {
+        // `app` allows replication from `dev`, `ops` doesn't.
+        let cluster = |target: (&str, &str)| {
+            let api = FakeApi::default();
+            for (name, allowed) in [("app", "dev"), ("ops", "other")] {
+                api.insert(&Namespace {
+                    metadata: ObjectMeta {
+                        name: Some(name.into()),
+                        annotations: Some(BTreeMap::from([(
+                            app_id!("allow-replication-from").to_string(),
+                            allowed.to_string(),
+                        )])),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                });
+            }
+            let annotations = [(app_id!("gen"), "{password: default}"), target];
+            let secret = api.insert(&declaring(&annotations, &[], "helm"));
+            (api, Arc::new(secret))
+        };
+        let args = ["--cross-namespace"];
+        let (api, secret) = cluster((app_id!("replicate-to"), "app,ops"));
+        let result = reconcile(secret, context(&api, &args)).await;
+        assert!(matches!(result, Err(Error::Conflict(_))), "{result:?}");
+        assert!(api.get::<Secret>(Some("app"), "db").is_none());
+        let (api, secret) = cluster((app_id!("replicate-to"), "app"));
+        reconcile(secret, context(&api, &args)).await.unwrap();
+        let replica: Secret = api.get(Some("app"), "db").unwrap();
+        assert!(replica.data.unwrap().contains_key("password"));
+
+        // Targets in other namespaces need the same consent.
+        let target = "{name: creds, namespace: ops}";
+        let (api, secret) = cluster((app_id!("target"), target));
+        let err = reconcile(secret, context(&api, &args)).await.unwrap_err();
+        assert!(err.to_string().contains("does not allow"), "{}", err);
+        assert!(api.get::<Secret>(Some("ops"), "creds").is_none());
+    }
+
+    #[tokio::test]
+    async fn job_hooks() {
+        use k8s_openapi::api::batch::v1::{Job, JobSpec};
+        let api = FakeApi::default();
+        let annotations = [
+            (app_id!("gen"), "{password: default}"),
+            (app_id!("post-generate"), "{job: register}"),
+        ];
+        let secret = api.insert(&declaring(&annotations, &[], "helm"));
+        // Jobs only run from templates the operator was given.
+        reconcile(Arc::new(secret.clone()), context(&api, &[]))
+            .await
+            .unwrap();
+        let status = |api: &FakeApi| {
+            let secret: Secret = api.get(Some("dev"), "db").unwrap();
+            secret.annotations()[app_id!("post-generate-status")].clone()
+        };
+        assert!(status(&api).contains("not configured"), "{}", status(&api));
+        assert!(api.list::<Job>(Some("dev")).is_empty());
+
+        let api = FakeApi::default();
+        let secret = api.insert(&declaring(&annotations, &[], "helm"));
+        let mut ctx = context(&api, &[]);
+        let template: JobSpec = serde_json::from_value(serde_json::json!({
+            "template": {"spec": {"containers": [{"name": "register"}]}},
+        }))
+        .unwrap();
+        Arc::get_mut(&mut ctx).unwrap().opts.job_hooks =
+            Arc::new(BTreeMap::from([("register".to_string(), template)]));
+        reconcile(Arc::new(secret), ctx).await.unwrap();
+        assert_eq!(status(&api), "pending");
+        let job: Job = api.get(Some("dev"), "db-post-generate").unwrap();
+        let pod = job.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod.containers[0].name, "register");
+        assert_eq!(
+            pod.volumes.unwrap()[0]
+                .secret
+                .as_ref()
+                .unwrap()
+                .secret_name
+                .as_deref(),
+            Some("db")
+        );
+    }
+}
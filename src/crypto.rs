@@ -0,0 +1,428 @@
+//! Randomness and digests. Everything random goes through [`fill_random`],
+//! so that `--rng` and FIPS mode apply to all generated values.
+
+use crate::error::Error;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use rand::{
+    rngs::{OsRng, StdRng},
+    seq::SliceRandom,
+    CryptoRng, RngCore, SeedableRng,
+};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::Read,
+    sync::{Mutex, OnceLock},
+};
+use zeroize::Zeroizing;
+
+// Randomness and digests go through the FIPS validated aws-lc module once
+// `--fips` is set, and through the RustCrypto crates otherwise.
+pub(crate) static FIPS: OnceLock<bool> = OnceLock::new();
+
+pub fn fips() -> bool {
+    FIPS.get().copied().unwrap_or(false)
+}
+
+#[cfg(feature = "fips")]
+pub fn enable_fips() -> Result<(), Error> {
+    aws_lc_rs::try_fips_mode().map_err(Error::Fips)?;
+    FIPS.set(true).unwrap();
+    Ok(())
+}
+
+#[cfg(not(feature = "fips"))]
+pub fn enable_fips() -> Result<(), Error> {
+    Err(Error::Fips(
+        "the operator was built without the fips feature",
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RngKind {
+    /// The operating system's CSPRNG
+    Os,
+    /// The kernel's hardware random number generator at /dev/hwrng
+    Hardware,
+    /// A deterministic generator seeded with --rng-seed. Every run generates
+    /// the same values, so this is only meant for tests and demos
+    UnsafeSeeded,
+}
+
+enum RngSource {
+    Os,
+    Hardware(Mutex<File>),
+    Seeded(Box<Mutex<StdRng>>),
+}
+
+// The source behind `fill_random`, selected with `--rng`.
+static RNG: OnceLock<RngSource> = OnceLock::new();
+
+pub fn init_rng(kind: RngKind, seed: Option<u64>) -> Result<(), Error> {
+    if fips() && kind != RngKind::Os {
+        return Err(Error::Rng(
+            "FIPS mode only supports the os source".to_string(),
+        ));
+    }
+    let source = match kind {
+        RngKind::Os => RngSource::Os,
+        RngKind::Hardware => {
+            let device = File::open(HWRNG).map_err(|e| {
+                Error::Rng(format!("cannot open {}: {}", HWRNG, e))
+            })?;
+            RngSource::Hardware(Mutex::new(device))
+        }
+        RngKind::UnsafeSeeded => {
+            let seed = seed.ok_or_else(|| {
+                Error::Rng("unsafe-seeded needs --rng-seed".to_string())
+            })?;
+            RngSource::Seeded(Box::new(Mutex::new(StdRng::seed_from_u64(seed))))
+        }
+    };
+    RNG.set(source)
+        .map_err(|_| Error::Rng("already initialized".to_string()))
+}
+
+const HWRNG: &str = "/dev/hwrng";
+
+pub fn fill_random(dest: &mut [u8]) {
+    #[cfg(feature = "fips")]
+    if fips() {
+        aws_lc_rs::rand::fill(dest).expect("the FIPS DRBG failed");
+        return;
+    }
+    match RNG.get().unwrap_or(&RngSource::Os) {
+        RngSource::Os => OsRng.fill_bytes(dest),
+        RngSource::Hardware(device) => device
+            .lock()
+            .unwrap()
+            .read_exact(dest)
+            .expect("the hardware random source failed"),
+        RngSource::Seeded(rng) => rng.lock().unwrap().fill_bytes(dest),
+    }
+}
+
+// An rng handle for `rand`'s helpers that draws from `fill_random`.
+pub struct Random;
+
+impl RngCore for Random {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        fill_random(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        fill_random(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_random(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        fill_random(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Random {}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    #[cfg(feature = "fips")]
+    if fips() {
+        use aws_lc_rs::digest;
+        return digest::digest(&digest::SHA256, data).as_ref().to_vec();
+    }
+    Sha256::digest(data).to_vec()
+}
+
+pub fn sha512(data: &[u8]) -> Vec<u8> {
+    #[cfg(feature = "fips")]
+    if fips() {
+        use aws_lc_rs::digest;
+        return digest::digest(&digest::SHA512, data).as_ref().to_vec();
+    }
+    Sha512::digest(data).to_vec()
+}
+
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    #[cfg(feature = "fips")]
+    if fips() {
+        use aws_lc_rs::hmac;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        return hmac::sign(&key, msg).as_ref().to_vec();
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(msg);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn hmac_sha512(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    #[cfg(feature = "fips")]
+    if fips() {
+        use aws_lc_rs::hmac;
+        let key = hmac::Key::new(hmac::HMAC_SHA512, key);
+        return hmac::sign(&key, msg).as_ref().to_vec();
+    }
+    let mut mac = Hmac::<Sha512>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(msg);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn pbkdf2_sha512(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    out: &mut [u8],
+) {
+    #[cfg(feature = "fips")]
+    if fips() {
+        use aws_lc_rs::pbkdf2;
+        use std::num::NonZeroU32;
+        let iterations = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN);
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA512,
+            iterations,
+            salt,
+            password,
+            out,
+        );
+        return;
+    }
+    pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, out);
+}
+
+// Alphabets of the random string generator. They match the ones of the
+// randstr crate it replaces, so stored settings keep their meaning.
+const UPPER_ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+const LOWER_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz";
+
+const DIGIT_ALPHABET: &str = "0123456789";
+
+const SYMBOL_ALPHABET: &str = r##"!"#$%&'()*+,-./:;<=>?@[\]^_`{|}~"##;
+
+const WHITESPACE_ALPHABET: &str = " ";
+
+#[derive(Debug, Default)]
+pub struct RandStrBuilder {
+    alphabet: BTreeSet<char>,
+    must: Vec<Vec<char>>,
+    len: usize,
+}
+
+pub fn randstr() -> RandStrBuilder {
+    RandStrBuilder::default()
+}
+
+impl RandStrBuilder {
+    fn alphabet(&mut self, alphabet: &str) -> &mut Self {
+        self.alphabet.extend(alphabet.chars());
+        self
+    }
+
+    fn must(&mut self, alphabet: &str) -> &mut Self {
+        let must: Vec<char> = alphabet.chars().collect();
+        if !self.must.contains(&must) {
+            self.must.push(must);
+        }
+        self.alphabet(alphabet)
+    }
+
+    pub fn upper(&mut self) -> &mut Self {
+        self.alphabet(UPPER_ALPHABET)
+    }
+
+    pub fn lower(&mut self) -> &mut Self {
+        self.alphabet(LOWER_ALPHABET)
+    }
+
+    pub fn letter(&mut self) -> &mut Self {
+        self.lower().upper()
+    }
+
+    pub fn digit(&mut self) -> &mut Self {
+        self.alphabet(DIGIT_ALPHABET)
+    }
+
+    pub fn symbol(&mut self) -> &mut Self {
+        self.alphabet(SYMBOL_ALPHABET)
+    }
+
+    pub fn whitespace(&mut self) -> &mut Self {
+        self.alphabet(WHITESPACE_ALPHABET)
+    }
+
+    pub fn custom(&mut self, custom: &str) -> &mut Self {
+        self.alphabet(custom)
+    }
+
+    pub fn all(&mut self) -> &mut Self {
+        self.letter().digit().symbol()
+    }
+
+    pub fn must_upper(&mut self) -> &mut Self {
+        self.must(UPPER_ALPHABET)
+    }
+
+    pub fn must_lower(&mut self) -> &mut Self {
+        self.must(LOWER_ALPHABET)
+    }
+
+    pub fn must_letter(&mut self) -> &mut Self {
+        self.must(&format!("{}{}", LOWER_ALPHABET, UPPER_ALPHABET))
+    }
+
+    pub fn must_digit(&mut self) -> &mut Self {
+        self.must(DIGIT_ALPHABET)
+    }
+
+    pub fn must_symbol(&mut self) -> &mut Self {
+        self.must(SYMBOL_ALPHABET)
+    }
+
+    pub fn must_whitespace(&mut self) -> &mut Self {
+        self.must(WHITESPACE_ALPHABET)
+    }
+
+    pub fn must_custom(&mut self, custom: &str) -> &mut Self {
+        self.must(custom)
+    }
+
+    pub fn len(&mut self, len: usize) -> &mut Self {
+        self.len = len;
+        self
+    }
+
+    pub fn try_build(&self) -> Result<RandStr, Error> {
+        if self.alphabet.is_empty() {
+            return Err(Error::RandStr("No alphabet specified"));
+        }
+        if self.len < self.must.len() {
+            return Err(Error::RandStr(
+                "Length is too short to contain all mandatory alphabets",
+            ));
+        }
+        Ok(RandStr {
+            alphabet: self.alphabet.iter().copied().collect(),
+            must: self.must.clone(),
+            len: self.len,
+        })
+    }
+}
+
+pub struct RandStr {
+    pub(crate) alphabet: Vec<char>,
+    must: Vec<Vec<char>>,
+    pub(crate) len: usize,
+}
+
+impl RandStr {
+    // Values are uniformly random, so the best attack is brute force over
+    // the alphabet.
+    pub fn guesses_log10(&self) -> f64 {
+        self.len as f64 * (self.alphabet.len() as f64).log10()
+    }
+
+    // The guesses mapped to zxcvbn's 0 (too guessable) to 4 (very
+    // unguessable) scale.
+    pub fn score(&self) -> u8 {
+        let guesses = self.guesses_log10();
+        [3.0, 6.0, 8.0, 10.0]
+            .iter()
+            .take_while(|threshold| guesses >= **threshold)
+            .count() as u8
+    }
+
+    pub fn generate(&self) -> String {
+        self.generate_with(&mut Random)
+    }
+
+    pub fn generate_with(&self, rng: &mut impl RngCore) -> String {
+        let mut result: Zeroizing<Vec<char>> = Zeroizing::new(
+            (0..self.len)
+                .map(|_| *self.alphabet.choose(rng).unwrap())
+                .collect(),
+        );
+        // Every mandatory alphabet keeps one position it is found at, the
+        // missing ones replace a random position that isn't kept.
+        let mut kept = vec![false; result.len()];
+        let mut missing = vec![];
+        for must in &self.must {
+            match (0..result.len())
+                .find(|&i| !kept[i] && must.contains(&result[i]))
+            {
+                Some(i) => kept[i] = true,
+                None => missing.push(must),
+            }
+        }
+        for must in missing {
+            let free: Vec<usize> =
+                (0..result.len()).filter(|&i| !kept[i]).collect();
+            let i = *free.choose(rng).unwrap();
+            result[i] = *must.choose(rng).unwrap();
+            kept[i] = true;
+        }
+        result.iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alphabet_and_length() {
+        let value = randstr().digit().len(20).try_build().unwrap().generate();
+        assert_eq!(value.len(), 20);
+        assert!(value.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn must_classes() {
+        let generator = randstr()
+            .lower()
+            .must_upper()
+            .must_digit()
+            .len(2)
+            .try_build()
+            .unwrap();
+        for _ in 0..32 {
+            let value = generator.generate();
+            assert!(value.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(value.chars().any(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn invalid_generators() {
+        assert!(randstr().len(8).try_build().is_err());
+        assert!(randstr()
+            .must_upper()
+            .must_digit()
+            .len(1)
+            .try_build()
+            .is_err());
+    }
+
+    #[test]
+    fn score() {
+        let score = |len| randstr().all().len(len).try_build().unwrap().score();
+        assert_eq!(score(1), 0);
+        assert_eq!(score(4), 2);
+        assert_eq!(score(32), 4);
+    }
+
+    #[test]
+    fn digests() {
+        assert_eq!(sha256(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf],);
+        assert_eq!(sha512(b"abc")[..4], [0xdd, 0xaf, 0x35, 0xa1]);
+    }
+}
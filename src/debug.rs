@@ -0,0 +1,219 @@
+// Diagnostics for a controller that hangs or lags, served with
+// `--debug-addr`: `/debug/queue` lists the objects being reconciled and the
+// ones waiting for their turn under `--namespace-qps`, `/debug/timings` the
+// reconciles of every object so far, the most expensive first. Both answer
+// with JSON. Nothing is recorded unless the server runs.
+
+use crate::error::Error;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use k8s_openapi::chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, info};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex,
+    },
+};
+use tokio::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// What is known about the reconciles of an object.
+#[derive(Default)]
+struct Object {
+    running_since: Option<Instant>,
+    throttled_until: Option<Instant>,
+    reconciles: u64,
+    failures: u64,
+    last: Duration,
+    max: Duration,
+    total: Duration,
+    last_error: Option<String>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+// The objects by their reference, prefixed with their cluster.
+static OBJECTS: LazyLock<Mutex<BTreeMap<String, Object>>> =
+    LazyLock::new(Mutex::default);
+
+fn record(object: &str, update: impl FnOnce(&mut Object)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut objects = OBJECTS.lock().unwrap();
+    update(objects.entry(object.to_string()).or_default());
+}
+
+// `object` waits `wait` for the reconciles of its namespace to slow down.
+pub(crate) fn throttled(object: &str, wait: Duration) {
+    record(object, |o| o.throttled_until = Some(Instant::now() + wait));
+}
+
+pub(crate) fn started(object: &str) {
+    record(object, |o| {
+        o.running_since = Some(Instant::now());
+        o.throttled_until = None;
+    });
+}
+
+pub(crate) fn finished(object: &str, error: Option<String>) {
+    record(object, |o| {
+        let Some(started) = o.running_since.take() else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        o.reconciles += 1;
+        o.failures += u64::from(error.is_some());
+        o.last = elapsed;
+        o.max = o.max.max(elapsed);
+        o.total += elapsed;
+        o.last_error = error;
+        o.finished_at = Some(Utc::now());
+    });
+}
+
+fn queue(now: Instant) -> serde_json::Value {
+    let objects = OBJECTS.lock().unwrap();
+    let reconciling: Vec<_> = objects
+        .iter()
+        .filter_map(|(name, o)| {
+            let running = now.saturating_duration_since(o.running_since?);
+            Some(serde_json::json!({
+                "object": name,
+                "runningSeconds": running.as_secs_f64(),
+            }))
+        })
+        .collect();
+    let throttled: Vec<_> = objects
+        .iter()
+        .filter(|(_, o)| o.running_since.is_none())
+        .filter_map(|(name, o)| {
+            let wait = o.throttled_until?.checked_duration_since(now)?;
+            Some(serde_json::json!({
+                "object": name,
+                "waitSeconds": wait.as_secs_f64(),
+            }))
+        })
+        .collect();
+    serde_json::json!({"reconciling": reconciling, "throttled": throttled})
+}
+
+fn timings() -> serde_json::Value {
+    let objects = OBJECTS.lock().unwrap();
+    let mut finished: Vec<_> =
+        objects.iter().filter(|(_, o)| o.reconciles > 0).collect();
+    finished.sort_by_key(|(_, o)| std::cmp::Reverse(o.total));
+    finished
+        .into_iter()
+        .map(|(name, o)| {
+            serde_json::json!({
+                "object": name,
+                "reconciles": o.reconciles,
+                "failures": o.failures,
+                "lastSeconds": o.last.as_secs_f64(),
+                "maxSeconds": o.max.as_secs_f64(),
+                "meanSeconds": o.total.as_secs_f64() / o.reconciles as f64,
+                "totalSeconds": o.total.as_secs_f64(),
+                "lastError": o.last_error,
+                "finishedAt": o.finished_at.map(|at| {
+                    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                }),
+            })
+        })
+        .collect()
+}
+
+pub(crate) struct DebugServer {
+    listener: TcpListener,
+}
+
+impl DebugServer {
+    pub(crate) async fn bind(addr: SocketAddr) -> Result<DebugServer, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(DebugServer { listener })
+    }
+
+    pub(crate) async fn serve(self) -> Result<(), Error> {
+        info!("serving diagnostics on {}", self.listener.local_addr()?);
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(handle))
+                    .await
+                {
+                    debug!("debug connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match req.uri().path() {
+        "/debug/queue" => queue(Instant::now()),
+        "/debug/timings" => timings(),
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+                .unwrap())
+        }
+    };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconciles() {
+        ENABLED.store(true, Ordering::Relaxed);
+        let (slow, fast) =
+            ("test:Secret.v1./slow.dev", "test:Secret.v1./fast.dev");
+        let entry = |value: &serde_json::Value| {
+            let entries = value.as_array().unwrap().iter();
+            entries
+                .filter(|e| e["object"].as_str().unwrap().starts_with("test:"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        throttled(fast, Duration::from_secs(60));
+        started(slow);
+        let queued = queue(Instant::now());
+        assert_eq!(entry(&queued["reconciling"])[0]["object"], slow);
+        assert_eq!(entry(&queued["throttled"])[0]["object"], fast);
+
+        started(fast);
+        finished(fast, None);
+        OBJECTS.lock().unwrap().get_mut(slow).unwrap().running_since =
+            Some(Instant::now() - Duration::from_secs(5));
+        finished(slow, Some("failed".into()));
+        let timings = entry(&timings());
+        assert_eq!(timings[0]["object"], slow);
+        assert_eq!(timings[0]["failures"], 1);
+        assert_eq!(timings[0]["lastError"], "failed");
+        assert_eq!(timings[1]["object"], fast);
+        assert!(entry(&queue(Instant::now())["reconciling"]).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! The error type shared by parsing, generation and the controllers.
+
+use crate::settings::SCHEMA_VERSIONS;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("serde error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("kube error: {0}")]
+    Kube(#[from] kube::Error),
+    #[error("kubeconfig error: {0}")]
+    Kubeconfig(#[from] kube::config::KubeconfigError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("webhook error: {0}")]
+    Webhook(String),
+    #[error("command {0:?} failed: {1}")]
+    Exec(String, String),
+    #[error("provider {0:?} failed: {1}")]
+    Provider(String, String),
+    #[error("plugin error: {0}")]
+    Plugin(String),
+    #[error("vault error: {0}")]
+    Vault(String),
+    #[error("AWS error: {0}")]
+    Aws(String),
+    #[error("GCP error: {0}")]
+    Gcp(String),
+    #[error("Azure error: {0}")]
+    Azure(String),
+    #[error("sealing error: {0}")]
+    Seal(String),
+    #[error("export error: {0}")]
+    Export(String),
+    #[error("git error: {0}")]
+    Git(String),
+    #[error("telemetry error: {0}")]
+    Telemetry(String),
+    #[error("certificate error: {0}")]
+    Certificate(String),
+    #[error("randstr error: {0}")]
+    RandStr(&'static str),
+    #[error("random source: {0}")]
+    Rng(String),
+    #[error("derive error: {0}")]
+    Derive(String),
+    #[error("FIPS mode: {0}")]
+    Fips(&'static str),
+    #[error("{0} is not FIPS approved")]
+    NotApproved(&'static str),
+    #[error("kind {0} cannot be used as a nested generator")]
+    NotNestable(String),
+    #[error("template error: {0}")]
+    Template(String),
+    #[error("dependency cycle between keys: {0}")]
+    Cycle(String),
+    #[error("referenced key {0:?} does not exist")]
+    MissingKey(String),
+    #[error("reference error: {0}")]
+    Reference(String),
+    #[error("could not generate a unique value for key {0:?}")]
+    NotUnique(String),
+    #[error("{0} has no values left")]
+    Exhausted(String),
+    #[error("invalid annotation {0}: {1}")]
+    InvalidAnnotation(String, String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("too large: {0}")]
+    TooLarge(String),
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error(
+        "unsupported schema version {:?}, this operator supports {}",
+        .0,
+        SCHEMA_VERSIONS.join(", ")
+    )]
+    UnsupportedVersion(String),
+    #[error("key {0:?} is below the strength floor: {1}")]
+    BelowFloor(String, String),
+    #[error("key {0:?} is too weak: {1}")]
+    TooWeak(String, String),
+    #[error("key {0:?}: {1}")]
+    Key(String, Box<Error>),
+    #[error("found {0} problems")]
+    Problems(usize),
+    #[error("failed to reconcile {0} objects")]
+    Unreconciled(usize),
+    #[error("timed out {0}")]
+    Timeout(String),
+    #[error("lost lease {0}")]
+    LeaseLost(String),
+}
+
+impl Error {
+    // Whether the error may go away by itself, so that the object is retried.
+    // Errors in its declaration need a change of the object instead.
+    pub(crate) fn transient(&self) -> bool {
+        match self {
+            Error::Kube(_)
+            | Error::Io(_)
+            | Error::Http(_)
+            | Error::Exec(..)
+            | Error::Provider(..)
+            | Error::Vault(_)
+            | Error::Aws(_)
+            | Error::Gcp(_)
+            | Error::Azure(_)
+            | Error::Git(_)
+            | Error::QuotaExceeded(_)
+            | Error::Rng(_)
+            | Error::Timeout(_) => true,
+            Error::Key(_, e) => e.transient(),
+            _ => false,
+        }
+    }
+
+    // The reason of the event reporting the error.
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Error::Yaml(_) | Error::Json(_) | Error::InvalidAnnotation(..) => {
+                "InvalidAnnotation"
+            }
+            Error::UnsupportedVersion(_) => "UnsupportedVersion",
+            Error::BelowFloor(..) => "BelowFloor",
+            Error::TooWeak(..) => "TooWeak",
+            Error::Conflict(_) => "Conflict",
+            Error::TooLarge(_) => "TooLarge",
+            Error::QuotaExceeded(_) => "QuotaExceeded",
+            Error::Key(_, e) => e.reason(),
+            _ => "Failed",
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// PushSecrets of the External Secrets Operator for generated secrets. The
+// values are generated here and ESO delivers them to its backends, so the
+// credentials of those backends stay with ESO.
+
+use crate::{controller::Context, error::Error};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams},
+    Api, Resource, ResourceExt,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+// The `push-secret` annotation.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PushTarget {
+    // The SecretStore as `[<kind>/]<name>`.
+    store: String,
+    // The key in the backend, `<namespace>/<name>` of the secret by default.
+    key: Option<String>,
+    refresh_interval: Option<String>,
+    #[serde(default)]
+    delete: bool,
+}
+
+impl PushTarget {
+    // The spec of the PushSecret pushing `keys` of the secret `name` in
+    // `namespace`, each into a property of the same name.
+    fn spec(
+        &self,
+        namespace: &str,
+        name: &str,
+        keys: &BTreeSet<String>,
+    ) -> serde_json::Value {
+        let (kind, store) = self
+            .store
+            .split_once('/')
+            .unwrap_or(("SecretStore", &self.store));
+        let remote_key = self
+            .key
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", namespace, name));
+        let data: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                serde_json::json!({"match": {
+                    "secretKey": key,
+                    "remoteRef": {"remoteKey": remote_key, "property": key},
+                }})
+            })
+            .collect();
+        let mut spec = serde_json::json!({
+            "secretStoreRefs": [{"kind": kind, "name": store}],
+            "selector": {"secret": {"name": name}},
+            "data": data,
+            "deletionPolicy": if self.delete { "Delete" } else { "None" },
+        });
+        if let Some(interval) = &self.refresh_interval {
+            spec["refreshInterval"] = interval.as_str().into();
+        }
+        spec
+    }
+}
+
+fn push_secret_resource() -> ApiResource {
+    let gvk =
+        GroupVersionKind::gvk("external-secrets.io", "v1alpha1", "PushSecret");
+    ApiResource::from_gvk_with_plural(&gvk, "pushsecrets")
+}
+
+// Applies the PushSecret named after the secret `name` in `namespace`,
+// pushing `keys` to the store of `target`. It is garbage collected with
+// `source` if they share the namespace.
+pub(crate) async fn apply_push_secret(
+    ctx: &Context,
+    source: &Secret,
+    namespace: &str,
+    name: &str,
+    keys: &BTreeSet<String>,
+    target: &PushTarget,
+) -> Result<(), Error> {
+    let resource = push_secret_resource();
+    let api = Api::<DynamicObject>::namespaced_with(
+        ctx.client.clone(),
+        namespace,
+        &resource,
+    );
+    let mut metadata = serde_json::json!({
+        "name": name,
+        "namespace": namespace,
+    });
+    if source.namespace().as_deref() == Some(namespace) {
+        if let Some(owner) = source.controller_owner_ref(&()) {
+            metadata["ownerReferences"] = serde_json::json!([owner]);
+        }
+    }
+    let patch = serde_json::json!({
+        "apiVersion": resource.api_version,
+        "kind": resource.kind,
+        "metadata": metadata,
+        "spec": target.spec(namespace, name, keys),
+    });
+    let params = PatchParams::apply(app_id!()).force();
+    api.patch(name, &params, &Patch::Apply(&patch)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::parse_value;
+
+    #[test]
+    fn spec() {
+        let keys = BTreeSet::from(["password".to_string(), "user".to_string()]);
+        let target: PushTarget =
+            parse_value("push-secret", "store: ClusterSecretStore/aws")
+                .unwrap();
+        let spec = target.spec("apps", "db", &keys);
+        assert_eq!(
+            spec["secretStoreRefs"],
+            serde_json::json!([{"kind": "ClusterSecretStore", "name": "aws"}])
+        );
+        assert_eq!(spec["selector"]["secret"]["name"], "db");
+        assert_eq!(spec["deletionPolicy"], "None");
+        assert_eq!(
+            spec["data"][1]["match"],
+            serde_json::json!({
+                "secretKey": "user",
+                "remoteRef": {"remoteKey": "apps/db", "property": "user"},
+            })
+        );
+
+        let target: PushTarget = parse_value(
+            "push-secret",
+            "{store: vault, key: db, refresh_interval: 10m, delete: true}",
+        )
+        .unwrap();
+        let spec = target.spec("apps", "db", &keys);
+        assert_eq!(spec["secretStoreRefs"][0]["kind"], "SecretStore");
+        assert_eq!(spec["data"][0]["match"]["remoteRef"]["remoteKey"], "db");
+        assert_eq!(spec["refreshInterval"], "10m");
+        assert_eq!(spec["deletionPolicy"], "Delete");
+    }
+}
@@ -0,0 +1,566 @@
+// Encrypted snapshots of generated secrets, as a way to recover them without
+// the cluster, and backups of values before they are overwritten. Secrets are
+// written as Secret manifests, encrypted with age
+// (https://age-encryption.org/v1) to the configured recipients, so the
+// directories never hold plaintext. `age -d` restores the manifest.
+
+use crate::{
+    controller::wipe,
+    crypto::{fill_random, fips, hmac_sha256},
+    error::Error,
+    generate::SecretData,
+    opts::Opts,
+};
+use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
+use hkdf::Hkdf;
+use k8s_openapi::chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    rand::SystemRandom,
+};
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const CHUNK_SIZE: usize = 64 * 1024;
+// Backups are named after the time they were taken at, in UTC.
+const BACKUP_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// An age X25519 recipient, given as `age1...`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Recipient([u8; 32]);
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        bech32_key(s, "age").map(Recipient).ok_or_else(|| {
+            Error::Export(format!("invalid age recipient {:?}", s))
+        })
+    }
+}
+
+// An age X25519 identity, given as `AGE-SECRET-KEY-1...`.
+pub(crate) struct Identity(Zeroizing<[u8; 32]>);
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Identities are secret, so they aren't part of the error.
+        bech32_key(s, "age-secret-key-")
+            .map(|key| Identity(Zeroizing::new(key)))
+            .ok_or_else(|| Error::Export("invalid age identity".to_string()))
+    }
+}
+
+impl Identity {
+    fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&StaticSecret::from(*self.0)).to_bytes())
+    }
+}
+
+// The identities in an age identity file, one per line with `#` comments.
+pub(crate) fn identities(file: &str) -> Result<Vec<Identity>, Error> {
+    file.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+// The 32 byte key encoded in the bech32 string `s` with the prefix `hrp`.
+fn bech32_key(s: &str, hrp: &str) -> Option<[u8; 32]> {
+    let lower = Zeroizing::new(s.to_ascii_lowercase());
+    let (prefix, data) = lower.rsplit_once('1')?;
+    if prefix != hrp || data.len() < 6 {
+        return None;
+    }
+    let values = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|x| *x == c))
+        .collect::<Option<Vec<_>>>()
+        .map(Zeroizing::new)?;
+    let mut checked: Zeroizing<Vec<u8>> =
+        Zeroizing::new(hrp.bytes().map(|c| c >> 5).collect());
+    checked.push(0);
+    checked.extend(hrp.bytes().map(|c| c & 31));
+    checked.extend(values.iter().map(|v| *v as u8));
+    if bech32_polymod(&checked) != 1 {
+        return None;
+    }
+    // The five bit groups before the checksum, as bytes.
+    let mut bytes = Zeroizing::new(vec![]);
+    let (mut acc, mut bits) = (0u32, 0);
+    for value in &values[..values.len() - 6] {
+        acc = (acc << 5) | *value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    bytes.as_slice().try_into().ok()
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] =
+        [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+// The recipients of `--export-recipients`, checked at startup.
+pub(crate) fn recipients(opts: &Opts) -> Result<Vec<Recipient>, Error> {
+    opts.export_recipients.iter().map(|r| r.parse()).collect()
+}
+
+// The X25519 function of RFC 7748, for identities, which ring only supports
+// ephemerally.
+fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    let shared =
+        StaticSecret::from(*scalar).diffie_hellman(&PublicKey::from(*u));
+    Zeroizing::new(shared.to_bytes())
+}
+
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0; 32]);
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut *key)
+        .expect("32 bytes are a valid HKDF-SHA256 length");
+    key
+}
+
+// Encrypts `data` in place with ChaCha20-Poly1305 and appends the tag.
+fn chacha20_poly1305(
+    key: &[u8],
+    nonce: [u8; 12],
+    data: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key)
+        .map_err(|_| Error::Export("invalid key".to_string()))?;
+    LessSafeKey::new(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            data,
+        )
+        .map_err(|_| Error::Export("encryption failed".to_string()))
+}
+
+// Decrypts `sealed`, returning nothing if it isn't authentic.
+fn chacha20_poly1305_open(
+    key: &[u8],
+    nonce: [u8; 12],
+    sealed: &[u8],
+) -> Option<Zeroizing<Vec<u8>>> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key).ok()?;
+    let mut data = Zeroizing::new(sealed.to_vec());
+    let nonce = Nonce::assume_unique_for_key(nonce);
+    let len = LessSafeKey::new(key)
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .ok()?
+        .len();
+    data.truncate(len);
+    Some(data)
+}
+
+// The X25519 stanza wrapping `file_key` for `recipient`.
+fn x25519_stanza(
+    recipient: &Recipient,
+    file_key: &[u8],
+) -> Result<String, Error> {
+    let failed = |_| Error::Export("key agreement failed".to_string());
+    let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+        .map_err(failed)?;
+    let share = private.compute_public_key().map_err(failed)?;
+    let peer = UnparsedPublicKey::new(&X25519, recipient.0);
+    let shared = agreement::agree_ephemeral(private, &peer, |shared| {
+        Zeroizing::new(shared.to_vec())
+    })
+    .map_err(failed)?;
+    let mut salt = share.as_ref().to_vec();
+    salt.extend_from_slice(&recipient.0);
+    let wrap_key = hkdf_sha256(&shared, &salt, X25519_INFO);
+    let mut body = file_key.to_vec();
+    chacha20_poly1305(&*wrap_key, [0; 12], &mut body)?;
+    Ok(format!(
+        "-> X25519 {}\n{}\n",
+        BASE64_STANDARD_NO_PAD.encode(share.as_ref()),
+        BASE64_STANDARD_NO_PAD.encode(body)
+    ))
+}
+
+// `plaintext` as an age file that each of `recipients` can decrypt.
+fn encrypt(
+    recipients: &[Recipient],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut file_key = Zeroizing::new([0; 16]);
+    fill_random(&mut *file_key);
+    let mut header = format!("{}\n", VERSION_LINE);
+    for recipient in recipients {
+        header.push_str(&x25519_stanza(recipient, &*file_key)?);
+    }
+    header.push_str("---");
+    let mac_key = hkdf_sha256(&*file_key, &[], b"header");
+    let mac = hmac_sha256(&*mac_key, header.as_bytes());
+    header.push_str(&format!(" {}\n", BASE64_STANDARD_NO_PAD.encode(mac)));
+
+    let mut nonce = [0; 16];
+    fill_random(&mut nonce);
+    let payload_key = hkdf_sha256(&*file_key, &nonce, b"payload");
+    let mut file = header.into_bytes();
+    file.extend_from_slice(&nonce);
+    // The STREAM construction, chunks with a counter and a flag marking the
+    // last one in their nonce. Empty plaintext is a single empty chunk.
+    let chunks: Vec<&[u8]> = match plaintext.is_empty() {
+        true => vec![&[]],
+        false => plaintext.chunks(CHUNK_SIZE).collect(),
+    };
+    for (counter, chunk) in chunks.iter().enumerate() {
+        let mut nonce = [0; 12];
+        nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+        nonce[11] = (counter == chunks.len() - 1) as u8;
+        let mut sealed = chunk.to_vec();
+        chacha20_poly1305(&*payload_key, nonce, &mut sealed)?;
+        file.extend_from_slice(&sealed);
+    }
+    Ok(file)
+}
+
+// Decrypts the age file `file` with the first of `identities` it is
+// encrypted to.
+pub(crate) fn decrypt(
+    identities: &[Identity],
+    file: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let invalid =
+        |what: &str| Error::Export(format!("invalid age file: {}", what));
+    // The MAC covers the header up to and including `---`.
+    let header_end = file
+        .windows(4)
+        .position(|w| w == b"\n---")
+        .ok_or_else(|| invalid("no header"))?
+        + 4;
+    let mac_end = file[header_end..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| invalid("no MAC"))?
+        + header_end;
+    let header = std::str::from_utf8(&file[..mac_end])
+        .map_err(|_| invalid("binary header"))?;
+    let mut lines = header.lines();
+    if lines.next() != Some(VERSION_LINE) {
+        return Err(invalid("unsupported version"));
+    }
+    // The arguments of each stanza, with its body.
+    let mut stanzas: Vec<(Vec<&str>, String)> = vec![];
+    let mut mac = None;
+    for line in lines {
+        if let Some(args) = line.strip_prefix("-> ") {
+            stanzas.push((args.split(' ').collect(), String::new()));
+        } else if let Some(encoded) = line.strip_prefix("--- ") {
+            mac = Some(encoded);
+        } else if let Some((_, body)) = stanzas.last_mut() {
+            body.push_str(line);
+        }
+    }
+    let decode = |s: &str| BASE64_STANDARD_NO_PAD.decode(s).ok();
+    let file_key = stanzas
+        .iter()
+        .filter(|(args, _)| args.len() == 2 && args[0] == "X25519")
+        .find_map(|(args, body)| {
+            let share: [u8; 32] = decode(args[1])?.try_into().ok()?;
+            let body = decode(body)?;
+            identities
+                .iter()
+                .find_map(|identity| unwrap_x25519(identity, &share, &body))
+        })
+        .ok_or_else(|| {
+            Error::Export("encrypted to none of the identities".to_string())
+        })?;
+    let mac_key = hkdf_sha256(&file_key, &[], b"header");
+    let expected = hmac_sha256(&*mac_key, &file[..header_end]);
+    let mac = mac.and_then(decode).ok_or_else(|| invalid("no MAC"))?;
+    let differences =
+        mac.iter().zip(&expected).fold(0, |d, (a, b)| d | (a ^ b));
+    if mac.len() != expected.len() || differences != 0 {
+        return Err(invalid("header MAC mismatch"));
+    }
+
+    let payload = &file[mac_end + 1..];
+    if payload.len() < 16 + 16 {
+        return Err(invalid("truncated payload"));
+    }
+    let (nonce, chunks) = payload.split_at(16);
+    let payload_key = hkdf_sha256(&file_key, nonce, b"payload");
+    let chunks: Vec<&[u8]> = chunks.chunks(CHUNK_SIZE + 16).collect();
+    let mut plaintext = Zeroizing::new(vec![]);
+    for (counter, chunk) in chunks.iter().enumerate() {
+        let mut nonce = [0; 12];
+        nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+        nonce[11] = (counter == chunks.len() - 1) as u8;
+        let opened = chacha20_poly1305_open(&*payload_key, nonce, chunk)
+            .ok_or_else(|| invalid("payload doesn't decrypt"))?;
+        plaintext.extend_from_slice(&opened);
+    }
+    Ok(plaintext)
+}
+
+// The file key in the body of an X25519 stanza with the ephemeral `share`,
+// if it is wrapped for `identity`.
+fn unwrap_x25519(
+    identity: &Identity,
+    share: &[u8; 32],
+    body: &[u8],
+) -> Option<Zeroizing<Vec<u8>>> {
+    let shared = x25519(&identity.0, share);
+    if shared.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let mut salt = share.to_vec();
+    salt.extend_from_slice(&identity.recipient().0);
+    let wrap_key = hkdf_sha256(&*shared, &salt, X25519_INFO);
+    chacha20_poly1305_open(&*wrap_key, [0; 12], body)
+}
+
+// Where the snapshot of the secret `name` in `namespace` is written,
+// relative to the export directory.
+pub(crate) fn snapshot_path(namespace: &str, name: &str) -> String {
+    format!("{}/{}.yaml.age", namespace, name)
+}
+
+// A secret as written to snapshots and backups.
+pub(crate) struct Snapshot<'a> {
+    pub(crate) namespace: &'a str,
+    pub(crate) name: &'a str,
+    pub(crate) type_: Option<&'a str>,
+    pub(crate) data: &'a SecretData,
+    // When the keys were generated, so that restored keys keep their
+    // rotation schedule.
+    pub(crate) generated_at: &'a BTreeMap<String, String>,
+}
+
+// Writes the encrypted snapshot of a secret to `dir`, if its values
+// `changed` or there is none yet. Returns whether it was written.
+pub(crate) fn export_secret(
+    opts: &Opts,
+    dir: &str,
+    snapshot: &Snapshot,
+    changed: bool,
+) -> Result<bool, Error> {
+    let path = PathBuf::from(dir)
+        .join(snapshot_path(snapshot.namespace, snapshot.name));
+    if !changed && path.exists() {
+        return Ok(false);
+    }
+    write_encrypted(opts, &path, snapshot)?;
+    Ok(true)
+}
+
+// Writes an encrypted backup of the values of a secret before they are
+// overwritten to `<dir>/<namespace>/<name>/`. Backups beyond
+// `--backup-retention` or older than `--backup-max-age` are removed. Returns
+// the path of the backup.
+pub(crate) fn backup_secret(
+    opts: &Opts,
+    dir: &str,
+    snapshot: &Snapshot,
+) -> Result<PathBuf, Error> {
+    let backups = PathBuf::from(dir)
+        .join(snapshot.namespace)
+        .join(snapshot.name);
+    let now = Utc::now();
+    let path =
+        backups.join(format!("{}.yaml.age", now.format(BACKUP_TIME_FORMAT)));
+    write_encrypted(opts, &path, snapshot)?;
+    let names = std::fs::read_dir(&backups)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    let max_age = opts.backup_max_age.as_ref().map(|a| a.0);
+    for expired in expired_backups(names, opts.backup_retention, max_age, now) {
+        std::fs::remove_file(backups.join(expired))?;
+    }
+    Ok(path)
+}
+
+// The backups among `names` that are beyond the newest `retention` ones or
+// older than `max_age`. The newest backup is always kept.
+fn expired_backups(
+    mut names: Vec<String>,
+    retention: usize,
+    max_age: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    names.retain(|name| backup_time(name).is_some());
+    // Timestamps sort like the times they stand for.
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    let cutoff =
+        max_age.and_then(|age| Some(now - TimeDelta::from_std(age).ok()?));
+    names
+        .into_iter()
+        .enumerate()
+        .filter(|(i, name)| {
+            let old = match (cutoff, backup_time(name)) {
+                (Some(cutoff), Some(time)) => time < cutoff,
+                _ => false,
+            };
+            *i > 0 && (*i >= retention.max(1) || old)
+        })
+        .map(|(_, name)| name)
+        .collect()
+}
+
+// The time a backup was taken at, from its file name.
+fn backup_time(name: &str) -> Option<DateTime<Utc>> {
+    let time = name.strip_suffix(".yaml.age")?;
+    let time = NaiveDateTime::parse_from_str(time, BACKUP_TIME_FORMAT).ok()?;
+    Some(time.and_utc())
+}
+
+// Writes `snapshot` as an encrypted Secret manifest to `path`.
+fn write_encrypted(
+    opts: &Opts,
+    path: &Path,
+    snapshot: &Snapshot,
+) -> Result<(), Error> {
+    if fips() {
+        return Err(Error::NotApproved("age encryption"));
+    }
+    let mut metadata = serde_json::json!({
+        "name": snapshot.name,
+        "namespace": snapshot.namespace,
+    });
+    if !snapshot.generated_at.is_empty() {
+        let generated_at = serde_json::to_string(snapshot.generated_at)?;
+        metadata["annotations"] =
+            serde_json::json!({app_id!("generated-at"): generated_at});
+    }
+    let mut secret = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": metadata,
+        "type": snapshot.type_.unwrap_or("Opaque"),
+        "data": snapshot.data,
+    });
+    let manifest = serde_yaml::to_string(&secret).map(Zeroizing::new);
+    wipe(&mut secret);
+    let manifest = manifest?;
+    let encrypted = encrypt(&recipients(opts)?, manifest.as_bytes())?;
+    // Written next to the file and renamed, so that a crash leaves the
+    // previous file intact.
+    std::fs::create_dir_all(path.parent().unwrap_or(path))?;
+    let partial = path.with_extension("age.tmp");
+    let mut file = std::fs::File::create(&partial)?;
+    file.write_all(&encrypted)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipient() {
+        let recipient: Recipient =
+            "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+                .parse()
+                .unwrap();
+        assert_eq!(recipient.0[0], 0x07);
+        // A typo breaks the checksum.
+        assert!(
+            "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8q"
+                .parse::<Recipient>()
+                .is_err()
+        );
+        assert!("age1qqqq".parse::<Recipient>().is_err());
+    }
+
+    #[test]
+    fn retention() {
+        let now = Utc::now();
+        let name = |days: i64| {
+            let time = now - TimeDelta::days(days);
+            format!("{}.yaml.age", time.format(BACKUP_TIME_FORMAT))
+        };
+        let names: Vec<String> = (0..5).map(name).collect();
+        let mut unrelated = names.clone();
+        unrelated.push("notes.txt".to_string());
+        assert_eq!(expired_backups(unrelated, 3, None, now), names[3..]);
+        let max_age = Some(Duration::from_secs(2 * 86400 + 60));
+        assert_eq!(
+            expired_backups(names.clone(), 10, max_age, now),
+            names[3..]
+        );
+        // The newest backup is kept however old it is.
+        let max_age = Some(Duration::from_secs(60));
+        let old = names[2..].to_vec();
+        assert_eq!(expired_backups(old.clone(), 10, max_age, now), old[1..]);
+    }
+
+    fn hex_key(hex: &str) -> [u8; 32] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn x25519_vector() {
+        // From RFC 7748, section 5.2.
+        let scalar = hex_key(
+            "a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4",
+        );
+        let u = hex_key(
+            "e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c",
+        );
+        let expected = hex_key(
+            "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552",
+        );
+        assert_eq!(*x25519(&scalar, &u), expected);
+    }
+
+    fn identity() -> Identity {
+        let mut key = Zeroizing::new([0; 32]);
+        fill_random(&mut *key);
+        Identity(key)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (alice, bob) = (identity(), identity());
+        let recipients = [alice.recipient(), bob.recipient()];
+        let file = encrypt(&recipients, b"kind: Secret\n").unwrap();
+        assert_eq!(&*decrypt(&[bob], &file).unwrap(), b"kind: Secret\n");
+        assert!(decrypt(&[identity()], &file).is_err());
+        // Larger plaintext spans several chunks.
+        let large = vec![7; CHUNK_SIZE * 2 + 1];
+        let file = encrypt(&[alice.recipient()], &large).unwrap();
+        assert_eq!(*decrypt(&[alice], &file).unwrap(), large);
+    }
+}
@@ -0,0 +1,553 @@
+// An in-memory Kubernetes API behind a `kube::Client`, to run reconciles in
+// tests without a cluster. Objects are kept by their path, and the requests
+// the operator makes are answered like the API server would: gets, lists,
+// creates, deletes, merge patches and server-side applies, writes failing
+// with a conflict when `metadata.resourceVersion` is not the current one.
+// Server-side apply records which data keys each field manager applied in
+// `managedFields`, the only part of it the operator reads, and takes keys
+// other managers own only when forced.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use kube::{client::Body, Client, Resource, ResourceExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+#[derive(Default)]
+struct State {
+    // Objects by their path, like `/api/v1/namespaces/dev/secrets/db`.
+    objects: BTreeMap<String, Value>,
+    version: u64,
+    // The requests answered so far, as `<method> <path>`.
+    requests: Vec<String>,
+    // Status codes to answer the next requests of a method with.
+    failures: Vec<(Method, StatusCode)>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct FakeApi {
+    state: Arc<Mutex<State>>,
+}
+
+type Answer = (StatusCode, Value);
+
+impl FakeApi {
+    // A client talking to this API. Needs a Tokio runtime.
+    pub(crate) fn client(&self) -> Client {
+        Client::new(self.clone(), "default")
+    }
+
+    // Stores `object` like creating it would, and returns what was stored.
+    pub(crate) fn insert<K>(&self, object: &K) -> K
+    where
+        K: Resource<DynamicType = ()> + Serialize + DeserializeOwned,
+    {
+        let collection = K::url_path(&(), object.namespace().as_deref());
+        let body = serde_json::to_vec(object).unwrap();
+        let mut state = self.state.lock().unwrap();
+        let (status, created) = state.create(&collection, &body, false);
+        assert_eq!(status, StatusCode::CREATED, "{}", created);
+        serde_json::from_value(created).unwrap()
+    }
+
+    pub(crate) fn get<K>(
+        &self,
+        namespace: Option<&str>,
+        name: &str,
+    ) -> Option<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let path = format!("{}/{}", K::url_path(&(), namespace), name);
+        let state = self.state.lock().unwrap();
+        let object = state.objects.get(&path)?;
+        Some(serde_json::from_value(object.clone()).unwrap())
+    }
+
+    // The objects stored under the collection of `K` in `namespace`.
+    pub(crate) fn list<K>(&self, namespace: Option<&str>) -> Vec<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let collection = K::url_path(&(), namespace);
+        let state = self.state.lock().unwrap();
+        state
+            .items(&collection)
+            .into_iter()
+            .map(|o| serde_json::from_value(o).unwrap())
+            .collect()
+    }
+
+    // The requests answered so far, as `<method> <path>`.
+    pub(crate) fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    // Answers the next request made with `method` with `status` instead.
+    pub(crate) fn fail(&self, method: Method, status: StatusCode) {
+        self.state.lock().unwrap().failures.push((method, status));
+    }
+}
+
+impl tower::Service<Request<Body>> for FakeApi {
+    type Response = Response<Full<Bytes>>;
+    type Error = http::Error;
+    type Future = Pin<
+        Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let state = self.state.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => return answer(bad_request(&e.to_string())),
+            };
+            let mut state = state.lock().unwrap();
+            answer(state.handle(&parts, &body))
+        })
+    }
+}
+
+fn answer(
+    (status, body): Answer,
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+}
+
+fn failure(status: StatusCode, reason: &str, message: &str) -> Answer {
+    let body = json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "status": "Failure",
+        "reason": reason,
+        "message": message,
+        "code": status.as_u16(),
+    });
+    (status, body)
+}
+
+fn bad_request(message: &str) -> Answer {
+    failure(StatusCode::BAD_REQUEST, "BadRequest", message)
+}
+
+fn not_found(path: &str) -> Answer {
+    failure(
+        StatusCode::NOT_FOUND,
+        "NotFound",
+        &format!("{} not found", path),
+    )
+}
+
+fn conflict(message: &str) -> Answer {
+    failure(StatusCode::CONFLICT, "Conflict", message)
+}
+
+// Whether `path` names a single object rather than a collection.
+fn is_object(path: &str) -> bool {
+    let segments: Vec<_> = path.trim_start_matches('/').split('/').collect();
+    let rest = match segments.first() {
+        Some(&"api") => &segments[2.min(segments.len())..],
+        _ => &segments[3.min(segments.len())..],
+    };
+    match rest {
+        ["namespaces", _, _] => false,
+        ["namespaces", _, _, _] => true,
+        [_] => false,
+        _ => true,
+    }
+}
+
+// Applies the JSON merge patch (RFC 7386) `patch` to `target`.
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = json!({});
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            value => merge(target.entry(key).or_insert(Value::Null), value),
+        }
+    }
+}
+
+// The data keys `object` sets.
+fn data_keys(object: &Value) -> BTreeSet<String> {
+    ["data", "stringData"]
+        .iter()
+        .filter_map(|field| object[field].as_object())
+        .flat_map(|data| data.keys().cloned())
+        .collect()
+}
+
+// The data keys `manager` owns in `object`.
+fn owned_keys(object: &Value, manager: &str) -> BTreeSet<String> {
+    let entries = object["metadata"]["managedFields"].as_array();
+    entries
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry["manager"] == manager)
+        .filter_map(|entry| entry["fieldsV1"]["f:data"].as_object())
+        .flat_map(|data| data.keys())
+        .filter_map(|key| key.strip_prefix("f:"))
+        .map(str::to_string)
+        .collect()
+}
+
+impl State {
+    fn handle(&mut self, parts: &http::request::Parts, body: &[u8]) -> Answer {
+        let path = parts.uri.path().to_string();
+        self.requests.push(format!("{} {}", parts.method, path));
+        let position =
+            self.failures.iter().position(|(m, _)| *m == parts.method);
+        if let Some(position) = position {
+            let (_, status) = self.failures.remove(position);
+            let reason = status.canonical_reason().unwrap_or_default();
+            return failure(
+                status,
+                &reason.replace(' ', ""),
+                "injected failure",
+            );
+        }
+        let query: BTreeMap<String, String> = parts
+            .uri
+            .query()
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let dry_run = query.contains_key("dryRun");
+        let content_type = parts
+            .headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        match (&parts.method, is_object(&path)) {
+            (&Method::GET, false) => {
+                let version = self.version.to_string();
+                let items = self.items(&path);
+                let list = json!({
+                    "apiVersion": "v1",
+                    "kind": "List",
+                    "metadata": {"resourceVersion": version},
+                    "items": items,
+                });
+                (StatusCode::OK, list)
+            }
+            (&Method::GET, true) => match self.objects.get(&path) {
+                Some(object) => (StatusCode::OK, object.clone()),
+                None => not_found(&path),
+            },
+            (&Method::POST, false) => self.create(&path, body, dry_run),
+            (&Method::PATCH, true) => {
+                let Ok(patch) = serde_json::from_slice::<Value>(body) else {
+                    return bad_request("patch is not JSON");
+                };
+                match content_type {
+                    "application/apply-patch+yaml" => {
+                        let manager = query.get("fieldManager").cloned();
+                        let force =
+                            query.get("force").is_some_and(|f| f == "true");
+                        self.apply(
+                            &path,
+                            &patch,
+                            &manager.unwrap_or_default(),
+                            force,
+                            dry_run,
+                        )
+                    }
+                    "application/merge-patch+json" => {
+                        self.merge_patch(&path, &patch, dry_run)
+                    }
+                    _ => failure(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "UnsupportedMediaType",
+                        content_type,
+                    ),
+                }
+            }
+            (&Method::PUT, true) => {
+                let Ok(object) = serde_json::from_slice::<Value>(body) else {
+                    return bad_request("object is not JSON");
+                };
+                let Some(current) = self.objects.get(&path) else {
+                    return not_found(&path);
+                };
+                if let Some(answer) = self.check_version(current, &object) {
+                    return answer;
+                }
+                self.store(path, object, dry_run)
+            }
+            (&Method::DELETE, true) => {
+                let Some(current) = self.objects.get(&path) else {
+                    return not_found(&path);
+                };
+                let params: Value =
+                    serde_json::from_slice(body).unwrap_or_default();
+                let preconditions = &params["preconditions"];
+                for (field, name) in
+                    [("resourceVersion", "version"), ("uid", "uid")]
+                {
+                    let expected = &preconditions[field];
+                    if !expected.is_null()
+                        && *expected != current["metadata"][field]
+                    {
+                        return conflict(&format!(
+                            "precondition failed: {} differs",
+                            name
+                        ));
+                    }
+                }
+                let current = current.clone();
+                if !dry_run {
+                    self.objects.remove(&path);
+                }
+                (StatusCode::OK, current)
+            }
+            _ => failure(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                &format!("{} {}", parts.method, path),
+            ),
+        }
+    }
+
+    fn items(&self, collection: &str) -> Vec<Value> {
+        let prefix = format!("{}/", collection);
+        self.objects
+            .iter()
+            .filter(|(path, _)| {
+                path.strip_prefix(&prefix)
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .map(|(_, object)| object.clone())
+            .collect()
+    }
+
+    fn create(
+        &mut self,
+        collection: &str,
+        body: &[u8],
+        dry_run: bool,
+    ) -> Answer {
+        let Ok(mut object) = serde_json::from_slice::<Value>(body) else {
+            return bad_request("object is not JSON");
+        };
+        let name = match (
+            object["metadata"]["name"].as_str(),
+            object["metadata"]["generateName"].as_str(),
+        ) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(prefix)) => format!("{}{}", prefix, self.version + 1),
+            (None, None) => return bad_request("object has no name"),
+        };
+        let path = format!("{}/{}", collection, name);
+        if self.objects.contains_key(&path) {
+            let message = format!("{} already exists", path);
+            return failure(StatusCode::CONFLICT, "AlreadyExists", &message);
+        }
+        let metadata = &mut object["metadata"];
+        metadata["name"] = name.into();
+        metadata["uid"] = format!("uid-{}", self.version + 1).into();
+        if let Some(namespace) = collection
+            .split('/')
+            .skip_while(|s| *s != "namespaces")
+            .nth(1)
+        {
+            metadata["namespace"] = namespace.into();
+        }
+        let (_, stored) = self.store(path, object, dry_run);
+        (StatusCode::CREATED, stored)
+    }
+
+    fn merge_patch(
+        &mut self,
+        path: &str,
+        patch: &Value,
+        dry_run: bool,
+    ) -> Answer {
+        let Some(current) = self.objects.get(path) else {
+            return not_found(path);
+        };
+        if let Some(answer) = self.check_version(current, patch) {
+            return answer;
+        }
+        let mut object = current.clone();
+        merge(&mut object, patch);
+        self.store(path.to_string(), object, dry_run)
+    }
+
+    fn apply(
+        &mut self,
+        path: &str,
+        patch: &Value,
+        manager: &str,
+        force: bool,
+        dry_run: bool,
+    ) -> Answer {
+        let mut object = match self.objects.get(path) {
+            Some(current) => {
+                if let Some(answer) = self.check_version(current, patch) {
+                    return answer;
+                }
+                current.clone()
+            }
+            None => json!({}),
+        };
+        let applied = data_keys(patch);
+        let entries = object["metadata"]["managedFields"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let others: Vec<(String, BTreeSet<String>)> = entries
+            .iter()
+            .filter_map(|e| e["manager"].as_str())
+            .filter(|m| *m != manager)
+            .map(|m| (m.to_string(), owned_keys(&object, m)))
+            .collect();
+        let conflicts: Vec<_> = others
+            .iter()
+            .flat_map(|(m, keys)| {
+                keys.intersection(&applied).map(move |k| (m, k))
+            })
+            .filter(|(_, key)| {
+                object["data"][key.as_str()] != patch["data"][key.as_str()]
+            })
+            .collect();
+        if !conflicts.is_empty() && !force {
+            let message = format!(
+                "Apply failed with {} conflict{}: {}",
+                conflicts.len(),
+                if conflicts.len() == 1 { "" } else { "s" },
+                conflicts
+                    .iter()
+                    .map(|(m, k)| format!("conflict with {:?}: .data.{}", m, k))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return conflict(&message);
+        }
+        // Keys the manager applied before and left out now are removed,
+        // unless someone else owns them as well.
+        let previous = owned_keys(&object, manager);
+        let shared: BTreeSet<_> =
+            others.iter().flat_map(|(_, keys)| keys.clone()).collect();
+        if let Some(data) = object["data"].as_object_mut() {
+            for key in previous.difference(&applied) {
+                if !shared.contains(key) {
+                    data.remove(key);
+                }
+            }
+        }
+        let mut patch = patch.clone();
+        if let Some(metadata) = patch["metadata"].as_object_mut() {
+            metadata.remove("resourceVersion");
+        }
+        merge(&mut object, &patch);
+
+        let fields: serde_json::Map<String, Value> = applied
+            .iter()
+            .map(|key| (format!("f:{}", key), json!({})))
+            .collect();
+        let mut managed: Vec<Value> = entries
+            .into_iter()
+            .filter(|e| e["manager"] != manager)
+            .map(|mut e| {
+                // Forcing takes the keys from the other managers.
+                if let Some(data) = e["fieldsV1"]["f:data"].as_object_mut() {
+                    for key in &applied {
+                        data.remove(&format!("f:{}", key));
+                    }
+                }
+                e
+            })
+            .collect();
+        managed.push(json!({
+            "manager": manager,
+            "operation": "Apply",
+            "fieldsType": "FieldsV1",
+            "fieldsV1": {"f:data": fields},
+        }));
+        object["metadata"]["managedFields"] = managed.into();
+        let created = !self.objects.contains_key(path);
+        let (_, stored) = self.store(path.to_string(), object, dry_run);
+        match created {
+            true => (StatusCode::CREATED, stored),
+            false => (StatusCode::OK, stored),
+        }
+    }
+
+    // A conflict if `request` names another version than that of `current`.
+    fn check_version(
+        &self,
+        current: &Value,
+        request: &Value,
+    ) -> Option<Answer> {
+        let expected = request["metadata"]["resourceVersion"].as_str()?;
+        let version = current["metadata"]["resourceVersion"].as_str();
+        (Some(expected) != version).then(|| {
+            conflict(
+                "Operation cannot be fulfilled: the object has been modified; \
+                 please apply your changes to the latest version and try again",
+            )
+        })
+    }
+
+    fn store(
+        &mut self,
+        path: String,
+        mut object: Value,
+        dry_run: bool,
+    ) -> Answer {
+        // The API server only keeps the encoded data of secrets.
+        if let Some(Value::Object(strings)) =
+            object.get_mut("stringData").map(Value::take)
+        {
+            for (key, value) in strings {
+                let value =
+                    BASE64_STANDARD.encode(value.as_str().unwrap_or_default());
+                object["data"][key] = value.into();
+            }
+            object.as_object_mut().unwrap().remove("stringData");
+        }
+        if dry_run {
+            return (StatusCode::OK, object);
+        }
+        self.version += 1;
+        object["metadata"]["resourceVersion"] = self.version.to_string().into();
+        if object["metadata"]["creationTimestamp"].is_null() {
+            object["metadata"]["creationTimestamp"] =
+                "2026-01-01T00:00:00Z".into();
+        }
+        self.objects.insert(path, object.clone());
+        (StatusCode::OK, object)
+    }
+}
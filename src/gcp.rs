@@ -0,0 +1,279 @@
+// Writes generated values to Google Secret Manager, for secrets annotated
+// with `gcp-secret`. The operator authenticates as the Google service
+// account bound to its Kubernetes service account through Workload Identity,
+// with tokens from the GKE metadata server.
+
+use crate::{
+    controller::wipe,
+    error::Error,
+    generate::SecretData,
+    store::{from_strings, string_values, SecretStore, StoreWrite},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_util::future::BoxFuture;
+use reqwest::{Method, StatusCode};
+use std::{collections::BTreeMap, sync::Mutex};
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+const GCP_TIMEOUT: Duration = Duration::from_secs(10);
+const TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/\
+                         instance/service-accounts/default/token";
+const SECRET_MANAGER_URL: &str = "https://secretmanager.googleapis.com/v1";
+
+pub(crate) struct SecretManager {
+    project: String,
+    client: reqwest::Client,
+    // The access token with the time it is renewed at.
+    token: Mutex<Option<(Zeroizing<String>, Instant)>>,
+}
+
+impl SecretManager {
+    pub(crate) fn new(project: &str) -> Result<SecretManager, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(GCP_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Gcp(e.to_string()))?;
+        Ok(SecretManager {
+            project: project.to_string(),
+            client,
+            token: Mutex::new(None),
+        })
+    }
+
+    // Adds `data` as a new version of `secret`, a secret id in the
+    // configured project or a `projects/<project>/secrets/<id>` name, and
+    // creates the secret with `labels` if it doesn't exist. Returns the name
+    // of the added version.
+    async fn add_version(
+        &self,
+        secret: &str,
+        labels: &BTreeMap<String, String>,
+        data: &SecretData,
+    ) -> Result<String, Error> {
+        let name = self.secret_name(secret);
+        let mut values = serde_json::Value::Object(string_values(data));
+        let payload = Zeroizing::new(values.to_string());
+        wipe(&mut values);
+        let mut body = serde_json::json!({
+            "payload": {"data": BASE64_STANDARD.encode(payload.as_bytes())},
+        });
+        let add_version = format!("{}:addVersion", name);
+        let mut added = self.post(&add_version, Some(&body)).await;
+        if let Ok(None) = added {
+            let (parent, id) = name.split_once("/secrets/").unwrap_or_default();
+            let create = serde_json::json!({
+                "replication": {"automatic": {}},
+                "labels": labels,
+            });
+            let created = self
+                .post(
+                    &format!("{}/secrets?secretId={}", parent, id),
+                    Some(&create),
+                )
+                .await;
+            added = match created {
+                Ok(_) => self.post(&add_version, Some(&body)).await,
+                Err(e) => Err(e),
+            };
+        }
+        wipe(&mut body);
+        let version = added?.ok_or_else(|| {
+            Error::Gcp(format!("{} is not a secret in a project", name))
+        })?;
+        Ok(version["name"].as_str().unwrap_or_default().to_string())
+    }
+
+    async fn get(&self, secret: &str) -> Result<SecretData, Error> {
+        let path =
+            format!("{}/versions/latest:access", self.secret_name(secret));
+        let Some(mut accessed) = self.request(Method::GET, &path, None).await?
+        else {
+            return Ok(SecretData::default());
+        };
+        let payload = accessed["payload"]["data"]
+            .as_str()
+            .and_then(|data| BASE64_STANDARD.decode(data).ok())
+            .map(Zeroizing::new);
+        wipe(&mut accessed);
+        let Some(payload) = payload else {
+            return Ok(SecretData::default());
+        };
+        let mut values: serde_json::Value = serde_json::from_slice(&payload)?;
+        let data = values.as_object().map(from_strings).unwrap_or_default();
+        wipe(&mut values);
+        Ok(data)
+    }
+
+    async fn latest_version(
+        &self,
+        secret: &str,
+    ) -> Result<Option<String>, Error> {
+        let path = format!("{}/versions/latest", self.secret_name(secret));
+        let latest = self.request(Method::GET, &path, None).await?;
+        Ok(latest.and_then(|v| Some(v["name"].as_str()?.to_string())))
+    }
+
+    // The full name of `secret`, which is a secret id in the configured
+    // project or a `projects/<project>/secrets/<id>` name.
+    fn secret_name(&self, secret: &str) -> String {
+        match secret.starts_with("projects/") {
+            true => secret.to_string(),
+            false => format!("projects/{}/secrets/{}", self.project, secret),
+        }
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>, Error> {
+        self.request(Method::POST, path, body).await
+    }
+
+    // Sends `body` to the API, returning nothing if `path` doesn't exist.
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>, Error> {
+        let token = self.token().await?;
+        let mut request = self
+            .client
+            .request(method, format!("{}/{}", SECRET_MANAGER_URL, path))
+            .bearer_auth(token.as_str());
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Error::Gcp(e.without_url().to_string()))?;
+        let status = response.status();
+        let response: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| Error::Gcp(e.without_url().to_string()))?;
+        match status {
+            _ if status.is_success() => Ok(Some(response)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(Error::Gcp(format!(
+                "{}: {}",
+                status,
+                response["error"]["message"].as_str().unwrap_or_default()
+            ))),
+        }
+    }
+
+    // The access token, fetched again once it is about to expire.
+    async fn token(&self) -> Result<Zeroizing<String>, Error> {
+        if let Some((token, renew)) = &*self.token.lock().unwrap() {
+            if Instant::now() < *renew {
+                return Ok(token.clone());
+            }
+        }
+        let mut response: serde_json::Value = self
+            .client
+            .get(TOKEN_URL)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| Error::Gcp(e.without_url().to_string()))?
+            .json()
+            .await
+            .map_err(|e| Error::Gcp(e.without_url().to_string()))?;
+        let token = response["access_token"]
+            .as_str()
+            .map(|token| Zeroizing::new(token.to_string()));
+        let expires_in = response["expires_in"].as_u64().unwrap_or(0);
+        wipe(&mut response);
+        let token = token.ok_or_else(|| {
+            Error::Gcp("metadata server answered without a token".to_string())
+        })?;
+        // Tokens are renewed after two thirds of their lifetime.
+        let renew = Instant::now() + Duration::from_secs(expires_in * 2 / 3);
+        *self.token.lock().unwrap() = Some((token.clone(), renew));
+        Ok(token)
+    }
+}
+
+impl SecretStore for SecretManager {
+    fn name(&self) -> &'static str {
+        "gcp"
+    }
+
+    fn annotation(&self) -> &'static str {
+        "gcp-secret"
+    }
+
+    fn write<'a>(
+        &'a self,
+        write: &'a StoreWrite<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move {
+            let labels = BTreeMap::from([
+                ("managed-by".to_string(), "auto-secret-operator".to_string()),
+                ("namespace".to_string(), label_value(write.namespace)),
+                ("name".to_string(), label_value(write.name)),
+            ]);
+            let version = self
+                .add_version(write.location, &labels, write.managed)
+                .await?;
+            Ok(Some(version))
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<SecretData, Error>> {
+        Box::pin(self.get(location))
+    }
+
+    // Deletes the secret with all its versions.
+    fn delete<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let name = self.secret_name(location);
+            self.request(Method::DELETE, &name, None).await?;
+            Ok(())
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(self.latest_version(location))
+    }
+}
+
+// `value` as the value of a label, which may only hold lowercase letters,
+// digits, `_` and `-`, up to 63 of them.
+fn label_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .take(63)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert_eq!(label_value("team-a"), "team-a");
+        assert_eq!(label_value("db.Credentials"), "db_credentials");
+        assert_eq!(label_value(&"a".repeat(100)).len(), 63);
+    }
+}
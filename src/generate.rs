@@ -0,0 +1,705 @@
+//! Generation of secret data from parsed settings, in dependency order.
+
+use crate::{
+    crypto::{fips, sha256, Random},
+    error::Error,
+    opts::Opts,
+    settings::{
+        parse_annotation, KeyCase, KeyErrors, Options, Settings, TransformKind,
+    },
+    template::Scope,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use hkdf::Hkdf;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::ObjectMeta, ByteString};
+use md5::Md5;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+impl TransformKind {
+    fn apply(&self, value: &ByteString) -> Result<ByteString, Error> {
+        let transformed = match self {
+            TransformKind::Sha256 => hex(&sha256(&value.0)),
+            TransformKind::Md5 if fips() => {
+                return Err(Error::NotApproved("the md5 transform"))
+            }
+            TransformKind::Md5 => hex(&Md5::digest(&value.0)),
+            TransformKind::Base64 => BASE64_STANDARD.encode(&value.0),
+            TransformKind::Urlencoded => url_query_escape(&value.0),
+        };
+        Ok(ByteString(transformed.into_bytes()))
+    }
+}
+
+// Aliases and transforms follow their source, even if one of them was
+// changed by hand.
+fn mirror(
+    key: &str,
+    source: &str,
+    old_data: &SecretData,
+    data: &SecretData,
+    f: impl FnOnce(&ByteString) -> Result<ByteString, Error>,
+) -> Result<SecretData, Error> {
+    let value = f(data
+        .get(source)
+        .or_else(|| old_data.get(source))
+        .ok_or_else(|| Error::MissingKey(source.to_string()))?)?;
+    if old_data.get(key) == Some(&value) {
+        return Ok(SecretData::default());
+    }
+    Ok([(key.to_string(), value)].into_iter().collect())
+}
+
+// Plaintext secret data. The values are overwritten when the map is dropped,
+// so credentials don't linger in freed heap memory, and are left out of its
+// Debug output, which only shows the keys and the lengths of the values.
+#[derive(Default, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct SecretData(pub(crate) BTreeMap<String, ByteString>);
+
+impl Deref for SecretData {
+    type Target = BTreeMap<String, ByteString>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretData {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for SecretData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(k, v)| (k, Redacted(v))))
+            .finish()
+    }
+}
+
+// Stands in for a value in logs.
+struct Redacted<'a>(pub(crate) &'a ByteString);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted, {} bytes>", self.0 .0.len())
+    }
+}
+
+impl Drop for SecretData {
+    fn drop(&mut self) {
+        for value in self.0.values_mut() {
+            value.0.zeroize();
+        }
+    }
+}
+
+impl FromIterator<(String, ByteString)> for SecretData {
+    fn from_iter<I: IntoIterator<Item = (String, ByteString)>>(
+        iter: I,
+    ) -> Self {
+        SecretData(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for SecretData {
+    type Item = (String, ByteString);
+    type IntoIter = std::collections::btree_map::IntoIter<String, ByteString>;
+
+    // The values move on, so wiping them is up to the new owner.
+    fn into_iter(mut self) -> Self::IntoIter {
+        std::mem::take(&mut self.0).into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a SecretData {
+    type Item = (&'a String, &'a ByteString);
+    type IntoIter = std::collections::btree_map::Iter<'a, String, ByteString>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+// A derived key needs to be (re)rendered when it is missing or when one of
+// the keys it is derived from was generated in this run.
+pub(crate) fn stale(
+    key: &str,
+    deps: &[String],
+    old_data: &SecretData,
+    data: &SecretData,
+) -> bool {
+    !old_data.contains_key(key) || deps.iter().any(|d| data.contains_key(d))
+}
+
+pub(crate) fn existing_or_gen(
+    old_data: &SecretData,
+    key: &str,
+    gen: impl FnOnce() -> Result<ByteString, Error>,
+) -> Result<(ByteString, bool), Error> {
+    match old_data.get(key) {
+        Some(value) => Ok((value.clone(), false)),
+        None => Ok((gen()?, true)),
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn url_query_escape(value: &[u8]) -> String {
+    value
+        .iter()
+        .map(|&b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'~' => (b as char).to_string(),
+            b' ' => "+".to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn gen_credential(
+    opts: &Opts,
+    settings: &Settings,
+) -> Result<ByteString, Error> {
+    gen_credential_with(opts, settings, &mut Random)
+}
+
+fn gen_credential_with(
+    opts: &Opts,
+    settings: &Settings,
+    rng: &mut impl RngCore,
+) -> Result<ByteString, Error> {
+    match settings {
+        Settings::Kind(kind) => return kind.gen_credential(opts),
+        Settings::Alias(_) => {
+            return Err(Error::NotNestable("alias_of".to_string()))
+        }
+        Settings::Transform(_) => {
+            return Err(Error::NotNestable("transform_of".to_string()))
+        }
+        _ => {}
+    }
+
+    let generator = settings.generator(opts)?;
+
+    let Settings::Options(Options {
+        count: Some(count),
+        separator,
+        ..
+    }) = settings
+    else {
+        return Ok(ByteString(generator.generate_with(rng).into_bytes()));
+    };
+    let values: Zeroizing<Vec<String>> = Zeroizing::new(
+        (0..*count).map(|_| generator.generate_with(rng)).collect(),
+    );
+    Ok(ByteString(
+        values
+            .join(separator.as_deref().unwrap_or(","))
+            .into_bytes(),
+    ))
+}
+
+fn gen_keys(
+    opts: &Opts,
+    key: &str,
+    settings: &Settings,
+    scope: &Scope,
+    old_data: &SecretData,
+    data: &SecretData,
+) -> Result<SecretData, Error> {
+    match settings {
+        Settings::Kind(kind) => {
+            return kind.generator.generate(opts, key, scope, old_data, data)
+        }
+        Settings::Alias(alias) => {
+            return mirror(key, &alias.alias_of, old_data, data, |v| {
+                Ok(v.clone())
+            })
+        }
+        Settings::Transform(t) => {
+            return mirror(key, &t.transform_of, old_data, data, |v| {
+                t.transform.apply(v)
+            })
+        }
+        _ => {}
+    }
+
+    let mut data = SecretData::default();
+    let (value, fresh) = existing_or_gen(old_data, key, || {
+        if settings.is_derived() {
+            derive_credential(opts, key, settings, scope)
+        } else {
+            gen_credential(opts, settings)
+        }
+    })?;
+    if let Settings::Options(o) = settings {
+        o.derive(key, &value, fresh, old_data, &mut data);
+    }
+    if fresh {
+        data.insert(key.to_string(), value);
+    }
+    Ok(data)
+}
+
+// Derives a key from the master seed with HKDF-SHA256, using
+// `<namespace>/<name>/<key>` as info. The output seeds a ChaCha20 stream the
+// value is drawn from, so the same seed always yields the same value.
+fn derive_credential(
+    opts: &Opts,
+    key: &str,
+    settings: &Settings,
+    scope: &Scope,
+) -> Result<ByteString, Error> {
+    if fips() {
+        return Err(Error::NotApproved("derive"));
+    }
+    let seed = scope
+        .seed
+        .ok_or_else(|| Error::Derive("no master seed available".to_string()))?;
+    let info = format!(
+        "{}/{}/{}",
+        scope.namespace(),
+        scope.meta.name.as_deref().unwrap_or_default(),
+        key
+    );
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, seed)
+        .expand(info.as_bytes(), okm.as_mut())
+        .expect("32 bytes are a valid HKDF-SHA256 output length");
+    gen_credential_with(opts, settings, &mut ChaCha20Rng::from_seed(*okm))
+}
+
+// Keys without dependencies come first, so that references to companion
+// keys (e.g. a kafka-scram jaas.conf) resolve. The remaining keys are
+// ordered so that every key comes after the declared keys it references.
+pub fn dependency_order(
+    settings: &BTreeMap<String, Settings>,
+) -> Result<Vec<&str>, Error> {
+    let mut pending: BTreeMap<&str, Vec<String>> = settings
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.dependencies()))
+        .collect();
+    let mut order = vec![];
+    while !pending.is_empty() {
+        let ready: Vec<&str> = pending
+            .iter()
+            .filter(|(_, deps)| {
+                deps.iter().all(|d| !pending.contains_key(d.as_str()))
+            })
+            .map(|(k, _)| *k)
+            .collect();
+        if ready.is_empty() {
+            let keys: Vec<&str> = pending.keys().copied().collect();
+            return Err(Error::Cycle(keys.join(", ")));
+        }
+        for key in ready {
+            pending.remove(key);
+            order.push(key);
+        }
+    }
+    order.sort_by_key(|k| !settings[*k].dependencies().is_empty());
+    Ok(order)
+}
+
+// Removes the empty values of declared keys from `data`, so that they are
+// generated like missing ones, and returns their names. Charts create such
+// keys for values to be filled in later. Only keys declared with
+// `overwrite_empty` qualify, or all keys with the overwrite-empty annotation.
+pub(crate) fn drop_empty(
+    meta: &ObjectMeta,
+    settings: &BTreeMap<String, Settings>,
+    data: &mut SecretData,
+) -> Result<Vec<String>, Error> {
+    let all = parse_annotation::<bool>(meta, app_id!("overwrite-empty"))?
+        .unwrap_or(false);
+    let case = parse_annotation::<KeyCase>(meta, app_id!("key-case"))?;
+    let overwritten: BTreeSet<String> = settings
+        .iter()
+        .filter(|(_, s)| all || s.overwrites_empty())
+        .flat_map(|(key, _)| [Some(key.clone()), case.map(|c| c.apply(key))])
+        .flatten()
+        .collect();
+    let empty: Vec<String> = data
+        .iter()
+        .filter(|(key, value)| value.0.is_empty() && overwritten.contains(*key))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in &empty {
+        data.remove(key);
+    }
+    Ok(empty)
+}
+
+// Runs `f` off the async workers. Generation is synchronous and `exec` and
+// `http` keys wait on commands and requests, which would keep the worker
+// from running other reconciles meanwhile. A current-thread runtime has no
+// other worker to hand them to, so `f` runs in place there.
+pub(crate) fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+// Generation always works on the declared key names. If the secret asks for
+// a different key case, existing values are looked up under their cased
+// names and generated values are written back with them (or with both
+// spellings).
+//
+// Keys listed in `expired` are generated again even if they exist. Keys
+// already in `failed` are skipped, and keys that fail are added to it.
+pub fn gen_data(
+    opts: &Opts,
+    settings: &BTreeMap<String, Settings>,
+    scope: &Scope,
+    old_data: &SecretData,
+    expired: &BTreeSet<String>,
+    failed: &mut KeyErrors,
+) -> Result<SecretData, Error> {
+    let Some(case) =
+        parse_annotation::<KeyCase>(scope.meta, app_id!("key-case"))?
+    else {
+        let mut view = old_data.clone();
+        view.retain(|key, _| !expired.contains(key));
+        return gen_declared(opts, settings, scope, &view, failed);
+    };
+    let both = match scope.annotation(app_id!("key-case-mode")) {
+        None | Some("replace") => false,
+        Some("both") => true,
+        Some(mode) => {
+            return Err(Error::InvalidAnnotation(
+                app_id!("key-case-mode").to_string(),
+                format!("unknown mode {:?}", mode),
+            ))
+        }
+    };
+    let cased: BTreeMap<&str, String> = settings
+        .keys()
+        .map(|k| (k.as_str(), case.apply(k)))
+        .filter(|(k, cased)| k != cased)
+        .collect();
+
+    let mut view = old_data.clone();
+    for (key, cased) in &cased {
+        if let Some(value) = old_data.get(cased) {
+            view.entry(key.to_string()).or_insert_with(|| value.clone());
+        }
+    }
+    view.retain(|key, _| !expired.contains(key));
+    let mut data = gen_declared(opts, settings, scope, &view, failed)?;
+    for (key, cased) in &cased {
+        let value = match data.get(*key) {
+            Some(value) => value.clone(),
+            None if !old_data.contains_key(cased) => match view.get(*key) {
+                Some(value) => value.clone(),
+                None => continue,
+            },
+            None => continue,
+        };
+        if !both {
+            data.remove(*key);
+        }
+        data.insert(cased.clone(), value);
+    }
+    Ok(data)
+}
+
+// Generates `keys` for a new, empty secret outside of any cluster: templates
+// see no other secrets and there is no master seed. The first key that
+// fails fails the whole map.
+pub(crate) fn gen_standalone(
+    opts: &Opts,
+    keys: &BTreeMap<String, Settings>,
+) -> Result<SecretData, Error> {
+    let meta = ObjectMeta::default();
+    let scope = Scope {
+        meta: &meta,
+        secrets: &BTreeMap::new(),
+        seed: None,
+    };
+    let mut failed = KeyErrors::new();
+    let data = gen_data(
+        opts,
+        keys,
+        &scope,
+        &SecretData::default(),
+        &BTreeSet::new(),
+        &mut failed,
+    )?;
+    match failed.pop_first() {
+        Some((key, e)) => Err(Error::Key(key, Box::new(e))),
+        None => Ok(data),
+    }
+}
+
+const UNIQUE_ATTEMPTS: usize = 16;
+
+fn gen_declared(
+    opts: &Opts,
+    settings: &BTreeMap<String, Settings>,
+    scope: &Scope,
+    old_data: &SecretData,
+    failed: &mut KeyErrors,
+) -> Result<SecretData, Error> {
+    let unique = scope.annotation(app_id!("unique")) == Some("true");
+    let mut data = SecretData::default();
+    for key in dependency_order(settings)? {
+        if failed.contains_key(key) {
+            continue;
+        }
+        match gen_unique(opts, key, settings, unique, scope, old_data, &data) {
+            Ok(generated) => data.extend(generated),
+            Err(e) => {
+                failed.insert(key.to_string(), e);
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn gen_unique(
+    opts: &Opts,
+    key: &str,
+    settings: &BTreeMap<String, Settings>,
+    unique: bool,
+    scope: &Scope,
+    old_data: &SecretData,
+    data: &SecretData,
+) -> Result<SecretData, Error> {
+    let mut generated =
+        gen_keys(opts, key, &settings[key], scope, old_data, data)?;
+    if unique && settings[key].is_random() {
+        let mut attempts = 0;
+        while generated.get(key).is_some_and(|value| {
+            settings.iter().any(|(k, s)| {
+                k != key
+                    && s.is_random()
+                    && data.get(k).or_else(|| old_data.get(k)) == Some(value)
+            })
+        }) {
+            attempts += 1;
+            if attempts == UNIQUE_ATTEMPTS {
+                return Err(Error::NotUnique(key.to_string()));
+            }
+            generated =
+                gen_keys(opts, key, &settings[key], scope, old_data, data)?;
+        }
+    }
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::parse_value;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn parse(yaml: &str) -> BTreeMap<String, Settings> {
+        parse_value("gen", yaml).unwrap()
+    }
+
+    fn data(entries: &[(&str, &str)]) -> SecretData {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.as_bytes().to_vec())))
+            .collect()
+    }
+
+    fn generate(
+        settings: &BTreeMap<String, Settings>,
+        annotations: &[(&str, &str)],
+        old_data: &SecretData,
+        expired: &[&str],
+    ) -> (SecretData, KeyErrors) {
+        let opts = Opts::default();
+        let meta = ObjectMeta {
+            annotations: Some(
+                annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let scope = Scope {
+            meta: &meta,
+            secrets: &BTreeMap::new(),
+            seed: None,
+        };
+        let expired = expired.iter().map(|k| k.to_string()).collect();
+        let mut failed = KeyErrors::new();
+        let data =
+            gen_data(&opts, settings, &scope, old_data, &expired, &mut failed)
+                .unwrap();
+        (data, failed)
+    }
+
+    #[test]
+    fn order() {
+        let settings = parse(
+            "{url: {kind: template, template: '{{ key \"hash\" }}'}, \
+             hash: {transform_of: password, transform: sha256}, \
+             password: digit, user: letter}",
+        );
+        assert_eq!(
+            dependency_order(&settings).unwrap(),
+            ["password", "user", "hash", "url"]
+        );
+        let cycle = parse("{a: {alias_of: b}, b: {alias_of: a}}");
+        assert!(matches!(dependency_order(&cycle), Err(Error::Cycle(_))));
+    }
+
+    #[test]
+    fn missing_keys_only() {
+        let settings =
+            parse("{password: {digit: true, length: 8}, pin: digit}");
+        let old = data(&[("pin", "1234")]);
+        let (generated, failed) = generate(&settings, &[], &old, &[]);
+        assert!(failed.is_empty());
+        assert_eq!(generated.keys().collect::<Vec<_>>(), ["password"]);
+        let password = &generated["password"].0;
+        assert_eq!(password.len(), 8);
+        assert!(password.iter().all(u8::is_ascii_digit));
+    }
+
+    #[test]
+    fn expired_keys() {
+        let settings =
+            parse("{pin: digit, hash: {transform_of: pin, transform: base64}}");
+        let old = data(&[("pin", "1234"), ("hash", "MTIzNA==")]);
+        let (generated, _) = generate(&settings, &[], &old, &[]);
+        assert!(generated.is_empty());
+        let (generated, _) = generate(&settings, &[], &old, &["pin"]);
+        assert_ne!(generated["pin"].0, b"1234");
+        assert_eq!(
+            generated["hash"].0,
+            BASE64_STANDARD.encode(&generated["pin"].0).into_bytes()
+        );
+    }
+
+    #[test]
+    fn empty_values() {
+        let settings = parse(
+            "{password: {letter: true, overwrite_empty: true}, pin: digit}",
+        );
+        let meta = |annotations: &[(&str, &str)]| ObjectMeta {
+            annotations: Some(
+                annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let mut old = data(&[("password", ""), ("pin", ""), ("other", "")]);
+        let dropped = drop_empty(&meta(&[]), &settings, &mut old).unwrap();
+        assert_eq!(dropped, ["password"]);
+        let (generated, _) = generate(&settings, &[], &old, &[]);
+        assert_eq!(generated.keys().collect::<Vec<_>>(), ["password"]);
+
+        let all = [(app_id!("overwrite-empty"), "true")];
+        let dropped = drop_empty(&meta(&all), &settings, &mut old).unwrap();
+        assert_eq!(dropped, ["pin"]);
+        assert_eq!(old.keys().collect::<Vec<_>>(), ["other"]);
+
+        let cased = [
+            (app_id!("overwrite-empty"), "true"),
+            (app_id!("key-case"), "upper-snake"),
+        ];
+        let mut old = data(&[("PIN", ""), ("password", "kept")]);
+        let dropped = drop_empty(&meta(&cased), &settings, &mut old).unwrap();
+        assert_eq!(dropped, ["PIN"]);
+        assert_eq!(old.keys().collect::<Vec<_>>(), ["password"]);
+    }
+
+    #[test]
+    fn dual_slots() {
+        let settings = parse("{token: {letter: true, dual: true}}");
+        let (first, _) = generate(&settings, &[], &data(&[]), &[]);
+        assert_eq!(first["token-a"], first["token"]);
+        assert_eq!(first["token-active"].0, b"token-a");
+        assert!(!first.contains_key("token-b"));
+
+        let (second, _) = generate(&settings, &[], &first, &["token"]);
+        assert_eq!(second["token-b"], second["token"]);
+        assert_eq!(second["token-active"].0, b"token-b");
+        assert!(!second.contains_key("token-a"));
+
+        let mut old = first.clone();
+        old.extend(second.clone());
+        let (third, _) = generate(&settings, &[], &old, &["token"]);
+        assert_eq!(third["token-a"], third["token"]);
+        assert_ne!(third["token-a"], first["token-a"]);
+        let (unchanged, _) = generate(&settings, &[], &old, &[]);
+        assert!(unchanged.is_empty());
+    }
+
+    #[test]
+    fn failures_stay_with_their_key() {
+        let settings = parse("{pin: digit, copy: {alias_of: missing}}");
+        let (generated, failed) = generate(&settings, &[], &data(&[]), &[]);
+        assert!(generated.contains_key("pin"));
+        assert!(matches!(failed.get("copy"), Some(Error::MissingKey(_))));
+    }
+
+    #[test]
+    fn key_case() {
+        let settings = parse("{db-password: digit}");
+        let case = [("auto-secret.k8s.eboland.de/key-case", "upper-snake")];
+        let (generated, _) = generate(&settings, &case, &data(&[]), &[]);
+        assert_eq!(generated.keys().collect::<Vec<_>>(), ["DB_PASSWORD"]);
+        let old = data(&[("DB_PASSWORD", "1234")]);
+        let (generated, _) = generate(&settings, &case, &old, &[]);
+        assert!(generated.is_empty());
+    }
+
+    #[test]
+    fn transforms() {
+        let value = ByteString(b"a b".to_vec());
+        let apply =
+            |t: TransformKind| String::from_utf8(t.apply(&value).unwrap().0);
+        assert_eq!(apply(TransformKind::Base64).unwrap(), "YSBi");
+        assert_eq!(apply(TransformKind::Urlencoded).unwrap(), "a+b");
+        assert_eq!(apply(TransformKind::Md5).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn redacted_debug() {
+        let secret = data(&[("password", "hunter2")]);
+        let debug = format!("{:?}", secret);
+        assert!(debug.contains("password"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_leaves_the_worker() {
+        // Waiting on the runtime from within a worker would panic.
+        let handle = tokio::runtime::Handle::current();
+        let value = blocking(|| handle.block_on(async { 1 }));
+        assert_eq!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn blocking_in_place() {
+        assert_eq!(blocking(|| 1), 1);
+    }
+}
@@ -0,0 +1,152 @@
+// Commits the encrypted snapshots in `--export-dir` to a git repository and
+// pushes them, so that a rebuilt cluster can restore identical credentials.
+// The export directory is the work tree of the repository. Every push moves
+// its HEAD to the tip of the remote branch first and only stages the written
+// snapshot, so commits of others to the branch are kept. The git and ssh
+// binaries aren't part of the image and have to be mounted.
+
+use crate::{error::Error, opts::Opts};
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    process::{Command, Stdio},
+    sync::{LazyLock, Mutex},
+};
+
+const COMMITTER: &str = "auto-secret-operator";
+const COMMITTER_EMAIL: &str = "auto-secret-operator@localhost";
+
+// Git commands of concurrent reconciles share the work tree.
+static WORK_TREE: LazyLock<Mutex<()>> = LazyLock::new(Mutex::default);
+
+// Snapshots whose push failed, pushed again with the next reconcile even if
+// they didn't change.
+static PENDING: LazyLock<Mutex<BTreeSet<String>>> =
+    LazyLock::new(Mutex::default);
+
+pub(crate) fn pending(path: &str) -> bool {
+    PENDING.lock().unwrap().contains(path)
+}
+
+// Commits the snapshot at `path`, relative to `--export-dir`, and pushes it
+// to `--export-git-branch` of `--export-git-repo`.
+pub(crate) async fn push(
+    opts: &Opts,
+    path: &str,
+    message: String,
+) -> Result<(), Error> {
+    PENDING.lock().unwrap().insert(path.to_string());
+    let opts = opts.clone();
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let _work_tree = WORK_TREE.lock().unwrap();
+        commit_and_push(&opts, &path, &message)?;
+        PENDING.lock().unwrap().remove(&path);
+        Ok(())
+    })
+    .await
+    .map_err(|e| Error::Git(e.to_string()))?
+}
+
+fn commit_and_push(
+    opts: &Opts,
+    path: &str,
+    message: &str,
+) -> Result<(), Error> {
+    let (Some(dir), Some(repo)) = (&opts.export_dir, &opts.export_git_repo)
+    else {
+        return Ok(());
+    };
+    let branch = &opts.export_git_branch;
+    if !Path::new(dir).join(".git").exists() {
+        git(opts, dir, &["init", "--quiet", "--initial-branch", branch])?;
+        git(opts, dir, &["remote", "add", "origin", repo])?;
+    }
+    // The branch doesn't exist before the first push.
+    let fetched = git(opts, dir, &["fetch", "--quiet", "origin", branch]);
+    if fetched.is_ok() {
+        git(opts, dir, &["reset", "--quiet", "--mixed", "FETCH_HEAD"])?;
+    }
+    git(opts, dir, &["add", "--", path])?;
+    // Nothing to commit when the snapshot is the pushed one.
+    if git(opts, dir, &["diff", "--cached", "--quiet"]).is_ok() {
+        return Ok(());
+    }
+    git(opts, dir, &["commit", "--quiet", "--message", message])?;
+    let refspec = format!("HEAD:refs/heads/{}", branch);
+    git(opts, dir, &["push", "--quiet", "origin", &refspec])
+}
+
+fn git(opts: &Opts, dir: &str, args: &[&str]) -> Result<(), Error> {
+    let output = Command::new(&opts.git)
+        .args([
+            "-c",
+            &format!("user.name={}", COMMITTER),
+            "-c",
+            &format!("user.email={}", COMMITTER_EMAIL),
+        ])
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_SSH_COMMAND", ssh_command(opts, dir))
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::Git(format!("{}: {}", opts.git, e)))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(Error::Git(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+// The ssh command git connects with, using the deploy key. Without a known
+// hosts file, the host key is trusted on first use and kept in the
+// repository.
+fn ssh_command(opts: &Opts, dir: &str) -> String {
+    let mut command = "ssh -o BatchMode=yes -o ConnectTimeout=10".to_string();
+    if let Some(key) = &opts.export_git_key {
+        command.push_str(&format!(" -o IdentitiesOnly=yes -i '{}'", key));
+    }
+    match &opts.export_git_known_hosts {
+        Some(known_hosts) => command.push_str(&format!(
+            " -o StrictHostKeyChecking=yes -o UserKnownHostsFile='{}'",
+            known_hosts
+        )),
+        None => command.push_str(&format!(
+            " -o StrictHostKeyChecking=accept-new \
+             -o UserKnownHostsFile='{}/.git/known_hosts'",
+            dir
+        )),
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn ssh() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--export-git-key",
+            "/keys/deploy",
+            "--export-git-known-hosts",
+            "/keys/known_hosts",
+        ]);
+        assert_eq!(
+            ssh_command(&opts, "/export"),
+            "ssh -o BatchMode=yes -o ConnectTimeout=10 -o IdentitiesOnly=yes \
+             -i '/keys/deploy' -o StrictHostKeyChecking=yes \
+             -o UserKnownHostsFile='/keys/known_hosts'"
+        );
+        let opts = Opts::parse_from(["auto-secret"]);
+        assert!(ssh_command(&opts, "/export").ends_with(
+            "accept-new -o UserKnownHostsFile='/export/.git/known_hosts'"
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+// `/healthz` and `/readyz`, served with `--health-addr` for the probes of the
+// Deployment. The operator is ready once every running watcher has listed its
+// objects and is connected, and the secrets of the first list were reconciled
+// or `--warm-up-timeout` passed. It is live as long as no reconcile hangs.
+// Standby replicas don't run the controllers' watchers, so they are ready
+// while they wait for the lease.
+
+use crate::error::Error;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use kube::runtime::watcher;
+use log::{debug, info, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+};
+use tokio::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+// Reconciles running longer than this are considered stuck.
+const STUCK_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchState {
+    Listing,
+    Synced,
+    Disconnected,
+}
+
+// The watchers by name, once they started.
+static WATCHERS: LazyLock<Mutex<BTreeMap<String, Option<WatchState>>>> =
+    LazyLock::new(Mutex::default);
+
+// The start of the reconciles in flight, by an id of each.
+static RECONCILES: LazyLock<Mutex<HashMap<u64, Instant>>> =
+    LazyLock::new(Mutex::default);
+static NEXT_RECONCILE: AtomicU64 = AtomicU64::new(0);
+
+// How often the progress of the warm-up is logged.
+const WARM_UP_PROGRESS: Duration = Duration::from_secs(10);
+
+// The first reconciles of the secrets listed at startup.
+#[derive(Default)]
+struct WarmUp {
+    // Until when readiness waits for them, once the warm-up started.
+    deadline: Option<Instant>,
+    ended: bool,
+    // Watchers that haven't finished their first list.
+    listing: usize,
+    listed: usize,
+    pending: BTreeSet<String>,
+}
+
+impl WarmUp {
+    fn warming_up(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now < deadline)
+            && (self.listing > 0 || !self.pending.is_empty())
+    }
+
+    fn progress(&self) -> String {
+        format!(
+            "reconciled {} of {} secrets",
+            self.listed - self.pending.len(),
+            self.listed
+        )
+    }
+}
+
+static WARM_UP: LazyLock<Mutex<WarmUp>> = LazyLock::new(Mutex::default);
+
+// A watcher whose state is reported by `/readyz`.
+pub(crate) struct Watch {
+    name: String,
+}
+
+impl Watch {
+    // Registers a watcher of `name`, numbered if there are several, e.g. of
+    // several clusters. It counts once it started.
+    pub(crate) fn new(name: &str) -> Watch {
+        let mut watchers = WATCHERS.lock().unwrap();
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while watchers.contains_key(&unique) {
+            n += 1;
+            unique = format!("{}-{}", name, n);
+        }
+        watchers.insert(unique.clone(), None);
+        Watch { name: unique }
+    }
+
+    pub(crate) fn observe<K>(
+        &self,
+        event: &Result<watcher::Event<K>, watcher::Error>,
+    ) {
+        let state = match event {
+            Ok(watcher::Event::Init) => WatchState::Listing,
+            Ok(watcher::Event::InitDone) => WatchState::Synced,
+            Ok(_) => return,
+            Err(_) => WatchState::Disconnected,
+        };
+        WATCHERS
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), Some(state));
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        WATCHERS.lock().unwrap().remove(&self.name);
+    }
+}
+
+// The first list of a secrets watcher, whose secrets are reconciled before
+// the operator turns ready.
+pub(crate) struct WarmUpList {
+    listing: bool,
+}
+
+impl WarmUpList {
+    // Starts the warm-up with the first watcher, unless it is over already
+    // or `timeout` is zero.
+    pub(crate) fn new(timeout: Duration) -> WarmUpList {
+        let mut warm_up = WARM_UP.lock().unwrap();
+        if warm_up.ended || timeout.is_zero() {
+            return WarmUpList { listing: false };
+        }
+        if warm_up.deadline.is_none() {
+            warm_up.deadline = Some(Instant::now() + timeout);
+            tokio::spawn(log_warm_up());
+        }
+        warm_up.listing += 1;
+        WarmUpList { listing: true }
+    }
+
+    // Records the secrets of the first list that `name` returns a name
+    // for, until it is done.
+    pub(crate) fn observe<K>(
+        &mut self,
+        event: &Result<watcher::Event<K>, watcher::Error>,
+        name: impl FnOnce(&K) -> Option<String>,
+    ) {
+        if !self.listing {
+            return;
+        }
+        let mut warm_up = WARM_UP.lock().unwrap();
+        match event {
+            Ok(watcher::Event::InitApply(object)) => {
+                if let Some(name) = name(object) {
+                    warm_up.listed += 1;
+                    warm_up.pending.insert(name);
+                }
+            }
+            Ok(watcher::Event::InitDone) => {
+                warm_up.listing -= 1;
+                self.listing = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for WarmUpList {
+    fn drop(&mut self) {
+        if self.listing {
+            WARM_UP.lock().unwrap().listing -= 1;
+        }
+    }
+}
+
+// Counts the first reconcile of a secret towards the warm-up, whether it
+// succeeded or not.
+pub(crate) fn warmed_up(name: &str) {
+    WARM_UP.lock().unwrap().pending.remove(name);
+}
+
+// Logs the progress of the warm-up until it is over.
+async fn log_warm_up() {
+    let started = Instant::now();
+    let mut logged = started;
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticks.tick().await;
+        let now = Instant::now();
+        let mut warm_up = WARM_UP.lock().unwrap();
+        if warm_up.warming_up(now) {
+            if now - logged >= WARM_UP_PROGRESS {
+                info!("warming up, {}", warm_up.progress());
+                logged = now;
+            }
+            continue;
+        }
+        warm_up.ended = true;
+        match warm_up.listing > 0 || !warm_up.pending.is_empty() {
+            true => warn!(
+                "warm-up timed out after {:?}, {}",
+                now - started,
+                warm_up.progress()
+            ),
+            false => info!(
+                "warmed up in {:?}, {}",
+                now - started,
+                warm_up.progress()
+            ),
+        }
+        return;
+    }
+}
+
+// Tracks a reconcile for `/healthz` until it is dropped.
+pub(crate) struct Reconciling(u64);
+
+impl Reconciling {
+    pub(crate) fn start() -> Reconciling {
+        let id = NEXT_RECONCILE.fetch_add(1, Ordering::Relaxed);
+        RECONCILES.lock().unwrap().insert(id, Instant::now());
+        Reconciling(id)
+    }
+}
+
+impl Drop for Reconciling {
+    fn drop(&mut self) {
+        RECONCILES.lock().unwrap().remove(&self.0);
+    }
+}
+
+// The problems keeping the operator from being ready.
+fn unready(now: Instant) -> Vec<String> {
+    let watchers = WATCHERS.lock().unwrap();
+    let mut problems: Vec<_> = watchers
+        .iter()
+        .filter_map(|(name, state)| match (*state)? {
+            WatchState::Synced => None,
+            WatchState::Listing => Some(format!("{} not listed yet", name)),
+            WatchState::Disconnected => Some(format!("{} disconnected", name)),
+        })
+        .collect();
+    let warm_up = WARM_UP.lock().unwrap();
+    if warm_up.warming_up(now) {
+        problems.push(format!("warming up, {}", warm_up.progress()));
+    }
+    problems
+}
+
+// The problems that need a restart.
+fn unhealthy(now: Instant) -> Vec<String> {
+    let reconciles = RECONCILES.lock().unwrap();
+    let stuck = reconciles
+        .values()
+        .filter(|started| {
+            now.saturating_duration_since(**started) > STUCK_AFTER
+        })
+        .count();
+    match stuck {
+        0 => vec![],
+        n => vec![format!(
+            "{} reconciles running for over {:?}",
+            n, STUCK_AFTER
+        )],
+    }
+}
+
+pub(crate) struct HealthServer {
+    listener: TcpListener,
+}
+
+impl HealthServer {
+    pub(crate) async fn bind(addr: SocketAddr) -> Result<HealthServer, Error> {
+        Ok(HealthServer {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    pub(crate) async fn serve(self) -> Result<(), Error> {
+        info!("serving probes on {}", self.listener.local_addr()?);
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(handle))
+                    .await
+                {
+                    debug!("probe connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let problems = match req.uri().path() {
+        "/healthz" => unhealthy(Instant::now()),
+        "/readyz" => unready(Instant::now()),
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+                .unwrap())
+        }
+    };
+    let (status, body) = match problems.is_empty() {
+        true => (StatusCode::OK, "ok\n".to_string()),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            problems.iter().map(|p| format!("{}\n", p)).collect(),
+        ),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::Secret;
+
+    #[test]
+    fn watchers() {
+        let watch = Watch::new("test-secrets");
+        let other = Watch::new("test-secrets");
+        assert_eq!(other.name, "test-secrets-2");
+        let problems = || {
+            unready(Instant::now())
+                .into_iter()
+                .filter(|p| p.starts_with("test-secrets"))
+                .collect::<Vec<_>>()
+        };
+        assert!(problems().is_empty());
+        watch.observe::<Secret>(&Ok(watcher::Event::Init));
+        assert_eq!(problems(), ["test-secrets not listed yet"]);
+        watch.observe::<Secret>(&Ok(watcher::Event::InitDone));
+        other.observe::<Secret>(&Ok(watcher::Event::InitDone));
+        assert!(problems().is_empty());
+        other.observe::<Secret>(&Err(watcher::Error::NoResourceVersion));
+        assert_eq!(problems(), ["test-secrets-2 disconnected"]);
+        drop(other);
+        assert!(problems().is_empty());
+    }
+
+    #[tokio::test]
+    async fn warm_up() {
+        let problems = || {
+            unready(Instant::now())
+                .into_iter()
+                .filter(|p| p.starts_with("warming up"))
+                .collect::<Vec<_>>()
+        };
+        let mut list = WarmUpList::new(Duration::from_secs(3600));
+        let name = |secret: &Secret| secret.metadata.name.clone();
+        let secret = |name: &str| {
+            let mut secret = Secret::default();
+            secret.metadata.name = Some(name.to_string());
+            watcher::Event::InitApply(secret)
+        };
+        list.observe(&Ok(secret("a")), name);
+        list.observe(&Ok(secret("b")), name);
+        list.observe(&Ok(secret("c")), |_| None);
+        warmed_up("a");
+        assert_eq!(problems(), ["warming up, reconciled 1 of 2 secrets"]);
+        list.observe::<Secret>(&Ok(watcher::Event::InitDone), name);
+        // Later lists don't count.
+        list.observe(&Ok(secret("d")), name);
+        warmed_up("b");
+        assert!(problems().is_empty());
+    }
+
+    #[test]
+    fn stuck_reconciles() {
+        let reconciling = Reconciling::start();
+        let later = Instant::now() + STUCK_AFTER + Duration::from_secs(1);
+        assert!(!unhealthy(later).is_empty());
+        drop(reconciling);
+        assert!(unhealthy(Instant::now()).is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+// Hooks let secrets run checks and actions around generation, e.g.
+// confirming that the consumers of a password are ready before it is rotated
+// or creating the database user after it was generated. A hook either calls
+// an HTTP endpoint or runs a Job in the namespace of the secret. Jobs can
+// run as any service account and mount anything, so secrets only name one
+// of the templates in `--job-hooks-file`.
+
+use crate::{controller::Context, error::Error};
+use k8s_openapi::{
+    api::{
+        batch::v1::{Job, JobSpec},
+        core::v1::{Secret, SecretVolumeSource, Volume, VolumeMount},
+    },
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+};
+use kube::{
+    api::{DeleteParams, PostParams},
+    Api, Resource, ResourceExt,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+};
+use tokio::time::Duration;
+
+// How often a running hook Job is checked, and when a failed hook is tried
+// again.
+pub const HOOK_POLL: Duration = Duration::from_secs(10);
+pub const HOOK_RETRY: Duration = Duration::from_secs(300);
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where hook Jobs find the generated secret.
+const MOUNT_PATH: &str = "/secret";
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "HookSettings")]
+pub enum Hook {
+    // POSTs the secret and keys as JSON, passes on a 2xx response.
+    Http(String),
+    // Runs a Job from the template of this name, passes once the Job
+    // succeeded.
+    Job(String),
+}
+
+// YAML only spells enums as tags, so hooks are read as a map with one of
+// the fields.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookSettings {
+    http: Option<String>,
+    job: Option<String>,
+}
+
+impl TryFrom<HookSettings> for Hook {
+    type Error = String;
+
+    fn try_from(settings: HookSettings) -> Result<Self, String> {
+        match (settings.http, settings.job) {
+            (Some(url), None) => Ok(Hook::Http(url)),
+            (None, Some(template)) => Ok(Hook::Job(template)),
+            _ => Err("a hook is either http or job".to_string()),
+        }
+    }
+}
+
+pub enum Outcome {
+    Passed,
+    Pending,
+    Failed(String),
+}
+
+impl Hook {
+    // Runs the hook `name` of `secret` for `keys`. Jobs run in the background,
+    // so calling this again reports whether they finished. They get the
+    // secret named `mount` mounted, if any.
+    pub async fn run(
+        &self,
+        ctx: &Context,
+        secret: &Secret,
+        name: &str,
+        keys: &BTreeSet<String>,
+        mount: Option<&str>,
+    ) -> Result<Outcome, Error> {
+        match self {
+            Hook::Http(url) => {
+                let body = serde_json::json!({
+                    "namespace": secret.namespace(),
+                    "secret": secret.name_any(),
+                    "hook": name,
+                    "keys": keys,
+                });
+                let response = ctx
+                    .http
+                    .post(url)
+                    .timeout(HTTP_TIMEOUT)
+                    .json(&body)
+                    .send()
+                    .await;
+                // Hook URLs may contain tokens.
+                Ok(match response {
+                    Ok(r) if r.status().is_success() => Outcome::Passed,
+                    Ok(r) => {
+                        Outcome::Failed(format!("answered {}", r.status()))
+                    }
+                    Err(e) => Outcome::Failed(e.without_url().to_string()),
+                })
+            }
+            Hook::Job(template) => {
+                let Some(spec) = ctx.opts.job_hooks.get(template) else {
+                    return Ok(Outcome::Failed(format!(
+                        "job template {:?} is not configured, see \
+                         --job-hooks-file",
+                        template
+                    )));
+                };
+                let mut spec = spec.clone();
+                if let Some(mount) = mount {
+                    mount_secret(&mut spec, mount);
+                }
+                run_job(ctx, secret, name, spec).await
+            }
+        }
+    }
+}
+
+pub(crate) fn load_job_hooks(
+    path: &str,
+) -> Result<BTreeMap<String, JobSpec>, Error> {
+    Ok(serde_yaml::from_reader(File::open(path)?)?)
+}
+
+fn mount_secret(spec: &mut JobSpec, secret: &str) {
+    let Some(pod) = spec.template.spec.as_mut() else {
+        return;
+    };
+    pod.volumes.get_or_insert_with(Vec::new).push(Volume {
+        name: "auto-secret".to_string(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(secret.to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    for container in &mut pod.containers {
+        container.volume_mounts.get_or_insert_with(Vec::new).push(
+            VolumeMount {
+                name: "auto-secret".to_string(),
+                mount_path: MOUNT_PATH.to_string(),
+                read_only: Some(true),
+                ..Default::default()
+            },
+        );
+    }
+}
+
+async fn run_job(
+    ctx: &Context,
+    secret: &Secret,
+    name: &str,
+    spec: JobSpec,
+) -> Result<Outcome, Error> {
+    let ns = secret.namespace().unwrap_or_default();
+    let api = Api::<Job>::namespaced(ctx.client.clone(), &ns);
+    let job_name = format!("{}-{}", secret.name_any(), name);
+    let Some(job) = api.get_opt(&job_name).await? else {
+        let job = Job {
+            metadata: ObjectMeta {
+                name: Some(job_name),
+                labels: Some(BTreeMap::from([(
+                    app_id!("hook").to_string(),
+                    name.to_string(),
+                )])),
+                owner_references: secret
+                    .controller_owner_ref(&())
+                    .map(|owner| vec![owner]),
+                ..Default::default()
+            },
+            spec: Some(spec),
+            ..Default::default()
+        };
+        return match api.create(&PostParams::default(), &job).await {
+            Ok(_) => Ok(Outcome::Pending),
+            Err(kube::Error::Api(e)) if e.code == 422 => {
+                Ok(Outcome::Failed(e.message))
+            }
+            Err(e) => Err(e.into()),
+        };
+    };
+
+    let status = job.status.unwrap_or_default();
+    let failed = status
+        .conditions
+        .iter()
+        .flatten()
+        .find(|c| c.type_ == "Failed" && c.status == "True");
+    let outcome = match failed {
+        _ if status.succeeded.unwrap_or_default() > 0 => Outcome::Passed,
+        Some(condition) => Outcome::Failed(format!(
+            "job {} failed: {}",
+            job_name,
+            condition.message.as_deref().unwrap_or("unknown reason")
+        )),
+        None => return Ok(Outcome::Pending),
+    };
+    // Finished Jobs are removed, so that the next run starts a new one.
+    api.delete(&job_name, &DeleteParams::background()).await?;
+    Ok(outcome)
+}
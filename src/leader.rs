@@ -0,0 +1,187 @@
+// Leader election through a coordination.k8s.io Lease, so that of several
+// replicas only the one holding the lease runs the controllers while the
+// others stand by.
+
+use crate::{error::Error, opts::Opts};
+use futures_util::future::{self, Either};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::{DateTime, TimeDelta, Utc},
+};
+use kube::{api::PostParams, Api, Client};
+use log::{info, warn};
+use std::{future::Future, pin::pin};
+use tokio::time::{sleep, Duration, Instant};
+
+pub(crate) struct Leader {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    duration: Duration,
+}
+
+impl Leader {
+    pub(crate) fn new(client: Client, opts: &Opts) -> Leader {
+        let identity = opts
+            .leader_election_identity
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| format!("auto-secret-{}", std::process::id()));
+        // Every shard elects its own leader.
+        let name = match opts.shard_count {
+            1 => opts.leader_election_lease.clone(),
+            _ => format!("{}-{}", opts.leader_election_lease, opts.shard_index),
+        };
+        Leader {
+            api: Api::namespaced(client, &opts.namespace),
+            name,
+            identity,
+            duration: opts.leader_election_duration.0,
+        }
+    }
+
+    // Runs `task` once the lease is acquired, for as long as it is held. The
+    // lease is released when `task` finishes, so a standby replica takes
+    // over without waiting for it to expire. Fails if the lease is lost.
+    pub(crate) async fn lead<F: Future>(&self, task: F) -> Result<(), Error> {
+        info!("waiting for lease {} as {}", self.name, self.identity);
+        while !self.try_acquire().await? {
+            sleep(self.retry_period()).await;
+        }
+        info!("acquired lease {}", self.name);
+        match future::select(pin!(task), pin!(self.hold())).await {
+            Either::Left(_) => {
+                self.release().await;
+                Ok(())
+            }
+            Either::Right((e, _)) => Err(e),
+        }
+    }
+
+    // Renews the lease until that fails for longer than the renew deadline
+    // or another replica took it over.
+    async fn hold(&self) -> Error {
+        let mut renewed = Instant::now();
+        loop {
+            sleep(self.retry_period()).await;
+            match self.try_acquire().await {
+                Ok(true) => renewed = Instant::now(),
+                Ok(false) => return Error::LeaseLost(self.name.clone()),
+                Err(e) if renewed.elapsed() < self.duration * 2 / 3 => {
+                    warn!("failed to renew lease {}: {}", self.name, e)
+                }
+                Err(_) => return Error::LeaseLost(self.name.clone()),
+            }
+        }
+    }
+
+    fn retry_period(&self) -> Duration {
+        self.duration / 5
+    }
+
+    // Takes the lease if it is free or expired, or renews it if it is held
+    // already. Returns false if another replica holds it.
+    async fn try_acquire(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+        let Some(mut lease) = self.api.get_opt(&self.name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(self.duration_seconds()),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                    ..Default::default()
+                }),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        };
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        if spec.holder_identity.as_ref() != Some(&self.identity) {
+            if !expired(spec, now) {
+                return Ok(false);
+            }
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions =
+                Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+        spec.lease_duration_seconds = Some(self.duration_seconds());
+        spec.renew_time = Some(MicroTime(now));
+        // The resource version makes the replace fail if another replica
+        // changed the lease in the meantime.
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn release(&self) {
+        let released = async {
+            let mut lease = self.api.get(&self.name).await?;
+            let Some(spec) = lease.spec.as_mut() else {
+                return Ok(());
+            };
+            if spec.holder_identity.as_ref() != Some(&self.identity) {
+                return Ok(());
+            }
+            spec.holder_identity = None;
+            self.api
+                .replace(&self.name, &PostParams::default(), &lease)
+                .await?;
+            Ok::<_, Error>(())
+        };
+        match released.await {
+            Ok(()) => info!("released lease {}", self.name),
+            Err(e) => warn!("failed to release lease {}: {}", self.name, e),
+        }
+    }
+
+    fn duration_seconds(&self) -> i32 {
+        self.duration.as_secs().try_into().unwrap_or(i32::MAX)
+    }
+}
+
+// Whether nobody holds the lease or its holder did not renew it in time.
+fn expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    let Some(MicroTime(renewed)) = spec.renew_time else {
+        return true;
+    };
+    let duration = spec.lease_duration_seconds.unwrap_or_default();
+    spec.holder_identity.is_none()
+        || renewed + TimeDelta::seconds(duration.into()) < now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let now = Utc::now();
+        let spec = |holder: Option<&str>, ago| LeaseSpec {
+            holder_identity: holder.map(str::to_string),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(now - TimeDelta::seconds(ago))),
+            ..Default::default()
+        };
+        assert!(!expired(&spec(Some("a"), 10), now));
+        assert!(expired(&spec(Some("a"), 20), now));
+        assert!(expired(&spec(None, 10), now));
+        assert!(expired(&LeaseSpec::default(), now));
+    }
+}
@@ -0,0 +1,82 @@
+//! Generates the values of Kubernetes secrets from the settings in their
+//! annotations. The operator binary is a thin wrapper around [`run`], the
+//! parsing and generation it is built on can be used on their own.
+
+use std::sync::OnceLock;
+
+macro_rules! app_id {
+    () => {
+        "auto-secret.k8s.eboland.de"
+    };
+    ($name:tt) => {{
+        static NAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        NAME.get_or_init(|| {
+            format!("{}/{}", $crate::annotation_domain(), $name)
+        })
+        .as_str()
+    }};
+}
+
+// Annotations live under `--annotation-domain`, which defaults to `app_id!()`.
+pub(crate) static ANNOTATION_DOMAIN: OnceLock<String> = OnceLock::new();
+
+pub(crate) fn annotation_domain() -> &'static str {
+    ANNOTATION_DOMAIN.get().map_or(app_id!(), String::as_str)
+}
+
+// Annotations of mittwald's kubernetes-secret-generator, understood with
+// `--mittwald-compat`.
+macro_rules! mittwald {
+    ($name:tt) => {
+        concat!("secret-generator.v1.mittwald.de/", $name)
+    };
+}
+
+mod audit;
+#[cfg(feature = "aws")]
+mod aws;
+#[cfg(feature = "azure")]
+mod azure;
+mod certmanager;
+mod cli;
+mod client;
+mod config;
+mod controller;
+pub mod crypto;
+mod debug;
+pub mod error;
+mod eso;
+mod export;
+#[cfg(test)]
+mod fake;
+#[cfg(feature = "gcp")]
+mod gcp;
+pub mod generate;
+mod git;
+mod health;
+mod hooks;
+pub mod kinds;
+mod leader;
+mod logging;
+mod metrics;
+mod mittwald;
+pub mod opts;
+mod overflow;
+mod plugins;
+mod quota;
+mod reports;
+mod restart;
+mod seal;
+pub mod settings;
+pub mod store;
+mod telemetry;
+pub mod template;
+mod throttle;
+#[cfg(feature = "vault")]
+mod vault;
+mod webhook;
+
+pub use controller::run;
+pub use error::Error;
+pub use logging::init_logger;
+pub use opts::Opts;
@@ -0,0 +1,54 @@
+// Sets up the log in the format and at the level given on the command line.
+
+use crate::opts::{LogFormat, Opts};
+use env_logger::{fmt::Formatter, Builder, Target};
+use log::Record;
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Installs the logger selected by `--log-format` and `--log-level`.
+/// Subcommands log to stderr, since they print their results to stdout.
+pub fn init_logger(opts: &Opts) {
+    let default_format = match cfg!(debug_assertions) {
+        true => LogFormat::Pretty,
+        false => LogFormat::Json,
+    };
+    let mut builder = match opts.log_format.unwrap_or(default_format) {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder(),
+        LogFormat::Compact => Builder::new(),
+        LogFormat::Json => {
+            let mut builder = Builder::new();
+            builder.format(json);
+            builder
+        }
+    };
+    let level = opts
+        .log_level
+        .clone()
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .unwrap_or_else(|| "info".to_string());
+    builder.parse_filters(&level);
+    builder.target(match opts.runs_controllers() {
+        true => Target::Stdout,
+        false => Target::Stderr,
+    });
+    builder.init();
+}
+
+// A JSON object per record, with the level, message, target and the time in
+// milliseconds since the epoch.
+fn json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let line = serde_json::json!({
+        "level": record.level().as_str(),
+        "message": record.args().to_string(),
+        "target": record.target(),
+        "timestamp": timestamp,
+    });
+    writeln!(buf, "{}", line)
+}
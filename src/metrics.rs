@@ -0,0 +1,93 @@
+// Metrics of reconciles, exported with OTLP by the meter provider that
+// `--otlp-metrics` installs. Without it the instruments record nothing.
+
+use crate::error::Error;
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    KeyValue,
+};
+use std::{sync::LazyLock, time::Duration};
+
+const METER: &str = "auto-secret-operator";
+
+struct Metrics {
+    reconciles: Counter<u64>,
+    reconcile_duration: Histogram<f64>,
+    keys: Counter<u64>,
+    drifted_keys: Counter<u64>,
+    stale_secrets: Counter<u64>,
+}
+
+// Created on first use, after the meter provider was installed, as meters
+// of the global provider stay no-ops when it is replaced later.
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let meter = global::meter(METER);
+    Metrics {
+        reconciles: meter
+            .u64_counter("auto_secret.reconciles")
+            .with_description("Reconciles of secrets by their result")
+            .build(),
+        reconcile_duration: meter
+            .f64_histogram("auto_secret.reconcile.duration")
+            .with_description("Duration of reconciles of secrets")
+            .with_unit("s")
+            .build(),
+        keys: meter
+            .u64_counter("auto_secret.keys")
+            .with_description("Keys written by action, generate or rotate")
+            .build(),
+        drifted_keys: meter
+            .u64_counter("auto_secret.drifted_keys")
+            .with_description("Keys whose values others changed")
+            .build(),
+        stale_secrets: meter
+            .u64_counter("auto_secret.stale_secrets")
+            .with_description("Secrets with keys older than their maximum age")
+            .build(),
+    }
+});
+
+// Records a reconcile of a secret in `namespace` that took `elapsed`, by the
+// reason of its error if it failed.
+pub(crate) fn reconciled<T>(
+    namespace: &str,
+    result: &Result<T, Error>,
+    elapsed: Duration,
+) {
+    let result = match result {
+        Ok(_) => "Success",
+        Err(e) => e.reason(),
+    };
+    let attributes = [
+        KeyValue::new("namespace", namespace.to_string()),
+        KeyValue::new("result", result),
+    ];
+    METRICS.reconciles.add(1, &attributes);
+    METRICS
+        .reconcile_duration
+        .record(elapsed.as_secs_f64(), &attributes[..1]);
+}
+
+pub(crate) fn keys_written(namespace: &str, action: &'static str, keys: usize) {
+    METRICS.keys.add(
+        keys as u64,
+        &[
+            KeyValue::new("namespace", namespace.to_string()),
+            KeyValue::new("action", action),
+        ],
+    );
+}
+
+// Counts keys found drifted, once when they start to drift.
+pub(crate) fn keys_drifted(namespace: &str, keys: usize) {
+    let namespace = KeyValue::new("namespace", namespace.to_string());
+    METRICS.drifted_keys.add(keys as u64, &[namespace]);
+}
+
+// Counts secrets found with keys older than their maximum age, once when
+// further keys get too old.
+pub(crate) fn secret_stale(namespace: &str) {
+    let namespace = KeyValue::new("namespace", namespace.to_string());
+    METRICS.stale_secrets.add(1, &[namespace]);
+}
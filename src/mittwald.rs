@@ -0,0 +1,225 @@
+// Compatibility with the annotations of mittwald's
+// kubernetes-secret-generator, and the `migrate` command translating them.
+
+use crate::{
+    controller::Context,
+    error::Error,
+    settings::{parse_annotation, Options, Settings},
+};
+use k8s_openapi::{
+    api::core::v1::Secret,
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{SecondsFormat, Utc},
+};
+use kube::{
+    api::{Api, ListParams, Patch, PatchParams, Resource},
+    Client, ResourceExt,
+};
+use log::info;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub(crate) struct Mittwald {
+    pub(crate) settings: BTreeMap<String, Settings>,
+    pub(crate) regenerate: BTreeSet<String>,
+}
+
+// Translates mittwald's `autogenerate` annotations into settings, or returns
+// `None` if the secret doesn't carry them. Only the `string` type has an
+// equivalent here.
+pub(crate) fn mittwald_settings(
+    meta: &ObjectMeta,
+) -> Result<Option<Mittwald>, Error> {
+    let annotations = meta.annotations.clone().unwrap_or_default();
+    let Some(keys) = annotations.get(mittwald!("autogenerate")) else {
+        return Ok(None);
+    };
+    let type_ = annotations.get(mittwald!("type")).map(String::as_str);
+    if type_.is_some_and(|t| t != "string") {
+        return Err(Error::InvalidAnnotation(
+            mittwald!("type").to_string(),
+            format!("type {:?} is not supported", type_.unwrap()),
+        ));
+    }
+    if annotations.contains_key(mittwald!("encoding")) {
+        return Err(Error::InvalidAnnotation(
+            mittwald!("encoding").to_string(),
+            "encodings are not supported".to_string(),
+        ));
+    }
+    let length =
+        parse_annotation::<usize>(meta, mittwald!("length"))?.unwrap_or(40);
+
+    let keys: BTreeSet<String> = keys
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(str::to_string)
+        .collect();
+    let settings = keys
+        .iter()
+        .map(|key| {
+            let options = Options {
+                letter: true,
+                digit: true,
+                length: Some(length),
+                ..Default::default()
+            };
+            (key.clone(), Settings::Options(options))
+        })
+        .collect();
+    let regenerate = match annotations.get(mittwald!("regenerate")) {
+        None => BTreeSet::new(),
+        Some(value) if value == "true" => keys,
+        Some(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|k| keys.contains(*k))
+            .map(str::to_string)
+            .collect(),
+    };
+    Ok(Some(Mittwald {
+        settings,
+        regenerate,
+    }))
+}
+
+// Like mittwald's operator, consume the regenerate annotation and record when
+// values were generated.
+pub(crate) async fn mittwald_mark(
+    ctx: &Context,
+    api: &Api<Secret>,
+    secret: &Secret,
+    generated: bool,
+) -> Result<(), Error> {
+    let mut annotations = serde_json::Map::new();
+    if secret.annotations().contains_key(mittwald!("regenerate")) {
+        annotations.insert(mittwald!("regenerate").into(), ().into());
+    }
+    if generated {
+        annotations.insert(
+            mittwald!("autogenerate-generated-at").into(),
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into(),
+        );
+    }
+    if annotations.is_empty() {
+        return Ok(());
+    }
+    let patched = api
+        .patch(
+            &secret.name_any(),
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(serde_json::json!({
+                "metadata": { "annotations": annotations }
+            })),
+        )
+        .await?;
+    ctx.wrote(&patched);
+    Ok(())
+}
+
+// The annotation patch replacing the mittwald annotations of a secret with an
+// equivalent gen annotation, or `None` if there is nothing to migrate.
+fn migration_patch(
+    meta: &ObjectMeta,
+) -> Result<Option<BTreeMap<String, Option<String>>>, Error> {
+    let Some(mittwald) = mittwald_settings(meta)? else {
+        return Ok(None);
+    };
+    if meta
+        .annotations
+        .iter()
+        .flatten()
+        .any(|(k, _)| k == app_id!("gen"))
+    {
+        return Err(Error::Conflict(format!(
+            "{} already carries {}",
+            meta.name.as_deref().unwrap_or_default(),
+            app_id!("gen")
+        )));
+    }
+    let length =
+        parse_annotation::<usize>(meta, mittwald!("length"))?.unwrap_or(40);
+    let keys: BTreeMap<&String, serde_json::Value> = mittwald
+        .settings
+        .keys()
+        .map(|key| {
+            let options = serde_json::json!({
+                "letter": true,
+                "digit": true,
+                "length": length,
+            });
+            (key, options)
+        })
+        .collect();
+
+    let mut patch: BTreeMap<String, Option<String>> = meta
+        .annotations
+        .iter()
+        .flatten()
+        .filter(|(k, _)| k.starts_with(mittwald!("")))
+        .map(|(k, _)| (k.clone(), None))
+        .collect();
+    patch.insert(
+        app_id!("gen").to_string(),
+        Some(serde_yaml::to_string(&keys)?),
+    );
+    Ok(Some(patch))
+}
+
+// Rewrites the annotations of secrets in a stream of manifests, passing
+// everything else through unchanged.
+pub(crate) fn migrate_manifests(input: &str) -> Result<String, Error> {
+    let mut output = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(input) {
+        let mut value = serde_yaml::Value::deserialize(document)?;
+        if value.get("kind").and_then(|k| k.as_str()) == Some("Secret") {
+            let secret: Secret = serde_yaml::from_value(value.clone())?;
+            if let Some(patch) = migration_patch(secret.meta())? {
+                let annotations = &mut value["metadata"]["annotations"];
+                let annotations = annotations.as_mapping_mut().unwrap();
+                for (key, update) in patch {
+                    match update {
+                        Some(update) => {
+                            annotations.insert(key.into(), update.into())
+                        }
+                        None => annotations.remove(key),
+                    };
+                }
+            }
+        }
+        output.push(serde_yaml::to_string(&value)?);
+    }
+    Ok(output.join("---\n"))
+}
+
+// Migrates the secrets of the cluster, printing the patches unless `apply`
+// is set.
+pub(crate) async fn migrate_cluster(
+    client: Client,
+    apply: bool,
+) -> Result<(), Error> {
+    let api = Api::<Secret>::all(client.clone());
+    for secret in api.list(&ListParams::default()).await? {
+        let Some(patch) = migration_patch(secret.meta())? else {
+            continue;
+        };
+        let ns = secret.namespace().unwrap();
+        let name = secret.name_any();
+        let patch = serde_json::json!({ "metadata": { "annotations": patch } });
+        if !apply {
+            println!(
+                "# {}/{}\n{}---",
+                ns,
+                name,
+                serde_yaml::to_string(&patch)?
+            );
+            continue;
+        }
+        Api::<Secret>::namespaced(client.clone(), &ns)
+            .patch(&name, &PatchParams::apply(app_id!()), &Patch::Merge(patch))
+            .await?;
+        info!("migrated secret {}/{}", ns, name);
+    }
+    Ok(())
+}
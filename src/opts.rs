@@ -0,0 +1,908 @@
+//! Command line options of the operator.
+
+use crate::{
+    audit::{AuditSink, Notifier},
+    client::Cluster,
+    crypto::{sha256, RngKind},
+    kinds::{ExecCommand, HttpProvider},
+    plugins::Plugins,
+    quota::Quota,
+    settings::{CharClass, Defaults, Interval},
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use k8s_openapi::api::batch::v1::JobSpec;
+use serde::Deserialize;
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+#[derive(Parser, Clone)]
+pub struct Opts {
+    #[clap(
+        short,
+        long,
+        env = "AUTO_SECRET_DEFAULT_LENGTH",
+        default_value = "32"
+    )]
+    pub(crate) default_length: usize,
+    /// Also generate values into annotated ConfigMaps
+    #[clap(long, env = "AUTO_SECRET_CONFIG_MAPS")]
+    pub(crate) config_maps: bool,
+    /// Create secrets declared on Deployments and StatefulSets
+    #[clap(long, env = "AUTO_SECRET_WORKLOADS")]
+    pub(crate) workloads: bool,
+    /// What to do when a generated key is to be changed, e.g. rotated, but
+    /// another field manager claims it: `force` takes it over, `fail`
+    /// reports a conflict, and `refuse` skips the key before generating it
+    #[clap(
+        long,
+        env = "AUTO_SECRET_APPLY_CONFLICTS",
+        value_enum,
+        default_value = "force"
+    )]
+    pub(crate) apply_conflicts: ApplyConflicts,
+    /// Allow writing generated data into secrets of other namespaces
+    #[clap(long, env = "AUTO_SECRET_CROSS_NAMESPACE")]
+    pub(crate) cross_namespace: bool,
+    /// Also generate secrets annotated for mittwald's
+    /// kubernetes-secret-generator
+    #[clap(long, env = "AUTO_SECRET_MITTWALD_COMPAT")]
+    pub(crate) mittwald_compat: bool,
+    /// Domain of the annotations the operator reads and writes
+    #[clap(
+        long,
+        env = "AUTO_SECRET_ANNOTATION_DOMAIN",
+        default_value = app_id!()
+    )]
+    pub(crate) annotation_domain: String,
+    /// Minimum length of random values
+    #[clap(long, env = "AUTO_SECRET_MIN_LENGTH")]
+    pub(crate) min_length: Option<usize>,
+    /// Character classes every random value has to contain
+    #[clap(long, env = "AUTO_SECRET_REQUIRE_CLASSES", value_delimiter = ',')]
+    pub(crate) require_classes: Vec<CharClass>,
+    /// Minimum strength score (0-4, on zxcvbn's scale) of random values
+    #[clap(
+        long,
+        env = "AUTO_SECRET_MIN_SCORE",
+        value_parser = clap::value_parser!(u8).range(0..=4)
+    )]
+    pub(crate) min_score: Option<u8>,
+    /// What to do with keys below --min-length or --require-classes
+    #[clap(
+        long,
+        env = "AUTO_SECRET_FLOOR_ACTION",
+        value_enum,
+        default_value = "clamp"
+    )]
+    pub(crate) floor_action: FloorAction,
+    /// ConfigMap (`[<namespace>/]<name>`) holding operator-wide defaults in
+    /// its `defaults.yaml` key
+    #[clap(long, env = "AUTO_SECRET_DEFAULTS_CONFIG_MAP")]
+    pub(crate) defaults_config_map: Option<String>,
+    /// Secret (`[<namespace>/]<name>`) holding the master seed in its `seed`
+    /// key, which keys with `derive: true` are derived from
+    #[clap(long, env = "AUTO_SECRET_MASTER_SEED_SECRET")]
+    pub(crate) master_seed_secret: Option<String>,
+    /// Where to write audit records of generated and rotated keys:
+    /// `file:<path>`, `syslog` or an `https://` URL
+    #[clap(long, env = "AUTO_SECRET_AUDIT_SINK")]
+    pub(crate) audit_sink: Option<AuditSink>,
+    /// Notify `webhook=<url>` or `slack=<url>` whenever keys are generated or
+    /// rotated. Can be given multiple times
+    #[clap(long, env = "AUTO_SECRET_NOTIFY")]
+    pub(crate) notify: Vec<Notifier>,
+    /// How often objects are reconciled again without changes, `0s` to only
+    /// reconcile them when they change or a rotation is due
+    #[clap(long, env = "AUTO_SECRET_REQUEUE_INTERVAL", default_value = "5m")]
+    pub(crate) requeue_interval: Interval,
+    /// Add a random delay of up to this much to every periodic reconcile, so
+    /// objects created together are spread out
+    #[clap(long, env = "AUTO_SECRET_REQUEUE_JITTER", default_value = "0s")]
+    pub(crate) requeue_jitter: Interval,
+    /// Make all requests to the API server as this user
+    #[clap(long = "as", env = "AUTO_SECRET_AS")]
+    pub(crate) impersonate: Option<String>,
+    /// Make all requests to the API server as a member of this group, with
+    /// `--as`. Can be given multiple times or comma separated
+    #[clap(
+        long = "as-group",
+        env = "AUTO_SECRET_AS_GROUP",
+        value_delimiter = ',',
+        requires = "impersonate"
+    )]
+    pub(crate) impersonate_groups: Vec<String>,
+    /// Limit the requests to the API server to this many per second, no limit
+    /// if unset
+    #[clap(long, env = "AUTO_SECRET_KUBE_QPS", value_parser = parse_qps)]
+    pub(crate) kube_qps: Option<f64>,
+    /// Allow bursts of this many requests above `--kube-qps`
+    #[clap(long, env = "AUTO_SECRET_KUBE_BURST", default_value = "10")]
+    pub(crate) kube_burst: u32,
+    /// Limit the reconciles of the objects of each namespace to this many
+    /// per second, no limit if unset
+    #[clap(long, env = "AUTO_SECRET_NAMESPACE_QPS", value_parser = parse_qps)]
+    pub(crate) namespace_qps: Option<f64>,
+    /// Allow bursts of this many reconciles per namespace above
+    /// `--namespace-qps`
+    #[clap(long, env = "AUTO_SECRET_NAMESPACE_BURST", default_value = "10")]
+    pub(crate) namespace_burst: u32,
+    /// How watchers list objects when they start or lost track:
+    /// `consistent` reads them from etcd, `cached` from the API server's
+    /// cache, which is cheaper but may be older, and `streaming` streams
+    /// them through the watch (Kubernetes 1.27 and later)
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WATCH_LIST",
+        value_enum,
+        default_value = "consistent"
+    )]
+    pub(crate) watch_list: WatchList,
+    /// The number of objects fetched per page by watchers listing objects
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WATCH_PAGE_SIZE",
+        default_value = "500",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub(crate) watch_page_size: u32,
+    /// How often all watched objects are reconciled again, even those that
+    /// would wait for changes, `0s` to never
+    #[clap(long, env = "AUTO_SECRET_RESYNC_INTERVAL", default_value = "0s")]
+    pub(crate) resync_interval: Interval,
+    /// How long reconciles in flight may take to finish on shutdown before
+    /// they are cut off
+    #[clap(
+        long,
+        env = "AUTO_SECRET_SHUTDOWN_GRACE_PERIOD",
+        default_value = "25s"
+    )]
+    pub(crate) shutdown_grace_period: Interval,
+    /// How long to wait before retrying an object that failed, doubled with
+    /// every further failure
+    #[clap(long, env = "AUTO_SECRET_RETRY_BACKOFF", default_value = "5s")]
+    pub(crate) retry_backoff: Interval,
+    /// The longest time to wait before retrying an object that failed
+    #[clap(long, env = "AUTO_SECRET_RETRY_BACKOFF_MAX", default_value = "5m")]
+    pub(crate) retry_backoff_max: Interval,
+    /// Reconcile every annotated object once and exit, with a non-zero
+    /// status if any of them failed
+    #[clap(long, env = "AUTO_SECRET_ONCE", conflicts_with = "webhook")]
+    pub(crate) once: bool,
+    /// Write the inventory of the `report` subcommand to this file
+    /// periodically while the controllers run
+    #[clap(long, env = "AUTO_SECRET_REPORT_FILE")]
+    pub(crate) report_file: Option<String>,
+    /// Maintain an AutoSecretReport with the inventory of the managed
+    /// secrets in each namespace, rewritten every `--report-interval`
+    #[clap(long, env = "AUTO_SECRET_REPORT_RESOURCES")]
+    pub(crate) report_resources: bool,
+    /// How often to write `--report-file` and the AutoSecretReports
+    #[clap(long, env = "AUTO_SECRET_REPORT_INTERVAL", default_value = "1h")]
+    pub(crate) report_interval: Interval,
+    /// Format of `--report-file`
+    #[clap(
+        long,
+        env = "AUTO_SECRET_REPORT_FORMAT",
+        value_enum,
+        default_value = "json"
+    )]
+    pub(crate) report_format: ReportFormat,
+    /// Restart the Deployments, StatefulSets and DaemonSets annotated with
+    /// `restart-on-rotation` that use a secret whose keys were rotated
+    #[clap(long, env = "AUTO_SECRET_RESTART_WORKLOADS")]
+    pub(crate) restart_workloads: bool,
+    /// Only watch secrets matching this label selector, e.g.
+    /// `auto-secret=enabled`
+    #[clap(long, env = "AUTO_SECRET_LABEL_SELECTOR")]
+    pub(crate) label_selector: Option<String>,
+    /// Only watch secrets matching this field selector, e.g.
+    /// `type=Opaque`
+    #[clap(long, env = "AUTO_SECRET_FIELD_SELECTOR")]
+    pub(crate) field_selector: Option<String>,
+    /// Only watch and write to these namespaces. Globs like `team-*` match
+    /// several namespaces. Can be given multiple times or comma separated
+    #[clap(long, env = "AUTO_SECRET_NAMESPACES", value_delimiter = ',')]
+    pub(crate) namespaces: Vec<String>,
+    /// Reconcile the cluster of a kubeconfig context instead of the current
+    /// one, as `<name>=<context>`. Can be given multiple times to reconcile
+    /// several clusters
+    #[clap(
+        long = "cluster",
+        env = "AUTO_SECRET_CLUSTER",
+        value_delimiter = ','
+    )]
+    pub(crate) clusters: Vec<Cluster>,
+    /// Only watch the objects in this namespace, through namespaced watches
+    /// that a Role grants access to. Namespace annotations are not read
+    #[clap(long, env = "AUTO_SECRET_WATCH_NAMESPACE")]
+    pub(crate) watch_namespace: Option<String>,
+    /// Never watch or write to these namespaces, e.g. `kube-*`. Can be given
+    /// multiple times or comma separated
+    #[clap(
+        long,
+        env = "AUTO_SECRET_EXCLUDE_NAMESPACES",
+        value_delimiter = ','
+    )]
+    pub(crate) exclude_namespaces: Vec<String>,
+    /// Number of shards namespaces are split into by a hash of their name,
+    /// to spread the reconciles over several replicas
+    #[clap(
+        long,
+        env = "AUTO_SECRET_SHARD_COUNT",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub(crate) shard_count: u32,
+    /// Shard whose namespaces this replica watches and reconciles, from 0 to
+    /// `--shard-count` - 1
+    #[clap(long, env = "AUTO_SECRET_SHARD_INDEX", default_value = "0")]
+    pub(crate) shard_index: u32,
+    /// Only run the controllers while holding a Lease, so several replicas
+    /// can run as an active/standby pair
+    #[clap(long, env = "AUTO_SECRET_LEADER_ELECTION")]
+    pub(crate) leader_election: bool,
+    /// Name of the Lease in the operator's namespace
+    #[clap(
+        long,
+        env = "AUTO_SECRET_LEADER_ELECTION_LEASE",
+        default_value = "auto-secret-operator"
+    )]
+    pub(crate) leader_election_lease: String,
+    /// How long the Lease stays valid without being renewed, which is how
+    /// long a standby replica waits for a crashed leader
+    #[clap(
+        long,
+        env = "AUTO_SECRET_LEADER_ELECTION_DURATION",
+        default_value = "15s"
+    )]
+    pub(crate) leader_election_duration: Interval,
+    /// Identity of this replica in the Lease, the hostname by default
+    #[clap(long, env = "POD_NAME")]
+    pub(crate) leader_election_identity: Option<String>,
+    /// Serve the validating admission webhook on this address, e.g.
+    /// `0.0.0.0:8443`
+    #[clap(long, env = "AUTO_SECRET_WEBHOOK")]
+    pub(crate) webhook: Option<SocketAddr>,
+    /// Serve `/healthz` and `/readyz` for the probes of the Deployment on
+    /// this address, e.g. `0.0.0.0:8081`
+    #[clap(long, env = "AUTO_SECRET_HEALTH_ADDR")]
+    pub(crate) health_addr: Option<SocketAddr>,
+    /// How long `/readyz` waits for the first reconcile of the annotated
+    /// secrets listed at startup. `0s` turns ready once they are listed
+    #[clap(long, env = "AUTO_SECRET_WARM_UP_TIMEOUT", default_value = "5m")]
+    pub(crate) warm_up_timeout: Interval,
+    /// Export traces of reconciles to the OpenTelemetry collector at this
+    /// base URL with OTLP over HTTP, e.g. `http://otel-collector:4318`.
+    /// Needs the `otel` build feature
+    #[clap(long, env = "AUTO_SECRET_OTLP_ENDPOINT")]
+    pub(crate) otlp_endpoint: Option<String>,
+    /// Export metrics of reconciles with OTLP over HTTP, to
+    /// `--otlp-endpoint` or else as the `OTEL_EXPORTER_OTLP_*` environment
+    /// variables configure it. Needs the `otel` build feature
+    #[clap(long, env = "AUTO_SECRET_OTLP_METRICS")]
+    pub(crate) otlp_metrics: bool,
+    /// Serve `/debug/queue` and `/debug/timings` on this address, e.g.
+    /// `127.0.0.1:8082`
+    #[clap(long, env = "AUTO_SECRET_DEBUG_ADDR")]
+    pub(crate) debug_addr: Option<SocketAddr>,
+    /// Serve tokio-console instrumentation of the runtime on this address,
+    /// e.g. `127.0.0.1:6669`. Needs the `console` build feature
+    #[clap(long, env = "AUTO_SECRET_TOKIO_CONSOLE_ADDR")]
+    pub(crate) tokio_console_addr: Option<SocketAddr>,
+    /// Certificate the webhook is served with
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WEBHOOK_CERT",
+        default_value = "/tls/tls.crt"
+    )]
+    pub(crate) webhook_cert: String,
+    /// Key of the webhook certificate
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WEBHOOK_KEY",
+        default_value = "/tls/tls.key"
+    )]
+    pub(crate) webhook_key: String,
+    /// Issue and renew a self-signed webhook certificate instead of reading
+    /// `--webhook-cert`, and patch it into the caBundle of the webhook
+    /// configurations named `--webhook-configuration`
+    #[clap(long, env = "AUTO_SECRET_WEBHOOK_SELF_SIGNED")]
+    pub(crate) webhook_self_signed: bool,
+    /// Service in the operator's namespace the webhook is reached through,
+    /// which self-signed certificates are issued for
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WEBHOOK_SERVICE",
+        default_value = "auto-secret-operator-webhook"
+    )]
+    pub(crate) webhook_service: String,
+    /// Name of the validating and mutating webhook configurations
+    #[clap(
+        long,
+        env = "AUTO_SECRET_WEBHOOK_CONFIGURATION",
+        default_value = "auto-secret-operator"
+    )]
+    pub(crate) webhook_configuration: String,
+    /// Namespace the operator runs in
+    #[clap(long, env = "POD_NAMESPACE", default_value = "default")]
+    pub(crate) namespace: String,
+    /// Generate and hash through the FIPS validated aws-lc module and refuse
+    /// algorithms that are not approved. Needs the `fips` build feature
+    #[clap(long, env = "AUTO_SECRET_FIPS")]
+    pub(crate) fips: bool,
+    /// Allow `kind: exec` keys to run a command, as `<name>=<path>`. Can be
+    /// given multiple times
+    #[clap(long, env = "AUTO_SECRET_EXEC")]
+    pub(crate) exec: Vec<ExecCommand>,
+    /// Time after which commands of `kind: exec` are killed, also the maximum
+    /// of their own timeout
+    #[clap(long, env = "AUTO_SECRET_EXEC_TIMEOUT", default_value = "10s")]
+    pub(crate) exec_timeout: Interval,
+    /// Run commands of `kind: exec` as this user id
+    #[clap(long, env = "AUTO_SECRET_EXEC_UID")]
+    pub(crate) exec_uid: Option<u32>,
+    /// Run commands of `kind: exec` as this group id
+    #[clap(long, env = "AUTO_SECRET_EXEC_GID")]
+    pub(crate) exec_gid: Option<u32>,
+    /// YAML file with the providers `kind: http` keys can fetch values from
+    #[clap(long, env = "AUTO_SECRET_HTTP_PROVIDERS_FILE")]
+    pub(crate) http_providers_file: Option<String>,
+    #[clap(skip)]
+    pub(crate) http_providers: BTreeMap<String, HttpProvider>,
+    /// YAML file with the Job templates hooks can run, by name. Hooks can't
+    /// run Jobs without it
+    #[clap(long, env = "AUTO_SECRET_JOB_HOOKS_FILE")]
+    pub(crate) job_hooks_file: Option<String>,
+    #[clap(skip)]
+    pub(crate) job_hooks: Arc<BTreeMap<String, JobSpec>>,
+    /// Directory with WebAssembly generator plugins (`<name>.wasm`) for
+    /// `kind: plugin`. Needs the `wasm` build feature
+    #[clap(long, env = "AUTO_SECRET_PLUGIN_DIR")]
+    pub(crate) plugin_dir: Option<String>,
+    #[clap(skip)]
+    pub(crate) plugins: Option<Arc<Plugins>>,
+    /// Address of a Vault server that generated values of secrets annotated
+    /// with `vault-path` are written to
+    #[clap(long, env = "AUTO_SECRET_VAULT_ADDR")]
+    pub(crate) vault_addr: Option<String>,
+    /// Vault role the operator logs in as with the Kubernetes auth method
+    #[clap(
+        long,
+        env = "AUTO_SECRET_VAULT_ROLE",
+        default_value = "auto-secret-operator"
+    )]
+    pub(crate) vault_role: String,
+    /// Mount path of the Kubernetes auth method in Vault
+    #[clap(
+        long,
+        env = "AUTO_SECRET_VAULT_AUTH_PATH",
+        default_value = "kubernetes"
+    )]
+    pub(crate) vault_auth_path: String,
+    /// Mount path of the KV version 2 secrets engine in Vault
+    #[clap(long, env = "AUTO_SECRET_VAULT_MOUNT", default_value = "secret")]
+    pub(crate) vault_mount: String,
+    /// Service account token the operator logs in to Vault with
+    #[clap(
+        long,
+        env = "AUTO_SECRET_VAULT_TOKEN_FILE",
+        default_value = "/var/run/secrets/kubernetes.io/serviceaccount/token"
+    )]
+    pub(crate) vault_token_file: String,
+    /// PEM file with the CA certificate of the Vault server
+    #[clap(long, env = "AUTO_SECRET_VAULT_CA", requires = "vault_addr")]
+    pub(crate) vault_ca: Option<String>,
+    /// AWS region of the Secrets Manager that values of secrets annotated
+    /// with `aws-secret` are mirrored to
+    #[clap(long, env = "AUTO_SECRET_AWS_REGION")]
+    pub(crate) aws_region: Option<String>,
+    /// IAM role to assume, `AWS_ROLE_ARN` as set by IRSA by default
+    #[clap(long, env = "AUTO_SECRET_AWS_ROLE_ARN")]
+    pub(crate) aws_role_arn: Option<String>,
+    /// Token to assume the IAM role with, `AWS_WEB_IDENTITY_TOKEN_FILE` as
+    /// set by IRSA by default
+    #[clap(long, env = "AUTO_SECRET_AWS_WEB_IDENTITY_TOKEN_FILE")]
+    pub(crate) aws_web_identity_token_file: Option<String>,
+    /// Tags (`<key>=<value>`) put on the secrets in Secrets Manager
+    #[clap(long, env = "AUTO_SECRET_AWS_TAGS", value_delimiter = ',')]
+    pub(crate) aws_tags: Vec<String>,
+    /// Google Cloud project of the Secret Manager that values of secrets
+    /// annotated with `gcp-secret` are written to
+    #[clap(long, env = "AUTO_SECRET_GCP_PROJECT")]
+    pub(crate) gcp_project: Option<String>,
+    /// Azure Key Vault, by name or URL, that keys mapped in the
+    /// `azure-key-vault` annotation are written to
+    #[clap(long, env = "AUTO_SECRET_AZURE_KEY_VAULT")]
+    pub(crate) azure_key_vault: Option<String>,
+    /// Write a SealedSecret next to secrets annotated with `seal`, encrypted
+    /// for the sealed-secrets controller
+    #[clap(long, env = "AUTO_SECRET_SEALED_SECRETS")]
+    pub(crate) sealed_secrets: bool,
+    /// Namespace and name of the sealed-secrets controller service that the
+    /// public key is fetched from
+    #[clap(
+        long,
+        env = "AUTO_SECRET_SEALED_SECRETS_CONTROLLER",
+        default_value = "kube-system/sealed-secrets-controller"
+    )]
+    pub(crate) sealed_secrets_controller: String,
+    /// PEM file with the certificate of the sealed-secrets controller, used
+    /// instead of fetching it
+    #[clap(long, env = "AUTO_SECRET_SEALED_SECRETS_CERT")]
+    pub(crate) sealed_secrets_cert: Option<String>,
+    /// Write an External Secrets Operator PushSecret next to secrets
+    /// annotated with `push-secret`, so that ESO delivers their values
+    #[clap(long, env = "AUTO_SECRET_PUSH_SECRETS")]
+    pub(crate) push_secrets: bool,
+    /// Directory, e.g. on a persistent volume, that age encrypted snapshots
+    /// of generated secrets are written to
+    #[clap(
+        long,
+        env = "AUTO_SECRET_EXPORT_DIR",
+        requires = "export_recipients"
+    )]
+    pub(crate) export_dir: Option<String>,
+    /// age recipients (`age1...`) that the snapshots in `--export-dir` and
+    /// the backups in `--backup-dir` are encrypted to
+    #[clap(long, env = "AUTO_SECRET_EXPORT_RECIPIENTS", value_delimiter = ',')]
+    pub(crate) export_recipients: Vec<String>,
+    /// Git repository, e.g. `git@github.com:org/secrets.git`, that the
+    /// snapshots in `--export-dir` are committed and pushed to
+    #[clap(long, env = "AUTO_SECRET_EXPORT_GIT_REPO", requires = "export_dir")]
+    pub(crate) export_git_repo: Option<String>,
+    /// Branch of `--export-git-repo` the snapshots are pushed to
+    #[clap(
+        long,
+        env = "AUTO_SECRET_EXPORT_GIT_BRANCH",
+        default_value = "main"
+    )]
+    pub(crate) export_git_branch: String,
+    /// Private key file of the deploy key used to push over ssh
+    #[clap(long, env = "AUTO_SECRET_EXPORT_GIT_KEY")]
+    pub(crate) export_git_key: Option<String>,
+    /// Known hosts file with the host key of the git server. Without it, the
+    /// host key is trusted on first use
+    #[clap(long, env = "AUTO_SECRET_EXPORT_GIT_KNOWN_HOSTS")]
+    pub(crate) export_git_known_hosts: Option<String>,
+    /// Path of the git binary, which needs ssh next to it for ssh remotes
+    #[clap(long, env = "AUTO_SECRET_GIT", default_value = "git")]
+    pub(crate) git: String,
+    /// Directory that age encrypted backups of a secret's values are written
+    /// to before they are rotated or the secret is recreated
+    #[clap(
+        long,
+        env = "AUTO_SECRET_BACKUP_DIR",
+        requires = "export_recipients"
+    )]
+    pub(crate) backup_dir: Option<String>,
+    /// Number of backups kept per secret
+    #[clap(long, env = "AUTO_SECRET_BACKUP_RETENTION", default_value = "10")]
+    pub(crate) backup_retention: usize,
+    /// Remove backups older than this, e.g. `90d`. The newest backup of a
+    /// secret is always kept
+    #[clap(long, env = "AUTO_SECRET_BACKUP_MAX_AGE")]
+    pub(crate) backup_max_age: Option<Interval>,
+    /// cert-manager issuer, as `[<kind>/]<name>`, that `kind: tls` keys are
+    /// issued by instead of being self-signed, if cert-manager is installed
+    #[clap(long, env = "AUTO_SECRET_CERT_MANAGER_ISSUER")]
+    pub(crate) cert_manager_issuer: Option<String>,
+    /// Source of randomness for generated values
+    #[clap(long, env = "AUTO_SECRET_RNG", value_enum, default_value = "os")]
+    pub(crate) rng: RngKind,
+    /// Seed of the unsafe-seeded random source
+    #[clap(
+        long,
+        env = "AUTO_SECRET_RNG_SEED",
+        required_if_eq("rng", "unsafe-seeded")
+    )]
+    pub(crate) rng_seed: Option<u64>,
+    /// Format of the log, `pretty` in debug builds and `json` in release
+    /// builds by default
+    #[clap(long, env = "AUTO_SECRET_LOG_FORMAT", value_enum)]
+    pub(crate) log_format: Option<LogFormat>,
+    /// Log level or filter in the env_logger syntax, e.g. `info,kube=debug`.
+    /// Falls back to `RUST_LOG`, then `info`
+    #[clap(long, env = "AUTO_SECRET_LOG_LEVEL")]
+    pub(crate) log_level: Option<String>,
+    #[clap(skip)]
+    pub(crate) defaults: Defaults,
+    // Defaults of single namespaces from `--config`.
+    #[clap(skip)]
+    pub(crate) namespace_defaults: BTreeMap<String, Defaults>,
+    /// Number of secrets per namespace that may declare keys
+    #[clap(long, env = "AUTO_SECRET_MAX_SECRETS_PER_NAMESPACE")]
+    pub(crate) max_secrets_per_namespace: Option<usize>,
+    /// Number of keys the secrets of a namespace may declare in total
+    #[clap(long, env = "AUTO_SECRET_MAX_KEYS_PER_NAMESPACE")]
+    pub(crate) max_keys_per_namespace: Option<usize>,
+    // Quotas of single namespaces from `--config`.
+    #[clap(skip)]
+    pub(crate) namespace_quotas: BTreeMap<String, Quota>,
+    /// YAML file with defaults, namespace defaults and quotas, policy and
+    /// audit sinks, which take precedence over the flags. Reloaded when it
+    /// changes
+    #[clap(long, env = "AUTO_SECRET_CONFIG")]
+    pub(crate) config: Option<String>,
+    #[clap(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+impl Opts {
+    /// Whether the operator runs its controllers, rather than a subcommand.
+    pub fn runs_controllers(&self) -> bool {
+        self.command.is_none()
+    }
+
+    // The labels `--label-selector` requires to equal a value, which secrets
+    // the operator creates are given so that they are watched as well.
+    pub(crate) fn selector_labels(&self) -> BTreeMap<String, String> {
+        let Some(selector) = &self.label_selector else {
+            return BTreeMap::new();
+        };
+        let mut depth = 0;
+        let terms = selector.split(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => (),
+            }
+            c == ',' && depth == 0
+        });
+        terms
+            .filter(|term| !term.contains("!="))
+            .filter_map(|term| {
+                let (key, value) = term.split_once('=')?;
+                let value = value.strip_prefix('=').unwrap_or(value);
+                Some((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect()
+    }
+
+    // Whether `namespace` passes `--namespaces`, `--exclude-namespaces` and
+    // `--watch-namespace`.
+    pub(crate) fn namespace_allowed(&self, namespace: &str) -> bool {
+        let matches = |pattern: &String| glob_match(pattern, namespace);
+        (self.namespaces.is_empty() || self.namespaces.iter().any(matches))
+            && !self.exclude_namespaces.iter().any(matches)
+            && self
+                .watch_namespace
+                .as_ref()
+                .is_none_or(|ns| ns == namespace)
+    }
+
+    // The quota of `namespace`, the limits it doesn't set from the flags.
+    pub(crate) fn quota(&self, namespace: &str) -> Quota {
+        let flags = Quota {
+            secrets: self.max_secrets_per_namespace,
+            keys: self.max_keys_per_namespace,
+        };
+        flags.layer(
+            self.namespace_quotas
+                .get(namespace)
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+
+    // Whether this replica watches and reconciles objects in `namespace`.
+    pub(crate) fn watches_namespace(&self, namespace: &str) -> bool {
+        self.namespace_allowed(namespace) && self.in_shard(namespace)
+    }
+
+    // A field selector leaving out the namespaces that are excluded by name,
+    // or selecting the only allowed one. Globs are filtered client-side.
+    pub(crate) fn namespace_selector(&self) -> Option<String> {
+        let literal = |pattern: &&String| !pattern.contains(['*', '?']);
+        let mut fields: Vec<String> = match self.namespaces.as_slice() {
+            [only] if literal(&only) => {
+                vec![format!("metadata.namespace={}", only)]
+            }
+            _ => vec![],
+        };
+        fields.extend(
+            self.exclude_namespaces
+                .iter()
+                .filter(literal)
+                .map(|ns| format!("metadata.namespace!={}", ns)),
+        );
+        (!fields.is_empty()).then(|| fields.join(","))
+    }
+
+    // Whether objects in `namespace` belong to the shard of this replica.
+    pub(crate) fn in_shard(&self, namespace: &str) -> bool {
+        if self.shard_count == 1 {
+            return true;
+        }
+        let hash = sha256(namespace.as_bytes());
+        let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        hash % u64::from(self.shard_count) == u64::from(self.shard_index)
+    }
+}
+
+// The options of an operator started without arguments.
+impl Default for Opts {
+    fn default() -> Self {
+        Opts::parse_from(["auto-secret"])
+    }
+}
+
+fn parse_qps(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(qps) if qps.is_finite() && qps > 0.0 => Ok(qps),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
+// Matches `text` against a pattern where `*` stands for any number of
+// characters and `?` for a single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(p) => text.chars().next().is_some_and(|t| {
+            (p == '?' || p == t)
+                && glob_match(&pattern[p.len_utf8()..], &text[t.len_utf8()..])
+        }),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FloorAction {
+    /// Raise the length and add the missing classes
+    Clamp,
+    /// Refuse to generate the key
+    Reject,
+}
+
+#[derive(Subcommand, Clone)]
+pub(crate) enum Command {
+    /// Rewrite mittwald kubernetes-secret-generator annotations to native ones
+    Migrate {
+        /// Read manifests from stdin and print them rewritten instead of
+        /// scanning the cluster
+        #[clap(long)]
+        stdin: bool,
+        /// Patch the secrets in the cluster instead of printing the patches
+        #[clap(long, conflicts_with = "stdin")]
+        apply: bool,
+    },
+    /// Generate the values of a gen annotation without a cluster and print
+    /// them
+    Generate {
+        /// The gen annotation, as YAML
+        #[clap(long)]
+        settings: String,
+        /// File with defaults in the format of the `defaults.yaml` key of
+        /// `--defaults-config-map`
+        #[clap(long)]
+        defaults_file: Option<String>,
+        /// Print the values or a Secret manifest holding them
+        #[clap(short, long, value_enum, default_value = "values")]
+        output: GenerateOutput,
+        /// Name of the printed Secret
+        #[clap(long, default_value = "generated")]
+        name: String,
+        /// Namespace of the printed Secret
+        #[clap(short, long)]
+        namespace: Option<String>,
+    },
+    /// Create a secret declaring the given keys and wait for the operator to
+    /// generate them
+    Create {
+        /// Name of the secret
+        name: String,
+        /// Namespace of the secret, the one of the current context by
+        /// default
+        #[clap(short, long)]
+        namespace: Option<String>,
+        /// Key to generate as `<key>=<settings>` in the syntax of per-key
+        /// annotations, e.g. `password=length:64,digit,letter`, or just
+        /// `<key>` for the default preset. Can be given multiple times
+        #[clap(short, long = "key", required = true)]
+        keys: Vec<String>,
+        /// How long to wait for the values, `0s` to not wait
+        #[clap(long, default_value = "30s")]
+        timeout: Interval,
+    },
+    /// List the secrets declaring keys, with how many of them were generated
+    /// and when the next one is rotated
+    List {
+        /// Namespace to list, the one of the current context by default
+        #[clap(short, long)]
+        namespace: Option<String>,
+        /// List the secrets of all namespaces
+        #[clap(short = 'A', long, conflicts_with = "namespace")]
+        all_namespaces: bool,
+    },
+    /// Show the declared keys of a secret: whether and when they were
+    /// generated, their settings and their next rotation
+    Inspect {
+        /// Name of the secret
+        name: String,
+        /// Namespace of the secret, the one of the current context by
+        /// default
+        #[clap(short, long)]
+        namespace: Option<String>,
+    },
+    /// Report the annotated secrets of all namespaces that are missing
+    /// values, and optionally generate them
+    Bootstrap {
+        /// Generate the missing values instead of only reporting them
+        #[clap(long)]
+        apply: bool,
+        /// How many secrets to generate per second with `--apply`
+        #[clap(long, default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+        rate: u32,
+    },
+    /// Print an inventory of every managed secret, with the generator,
+    /// generation time, age and next rotation of its keys
+    Report {
+        /// Format of the inventory
+        #[clap(short, long, value_enum, default_value = "json")]
+        format: ReportFormat,
+    },
+    /// Apply the values of an encrypted snapshot or backup to its secret
+    /// again, as values the operator manages
+    Restore {
+        /// The snapshot or backup to restore
+        #[clap(long)]
+        from: String,
+        /// age identity file with the key the snapshot is encrypted to
+        #[clap(short, long)]
+        identity: String,
+    },
+    /// Check the annotations of Secret and ConfigMap manifests against the
+    /// configured policy and exit non-zero if there are problems
+    Validate {
+        /// Manifest files to check, `-` for stdin. Reads stdin if none are
+        /// given
+        #[clap(short = 'f', long = "filename")]
+        files: Vec<String>,
+        /// File with defaults in the format of the `defaults.yaml` key of
+        /// `--defaults-config-map`
+        #[clap(long)]
+        defaults_file: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ApplyConflicts {
+    /// Take the keys over from the other field managers
+    Force,
+    /// Leave the keys alone and report a conflict
+    Fail,
+    /// Never generate into keys other field managers own, unless the secret
+    /// allows adopting them
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum WatchList {
+    /// List with a quorum read
+    Consistent,
+    /// List from the API server's cache
+    Cached,
+    /// Stream the initial objects through the watch
+    Streaming,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// A JSON object per line
+    Json,
+    /// Coloured lines
+    Pretty,
+    /// Plain lines with a timestamp
+    Compact,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReportFormat {
+    /// A JSON array of the secrets and their keys
+    Json,
+    /// A CSV row per key
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum GenerateOutput {
+    /// A YAML map of the keys to their values
+    Values,
+    /// A Secret annotated with the settings, ready to be applied
+    Secret,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn flags_have_env_vars() {
+        for arg in Opts::command().get_arguments() {
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            let env = arg.get_env().and_then(|env| env.to_str());
+            let expected = match long {
+                "namespace" => "POD_NAMESPACE".to_string(),
+                "leader-election-identity" => "POD_NAME".to_string(),
+                _ => format!(
+                    "AUTO_SECRET_{}",
+                    long.to_uppercase().replace('-', "_")
+                ),
+            };
+            assert_eq!(env, Some(expected.as_str()), "--{long}");
+        }
+    }
+
+    #[test]
+    fn shards() {
+        let shard = |index: &str| {
+            Opts::parse_from(["auto-secret", "--shard-count=3", index])
+        };
+        let shards = [
+            shard("--shard-index=0"),
+            shard("--shard-index=1"),
+            shard("--shard-index=2"),
+        ];
+        for namespace in ["default", "kube-system", "team-a", "team-b"] {
+            let owners = shards.iter().filter(|o| o.in_shard(namespace));
+            assert_eq!(owners.count(), 1, "{namespace}");
+        }
+        assert!(Opts::default().in_shard("default"));
+    }
+
+    #[test]
+    fn namespace_lists() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--namespaces=team-*,default",
+            "--exclude-namespaces=team-legacy,*-tmp",
+        ]);
+        assert!(opts.namespace_allowed("team-a"));
+        assert!(opts.namespace_allowed("default"));
+        assert!(!opts.namespace_allowed("kube-system"));
+        assert!(!opts.namespace_allowed("team-legacy"));
+        assert!(!opts.namespace_allowed("team-b-tmp"));
+        assert_eq!(
+            opts.namespace_selector().as_deref(),
+            Some("metadata.namespace!=team-legacy")
+        );
+
+        let opts = Opts::parse_from(["auto-secret", "--namespaces=team-a"]);
+        assert_eq!(
+            opts.namespace_selector().as_deref(),
+            Some("metadata.namespace=team-a")
+        );
+        let opts =
+            Opts::parse_from(["auto-secret", "--watch-namespace=team-a"]);
+        assert!(opts.namespace_allowed("team-a"));
+        assert!(!opts.namespace_allowed("team-b"));
+        assert!(glob_match("t?am-*", "team-ä"));
+        assert!(!glob_match("team", "team-a"));
+        assert_eq!(Opts::default().namespace_selector(), None);
+    }
+
+    #[test]
+    fn selector_labels() {
+        let opts = Opts::parse_from([
+            "auto-secret",
+            "--label-selector=auto-secret=enabled, tier==db,env in (a,b),!x,y!=z",
+        ]);
+        assert_eq!(
+            opts.selector_labels(),
+            BTreeMap::from([
+                ("auto-secret".to_string(), "enabled".to_string()),
+                ("tier".to_string(), "db".to_string()),
+            ])
+        );
+        assert!(Opts::default().selector_labels().is_empty());
+    }
+}
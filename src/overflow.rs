@@ -0,0 +1,138 @@
+// The size limit of secrets. Generated keystores or DH parameters can push a
+// secret past the 1 MiB the API server accepts, so its size is checked before
+// it is written, and keys listed in `overflow-keys` go into a companion
+// secret named `<name>-overflow` instead.
+
+use crate::{controller::wipe, error::Error, generate::SecretData};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, ResourceExt,
+};
+use std::collections::BTreeSet;
+
+// The limit of the API server on the keys and values of a secret.
+pub(crate) const MAX_SECRET_SIZE: usize = 1024 * 1024;
+
+// Secrets above this percentage of the limit are reported.
+const WARN_PERCENT: usize = 80;
+
+// The size of `data` as the API server counts it against the limit.
+pub(crate) fn data_size(data: &SecretData) -> usize {
+    data.iter()
+        .map(|(key, value)| key.len() + value.0.len())
+        .sum()
+}
+
+// Fails if `data` doesn't fit into the secret `name`. Returns a warning when
+// it gets close to the limit.
+pub(crate) fn check_size(
+    name: &str,
+    data: &SecretData,
+) -> Result<Option<String>, Error> {
+    let size = data_size(data);
+    let largest = data
+        .iter()
+        .max_by_key(|(_, value)| value.0.len())
+        .map(|(key, _)| key.as_str())
+        .unwrap_or_default();
+    if size > MAX_SECRET_SIZE {
+        return Err(Error::TooLarge(format!(
+            "secret {} would hold {} bytes, more than the limit of {}, the \
+             largest key is {:?}",
+            name, size, MAX_SECRET_SIZE, largest
+        )));
+    }
+    Ok((size * 100 > MAX_SECRET_SIZE * WARN_PERCENT).then(|| {
+        format!(
+            "secret holds {} bytes, {}% of the limit, the largest key is {:?}",
+            size,
+            size * 100 / MAX_SECRET_SIZE,
+            largest
+        )
+    }))
+}
+
+// The keys listed in the `overflow-keys` annotation.
+pub(crate) fn overflow_keys(secret: &Secret) -> BTreeSet<String> {
+    secret
+        .annotations()
+        .get(app_id!("overflow-keys"))
+        .into_iter()
+        .flat_map(|keys| keys.split(','))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn companion_name(name: &str) -> String {
+    format!("{}-overflow", name)
+}
+
+// Reads the data of the companion of the secret `name`, empty if there is
+// none. Secrets of the same name that aren't marked as its companion are
+// never used.
+pub(crate) async fn read_companion(
+    api: &Api<Secret>,
+    name: &str,
+) -> Result<SecretData, Error> {
+    let companion = companion_name(name);
+    let Some(secret) = api.get_opt(&companion).await? else {
+        return Ok(SecretData::default());
+    };
+    match secret.annotations().get(app_id!("overflow-of")) {
+        Some(of) if of == name => {
+            Ok(SecretData(secret.data.unwrap_or_default()))
+        }
+        _ => Err(Error::Conflict(format!(
+            "secret {} exists but is not the overflow of {}",
+            companion, name
+        ))),
+    }
+}
+
+// Applies `data` to the companion of the secret `name`.
+pub(crate) async fn write_companion(
+    api: &Api<Secret>,
+    name: &str,
+    data: SecretData,
+) -> Result<(), Error> {
+    let companion = companion_name(name);
+    let mut patch = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": companion,
+            "annotations": {app_id!("overflow-of"): name},
+        },
+        "data": data,
+    });
+    let params = PatchParams::apply(app_id!()).force();
+    let patched = api.patch(&companion, &params, &Patch::Apply(&patch)).await;
+    wipe(&mut patch);
+    patched?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::ByteString;
+
+    #[test]
+    fn sizes() {
+        let mut data = SecretData::default();
+        data.insert("small".to_string(), ByteString(vec![0; 1024]));
+        assert_eq!(data_size(&data), 1029);
+        assert_eq!(check_size("db", &data).unwrap(), None);
+
+        data.insert("dhparam.pem".to_string(), ByteString(vec![0; 900_000]));
+        let warning = check_size("db", &data).unwrap().unwrap();
+        assert!(warning.contains("85% of the limit"));
+        assert!(warning.contains("\"dhparam.pem\""));
+
+        data.insert("keystore".to_string(), ByteString(vec![0; 200_000]));
+        assert!(matches!(check_size("db", &data), Err(Error::TooLarge(_))));
+    }
+}
@@ -0,0 +1,145 @@
+// Generator plugins are WebAssembly components implementing the `generator`
+// world of `wit/plugin.wit`. They are loaded from `--plugin-dir`, e.g. a
+// mounted ConfigMap, and used by their file name, either as `kind: <name>`
+// or with `kind: plugin`.
+//
+// Plugins only see what they are passed and draw randomness from the
+// operator. Their memory and the instructions they may execute per value are
+// limited.
+
+use crate::error::Error;
+#[cfg(feature = "wasm")]
+use std::collections::BTreeMap;
+use zeroize::Zeroizing;
+
+#[cfg(feature = "wasm")]
+use wasmtime::{
+    component::{Component, HasSelf, Linker},
+    Config, Engine, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+#[cfg(feature = "wasm")]
+wasmtime::component::bindgen!({
+    world: "generator",
+    path: "wit/plugin.wit",
+});
+
+#[cfg(feature = "wasm")]
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 1_000_000_000;
+#[cfg(feature = "wasm")]
+const MAX_RANDOM: u32 = 64 * 1024;
+
+#[cfg(feature = "wasm")]
+pub struct Plugins {
+    engine: Engine,
+    linker: Linker<Host>,
+    components: BTreeMap<String, Component>,
+}
+
+#[cfg(not(feature = "wasm"))]
+pub enum Plugins {}
+
+#[cfg(feature = "wasm")]
+struct Host {
+    limits: StoreLimits,
+}
+
+#[cfg(feature = "wasm")]
+impl GeneratorImports for Host {
+    fn random(&mut self, len: u32) -> Vec<u8> {
+        let mut bytes = vec![0; len.min(MAX_RANDOM) as usize];
+        crate::crypto::fill_random(&mut bytes);
+        bytes
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Plugins {
+    pub fn load(dir: &str) -> Result<Self, Error> {
+        let failed = |e: wasmtime::Error| Error::Plugin(e.to_string());
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(failed)?;
+        let mut linker = Linker::new(&engine);
+        Generator::add_to_linker::<_, HasSelf<_>>(&mut linker, |host| host)
+            .map_err(failed)?;
+        let mut components = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            // ConfigMap volumes also contain hidden directories.
+            if !path.is_file() || path.extension().is_none_or(|e| e != "wasm") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let component = Component::from_file(&engine, &path)
+                .map_err(|e| Error::Plugin(format!("{}: {}", name, e)))?;
+            components.insert(name.to_string(), component);
+        }
+        log::info!(
+            "loaded plugins: {}",
+            components.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        Ok(Plugins {
+            engine,
+            linker,
+            components,
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.components.keys().cloned().collect()
+    }
+
+    // Each value is generated in a fresh instance, so that plugins can't
+    // keep state between values.
+    pub fn generate(
+        &self,
+        name: &str,
+        settings: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let failed = |e: String| Error::Plugin(format!("{}: {}", name, e));
+        let component = self
+            .components
+            .get(name)
+            .ok_or_else(|| failed("not loaded".to_string()))?;
+        let host = Host {
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL).map_err(|e| failed(e.to_string()))?;
+        let generator =
+            Generator::instantiate(&mut store, component, &self.linker)
+                .map_err(|e| failed(e.to_string()))?;
+        let value = generator
+            .call_generate(&mut store, settings)
+            .map_err(|e| failed(e.to_string()))?
+            .map_err(failed)?;
+        Ok(Zeroizing::new(value))
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugins {
+    pub fn load(_dir: &str) -> Result<Self, Error> {
+        Err(Error::Plugin(
+            "plugins need a build with the wasm feature".to_string(),
+        ))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        match *self {}
+    }
+
+    pub fn generate(
+        &self,
+        _name: &str,
+        _settings: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        match *self {}
+    }
+}
@@ -0,0 +1,152 @@
+// Quotas on the secrets declaring keys and on the keys they declare, per
+// namespace. Secrets are admitted in the order they were created, so that a
+// new secret over the quota doesn't hold back the ones before it.
+
+use crate::{error::Error, opts::Opts, settings::declared_keys};
+use k8s_openapi::{
+    api::core::v1::Secret,
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+};
+use kube::{api::ListParams, Api, Client, ResourceExt};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Quota {
+    pub(crate) secrets: Option<usize>,
+    pub(crate) keys: Option<usize>,
+}
+
+impl Quota {
+    // `other` with the limits it doesn't set taken from `self`.
+    pub(crate) fn layer(self, other: Quota) -> Quota {
+        Quota {
+            secrets: other.secrets.or(self.secrets),
+            keys: other.keys.or(self.keys),
+        }
+    }
+}
+
+// A secret declaring keys.
+struct Declaration {
+    created: Option<Time>,
+    name: String,
+    keys: usize,
+}
+
+// The number of keys declared in `meta`, including invalid ones, or `None`
+// if it declares none.
+fn declared(meta: &ObjectMeta) -> Option<usize> {
+    match declared_keys(meta) {
+        Ok(declared) => {
+            declared.map(|(keys, failed)| keys.len() + failed.len())
+        }
+        // Broken declarations don't get anything generated.
+        Err(_) => Some(0),
+    }
+}
+
+// Why `name` exceeds `quota`, counting the declarations created before it.
+fn exceeded(
+    quota: Quota,
+    mut declarations: Vec<Declaration>,
+    name: &str,
+) -> Option<String> {
+    declarations
+        .sort_by(|a, b| (&a.created, &a.name).cmp(&(&b.created, &b.name)));
+    let (mut secrets, mut keys) = (0, 0);
+    for declaration in declarations {
+        secrets += 1;
+        keys += declaration.keys;
+        if declaration.name == name {
+            break;
+        }
+    }
+    if let Some(max) = quota.secrets.filter(|max| secrets > *max) {
+        return Some(format!(
+            "the namespace may declare keys in {} secrets",
+            max
+        ));
+    }
+    if let Some(max) = quota.keys.filter(|max| keys > *max) {
+        return Some(format!(
+            "the namespace may declare {} keys, {} with this secret",
+            max, keys
+        ));
+    }
+    None
+}
+
+// Fails if `secret` exceeds the quota of its namespace.
+pub(crate) async fn check_quota(
+    client: &Client,
+    opts: &Opts,
+    secret: &Secret,
+) -> Result<(), Error> {
+    let namespace = secret.namespace().unwrap_or_default();
+    let quota = opts.quota(&namespace);
+    if quota == Quota::default() {
+        return Ok(());
+    }
+    // Only the annotations are needed, of all secrets and not just those of
+    // this replica's shard.
+    let api = Api::<Secret>::namespaced(client.clone(), &namespace);
+    let secrets = api.list_metadata(&ListParams::default()).await?;
+    let declarations = secrets
+        .items
+        .iter()
+        .filter_map(|s| {
+            Some(Declaration {
+                created: s.metadata.creation_timestamp.clone(),
+                name: s.name_any(),
+                keys: declared(&s.metadata)?,
+            })
+        })
+        .collect();
+    match exceeded(quota, declarations, &secret.name_any()) {
+        Some(e) => Err(Error::QuotaExceeded(e)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::chrono::{TimeZone, Utc};
+
+    fn declaration(name: &str, second: u32, keys: usize) -> Declaration {
+        let created = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, second).unwrap();
+        Declaration {
+            created: Some(Time(created)),
+            name: name.to_string(),
+            keys,
+        }
+    }
+
+    #[test]
+    fn oldest_first() {
+        let declarations = || {
+            vec![
+                declaration("c", 2, 2),
+                declaration("a", 0, 2),
+                declaration("b", 1, 2),
+            ]
+        };
+        let quota = Quota {
+            secrets: Some(2),
+            keys: None,
+        };
+        assert_eq!(exceeded(quota, declarations(), "a"), None);
+        assert_eq!(exceeded(quota, declarations(), "b"), None);
+        assert!(exceeded(quota, declarations(), "c").is_some());
+        let quota = Quota {
+            secrets: None,
+            keys: Some(3),
+        };
+        assert_eq!(exceeded(quota, declarations(), "a"), None);
+        assert_eq!(
+            exceeded(quota, declarations(), "b").unwrap(),
+            "the namespace may declare 3 keys, 4 with this secret"
+        );
+    }
+}
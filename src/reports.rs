@@ -0,0 +1,178 @@
+// AutoSecretReports summarizing the managed secrets of each namespace, so
+// that teams can review them with kubectl without reading the secrets. They
+// hold the inventory of the `report` subcommand and are rewritten every
+// `--report-interval` by the replica running the controllers.
+
+use crate::{
+    cli::{inventory, SecretReport},
+    controller::Context,
+    error::Error,
+};
+use kube::{
+    api::{
+        Api, ApiResource, DeleteParams, DynamicObject, GroupVersionKind,
+        ListParams, Patch, PatchParams,
+    },
+    ResourceExt,
+};
+use log::{info, warn};
+use std::{collections::BTreeMap, sync::Arc};
+
+// The name of the report in each namespace.
+const REPORT_NAME: &str = "auto-secret";
+const MANAGED_BY: &str = "app.kubernetes.io/managed-by=auto-secret-operator";
+
+fn report_resource() -> ApiResource {
+    let gvk = GroupVersionKind::gvk(app_id!(), "v1alpha1", "AutoSecretReport");
+    ApiResource::from_gvk_with_plural(&gvk, "autosecretreports")
+}
+
+// The report of the managed secrets of a namespace.
+fn report(namespace: &str, secrets: &[&SecretReport]) -> serde_json::Value {
+    let keys = secrets.iter().flat_map(|s| &s.keys);
+    let missing = keys.clone().filter(|k| !k.generated).count();
+    // Timestamps in the same format sort by time.
+    let next_rotation = keys.clone().filter_map(|k| k.next_rotation.as_ref());
+    let secrets: Vec<_> = secrets
+        .iter()
+        .map(|secret| {
+            let keys: Vec<_> = secret
+                .keys
+                .iter()
+                .map(|key| {
+                    serde_json::json!({
+                        "key": key.key,
+                        "kind": key.kind,
+                        "generated": key.generated,
+                        "generatedAt": key.generated_at,
+                        "ageSeconds": key.age_seconds,
+                        "nextRotation": key.next_rotation,
+                        "policy": key.policy,
+                    })
+                })
+                .collect();
+            serde_json::json!({"name": secret.name, "keys": keys})
+        })
+        .collect();
+    let resource = report_resource();
+    serde_json::json!({
+        "apiVersion": resource.api_version,
+        "kind": resource.kind,
+        "metadata": {
+            "name": REPORT_NAME,
+            "namespace": namespace,
+            "labels": {"app.kubernetes.io/managed-by": "auto-secret-operator"},
+        },
+        "summary": {
+            "secrets": secrets.len(),
+            "keys": keys.count(),
+            "missing": missing,
+            "nextRotation": next_rotation.min(),
+        },
+        "secrets": secrets,
+    })
+}
+
+// Applies the reports of the namespaces with managed secrets and deletes the
+// reports of namespaces without any.
+async fn write_reports(ctx: &Context) -> Result<(), Error> {
+    let opts = ctx.configured();
+    let inventory = inventory(&ctx.client, &opts).await?;
+    let mut namespaces: BTreeMap<&str, Vec<&SecretReport>> = BTreeMap::new();
+    for secret in &inventory {
+        namespaces
+            .entry(&secret.namespace)
+            .or_default()
+            .push(secret);
+    }
+    let resource = report_resource();
+    let params = PatchParams::apply(app_id!()).force();
+    for (namespace, secrets) in &namespaces {
+        let api = Api::<DynamicObject>::namespaced_with(
+            ctx.client.clone(),
+            namespace,
+            &resource,
+        );
+        let patch = report(namespace, secrets);
+        api.patch(REPORT_NAME, &params, &Patch::Apply(&patch))
+            .await?;
+    }
+    let all = match &opts.watch_namespace {
+        Some(ns) => Api::namespaced_with(ctx.client.clone(), ns, &resource),
+        None => Api::<DynamicObject>::all_with(ctx.client.clone(), &resource),
+    };
+    let stale = all.list(&ListParams::default().labels(MANAGED_BY)).await?;
+    for report in stale {
+        let namespace = report.namespace().unwrap_or_default();
+        if namespaces.contains_key(namespace.as_str()) {
+            continue;
+        }
+        let api = Api::<DynamicObject>::namespaced_with(
+            ctx.client.clone(),
+            &namespace,
+            &resource,
+        );
+        api.delete(&report.name_any(), &DeleteParams::default())
+            .await?;
+    }
+    info!("wrote reports of {} namespaces", namespaces.len());
+    Ok(())
+}
+
+// Writes the reports every `--report-interval`, for as long as the
+// controllers run.
+pub(crate) async fn write_report_resources(ctx: Arc<Context>) {
+    let mut ticks = tokio::time::interval(ctx.opts.report_interval.0);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = write_reports(&ctx).await {
+            warn!("failed to write AutoSecretReports: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::KeyState;
+
+    #[test]
+    fn summary() {
+        let key = |key: &str, generated, next: Option<&str>| KeyState {
+            key: key.into(),
+            kind: "random".into(),
+            generated,
+            generated_at: None,
+            age_seconds: None,
+            next_rotation: next.map(Into::into),
+            policy: None,
+            settings: String::new(),
+        };
+        let db = SecretReport {
+            namespace: "dev".into(),
+            name: "db".into(),
+            keys: vec![
+                key("password", true, Some("2026-05-01T00:00:00Z")),
+                key("token", false, None),
+            ],
+        };
+        let api = SecretReport {
+            namespace: "dev".into(),
+            name: "api".into(),
+            keys: vec![key("key", true, Some("2026-04-01T00:00:00Z"))],
+        };
+        let report = report("dev", &[&db, &api]);
+        assert_eq!(report["metadata"]["namespace"], "dev");
+        assert_eq!(
+            report["summary"],
+            serde_json::json!({
+                "secrets": 2,
+                "keys": 3,
+                "missing": 1,
+                "nextRotation": "2026-04-01T00:00:00Z",
+            })
+        );
+        assert_eq!(report["secrets"][0]["keys"][1]["key"], "token");
+        assert!(report["secrets"][0]["keys"][0]["generatedAt"].is_null());
+    }
+}
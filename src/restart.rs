@@ -0,0 +1,161 @@
+// Rollout restarts of the workloads using a secret whose keys were rotated,
+// so their pods pick up the new values. Only workloads annotated with
+// `restart-on-rotation` are restarted, like `kubectl rollout restart` does.
+
+use crate::{controller::Context, error::Error};
+use k8s_openapi::{
+    api::{
+        apps::v1::{DaemonSet, Deployment, StatefulSet},
+        core::v1::{PodSpec, PodTemplateSpec},
+    },
+    chrono::{SecondsFormat, Utc},
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, Resource, ResourceExt,
+};
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+// Restarts the opted-in Deployments, StatefulSets and DaemonSets in
+// `namespace` whose pods use the secret `name`. Returns them as
+// `<kind>/<name>`.
+pub(crate) async fn restart_workloads(
+    ctx: &Context,
+    namespace: &str,
+    name: &str,
+) -> Result<Vec<String>, Error> {
+    let mut restarted = vec![];
+    restarted.extend(
+        restart::<Deployment>(ctx, namespace, name, |d| {
+            d.spec.as_ref().map(|s| &s.template)
+        })
+        .await?,
+    );
+    restarted.extend(
+        restart::<StatefulSet>(ctx, namespace, name, |s| {
+            s.spec.as_ref().map(|s| &s.template)
+        })
+        .await?,
+    );
+    restarted.extend(
+        restart::<DaemonSet>(ctx, namespace, name, |d| {
+            d.spec.as_ref().map(|s| &s.template)
+        })
+        .await?,
+    );
+    Ok(restarted)
+}
+
+async fn restart<K>(
+    ctx: &Context,
+    namespace: &str,
+    name: &str,
+    template: fn(&K) -> Option<&PodTemplateSpec>,
+) -> Result<Vec<String>, Error>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+        + Clone
+        + DeserializeOwned
+        + fmt::Debug,
+{
+    let api = Api::<K>::namespaced(ctx.client.clone(), namespace);
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    // Changing the pod template makes the workload replace its pods.
+    let patch = serde_json::json!({
+        "spec": {"template": {"metadata": {"annotations": {
+            "kubectl.kubernetes.io/restartedAt": now,
+        }}}},
+    });
+    let mut restarted = vec![];
+    for workload in api.list(&ListParams::default()).await? {
+        let opted_in = workload
+            .annotations()
+            .get(app_id!("restart-on-rotation"))
+            .is_some_and(|v| v == "true");
+        let uses = template(&workload)
+            .and_then(|t| t.spec.as_ref())
+            .is_some_and(|spec| uses_secret(spec, name));
+        if !opted_in || !uses {
+            continue;
+        }
+        let workload_name = workload.name_any();
+        api.patch(
+            &workload_name,
+            &PatchParams::apply(app_id!()),
+            &Patch::Merge(&patch),
+        )
+        .await?;
+        restarted.push(format!(
+            "{}/{}",
+            K::kind(&()).to_lowercase(),
+            workload_name
+        ));
+    }
+    Ok(restarted)
+}
+
+// Whether pods with `spec` read the secret `name` through environment
+// variables or volumes.
+fn uses_secret(spec: &PodSpec, name: &str) -> bool {
+    let containers = spec
+        .containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten());
+    let env = containers.clone().flat_map(|c| c.env.iter().flatten());
+    let env_from = containers.flat_map(|c| c.env_from.iter().flatten());
+    let volumes = spec.volumes.iter().flatten();
+    let projected = volumes
+        .clone()
+        .filter_map(|v| v.projected.as_ref()?.sources.as_ref())
+        .flatten();
+    env.filter_map(|e| e.value_from.as_ref()?.secret_key_ref.as_ref())
+        .any(|r| r.name == name)
+        || env_from
+            .filter_map(|e| e.secret_ref.as_ref())
+            .any(|r| r.name == name)
+        || volumes
+            .filter_map(|v| v.secret.as_ref()?.secret_name.as_deref())
+            .any(|n| n == name)
+        || projected
+            .filter_map(|p| p.secret.as_ref())
+            .any(|s| s.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_use() {
+        let spec = |spec: serde_json::Value| -> PodSpec {
+            serde_json::from_value(spec).unwrap()
+        };
+        let env = spec(serde_json::json!({"containers": [{
+            "name": "app",
+            "env": [{"name": "PASSWORD", "valueFrom": {
+                "secretKeyRef": {"name": "db", "key": "password"},
+            }}],
+        }]}));
+        assert!(uses_secret(&env, "db"));
+        assert!(!uses_secret(&env, "api"));
+        let env_from = spec(serde_json::json!({"initContainers": [{
+            "name": "migrate",
+            "envFrom": [{"secretRef": {"name": "db"}}],
+        }], "containers": []}));
+        assert!(uses_secret(&env_from, "db"));
+        let volume = spec(serde_json::json!({
+            "containers": [],
+            "volumes": [{"name": "tls", "secret": {"secretName": "db"}}],
+        }));
+        assert!(uses_secret(&volume, "db"));
+        let projected = spec(serde_json::json!({
+            "containers": [],
+            "volumes": [{"name": "all", "projected": {
+                "sources": [{"secret": {"name": "db"}}],
+            }}],
+        }));
+        assert!(uses_secret(&projected, "db"));
+    }
+}
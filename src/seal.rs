@@ -0,0 +1,223 @@
+// SealedSecrets of generated secrets, for committing them to git. The data is
+// encrypted against the public key of the sealed-secrets controller the way
+// kubeseal does it with the strict scope, so that the SealedSecret only
+// unseals into a secret of the same name and namespace.
+
+use crate::{
+    controller::{data_checksum, Context},
+    crypto::{fill_random, fips, Random},
+    error::Error,
+    generate::SecretData,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams},
+    Api,
+};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use rsa::{pkcs8::DecodePublicKey, traits::PublicKeyParts, Oaep, RsaPublicKey};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use zeroize::Zeroizing;
+
+const SESSION_KEY_BYTES: usize = 32;
+// Smaller keys are refused, sealed-secrets generates 4096 bit ones.
+const MIN_KEY_BITS: usize = 2048;
+
+// An RSA public key, from the controller's certificate.
+pub(crate) struct PublicKey(RsaPublicKey);
+
+impl PublicKey {
+    pub(crate) fn from_pem(pem: &[u8]) -> Result<PublicKey, Error> {
+        use x509_parser::pem::parse_x509_pem;
+        let invalid = |e: String| Error::Seal(format!("certificate: {}", e));
+        let (_, pem) =
+            parse_x509_pem(pem).map_err(|e| invalid(e.to_string()))?;
+        let cert = pem.parse_x509().map_err(|e| invalid(e.to_string()))?;
+        let key = RsaPublicKey::from_public_key_der(cert.public_key().raw)
+            .map_err(|_| invalid("not an RSA key".to_string()))?;
+        PublicKey::new(key)
+    }
+
+    fn new(key: RsaPublicKey) -> Result<PublicKey, Error> {
+        let bits = key.size() * 8;
+        if bits < MIN_KEY_BITS {
+            return Err(Error::Seal(format!(
+                "certificate: RSA key of {} bits, at least {} are required",
+                bits, MIN_KEY_BITS
+            )));
+        }
+        Ok(PublicKey(key))
+    }
+
+    // RSAES-OAEP with SHA-256 of RFC 8017.
+    fn encrypt_oaep(
+        &self,
+        message: &[u8],
+        label: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let padding = Oaep::new_with_label::<Sha256, _>(label);
+        self.0
+            .encrypt(&mut Random, padding, message)
+            .map_err(|e| Error::Seal(e.to_string()))
+    }
+}
+
+// Encrypts `plaintext` like sealed-secrets does: a random AES-256-GCM session
+// key encrypted with RSA-OAEP, its length as two bytes before it, followed by
+// the plaintext encrypted with the session key.
+fn hybrid_encrypt(
+    key: &PublicKey,
+    plaintext: &[u8],
+    label: &str,
+) -> Result<Vec<u8>, Error> {
+    let mut session_key = Zeroizing::new([0; SESSION_KEY_BYTES]);
+    fill_random(&mut *session_key);
+    let encrypted_key = key.encrypt_oaep(&*session_key, label)?;
+    let mut ciphertext = (encrypted_key.len() as u16).to_be_bytes().to_vec();
+    ciphertext.extend_from_slice(&encrypted_key);
+    let session = UnboundKey::new(&AES_256_GCM, &*session_key)
+        .map_err(|_| Error::Seal("invalid session key".to_string()))?;
+    // Every session key encrypts a single value, so a zero nonce is safe.
+    let nonce = Nonce::assume_unique_for_key([0; 12]);
+    let mut sealed = plaintext.to_vec();
+    LessSafeKey::new(session)
+        .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+        .map_err(|_| Error::Seal("encryption failed".to_string()))?;
+    ciphertext.extend_from_slice(&sealed);
+    Ok(ciphertext)
+}
+
+// The values of `data`, encrypted for the secret `name` in `namespace`.
+fn encrypt_data(
+    key: &PublicKey,
+    namespace: &str,
+    name: &str,
+    data: &SecretData,
+) -> Result<BTreeMap<String, String>, Error> {
+    let label = format!("{}/{}", namespace, name);
+    data.iter()
+        .map(|(k, v)| {
+            let sealed = hybrid_encrypt(key, &v.0, &label)?;
+            Ok((k.clone(), BASE64_STANDARD.encode(sealed)))
+        })
+        .collect()
+}
+
+fn sealed_secret_resource() -> ApiResource {
+    let gvk = GroupVersionKind::gvk("bitnami.com", "v1alpha1", "SealedSecret");
+    ApiResource::from_gvk_with_plural(&gvk, "sealedsecrets")
+}
+
+// The public key to seal with, from `--sealed-secrets-cert` or fetched from
+// the controller like `kubeseal --fetch-cert` does.
+async fn public_key(ctx: &Context) -> Result<PublicKey, Error> {
+    if let Some(path) = &ctx.opts.sealed_secrets_cert {
+        return PublicKey::from_pem(&std::fs::read(path)?);
+    }
+    let controller = &ctx.opts.sealed_secrets_controller;
+    let (namespace, name) = controller.split_once('/').ok_or_else(|| {
+        Error::Seal(format!(
+            "invalid controller {:?}, expected <namespace>/<name>",
+            controller
+        ))
+    })?;
+    let uri = format!(
+        "/api/v1/namespaces/{}/services/http:{}:/proxy/v1/cert.pem",
+        namespace, name
+    );
+    let request = http::Request::get(uri)
+        .body(vec![])
+        .map_err(|e| Error::Seal(e.to_string()))?;
+    let pem = ctx.client.request_text(request).await?;
+    PublicKey::from_pem(pem.as_bytes())
+}
+
+// Writes the SealedSecret of the secret `name` in `namespace`, unless it
+// holds `data` already. Returns whether it was written.
+pub(crate) async fn seal_secret(
+    ctx: &Context,
+    namespace: &str,
+    name: &str,
+    type_: Option<&str>,
+    data: &SecretData,
+) -> Result<bool, Error> {
+    if fips() {
+        return Err(Error::NotApproved("sealing with sealed-secrets"));
+    }
+    let resource = sealed_secret_resource();
+    let api = Api::<DynamicObject>::namespaced_with(
+        ctx.client.clone(),
+        namespace,
+        &resource,
+    );
+    // Encryption is randomized, so the checksum tells whether the values
+    // changed since they were sealed.
+    let checksum = data_checksum(data);
+    let sealed = api.get_opt(name).await?;
+    let current = sealed.as_ref().and_then(|s| {
+        s.metadata.annotations.as_ref()?.get(app_id!("checksum"))
+    });
+    if current == Some(&checksum) {
+        return Ok(false);
+    }
+    let key = public_key(ctx).await?;
+    let patch = serde_json::json!({
+        "apiVersion": resource.api_version,
+        "kind": resource.kind,
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+            "annotations": {app_id!("checksum"): checksum},
+        },
+        "spec": {
+            "encryptedData": encrypt_data(&key, namespace, name, data)?,
+            "template": {
+                "metadata": {"name": name, "namespace": namespace},
+                "type": type_.unwrap_or("Opaque"),
+            },
+        },
+    });
+    let params = PatchParams::apply(app_id!()).force();
+    api.patch(name, &params, &Patch::Apply(&patch)).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::RsaPrivateKey;
+
+    // Decrypts like the sealed-secrets controller.
+    fn open(key: &RsaPrivateKey, ciphertext: &[u8], label: &str) -> Vec<u8> {
+        let len = u16::from_be_bytes([ciphertext[0], ciphertext[1]]) as usize;
+        let (encrypted_key, sealed) = ciphertext[2..].split_at(len);
+        let padding = Oaep::new_with_label::<Sha256, _>(label);
+        let session_key = key.decrypt(padding, encrypted_key).unwrap();
+        let session = UnboundKey::new(&AES_256_GCM, &session_key).unwrap();
+        let mut sealed = sealed.to_vec();
+        let nonce = Nonce::assume_unique_for_key([0; 12]);
+        LessSafeKey::new(session)
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        let private = RsaPrivateKey::new(&mut Random, 2048).unwrap();
+        let key = PublicKey::new(private.to_public_key()).unwrap();
+        let sealed = hybrid_encrypt(&key, b"hunter2", "ns/db").unwrap();
+        assert_eq!(&sealed[..2], &[1, 0]);
+        assert_eq!(open(&private, &sealed, "ns/db"), b"hunter2");
+        // Sealing is randomized.
+        assert_ne!(sealed, hybrid_encrypt(&key, b"hunter2", "ns/db").unwrap());
+    }
+
+    #[test]
+    fn small_keys() {
+        let private = RsaPrivateKey::new(&mut Random, 1024).unwrap();
+        let err = PublicKey::new(private.to_public_key()).err().unwrap();
+        assert!(err.to_string().contains("at least 2048"), "{}", err);
+    }
+}
@@ -0,0 +1,1065 @@
+//! Parsing of the gen annotations into per-key settings, and the defaults
+//! and policies applied to them.
+
+use crate::{
+    crypto::{randstr, RandStr, RandStrBuilder},
+    error::Error,
+    generate::{gen_standalone, SecretData},
+    kinds::{rabbitmq_hash, Kind},
+    opts::{FloorAction, Opts},
+};
+use clap::ValueEnum;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::ObjectMeta, ByteString};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+use tokio::time::Duration;
+
+// Schema versions of the gen annotation this operator understands. Payloads
+// without an apiVersion are treated as the first version.
+pub(crate) const SCHEMA_VERSIONS: &[&str] = &[concat!(app_id!(), "/v1")];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    #[serde(default)]
+    pub(crate) upper: bool,
+    #[serde(default)]
+    pub(crate) lower: bool,
+    #[serde(default, alias = "letters")]
+    pub(crate) letter: bool,
+    #[serde(default, alias = "digits")]
+    pub(crate) digit: bool,
+    #[serde(default, alias = "symbols")]
+    pub(crate) symbol: bool,
+    #[serde(default, alias = "whitespaces")]
+    pub(crate) whitespace: bool,
+    pub(crate) custom: Option<String>,
+
+    #[serde(default)]
+    pub(crate) must_upper: bool,
+    #[serde(default)]
+    pub(crate) must_lower: bool,
+    #[serde(default, alias = "must_letters")]
+    pub(crate) must_letter: bool,
+    #[serde(default, alias = "must_digits")]
+    pub(crate) must_digit: bool,
+    #[serde(default, alias = "must_symbols")]
+    pub(crate) must_symbol: bool,
+    #[serde(default, alias = "must_whitespaces")]
+    pub(crate) must_whitespace: bool,
+    pub(crate) must_custom: Option<String>,
+
+    #[serde(default)]
+    pub(crate) length: Option<usize>,
+    pub(crate) count: Option<usize>,
+    pub(crate) separator: Option<String>,
+
+    pub(crate) rabbitmq_hash_key: Option<String>,
+    #[serde(default)]
+    pub(crate) dual: bool,
+    #[serde(default)]
+    pub(crate) overwrite_empty: bool,
+
+    pub(crate) rotate: Option<Period>,
+    pub(crate) max_age: Option<Period>,
+    pub(crate) policy: Option<Policy>,
+    #[serde(default)]
+    pub(crate) derive: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Preset {
+    #[serde(rename = "all", alias = "default")]
+    All,
+    #[serde(rename = "digit", alias = "digits")]
+    Digit,
+    #[serde(rename = "letter", alias = "letters")]
+    Letter,
+    #[serde(rename = "upper")]
+    Upper,
+    #[serde(rename = "lower")]
+    Lower,
+}
+
+// A duration like `90d` or `1h30m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Interval(pub(crate) Duration);
+
+impl TryFrom<String> for Interval {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, String> {
+        let invalid = || format!("invalid interval {:?}", src);
+        let mut secs = 0u64;
+        let mut rest = src.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+        while !rest.is_empty() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let value: u64 = rest[..end].parse().map_err(|_| invalid())?;
+            let unit = rest[end..].chars().next().ok_or_else(invalid)?;
+            let factor = match unit {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => return Err(invalid()),
+            };
+            secs = value
+                .checked_mul(factor)
+                .and_then(|s| secs.checked_add(s))
+                .ok_or_else(invalid)?;
+            rest = &rest[end + unit.len_utf8()..];
+        }
+        Ok(Interval(Duration::from_secs(secs)))
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        Interval::try_from(src.to_string())
+    }
+}
+
+// An interval other than zero, for rotations and maximum ages, which would be
+// due again right away otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Period(pub(crate) Duration);
+
+impl TryFrom<String> for Period {
+    type Error = String;
+
+    fn try_from(src: String) -> Result<Self, String> {
+        match Interval::try_from(src.clone())? {
+            Interval(interval) if interval.is_zero() => {
+                Err(format!("interval {:?} is zero", src))
+            }
+            Interval(interval) => Ok(Period(interval)),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        Period::try_from(src.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CharClass {
+    Upper,
+    Lower,
+    Letter,
+    Digit,
+    Symbol,
+    Whitespace,
+}
+
+impl CharClass {
+    fn apply(self, builder: &mut RandStrBuilder) {
+        match self {
+            CharClass::Upper => builder.upper(),
+            CharClass::Lower => builder.lower(),
+            CharClass::Letter => builder.letter(),
+            CharClass::Digit => builder.digit(),
+            CharClass::Symbol => builder.symbol(),
+            CharClass::Whitespace => builder.whitespace(),
+        };
+    }
+
+    fn apply_must(self, builder: &mut RandStrBuilder) {
+        match self {
+            CharClass::Upper => builder.must_upper(),
+            CharClass::Lower => builder.must_lower(),
+            CharClass::Letter => builder.must_letter(),
+            CharClass::Digit => builder.must_digit(),
+            CharClass::Symbol => builder.must_symbol(),
+            CharClass::Whitespace => builder.must_whitespace(),
+        };
+    }
+}
+
+/// Operator-wide defaults, read from the `--defaults-config-map`. They apply
+/// to random keys that don't configure the respective setting themselves.
+/// Deserialize them from the `defaults.yaml` format, or start from
+/// `Defaults::default()`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    length: Option<usize>,
+    #[serde(default)]
+    charset: Vec<CharClass>,
+    #[serde(default)]
+    must: Vec<CharClass>,
+    pub(crate) rotate: Option<Period>,
+    pub(crate) max_age: Option<Period>,
+    pub(crate) policy: Option<Policy>,
+}
+
+// Compliance presets, applied as defaults to the keys selecting them.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize,
+)]
+pub enum Policy {
+    // NIST SP 800-63B, 5.1.1.2: no composition rules, and verifiers accept
+    // memorized secrets of at least 64 characters, which we use.
+    #[serde(rename = "nist-800-63b")]
+    Nist80063b,
+    // PCI DSS v4.0, 8.3.6 and 8.3.9: at least 12 characters with numeric and
+    // alphabetic characters, changed at least every 90 days.
+    #[serde(rename = "pci-dss")]
+    PciDss,
+}
+
+impl Policy {
+    fn defaults(self) -> Defaults {
+        const DAY: u64 = 24 * 60 * 60;
+        match self {
+            Policy::Nist80063b => Defaults {
+                length: Some(64),
+                charset: vec![
+                    CharClass::Letter,
+                    CharClass::Digit,
+                    CharClass::Symbol,
+                ],
+                ..Default::default()
+            },
+            Policy::PciDss => Defaults {
+                length: Some(12),
+                charset: vec![CharClass::Letter, CharClass::Digit],
+                must: vec![CharClass::Letter, CharClass::Digit],
+                rotate: Some(Period(Duration::from_secs(90 * DAY))),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Defaults {
+    // Settings of `over` take precedence over the ones of `self`.
+    pub(crate) fn layer(self, over: Defaults) -> Defaults {
+        Defaults {
+            length: over.length.or(self.length),
+            charset: match over.charset.is_empty() {
+                true => self.charset,
+                false => over.charset,
+            },
+            must: match over.must.is_empty() {
+                true => self.must,
+                false => over.must,
+            },
+            rotate: over.rotate.or(self.rotate),
+            max_age: over.max_age.or(self.max_age),
+            policy: over.policy.or(self.policy),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Alias {
+    pub(crate) alias_of: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformKind {
+    Sha256,
+    Md5,
+    Base64,
+    Urlencoded,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+    pub(crate) transform_of: String,
+    pub(crate) transform: TransformKind,
+}
+
+/// How the value of one key is generated, as declared in the gen annotation.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Settings {
+    Preset(Preset),
+    Kind(Kind),
+    Alias(Alias),
+    Transform(Transform),
+    Options(Options),
+}
+
+impl Settings {
+    /// Parses the value of a gen annotation into the settings of its keys.
+    /// Takes YAML or JSON, with or without an `apiVersion`, like the
+    /// operator, and fails on the first invalid key.
+    ///
+    /// ```
+    /// use auto_secret_operator::settings::Settings;
+    ///
+    /// let keys = Settings::parse("{password: {length: 16}, pin: digit}")?;
+    /// assert_eq!(keys.len(), 2);
+    /// # Ok::<(), auto_secret_operator::Error>(())
+    /// ```
+    pub fn parse(
+        annotation: &str,
+    ) -> Result<BTreeMap<String, Settings>, Error> {
+        versioned_settings(parse_value(app_id!("gen"), annotation)?)?
+            .into_iter()
+            .map(|(key, settings)| Ok((key, settings?)))
+            .collect()
+    }
+
+    /// Generates the values of `keys` like the operator does for a new,
+    /// empty secret with `defaults` in effect. Nothing outside of the
+    /// annotation is available: templates can't reference other secrets,
+    /// and derived keys fail for lack of a master seed. `http` keys need to
+    /// be generated on a multi-threaded tokio runtime and fail elsewhere.
+    /// Errors name the first key that failed.
+    ///
+    /// ```
+    /// use auto_secret_operator::settings::{Defaults, Settings};
+    ///
+    /// let keys = Settings::parse("{pin: {digit: true, length: 4}}")?;
+    /// let data = Settings::generate(&keys, &Defaults::default())?;
+    /// assert_eq!(data["pin"].0.len(), 4);
+    /// # Ok::<(), auto_secret_operator::Error>(())
+    /// ```
+    pub fn generate(
+        keys: &BTreeMap<String, Settings>,
+        defaults: &Defaults,
+    ) -> Result<SecretData, Error> {
+        let opts = Opts {
+            defaults: defaults.clone(),
+            ..Opts::default()
+        };
+        gen_standalone(&opts, keys)
+    }
+
+    /// The keys this key is generated from.
+    pub fn dependencies(&self) -> Vec<String> {
+        match self {
+            Settings::Kind(kind) => kind.generator.dependencies(),
+            Settings::Alias(alias) => vec![alias.alias_of.clone()],
+            Settings::Transform(t) => vec![t.transform_of.clone()],
+            _ => vec![],
+        }
+    }
+
+    pub(crate) fn is_random(&self) -> bool {
+        matches!(self, Settings::Preset(_) | Settings::Options(_))
+    }
+
+    // The name of the generator, for reports.
+    pub(crate) fn kind(&self) -> &str {
+        match self {
+            Settings::Kind(kind) => &kind.name,
+            Settings::Alias(_) => "alias",
+            Settings::Transform(_) => "transform",
+            _ if self.is_derived() => "derived",
+            _ => "random",
+        }
+    }
+
+    pub(crate) fn is_derived(&self) -> bool {
+        matches!(self, Settings::Options(Options { derive: true, .. }))
+    }
+
+    // Whether an empty value counts as missing, as declared per key.
+    pub(crate) fn overwrites_empty(&self) -> bool {
+        matches!(
+            self,
+            Settings::Options(Options {
+                overwrite_empty: true,
+                ..
+            })
+        )
+    }
+
+    /// The secrets, as `[<namespace>/]<name>`, this key reads from.
+    pub fn secret_refs(&self) -> Vec<&str> {
+        match self {
+            Settings::Kind(kind) => kind.generator.secret_refs(),
+            _ => vec![],
+        }
+    }
+
+    // The time until the value of `key` in `data` expires and is generated
+    // again.
+    pub(crate) fn renew_after(
+        &self,
+        key: &str,
+        data: &SecretData,
+    ) -> Option<Duration> {
+        match self {
+            Settings::Kind(kind) => kind.generator.renew_after(key, data),
+            _ => None,
+        }
+    }
+
+    // The character classes a random key draws from, which are the only
+    // ones default `must` constraints can require.
+    fn char_classes(&self, defaults: &Defaults) -> Vec<CharClass> {
+        match self {
+            Settings::Preset(Preset::All) => {
+                vec![CharClass::Letter, CharClass::Digit, CharClass::Symbol]
+            }
+            Settings::Preset(Preset::Digit) => vec![CharClass::Digit],
+            Settings::Preset(Preset::Letter) => vec![CharClass::Letter],
+            Settings::Preset(Preset::Upper) => vec![CharClass::Upper],
+            Settings::Preset(Preset::Lower) => vec![CharClass::Lower],
+            Settings::Options(o) if !o.has_charset() => {
+                defaults.charset.clone()
+            }
+            Settings::Options(o) => [
+                (o.upper, CharClass::Upper),
+                (o.lower, CharClass::Lower),
+                (o.letter, CharClass::Letter),
+                (o.digit, CharClass::Digit),
+                (o.symbol, CharClass::Symbol),
+                (o.whitespace, CharClass::Whitespace),
+            ]
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, class)| class)
+            .collect(),
+            _ => vec![],
+        }
+    }
+
+    // The defaults for this key, with its policy layered over the
+    // operator and namespace defaults.
+    pub(crate) fn defaults(&self, opts: &Opts) -> Defaults {
+        let policy = match self {
+            Settings::Options(Options {
+                policy: Some(policy),
+                ..
+            }) => Some(*policy),
+            _ => opts.defaults.policy,
+        };
+        match policy {
+            Some(policy) => opts.defaults.clone().layer(policy.defaults()),
+            None => opts.defaults.clone(),
+        }
+    }
+
+    fn length(&self, opts: &Opts) -> usize {
+        let length = match self {
+            Settings::Options(o) => o.length,
+            _ => None,
+        };
+        length
+            .or(self.defaults(opts).length)
+            .unwrap_or(opts.default_length)
+    }
+
+    // Describes how a random key falls short of `--min-length` and
+    // `--require-classes`, or returns `None` if it doesn't.
+    pub(crate) fn below_floor(&self, opts: &Opts) -> Option<String> {
+        if !self.is_random() {
+            return None;
+        }
+        let mut problems = vec![];
+        let length = self.length(opts);
+        if let Some(min) = opts.min_length.filter(|min| length < *min) {
+            problems.push(format!("length {} is below {}", length, min));
+        }
+        let classes = self.char_classes(&self.defaults(opts));
+        let covers = |class: &CharClass| {
+            classes.contains(class)
+                || matches!(class, CharClass::Upper | CharClass::Lower)
+                    && classes.contains(&CharClass::Letter)
+        };
+        let missing: Vec<String> = opts
+            .require_classes
+            .iter()
+            .filter(|c| !covers(c))
+            .map(|c| format!("{:?}", c).to_lowercase())
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!("missing {}", missing.join(", ")));
+        }
+        (!problems.is_empty()).then(|| problems.join(", "))
+    }
+
+    pub(crate) fn generator(&self, opts: &Opts) -> Result<RandStr, Error> {
+        let mut builder = randstr();
+        builder.len(self.length(opts));
+
+        self.apply_defaults(&self.defaults(opts), &mut builder);
+        self.apply(&mut builder);
+        self.apply_floor(opts, &mut builder);
+        builder.try_build()
+    }
+
+    // Explains why values of a random key cannot reach `--min-score`.
+    pub(crate) fn too_weak(
+        &self,
+        opts: &Opts,
+    ) -> Result<Option<String>, Error> {
+        let Some(min) = opts.min_score.filter(|_| self.is_random()) else {
+            return Ok(None);
+        };
+        let generator = self.generator(opts)?;
+        let score = generator.score();
+        Ok((score < min).then(|| {
+            format!(
+                "score {} is below {} (about 10^{:.1} guesses for {} \
+                 characters from an alphabet of {})",
+                score,
+                min,
+                generator.guesses_log10(),
+                generator.len,
+                generator.alphabet.len()
+            )
+        }))
+    }
+
+    // Why generating `key` is refused: it cannot reach `--min-score`, or it
+    // is below a rejecting strength floor.
+    pub(crate) fn refused(&self, key: &str, opts: &Opts) -> Option<Error> {
+        match self.too_weak(opts) {
+            Err(e) => Some(e),
+            Ok(Some(problem)) => Some(Error::TooWeak(key.into(), problem)),
+            Ok(None) if opts.floor_action == FloorAction::Reject => self
+                .below_floor(opts)
+                .map(|problem| Error::BelowFloor(key.into(), problem)),
+            Ok(None) => None,
+        }
+    }
+
+    // Raises the generator to the operator's strength floor.
+    fn apply_floor(&self, opts: &Opts, builder: &mut RandStrBuilder) {
+        if let Some(min) = opts.min_length {
+            builder.len(self.length(opts).max(min));
+        }
+        for class in &opts.require_classes {
+            class.apply(builder);
+            class.apply_must(builder);
+        }
+    }
+
+    fn apply_defaults(
+        &self,
+        defaults: &Defaults,
+        builder: &mut RandStrBuilder,
+    ) {
+        let Settings::Options(o) = self else {
+            return;
+        };
+        if !o.has_charset() {
+            for class in &defaults.charset {
+                class.apply(builder);
+            }
+        }
+        if !o.has_must() {
+            let classes = self.char_classes(defaults);
+            for class in defaults.must.iter().filter(|c| classes.contains(c)) {
+                class.apply_must(builder);
+            }
+        }
+    }
+
+    fn apply(&self, builder: &mut RandStrBuilder) {
+        match self {
+            Settings::Preset(Preset::All) => {
+                builder.all();
+            }
+            Settings::Preset(Preset::Digit) => {
+                builder.digit();
+            }
+            Settings::Preset(Preset::Letter) => {
+                builder.letter();
+            }
+            Settings::Preset(Preset::Upper) => {
+                builder.upper();
+            }
+            Settings::Preset(Preset::Lower) => {
+                builder.lower();
+            }
+            Settings::Kind(_) | Settings::Alias(_) | Settings::Transform(_) => {
+                unreachable!("only random settings configure randstr")
+            }
+            Settings::Options(o) => {
+                if o.upper {
+                    builder.upper();
+                }
+                if o.lower {
+                    builder.lower();
+                }
+                if o.letter {
+                    builder.letter();
+                }
+                if o.digit {
+                    builder.digit();
+                }
+                if o.symbol {
+                    builder.symbol();
+                }
+                if o.whitespace {
+                    builder.whitespace();
+                }
+                if let Some(custom) = &o.custom {
+                    builder.custom(custom);
+                }
+
+                if o.must_upper {
+                    builder.must_upper();
+                }
+                if o.must_lower {
+                    builder.must_lower();
+                }
+                if o.must_letter {
+                    builder.must_letter();
+                }
+                if o.must_digit {
+                    builder.must_digit();
+                }
+                if o.must_symbol {
+                    builder.must_symbol();
+                }
+                if o.must_whitespace {
+                    builder.must_whitespace();
+                }
+                if let Some(must_custom) = &o.must_custom {
+                    builder.must_custom(must_custom);
+                }
+                if let Some(len) = o.length {
+                    builder.len(len);
+                }
+            }
+        }
+    }
+}
+
+impl Options {
+    fn has_charset(&self) -> bool {
+        self.upper
+            || self.lower
+            || self.letter
+            || self.digit
+            || self.symbol
+            || self.whitespace
+            || self.custom.is_some()
+    }
+
+    fn has_must(&self) -> bool {
+        self.must_upper
+            || self.must_lower
+            || self.must_letter
+            || self.must_digit
+            || self.must_symbol
+            || self.must_whitespace
+            || self.must_custom.is_some()
+    }
+
+    pub(crate) fn derive(
+        &self,
+        key: &str,
+        value: &ByteString,
+        fresh: bool,
+        old_data: &SecretData,
+        data: &mut SecretData,
+    ) {
+        if let Some(hash_key) = &self.rabbitmq_hash_key {
+            if fresh || !old_data.contains_key(hash_key) {
+                data.insert(hash_key.clone(), rabbitmq_hash(&value.0));
+            }
+        }
+        if self.dual {
+            dual_slots(key, value, fresh, old_data, data);
+        }
+    }
+}
+
+// Writes a new value of `key` into the inactive one of its slots `<key>-a`
+// and `<key>-b` and points `<key>-active` at it, so that the previous value
+// stays in the other slot. The first value goes into `<key>-a`.
+fn dual_slots(
+    key: &str,
+    value: &ByteString,
+    fresh: bool,
+    old_data: &SecretData,
+    data: &mut SecretData,
+) {
+    let pointer = format!("{}-active", key);
+    let slots = [format!("{}-a", key), format!("{}-b", key)];
+    let active = old_data
+        .get(&pointer)
+        .and_then(|v| slots.iter().position(|s| s.as_bytes() == v.0));
+    let slot = match (active, fresh) {
+        (Some(_), false) => return,
+        (Some(active), true) => &slots[1 - active],
+        (None, _) => &slots[0],
+    };
+    data.insert(slot.clone(), value.clone());
+    data.insert(pointer, ByteString(slot.clone().into_bytes()));
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KeyCase {
+    UpperSnake,
+    LowerSnake,
+    Kebab,
+}
+
+impl KeyCase {
+    pub(crate) fn apply(&self, key: &str) -> String {
+        let words = key
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty());
+        match self {
+            KeyCase::UpperSnake => {
+                words.map(str::to_uppercase).collect::<Vec<_>>().join("_")
+            }
+            KeyCase::LowerSnake => {
+                words.map(str::to_lowercase).collect::<Vec<_>>().join("_")
+            }
+            KeyCase::Kebab => {
+                words.map(str::to_lowercase).collect::<Vec<_>>().join("-")
+            }
+        }
+    }
+}
+
+pub fn parse_annotation<T: for<'de> Deserialize<'de>>(
+    meta: &ObjectMeta,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    meta.annotations
+        .as_ref()
+        .and_then(|a| a.get(name))
+        .map(|value| parse_value(name, value))
+        .transpose()
+}
+
+// Annotation values are YAML, or JSON for tools that build them
+// programmatically. Values that look like JSON objects or arrays are parsed
+// as JSON first, which gives better error messages than YAML flow syntax.
+pub fn parse_value<T: for<'de> Deserialize<'de>>(
+    name: &str,
+    value: &str,
+) -> Result<T, Error> {
+    let invalid = |e: String| Error::InvalidAnnotation(name.to_string(), e);
+    if !value.trim_start().starts_with(['{', '[']) {
+        return serde_yaml::from_str(value).map_err(|e| invalid(e.to_string()));
+    }
+    serde_json::from_str(value).or_else(|json| {
+        serde_yaml::from_str(value).map_err(|yaml| {
+            invalid(format!(
+                "neither valid JSON ({}) nor YAML ({})",
+                json, yaml
+            ))
+        })
+    })
+}
+
+// Errors of single keys, which don't keep the other keys from being
+// generated.
+pub type KeyErrors = BTreeMap<String, Error>;
+
+// Valid declarations by key, and the errors of invalid ones.
+type Declared = (BTreeMap<String, Settings>, KeyErrors);
+
+// Collects the keys declared in the gen annotation and in per-key
+// `gen-<key>` annotations. Returns `None` if there is neither.
+pub fn declared_settings(
+    meta: &ObjectMeta,
+) -> Result<Option<BTreeMap<String, Settings>>, Error> {
+    let Some((settings, mut failed)) = declared_keys(meta)? else {
+        return Ok(None);
+    };
+    match failed.pop_first() {
+        Some((_, e)) => Err(e),
+        None => Ok(Some(settings)),
+    }
+}
+
+// Like `declared_settings`, but keys with an invalid declaration are
+// returned with their errors instead of failing all keys.
+pub(crate) fn declared_keys(
+    meta: &ObjectMeta,
+) -> Result<Option<Declared>, Error> {
+    let mut declared = parse_annotation(meta, app_id!("gen"))?
+        .map(versioned_settings)
+        .transpose()?;
+    for (name, value) in meta.annotations.iter().flatten() {
+        let Some(key) = name.strip_prefix(app_id!("gen-")) else {
+            continue;
+        };
+        let declared = declared.get_or_insert_with(BTreeMap::new);
+        let settings = match declared.contains_key(key) {
+            true => Err(Error::InvalidAnnotation(
+                name.clone(),
+                format!("key {:?} is also declared in {}", key, app_id!("gen")),
+            )),
+            false => parse_inline(name, value),
+        };
+        declared.insert(key.to_string(), settings);
+    }
+    Ok(declared.map(|declared| {
+        let mut settings = BTreeMap::new();
+        let mut failed = KeyErrors::new();
+        for (key, result) in declared {
+            match result {
+                Ok(s) => {
+                    settings.insert(key, s);
+                }
+                Err(e) => {
+                    failed.insert(key, e);
+                }
+            }
+        }
+        (settings, failed)
+    }))
+}
+
+// The gen annotation is either a map of keys or, with an explicit schema
+// version, `{apiVersion: ..., keys: ...}`. Checking the version before the
+// keys tells users of outdated operators why their annotation is rejected.
+fn versioned_settings(
+    mut value: serde_yaml::Value,
+) -> Result<BTreeMap<String, Result<Settings, Error>>, Error> {
+    let invalid =
+        |e: String| Error::InvalidAnnotation(app_id!("gen").into(), e);
+    if let Some(map) = value.as_mapping_mut() {
+        if let Some(version) = map.remove("apiVersion") {
+            let version = match version {
+                serde_yaml::Value::String(version) => version,
+                _ => return Err(invalid("apiVersion must be a string".into())),
+            };
+            if !SCHEMA_VERSIONS.contains(&version.as_str()) {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            let keys = map.remove("keys").unwrap_or_default();
+            if let Some(field) = map.keys().next() {
+                return Err(invalid(format!(
+                    "unknown field {:?} next to apiVersion",
+                    field
+                )));
+            }
+            value = keys;
+        }
+    }
+    let keys: BTreeMap<String, serde_yaml::Value> =
+        serde_yaml::from_value(value).map_err(|e| invalid(e.to_string()))?;
+    Ok(keys
+        .into_iter()
+        .map(|(key, value)| {
+            let settings = serde_yaml::from_value(value)
+                .map_err(|e| invalid(format!("key {:?}: {}", key, e)));
+            (key, settings)
+        })
+        .collect())
+}
+
+// Per-key annotations use a compact syntax that is easy to set with
+// `kubectl annotate`: a preset name, or comma separated options where flags
+// stand on their own and everything else is `name=value` or `name:value`.
+// Values starting with `{` are parsed like any other annotation.
+pub(crate) fn parse_inline(name: &str, value: &str) -> Result<Settings, Error> {
+    let value = value.trim();
+    if value.starts_with('{') || !value.contains(['=', ':', ',']) {
+        return parse_value(name, value);
+    }
+    let invalid = |e: serde_yaml::Error| {
+        Error::InvalidAnnotation(name.to_string(), e.to_string())
+    };
+    let mut options = serde_yaml::Mapping::new();
+    for item in value.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let split = match item.contains('=') {
+            true => item.split_once('='),
+            false => item.split_once(':'),
+        };
+        let (option, value) = match split {
+            Some((option, value)) => (
+                option.trim(),
+                serde_yaml::from_str(value.trim()).map_err(invalid)?,
+            ),
+            None => (item, serde_yaml::Value::Bool(true)),
+        };
+        options.insert(option.into(), value);
+    }
+    serde_yaml::from_value(serde_yaml::Value::Mapping(options)).map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(annotations: &[(&str, &str)]) -> ObjectMeta {
+        ObjectMeta {
+            annotations: Some(
+                annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn interval() {
+        let interval: Interval = "1h30m".parse().unwrap();
+        assert_eq!(interval.0, Duration::from_secs(90 * 60));
+        assert_eq!("90d".parse::<Interval>().unwrap().0.as_secs(), 7776000);
+        assert!("".parse::<Interval>().is_err());
+        assert!("10".parse::<Interval>().is_err());
+        assert!("10w".parse::<Interval>().is_err());
+        assert!("5é".parse::<Interval>().is_err());
+        assert!("1sé".parse::<Interval>().is_err());
+        assert!("18446744073709551615d".parse::<Interval>().is_err());
+        assert!("18446744073709551615s1s".parse::<Interval>().is_err());
+
+        assert_eq!("0s".parse::<Interval>().unwrap().0, Duration::ZERO);
+        assert!("0s".parse::<Period>().is_err());
+        assert!("0d0h".parse::<Period>().is_err());
+        assert_eq!("1s".parse::<Period>().unwrap().0, Duration::from_secs(1));
+        let err = parse_value::<Options>("gen", "{rotate: 0s}").unwrap_err();
+        assert!(err.to_string().contains("is zero"), "{}", err);
+    }
+
+    #[test]
+    fn inline_settings() {
+        let settings = parse_inline("gen-pin", "digit,length=6").unwrap();
+        let Settings::Options(options) = settings else {
+            panic!("expected options, got {:?}", settings);
+        };
+        assert!(options.digit);
+        assert_eq!(options.length, Some(6));
+        assert!(matches!(
+            parse_inline("gen-pin", "letter").unwrap(),
+            Settings::Preset(Preset::Letter)
+        ));
+        assert!(parse_inline("gen-pin", "digit,colour=red").is_err());
+        let Settings::Options(options) =
+            parse_inline("gen-password", "length:64,digit,letter").unwrap()
+        else {
+            panic!("expected options");
+        };
+        assert_eq!(options.length, Some(64));
+        assert!(options.digit && options.letter);
+    }
+
+    #[test]
+    fn declared_keys_of_all_annotations() {
+        let meta = meta(&[
+            (
+                "auto-secret.k8s.eboland.de/gen",
+                "{password: {length: 12}, url: {alias_of: password}}",
+            ),
+            ("auto-secret.k8s.eboland.de/gen-pin", "digit,length=4"),
+        ]);
+        let settings = declared_settings(&meta).unwrap().unwrap();
+        assert_eq!(
+            settings.keys().collect::<Vec<_>>(),
+            ["password", "pin", "url"]
+        );
+        assert_eq!(settings["url"].dependencies(), ["password"]);
+        assert!(declared_settings(&ObjectMeta::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_keys_fail_alone() {
+        let meta = meta(&[
+            (
+                "auto-secret.k8s.eboland.de/gen",
+                "{password: {colour: red}}",
+            ),
+            ("auto-secret.k8s.eboland.de/gen-pin", "digit"),
+        ]);
+        let (settings, failed) = declared_keys(&meta).unwrap().unwrap();
+        assert!(settings.contains_key("pin"));
+        assert!(failed.contains_key("password"));
+        assert!(declared_settings(&meta).is_err());
+    }
+
+    #[test]
+    fn key_declared_twice() {
+        let meta = meta(&[
+            ("auto-secret.k8s.eboland.de/gen", "{pin: digit}"),
+            ("auto-secret.k8s.eboland.de/gen-pin", "digit"),
+        ]);
+        let (_, failed) = declared_keys(&meta).unwrap().unwrap();
+        assert!(failed.contains_key("pin"));
+    }
+
+    #[test]
+    fn schema_version() {
+        let versioned = meta(&[(
+            "auto-secret.k8s.eboland.de/gen",
+            "{apiVersion: auto-secret.k8s.eboland.de/v1, keys: {pin: digit}}",
+        )]);
+        assert!(declared_settings(&versioned).unwrap().unwrap()["pin"]
+            .dependencies()
+            .is_empty());
+        let unsupported = meta(&[(
+            "auto-secret.k8s.eboland.de/gen",
+            "{apiVersion: auto-secret.k8s.eboland.de/v2, keys: {}}",
+        )]);
+        assert!(matches!(
+            declared_settings(&unsupported),
+            Err(Error::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn parse_annotation_values() {
+        let keys = Settings::parse(
+            "apiVersion: auto-secret.k8s.eboland.de/v1\nkeys:\n  pin: digit\n",
+        )
+        .unwrap();
+        assert!(matches!(keys["pin"], Settings::Preset(Preset::Digit)));
+        assert!(Settings::parse("{pin: {colour: red}}").is_err());
+        assert!(Settings::parse("[pin]").is_err());
+    }
+
+    #[test]
+    fn generate_with_defaults() {
+        let keys = Settings::parse("{password: {}, url: {alias_of: password}}")
+            .unwrap();
+        let defaults: Defaults =
+            parse_value("defaults.yaml", "{length: 20, charset: [digit]}")
+                .unwrap();
+        let data = Settings::generate(&keys, &defaults).unwrap();
+        assert_eq!(data["password"].0.len(), 20);
+        assert!(data["password"].0.iter().all(u8::is_ascii_digit));
+        assert_eq!(data["url"], data["password"]);
+
+        let keys = Settings::parse("{copy: {alias_of: missing}}").unwrap();
+        assert!(matches!(
+            Settings::generate(&keys, &Defaults::default()),
+            Err(Error::Key(key, _)) if key == "copy"
+        ));
+    }
+
+    #[test]
+    fn key_case() {
+        assert_eq!(KeyCase::UpperSnake.apply("db-password"), "DB_PASSWORD");
+        assert_eq!(KeyCase::LowerSnake.apply("DB.Password"), "db_password");
+        assert_eq!(KeyCase::Kebab.apply("DB_PASSWORD"), "db-password");
+    }
+
+    #[test]
+    fn json_and_yaml_values() {
+        let json: BTreeMap<String, u32> =
+            parse_value("x", r#"{"a": 1}"#).unwrap();
+        let yaml: BTreeMap<String, u32> = parse_value("x", "a: 1").unwrap();
+        assert_eq!(json, yaml);
+        assert!(matches!(
+            parse_value::<BTreeMap<String, u32>>("x", "{a: nope}"),
+            Err(Error::InvalidAnnotation(name, _)) if name == "x"
+        ));
+    }
+}
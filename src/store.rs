@@ -0,0 +1,237 @@
+//! External stores that generated values are mirrored to, like Vault or the
+//! secret managers of cloud providers. A secret is mirrored to every
+//! registered store whose annotation it carries.
+
+use crate::{error::Error, generate::SecretData, opts::Opts};
+#[cfg(any(
+    feature = "vault",
+    feature = "aws",
+    feature = "gcp",
+    feature = "azure"
+))]
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_util::future::BoxFuture;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// A write of generated values to a store.
+pub struct StoreWrite<'a> {
+    /// Where the values go, the value of the store's annotation.
+    pub location: &'a str,
+    /// Namespace of the Kubernetes secret.
+    pub namespace: &'a str,
+    /// Name of the Kubernetes secret.
+    pub name: &'a str,
+    /// The keys generated or rotated by this write.
+    pub generated: &'a SecretData,
+    /// All keys the operator manages in the secret, with their values.
+    pub managed: &'a SecretData,
+    /// Checksum of `managed`, as in the `checksum` annotation.
+    pub checksum: &'a str,
+}
+
+/// A store that generated values are mirrored to. Values are written to
+/// stores before the Kubernetes secret, so a failed write is retried before
+/// the secret holds values a store is missing.
+pub trait SecretStore: Send + Sync {
+    /// Name of the store in errors and in the `<name>-version` annotation.
+    fn name(&self) -> &'static str;
+
+    /// The annotation, without the domain, naming where a secret's values
+    /// are stored.
+    fn annotation(&self) -> &'static str;
+
+    /// Checks the location in the annotation. Runs for all stores before
+    /// any of them is written to.
+    fn validate(&self, _location: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Writes the values and returns the version they were stored as, if
+    /// the store keeps versions.
+    fn write<'a>(
+        &'a self,
+        write: &'a StoreWrite<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>>;
+
+    /// Reads back the values at `location`, empty if there are none.
+    fn read<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<SecretData, Error>>;
+
+    /// Deletes the values at `location`.
+    fn delete<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// The current version of the values at `location`, if there are any.
+    fn version<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>>;
+}
+
+// Stores configured with flags are registered at startup, next to the ones
+// of library users.
+static STORES: LazyLock<RwLock<Vec<Arc<dyn SecretStore>>>> =
+    LazyLock::new(RwLock::default);
+
+/// Mirrors values to `store` from now on, unless a store of the same name is
+/// registered already.
+pub fn register_store(store: Arc<dyn SecretStore>) -> bool {
+    let mut stores = STORES.write().unwrap();
+    if stores.iter().any(|s| s.name() == store.name()) {
+        return false;
+    }
+    stores.push(store);
+    true
+}
+
+pub(crate) fn stores() -> Vec<Arc<dyn SecretStore>> {
+    STORES.read().unwrap().clone()
+}
+
+// The annotation naming where a secret's values are stored in `store`.
+pub(crate) fn location_annotation(store: &dyn SecretStore) -> String {
+    format!("{}/{}", crate::annotation_domain(), store.annotation())
+}
+
+// The annotation recording the version last written to `store`.
+pub(crate) fn version_annotation(store: &dyn SecretStore) -> String {
+    format!("{}/{}-version", crate::annotation_domain(), store.name())
+}
+
+// The stores the secret with `meta` is mirrored to, with the locations its
+// annotations give.
+pub(crate) fn annotated_stores(
+    meta: &ObjectMeta,
+) -> Vec<(Arc<dyn SecretStore>, String)> {
+    let annotations = meta.annotations.as_ref();
+    stores()
+        .into_iter()
+        .filter_map(|store| {
+            let annotation = location_annotation(store.as_ref());
+            let location = annotations?.get(&annotation)?.clone();
+            Some((store, location))
+        })
+        .collect()
+}
+
+// Whether `annotation` records a store version, which the operator sets
+// along with the data.
+pub(crate) fn is_version_annotation(annotation: &str) -> bool {
+    stores()
+        .iter()
+        .any(|store| version_annotation(store.as_ref()) == annotation)
+}
+
+// Values read back from a store, which hold text.
+#[cfg(any(
+    feature = "vault",
+    feature = "aws",
+    feature = "gcp",
+    feature = "azure"
+))]
+pub(crate) fn from_strings(
+    values: &serde_json::Map<String, serde_json::Value>,
+) -> SecretData {
+    values
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = value.as_str()?.as_bytes().to_vec();
+            Some((key.clone(), k8s_openapi::ByteString(value)))
+        })
+        .collect()
+}
+
+// The values of `data` as JSON strings, for stores that only hold text.
+// Values that are not valid UTF-8 are base64 encoded.
+#[cfg(any(
+    feature = "vault",
+    feature = "aws",
+    feature = "gcp",
+    feature = "azure"
+))]
+pub(crate) fn string_values(
+    data: &SecretData,
+) -> serde_json::Map<String, serde_json::Value> {
+    data.iter()
+        .map(|(key, value)| {
+            let value = match std::str::from_utf8(&value.0) {
+                Ok(s) => s.to_string(),
+                Err(_) => BASE64_STANDARD.encode(&value.0),
+            };
+            (key.clone(), value.into())
+        })
+        .collect()
+}
+
+// Registers the built-in stores configured with flags. Stores left out of
+// the build can't be configured.
+pub(crate) fn register_built_in_stores(opts: &Opts) -> Result<(), Error> {
+    #[cfg(feature = "vault")]
+    if let Some(addr) = &opts.vault_addr {
+        register_store(Arc::new(crate::vault::Vault::new(addr, opts)?));
+    }
+    #[cfg(not(feature = "vault"))]
+    if opts.vault_addr.is_some() {
+        return Err(Error::Vault(
+            "--vault-addr needs a build with the vault feature".to_string(),
+        ));
+    }
+    #[cfg(feature = "aws")]
+    if let Some(region) = &opts.aws_region {
+        let secrets_manager = crate::aws::SecretsManager::new(region, opts)?;
+        register_store(Arc::new(secrets_manager));
+    }
+    #[cfg(not(feature = "aws"))]
+    if opts.aws_region.is_some() {
+        return Err(Error::Aws(
+            "--aws-region needs a build with the aws feature".to_string(),
+        ));
+    }
+    #[cfg(feature = "gcp")]
+    if let Some(project) = &opts.gcp_project {
+        register_store(Arc::new(crate::gcp::SecretManager::new(project)?));
+    }
+    #[cfg(not(feature = "gcp"))]
+    if opts.gcp_project.is_some() {
+        return Err(Error::Gcp(
+            "--gcp-project needs a build with the gcp feature".to_string(),
+        ));
+    }
+    #[cfg(feature = "azure")]
+    if let Some(vault) = &opts.azure_key_vault {
+        register_store(Arc::new(crate::azure::KeyVault::new(vault)?));
+    }
+    #[cfg(not(feature = "azure"))]
+    if opts.azure_key_vault.is_some() {
+        return Err(Error::Azure(
+            "--azure-key-vault needs a build with the azure feature"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "vault"))]
+mod tests {
+    use super::*;
+    use k8s_openapi::ByteString;
+
+    #[test]
+    fn string_data() {
+        let secret: SecretData = [
+            ("password".to_string(), ByteString(b"hunter2".to_vec())),
+            ("key".to_string(), ByteString(vec![0xff, 0x00])),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            serde_json::Value::Object(string_values(&secret)),
+            serde_json::json!({"password": "hunter2", "key": "/wA="})
+        );
+    }
+}
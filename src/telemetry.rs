@@ -0,0 +1,208 @@
+// Exports the spans and metrics of reconciles to an OpenTelemetry collector
+// with OTLP over HTTP, when built with the `otel` feature. Spans are exported
+// with `--otlp-endpoint`, metrics with `--otlp-metrics`. The spans are
+// recorded with `tracing` and cost next to nothing without an exporter. Logs
+// are still written by the logger, not exported. With the `console` feature,
+// `--tokio-console-addr` serves the tasks of the runtime to tokio-console from
+// the same subscriber.
+
+use crate::{error::Error, opts::Opts};
+
+#[cfg(feature = "otel")]
+use opentelemetry::{global, trace::TracerProvider as _};
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource,
+};
+#[cfg(feature = "otel")]
+use tracing::Level;
+#[cfg(feature = "otel")]
+use tracing_subscriber::filter::Targets;
+#[cfg(any(feature = "otel", feature = "console"))]
+use tracing_subscriber::{layer::SubscriberExt, Layer, Registry};
+
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "auto-secret-operator";
+
+// Exports until dropped, then sends the spans and metrics that are left.
+#[cfg(feature = "otel")]
+pub(crate) struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) enum Telemetry {}
+
+// The URL of a signal, `traces` or `metrics`, for the base URL of a collector
+// like `OTEL_EXPORTER_OTLP_ENDPOINT` takes it.
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+fn signal_url(endpoint: &str, signal: &str) -> String {
+    format!("{}/v1/{}", endpoint.trim_end_matches('/'), signal)
+}
+
+// The layers of the global subscriber.
+#[cfg(any(feature = "otel", feature = "console"))]
+type Layers = Vec<Box<dyn Layer<Registry> + Send + Sync>>;
+
+#[cfg(any(feature = "otel", feature = "console"))]
+fn install(layers: Layers) -> Result<(), Error> {
+    if layers.is_empty() {
+        return Ok(());
+    }
+    let subscriber = tracing_subscriber::registry().with(layers);
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| Error::Telemetry(e.to_string()))
+}
+
+#[cfg(feature = "console")]
+fn serve_console(opts: &Opts, layers: &mut Layers) -> Result<(), Error> {
+    if let Some(addr) = opts.tokio_console_addr {
+        let layer = console_subscriber::ConsoleLayer::builder()
+            .server_addr(addr)
+            .spawn();
+        layers.push(Box::new(layer));
+        log::info!("serving tokio-console on {}", addr);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "console"))]
+fn serve_console<L>(opts: &Opts, _layers: &mut L) -> Result<(), Error> {
+    match opts.tokio_console_addr {
+        Some(_) => Err(Error::Telemetry(
+            "tokio-console needs a build with the console feature".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "otel")]
+fn resource() -> Resource {
+    Resource::builder().with_service_name(SERVICE_NAME).build()
+}
+
+#[cfg(feature = "otel")]
+impl Telemetry {
+    // Starts the exports `opts` ask for, if any.
+    pub(crate) fn init(opts: &Opts) -> Result<Option<Self>, Error> {
+        let mut layers = Layers::new();
+        serve_console(opts, &mut layers)?;
+        let endpoint = opts.otlp_endpoint.as_deref();
+        let tracer_provider = endpoint
+            .map(|endpoint| export_traces(endpoint, &mut layers))
+            .transpose()?;
+        install(layers)?;
+        let meter_provider = match opts.otlp_metrics {
+            true => Some(export_metrics(endpoint)?),
+            false => None,
+        };
+        if tracer_provider.is_none() && meter_provider.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Telemetry {
+            tracer_provider,
+            meter_provider,
+        }))
+    }
+}
+
+#[cfg(feature = "otel")]
+fn export_traces(
+    endpoint: &str,
+    layers: &mut Layers,
+) -> Result<SdkTracerProvider, Error> {
+    let url = signal_url(endpoint, "traces");
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&url)
+        .build()
+        .map_err(|e| Error::Telemetry(e.to_string()))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource())
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME));
+    // The requests to the API server are traced at the debug level.
+    let filter = Targets::new()
+        .with_default(Level::INFO)
+        .with_target("kube_client", Level::DEBUG);
+    layers.push(Box::new(layer.with_filter(filter)));
+    log::info!("exporting traces to {}", url);
+    Ok(provider)
+}
+
+// Without `--otlp-endpoint` the exporter is configured by the standard
+// `OTEL_EXPORTER_OTLP_*` environment variables.
+#[cfg(feature = "otel")]
+fn export_metrics(endpoint: Option<&str>) -> Result<SdkMeterProvider, Error> {
+    let mut exporter = MetricExporter::builder().with_http();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(signal_url(endpoint, "metrics"));
+    }
+    let exporter = exporter
+        .build()
+        .map_err(|e| Error::Telemetry(e.to_string()))?;
+    let provider = SdkMeterProvider::builder()
+        .with_periodic_exporter(exporter)
+        .with_resource(resource())
+        .build();
+    global::set_meter_provider(provider.clone());
+    log::info!("exporting metrics with OTLP");
+    Ok(provider)
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(Err(e)) =
+            self.tracer_provider.as_ref().map(|p| p.shutdown())
+        {
+            log::warn!("failed to export the last traces: {}", e);
+        }
+        if let Some(Err(e)) = self.meter_provider.as_ref().map(|p| p.shutdown())
+        {
+            log::warn!("failed to export the last metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl Telemetry {
+    pub(crate) fn init(opts: &Opts) -> Result<Option<Self>, Error> {
+        if opts.otlp_endpoint.is_some() || opts.otlp_metrics {
+            return Err(Error::Telemetry(
+                "OTLP export needs a build with the otel feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "console")]
+        {
+            let mut layers = Layers::new();
+            serve_console(opts, &mut layers)?;
+            install(layers)?;
+        }
+        #[cfg(not(feature = "console"))]
+        serve_console(opts, &mut ())?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            signal_url("http://collector:4318/", "traces"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            signal_url("http://collector:4318", "metrics"),
+            "http://collector:4318/v1/metrics"
+        );
+    }
+}
@@ -0,0 +1,537 @@
+//! The template language of `kind: template`, a small subset of Go
+//! templates.
+
+use crate::{
+    crypto::sha256,
+    error::Error,
+    generate::{hex, url_query_escape, SecretData},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use k8s_openapi::{
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{SecondsFormat, Utc},
+    ByteString,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+enum Token {
+    Str(String),
+    Field(String),
+    Ident(String),
+    Pipe,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Func(String),
+    Str(String),
+    Field(String),
+    Pipeline(Vec<Vec<Operand>>),
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Action(Vec<Vec<Operand>>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+// Everything generation may look at besides the secret's own data.
+// `secrets` holds the data of secrets referenced from templates, keyed by
+// `<namespace>/<name>`.
+pub struct Scope<'a> {
+    pub meta: &'a ObjectMeta,
+    pub secrets: &'a BTreeMap<String, SecretData>,
+    pub seed: Option<&'a [u8]>,
+}
+
+pub(crate) struct TemplateContext<'a> {
+    pub(crate) scope: &'a Scope<'a>,
+    pub(crate) old_data: &'a SecretData,
+    pub(crate) data: &'a SecretData,
+}
+
+impl Scope<'_> {
+    pub(crate) fn namespace(&self) -> &str {
+        self.meta.namespace.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn annotation(&self, name: &str) -> Option<&str> {
+        self.meta
+            .annotations
+            .as_ref()?
+            .get(name)
+            .map(String::as_str)
+    }
+}
+
+// `<namespace>/<name>` or just `<name>` for secrets in `namespace`.
+pub(crate) fn split_secret_ref<'a>(
+    reference: &'a str,
+    namespace: &'a str,
+) -> (&'a str, &'a str) {
+    reference.split_once('/').unwrap_or((namespace, reference))
+}
+
+impl TryFrom<String> for Template {
+    type Error = Error;
+
+    fn try_from(src: String) -> Result<Self, Error> {
+        Template::parse(&src)
+    }
+}
+
+impl Template {
+    fn parse(src: &str) -> Result<Self, Error> {
+        let mut nodes = vec![];
+        let mut rest = src;
+        let mut trim_next = false;
+        loop {
+            let (mut text, action) = match rest.find("{{") {
+                Some(i) => (&rest[..i], Some(&rest[i + 2..])),
+                None => (rest, None),
+            };
+            if trim_next {
+                text = text.trim_start();
+            }
+            let Some(mut action) = action else {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text.to_string()));
+                }
+                break;
+            };
+            if let Some(trimmed) = action
+                .strip_prefix('-')
+                .filter(|a| a.starts_with(char::is_whitespace))
+            {
+                text = text.trim_end();
+                action = trimmed;
+            }
+            if !text.is_empty() {
+                nodes.push(Node::Text(text.to_string()));
+            }
+
+            let (tokens, remaining, trim) = Self::lex(action)?;
+            let mut tokens = tokens.into_iter().peekable();
+            let pipeline = Self::parse_pipeline(&mut tokens)?;
+            if let Some(token) = tokens.next() {
+                return Err(Error::Template(format!(
+                    "unexpected {:?} in action",
+                    token
+                )));
+            }
+            nodes.push(Node::Action(pipeline));
+            rest = remaining;
+            trim_next = trim;
+        }
+        Ok(Template { nodes })
+    }
+
+    fn lex(src: &str) -> Result<(Vec<Token>, &str, bool), Error> {
+        let mut tokens = vec![];
+        let mut i = 0;
+        while let Some(c) = src[i..].chars().next() {
+            let rest = &src[i..];
+            if c.is_whitespace() {
+                if let Some(remaining) =
+                    rest[c.len_utf8()..].trim_start().strip_prefix("-}}")
+                {
+                    return Ok((tokens, remaining, true));
+                }
+                i += c.len_utf8();
+                continue;
+            }
+            if let Some(remaining) = rest.strip_prefix("}}") {
+                return Ok((tokens, remaining, false));
+            }
+            match c {
+                '|' => tokens.push(Token::Pipe),
+                '(' => tokens.push(Token::LParen),
+                ')' => tokens.push(Token::RParen),
+                '`' => {
+                    let end = rest[1..].find('`').ok_or_else(|| {
+                        Error::Template("unterminated raw string".into())
+                    })?;
+                    tokens.push(Token::Str(rest[1..end + 1].to_string()));
+                    i += end + 2;
+                    continue;
+                }
+                '"' => {
+                    let mut value = String::new();
+                    let mut chars = rest[1..].char_indices();
+                    let end = loop {
+                        match chars.next() {
+                            Some((j, '"')) => break j + 2,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, 'n')) => value.push('\n'),
+                                Some((_, 't')) => value.push('\t'),
+                                Some((_, c @ ('"' | '\\'))) => value.push(c),
+                                _ => {
+                                    return Err(Error::Template(
+                                        "invalid escape in string".into(),
+                                    ))
+                                }
+                            },
+                            Some((_, c)) => value.push(c),
+                            None => {
+                                return Err(Error::Template(
+                                    "unterminated string".into(),
+                                ))
+                            }
+                        }
+                    };
+                    tokens.push(Token::Str(value));
+                    i += end;
+                    continue;
+                }
+                '.' | '_' | 'a'..='z' | 'A'..='Z' | '0'..='9' => {
+                    let start = usize::from(c == '.');
+                    let len = rest[start..]
+                        .find(|c: char| !c.is_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len() - start);
+                    let word = rest[start..start + len].to_string();
+                    tokens.push(match c {
+                        '.' => Token::Field(word),
+                        '0'..='9' => Token::Str(word),
+                        _ => Token::Ident(word),
+                    });
+                    i += start + len;
+                    continue;
+                }
+                c => {
+                    return Err(Error::Template(format!(
+                        "unexpected character {:?} in action",
+                        c
+                    )))
+                }
+            }
+            i += c.len_utf8();
+        }
+        Err(Error::Template("unclosed action".into()))
+    }
+
+    fn parse_pipeline(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = Token>>,
+    ) -> Result<Vec<Vec<Operand>>, Error> {
+        let mut pipeline = vec![];
+        loop {
+            let mut command = vec![];
+            while let Some(token) =
+                tokens.next_if(|t| !matches!(t, Token::Pipe | Token::RParen))
+            {
+                command.push(match token {
+                    Token::Str(s) => Operand::Str(s),
+                    Token::Field(f) => Operand::Field(f),
+                    Token::Ident(i) => Operand::Func(i),
+                    Token::LParen => {
+                        let inner = Self::parse_pipeline(tokens)?;
+                        if !matches!(tokens.next(), Some(Token::RParen)) {
+                            return Err(Error::Template(
+                                "unclosed parenthesis".into(),
+                            ));
+                        }
+                        Operand::Pipeline(inner)
+                    }
+                    Token::Pipe | Token::RParen => unreachable!(),
+                });
+            }
+            if command.is_empty() {
+                return Err(Error::Template("missing command".into()));
+            }
+            pipeline.push(command);
+            if tokens.next_if(|t| matches!(t, Token::Pipe)).is_none() {
+                return Ok(pipeline);
+            }
+        }
+    }
+
+    // Returns the arguments of all calls to `func`.
+    fn calls(&self, func: &str) -> Vec<&[Operand]> {
+        fn walk<'a>(
+            pipeline: &'a [Vec<Operand>],
+            func: &str,
+            calls: &mut Vec<&'a [Operand]>,
+        ) {
+            for command in pipeline {
+                if let [Operand::Func(f), args @ ..] = &command[..] {
+                    if f == func {
+                        calls.push(args);
+                    }
+                }
+                for operand in command {
+                    if let Operand::Pipeline(inner) = operand {
+                        walk(inner, func, calls);
+                    }
+                }
+            }
+        }
+        let mut calls = vec![];
+        for node in &self.nodes {
+            if let Node::Action(pipeline) = node {
+                walk(pipeline, func, &mut calls);
+            }
+        }
+        calls
+    }
+
+    pub fn dependencies(&self) -> Vec<String> {
+        self.calls("key")
+            .into_iter()
+            .filter_map(|args| match args {
+                [Operand::Str(key), ..] => Some(key.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Secrets referenced by `secret`, as written in the template. Only
+    // literal references can be resolved, as they are fetched before
+    // rendering.
+    pub fn secret_refs(&self) -> Vec<&str> {
+        self.calls("secret")
+            .into_iter()
+            .filter_map(|args| match args {
+                [Operand::Str(reference), ..] => Some(reference.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn render(
+        &self,
+        ctx: &TemplateContext,
+    ) -> Result<ByteString, Error> {
+        let mut out = vec![];
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => out.extend_from_slice(text.as_bytes()),
+                Node::Action(pipeline) => {
+                    out.extend(Self::eval_pipeline(pipeline, ctx)?)
+                }
+            }
+        }
+        Ok(ByteString(out))
+    }
+
+    fn eval_pipeline(
+        pipeline: &[Vec<Operand>],
+        ctx: &TemplateContext,
+    ) -> Result<Vec<u8>, Error> {
+        let mut value = None;
+        for command in pipeline {
+            value = Some(match &command[..] {
+                [Operand::Func(name), args @ ..] => {
+                    let mut args = args
+                        .iter()
+                        .map(|a| Self::eval_operand(a, ctx))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    args.extend(value);
+                    Self::call(name, args, ctx)?
+                }
+                [operand] if value.is_none() => {
+                    Self::eval_operand(operand, ctx)?
+                }
+                _ => {
+                    return Err(Error::Template(
+                        "can't give argument to non-function".into(),
+                    ))
+                }
+            });
+        }
+        Ok(value.unwrap_or_default())
+    }
+
+    fn eval_operand(
+        operand: &Operand,
+        ctx: &TemplateContext,
+    ) -> Result<Vec<u8>, Error> {
+        match operand {
+            Operand::Str(s) => Ok(s.clone().into_bytes()),
+            Operand::Field(f) => match f.as_str() {
+                "Namespace" => Ok(ctx.scope.namespace().as_bytes().to_vec()),
+                "SecretName" => Ok(ctx
+                    .scope
+                    .meta
+                    .name
+                    .clone()
+                    .unwrap_or_default()
+                    .into_bytes()),
+                _ => Err(Error::Template(format!("unknown variable .{}", f))),
+            },
+            Operand::Func(name) => Self::call(name, vec![], ctx),
+            Operand::Pipeline(inner) => Self::eval_pipeline(inner, ctx),
+        }
+    }
+
+    fn call(
+        name: &str,
+        args: Vec<Vec<u8>>,
+        ctx: &TemplateContext,
+    ) -> Result<Vec<u8>, Error> {
+        match (name, &args[..]) {
+            ("key", [key]) => {
+                let key = String::from_utf8_lossy(key);
+                ctx.data
+                    .get(key.as_ref())
+                    .or_else(|| ctx.old_data.get(key.as_ref()))
+                    .map(|v| v.0.clone())
+                    .ok_or_else(|| {
+                        Error::Template(format!("key {:?} does not exist", key))
+                    })
+            }
+            ("secret", [reference, key]) => {
+                let reference = String::from_utf8_lossy(reference);
+                let key = String::from_utf8_lossy(key);
+                let (ns, name) =
+                    split_secret_ref(&reference, ctx.scope.namespace());
+                ctx.scope
+                    .secrets
+                    .get(&format!("{}/{}", ns, name))
+                    .ok_or_else(|| {
+                        Error::Template(format!(
+                            "secret {:?} must be referenced by a literal",
+                            reference
+                        ))
+                    })?
+                    .get(key.as_ref())
+                    .map(|v| v.0.clone())
+                    .ok_or_else(|| {
+                        Error::Template(format!(
+                            "key {:?} does not exist in secret {}/{}",
+                            key, ns, name
+                        ))
+                    })
+            }
+            ("secret", _) => Err(Error::Template(format!(
+                "wrong number of args for {}: want 2 got {}",
+                name,
+                args.len()
+            ))),
+            ("now", []) => Ok(Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+                .into_bytes()),
+            ("b64enc", [value]) => {
+                Ok(BASE64_STANDARD.encode(value).into_bytes())
+            }
+            ("urlquery", [value]) => Ok(url_query_escape(value).into_bytes()),
+            ("sha256", [value]) => Ok(hex(&sha256(value)).into_bytes()),
+            ("key" | "b64enc" | "urlquery" | "sha256", _) => {
+                Err(Error::Template(format!(
+                    "wrong number of args for {}: want 1 got {}",
+                    name,
+                    args.len()
+                )))
+            }
+            ("now", _) => Err(Error::Template(format!(
+                "wrong number of args for {}: want 0 got {}",
+                name,
+                args.len()
+            ))),
+            _ => {
+                Err(Error::Template(format!("function {:?} not defined", name)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(src: &str, data: &[(&str, &str)]) -> Result<String, Error> {
+        let meta = ObjectMeta {
+            name: Some("db".to_string()),
+            namespace: Some("app".to_string()),
+            ..Default::default()
+        };
+        let secrets = BTreeMap::from([(
+            "infra/endpoint".to_string(),
+            [("host".to_string(), ByteString(b"db.infra".to_vec()))]
+                .into_iter()
+                .collect(),
+        )]);
+        let scope = Scope {
+            meta: &meta,
+            secrets: &secrets,
+            seed: None,
+        };
+        let data: SecretData = data
+            .iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.as_bytes().to_vec())))
+            .collect();
+        let ctx = TemplateContext {
+            scope: &scope,
+            old_data: &SecretData::default(),
+            data: &data,
+        };
+        let value = Template::parse(src)?.render(&ctx)?;
+        Ok(String::from_utf8(value.0).unwrap())
+    }
+
+    #[test]
+    fn keys_and_pipelines() {
+        assert_eq!(
+            render(
+                r#"postgres://app:{{ key "password" | urlquery }}@db"#,
+                &[("password", "a b&c")]
+            )
+            .unwrap(),
+            "postgres://app:a+b%26c@db"
+        );
+        assert_eq!(
+            render(r#"{{ key "user" | b64enc }}"#, &[("user", "app")]).unwrap(),
+            "YXBw"
+        );
+        assert_eq!(
+            render("{{ .SecretName }}.{{ .Namespace }}", &[]).unwrap(),
+            "db.app"
+        );
+    }
+
+    #[test]
+    fn trimmed_actions() {
+        assert_eq!(
+            render("a  {{- key \"x\" -}}  b", &[("x", "1")]).unwrap(),
+            "a1b"
+        );
+    }
+
+    #[test]
+    fn referenced_secrets() {
+        let template =
+            Template::parse(r#"{{ secret "infra/endpoint" "host" }}"#).unwrap();
+        assert_eq!(template.secret_refs(), ["infra/endpoint"]);
+        assert_eq!(
+            render(r#"{{ secret "infra/endpoint" "host" }}"#, &[]).unwrap(),
+            "db.infra"
+        );
+        assert!(render(r#"{{ secret "endpoint" "host" }}"#, &[]).is_err());
+    }
+
+    #[test]
+    fn dependencies() {
+        let template = Template::parse(
+            r#"{{ key "user" }}:{{ key "password" | sha256 }}"#,
+        )
+        .unwrap();
+        assert_eq!(template.dependencies(), ["user", "password"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Template::parse("{{ key \"x\"").is_err());
+        assert!(render(r#"{{ key "missing" }}"#, &[]).is_err());
+        assert!(render("{{ nope }}", &[]).is_err());
+        assert!(render("{{ .Nope }}", &[]).is_err());
+        assert!(render(r#"{{ key "a" "b" }}"#, &[]).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+// Reconciles limited to `--namespace-qps` per second and namespace, so that a
+// tenant annotating thousands of secrets at once doesn't starve the others.
+// Objects over the limit reserve a later turn and are requeued until then,
+// which frees the reconcile slots for the other namespaces meanwhile.
+
+use crate::client::Bucket;
+use kube::{api::DynamicObject, runtime::reflector::ObjectRef};
+use std::{collections::HashMap, sync::Mutex};
+use tokio::time::{Duration, Instant};
+
+pub(crate) struct Throttle {
+    qps: f64,
+    burst: u32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    // The turns objects over the limit reserved.
+    turns: Mutex<HashMap<ObjectRef<DynamicObject>, Instant>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(qps: f64, burst: u32) -> Throttle {
+        Throttle {
+            qps,
+            burst,
+            buckets: Mutex::default(),
+            turns: Mutex::default(),
+        }
+    }
+
+    // Returns how long `object` in `namespace` has to wait for its turn, or
+    // `None` if it may be reconciled now.
+    pub(crate) fn wait(
+        &self,
+        namespace: &str,
+        object: &ObjectRef<DynamicObject>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let mut turns = self.turns.lock().unwrap();
+        if let Some(turn) = turns.get(object).copied() {
+            if turn <= now {
+                turns.remove(object);
+                return None;
+            }
+            return Some(turn - now);
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let wait = buckets
+            .entry(namespace.to_string())
+            .or_insert_with(|| Bucket::new(self.qps, self.burst))
+            .reserve(now);
+        if wait.is_zero() {
+            return None;
+        }
+        turns.insert(object.clone(), now + wait);
+        Some(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::api::ApiResource;
+
+    fn object(namespace: &str, name: &str) -> ObjectRef<DynamicObject> {
+        let resource =
+            ApiResource::erase::<k8s_openapi::api::core::v1::Secret>(&());
+        ObjectRef::new_with(name, resource).within(namespace)
+    }
+
+    #[test]
+    fn per_namespace() {
+        let throttle = Throttle::new(1.0, 2);
+        let start = Instant::now();
+        let ms = |ms| Duration::from_millis(ms);
+        let busy: Vec<_> = (0..4)
+            .map(|i| {
+                throttle.wait("busy", &object("busy", &i.to_string()), start)
+            })
+            .collect();
+        assert_eq!(busy, [None, None, Some(ms(1000)), Some(ms(2000))]);
+        // Other namespaces are not held back.
+        assert_eq!(throttle.wait("quiet", &object("quiet", "0"), start), None);
+        // Waiting objects keep their turn.
+        let third = object("busy", "2");
+        assert_eq!(
+            throttle.wait("busy", &third, start + ms(400)),
+            Some(ms(600))
+        );
+        assert_eq!(throttle.wait("busy", &third, start + ms(1000)), None);
+    }
+}
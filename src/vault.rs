@@ -0,0 +1,228 @@
+// Pushes generated values to a HashiCorp Vault KV version 2 engine, for
+// secrets annotated with `vault-path`. The operator logs in with Vault's
+// Kubernetes auth method using its service account token.
+
+use crate::{
+    controller::wipe,
+    error::Error,
+    generate::SecretData,
+    opts::Opts,
+    store::{from_strings, string_values, SecretStore, StoreWrite},
+};
+use futures_util::future::BoxFuture;
+use reqwest::{Method, StatusCode};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Vault {
+    addr: String,
+    role: String,
+    auth_path: String,
+    mount: String,
+    token_file: String,
+    client: reqwest::Client,
+    // The client token with the time it is renewed at.
+    token: Mutex<Option<(Zeroizing<String>, Instant)>>,
+}
+
+impl Vault {
+    pub(crate) fn new(addr: &str, opts: &Opts) -> Result<Vault, Error> {
+        let invalid = |e: reqwest::Error| Error::Vault(e.to_string());
+        let mut client = reqwest::Client::builder().timeout(VAULT_TIMEOUT);
+        if let Some(ca) = &opts.vault_ca {
+            let ca = reqwest::Certificate::from_pem(&std::fs::read(ca)?)
+                .map_err(invalid)?;
+            client = client.add_root_certificate(ca);
+        }
+        Ok(Vault {
+            addr: addr.trim_end_matches('/').to_string(),
+            role: opts.vault_role.clone(),
+            auth_path: opts.vault_auth_path.clone(),
+            mount: opts.vault_mount.clone(),
+            token_file: opts.vault_token_file.clone(),
+            client: client.build().map_err(invalid)?,
+            token: Mutex::new(None),
+        })
+    }
+
+    // Writes `data` into the KV secret at `path`, keeping the keys it holds
+    // already, and returns the version it created.
+    async fn put(
+        &self,
+        path: &str,
+        data: &SecretData,
+    ) -> Result<Option<String>, Error> {
+        let mut body = serde_json::json!({ "data": string_values(data) });
+        let payload = Zeroizing::new(serde_json::to_vec(&body)?);
+        wipe(&mut body);
+        // Only existing secrets can be patched.
+        let patched = self
+            .request(Method::PATCH, "data", path, &payload)
+            .await
+            .transpose();
+        let written = match patched {
+            Some(written) => written?,
+            None => self
+                .request(Method::POST, "data", path, &payload)
+                .await?
+                .unwrap_or_default(),
+        };
+        Ok(written["data"]["version"].as_u64().map(|v| v.to_string()))
+    }
+
+    async fn get(&self, path: &str) -> Result<SecretData, Error> {
+        let mut read = self
+            .request(Method::GET, "data", path, &[])
+            .await?
+            .unwrap_or_default();
+        let data = read["data"]["data"]
+            .as_object()
+            .map(from_strings)
+            .unwrap_or_default();
+        wipe(&mut read);
+        Ok(data)
+    }
+
+    async fn current_version(
+        &self,
+        path: &str,
+    ) -> Result<Option<String>, Error> {
+        let metadata = self.request(Method::GET, "metadata", path, &[]).await?;
+        Ok(metadata
+            .and_then(|m| m["data"]["current_version"].as_u64())
+            .map(|v| v.to_string()))
+    }
+
+    // Sends `body` to the `data` or `metadata` endpoint of `path`. Returns
+    // nothing if there is no secret at `path`.
+    async fn request(
+        &self,
+        method: Method,
+        endpoint: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Option<serde_json::Value>, Error> {
+        let token = self.token().await?;
+        let content_type = match method {
+            Method::PATCH => "application/merge-patch+json",
+            _ => "application/json",
+        };
+        let url = format!(
+            "{}/v1/{}/{}/{}",
+            self.addr,
+            self.mount,
+            endpoint,
+            path.trim_matches('/')
+        );
+        let failed =
+            |e: reqwest::Error| Error::Vault(e.without_url().to_string());
+        let response = self
+            .client
+            .request(method, url)
+            .header("X-Vault-Token", token.as_str())
+            .header("Content-Type", content_type)
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(failed)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = response
+            .error_for_status()
+            .map_err(failed)?
+            .bytes()
+            .await
+            .map_err(failed)?;
+        // Deletes answer without a body.
+        match body.is_empty() {
+            true => Ok(Some(serde_json::Value::Null)),
+            false => Ok(Some(serde_json::from_slice(&body)?)),
+        }
+    }
+
+    // The client token, logging in again once it is about to expire.
+    async fn token(&self) -> Result<Zeroizing<String>, Error> {
+        if let Some((token, renew)) = &*self.token.lock().unwrap() {
+            if Instant::now() < *renew {
+                return Ok(token.clone());
+            }
+        }
+        let jwt = Zeroizing::new(std::fs::read_to_string(&self.token_file)?);
+        let mut login = serde_json::json!({
+            "role": self.role,
+            "jwt": jwt.trim(),
+        });
+        let response = self
+            .client
+            .post(format!("{}/v1/auth/{}/login", self.addr, self.auth_path))
+            .json(&login)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        wipe(&mut login);
+        let mut auth: serde_json::Value = response
+            .map_err(|e| Error::Vault(e.without_url().to_string()))?
+            .json()
+            .await
+            .map_err(|e| Error::Vault(e.without_url().to_string()))?;
+        let token = auth["auth"]["client_token"]
+            .as_str()
+            .map(|token| Zeroizing::new(token.to_string()));
+        let lease = auth["auth"]["lease_duration"].as_u64().unwrap_or(0);
+        wipe(&mut auth);
+        let token = token.ok_or_else(|| {
+            Error::Vault("login answered without a client token".to_string())
+        })?;
+        // Tokens are renewed after two thirds of their lease.
+        let renew = Instant::now() + Duration::from_secs(lease * 2 / 3);
+        *self.token.lock().unwrap() = Some((token.clone(), renew));
+        Ok(token)
+    }
+}
+
+impl SecretStore for Vault {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn annotation(&self) -> &'static str {
+        "vault-path"
+    }
+
+    fn write<'a>(
+        &'a self,
+        write: &'a StoreWrite<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(self.put(write.location, write.generated))
+    }
+
+    fn read<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<SecretData, Error>> {
+        Box::pin(self.get(location))
+    }
+
+    // Deletes all versions along with the metadata.
+    fn delete<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.request(Method::DELETE, "metadata", location, &[])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        location: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(self.current_version(location))
+    }
+}
@@ -0,0 +1,480 @@
+// Admission webhooks, served with `--webhook`. `/validate` rejects secrets
+// whose annotations the reconciler would fail on, so typos surface on
+// `kubectl apply` instead of in the operator's logs. `/mutate` additionally
+// generates the keys of secrets being created, so that workloads mounting
+// them don't have to wait for the controller.
+
+use crate::{
+    audit::{audit_records, publish_records},
+    controller::{
+        check_settings, data_elsewhere, fetch_references, master_seed,
+        secret_references, wipe, Context, Rotation,
+    },
+    error::Error,
+    generate::{blocking, drop_empty, gen_data, SecretData},
+    kinds::{advance_counters, recorded_counters},
+    settings::{declared_settings, parse_annotation, KeyCase, KeyErrors},
+    template::Scope,
+};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use k8s_openapi::{
+    api::{
+        admissionregistration::v1::{
+            MutatingWebhookConfiguration, ValidatingWebhookConfiguration,
+        },
+        core::v1::Secret,
+    },
+    chrono::{Datelike, Days, Utc},
+    ByteString,
+};
+use kube::{
+    api::PostParams,
+    core::{
+        admission::{
+            AdmissionRequest, AdmissionResponse, AdmissionReview, Operation,
+        },
+        DynamicObject,
+    },
+    Api, Client,
+};
+use log::{debug, info, warn};
+use rcgen::{CertificateParams, KeyPair};
+use rustls_pki_types::{
+    pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer,
+};
+use std::{
+    collections::BTreeSet,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+// Self-signed certificates are valid for 90 days and renewed after 30.
+const VALIDITY: u64 = 90;
+const RENEW_AFTER: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+pub struct Webhook {
+    listener: TcpListener,
+    tls: Arc<Tls>,
+    self_signed: Option<SelfSigned>,
+}
+
+pub enum Certificate {
+    // PEM files, e.g. issued by cert-manager.
+    Files {
+        cert: String,
+        key: String,
+    },
+    // Issued by the operator itself for `dns_name` and trusted by patching it
+    // into the caBundle of the webhook configurations named `configuration`.
+    SelfSigned {
+        dns_name: String,
+        configuration: String,
+    },
+}
+
+struct SelfSigned {
+    dns_name: String,
+    configuration: String,
+    pem: String,
+}
+
+// The serving certificate. Certificates read from files are reloaded when
+// the files change, e.g. after cert-manager renewed them.
+struct Tls {
+    files: Option<(String, String)>,
+    loaded: Mutex<(Option<SystemTime>, TlsAcceptor)>,
+}
+
+impl Tls {
+    fn load(cert: &str, key: &str) -> Result<Self, Error> {
+        let modified = std::fs::metadata(cert)?.modified().ok();
+        Ok(Tls {
+            files: Some((cert.to_string(), key.to_string())),
+            loaded: Mutex::new((modified, read_acceptor(cert, key)?)),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some((cert, key)) = &self.files {
+            let modified =
+                std::fs::metadata(cert).and_then(|m| m.modified()).ok();
+            if modified != loaded.0 {
+                match read_acceptor(cert, key) {
+                    Ok(acceptor) => {
+                        info!("reloaded webhook certificate");
+                        *loaded = (modified, acceptor);
+                    }
+                    Err(e) => debug!("keeping webhook certificate: {}", e),
+                }
+            }
+        }
+        loaded.1.clone()
+    }
+
+    fn install(&self, acceptor: TlsAcceptor) {
+        *self.loaded.lock().unwrap() = (None, acceptor);
+    }
+}
+
+fn read_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, Error> {
+    let invalid = |e: rustls_pki_types::pem::Error| {
+        Error::Webhook(format!("cannot read certificate: {}", e))
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+    acceptor(certs, key)
+}
+
+fn acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<TlsAcceptor, Error> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Webhook(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Issues a self-signed certificate for `dns_name`, returning it as PEM
+// together with an acceptor serving it.
+fn issue(dns_name: &str) -> Result<(String, TlsAcceptor), Error> {
+    let failed = |e: rcgen::Error| {
+        Error::Webhook(format!("cannot issue certificate: {}", e))
+    };
+    let date = |t: k8s_openapi::chrono::DateTime<Utc>| {
+        rcgen::date_time_ymd(t.year(), t.month() as u8, t.day() as u8)
+    };
+    let now = Utc::now();
+    let mut params =
+        CertificateParams::new(vec![dns_name.to_string()]).map_err(failed)?;
+    params.not_before = date(now - Days::new(1));
+    params.not_after = date(now + Days::new(VALIDITY));
+    let key = KeyPair::generate().map_err(failed)?;
+    let cert = params.self_signed(&key).map_err(failed)?;
+    let der = PrivatePkcs8KeyDer::from(key.serialize_der());
+    Ok((cert.pem(), acceptor(vec![cert.der().clone()], der.into())?))
+}
+
+// Sets the caBundle of all webhooks in the validating and mutating webhook
+// configurations named `name`. Missing configurations are skipped.
+async fn inject_ca(
+    client: &Client,
+    name: &str,
+    bundle: &str,
+) -> Result<(), Error> {
+    let ca_bundle = Some(ByteString(bundle.as_bytes().to_vec()));
+    let pp = PostParams::default();
+    let api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
+    if let Some(mut config) = api.get_opt(name).await? {
+        for webhook in config.webhooks.iter_mut().flatten() {
+            webhook.client_config.ca_bundle = ca_bundle.clone();
+        }
+        api.replace(name, &pp, &config).await?;
+    }
+    let api = Api::<MutatingWebhookConfiguration>::all(client.clone());
+    if let Some(mut config) = api.get_opt(name).await? {
+        for webhook in config.webhooks.iter_mut().flatten() {
+            webhook.client_config.ca_bundle = ca_bundle.clone();
+        }
+        api.replace(name, &pp, &config).await?;
+    }
+    Ok(())
+}
+
+// Keeps the self-signed certificate trusted and renews it. A renewed
+// certificate is only served once the API server trusts both the old and the
+// new one, so that requests in flight keep being accepted.
+async fn renew(client: Client, tls: Arc<Tls>, self_signed: SelfSigned) {
+    let SelfSigned {
+        dns_name,
+        configuration,
+        mut pem,
+    } = self_signed;
+    let mut bundle = pem.clone();
+    let mut pending = None;
+    loop {
+        while let Err(e) = inject_ca(&client, &configuration, &bundle).await {
+            warn!("cannot patch the webhook caBundle: {}", e);
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+        if let Some(acceptor) = pending.take() {
+            tls.install(acceptor);
+            info!("renewed webhook certificate");
+        }
+        tokio::time::sleep(RENEW_AFTER).await;
+        match issue(&dns_name) {
+            Ok((renewed, acceptor)) => {
+                bundle = format!("{}{}", renewed, pem);
+                pem = renewed;
+                pending = Some(acceptor);
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+impl Webhook {
+    pub async fn bind(
+        addr: SocketAddr,
+        certificate: Certificate,
+    ) -> Result<Self, Error> {
+        let (tls, self_signed) = match certificate {
+            Certificate::Files { cert, key } => (Tls::load(&cert, &key)?, None),
+            Certificate::SelfSigned {
+                dns_name,
+                configuration,
+            } => {
+                let (pem, acceptor) = issue(&dns_name)?;
+                let tls = Tls {
+                    files: None,
+                    loaded: Mutex::new((None, acceptor)),
+                };
+                let self_signed = SelfSigned {
+                    dns_name,
+                    configuration,
+                    pem,
+                };
+                (tls, Some(self_signed))
+            }
+        };
+        Ok(Webhook {
+            listener: TcpListener::bind(addr).await?,
+            tls: Arc::new(tls),
+            self_signed,
+        })
+    }
+
+    pub async fn serve(self, ctx: Arc<Context>) -> Result<(), Error> {
+        info!("serving webhook on {}", self.listener.local_addr()?);
+        if let Some(self_signed) = self.self_signed {
+            let renewal =
+                renew(ctx.client.clone(), self.tls.clone(), self_signed);
+            tokio::spawn(renewal);
+        }
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            let tls = self.tls.acceptor();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let stream = match tls.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+                let service = service_fn(|req| handle(ctx.clone(), req));
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("webhook connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    ctx: Arc<Context>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mutate = match req.uri().path() {
+        "/validate" => false,
+        "/mutate" => true,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+                .unwrap())
+        }
+    };
+    let response = match req.into_body().collect().await {
+        Ok(body) => review(&ctx, &body.to_bytes(), mutate).await,
+        Err(e) => AdmissionResponse::invalid(e),
+    };
+    let body = serde_json::to_vec(&response.into_review())
+        .expect("admission reviews serialize");
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap())
+}
+
+async fn review(ctx: &Context, body: &[u8], mutate: bool) -> AdmissionResponse {
+    let request: AdmissionRequest<DynamicObject> =
+        match serde_json::from_slice::<AdmissionReview<DynamicObject>>(body)
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.try_into().map_err(|e| format!("{}", e)))
+        {
+            Ok(request) => request,
+            Err(e) => return AdmissionResponse::invalid(e),
+        };
+    let response = AdmissionResponse::from(&request);
+    let Some(object) = &request.object else {
+        return response;
+    };
+    let namespace = request.namespace.as_deref().unwrap_or_default();
+    if let Err(e) = validate(ctx, namespace, object).await {
+        return response.deny(e);
+    }
+    if !mutate || request.operation != Operation::Create {
+        return response;
+    }
+    // Keys that cannot be generated yet, e.g. because a referenced secret
+    // is missing, are left to the controller.
+    let mut patch =
+        match generate(ctx, namespace, object, request.dry_run).await {
+            Ok(Some(patch)) => patch,
+            Ok(None) => return response,
+            Err(e) => {
+                debug!("not generating keys at admission: {}", e);
+                return response;
+            }
+        };
+    let response = match serde_json::from_value(patch.clone()) {
+        Ok(ops) => response
+            .with_patch(ops)
+            .unwrap_or_else(AdmissionResponse::invalid),
+        Err(e) => AdmissionResponse::invalid(e),
+    };
+    wipe(&mut patch);
+    response
+}
+
+// Generates the declared keys of a secret being created and returns the JSON
+// patch adding them. Secrets whose data ends up elsewhere are left to the
+// controller, as is rotating the keys later on.
+async fn generate(
+    ctx: &Context,
+    namespace: &str,
+    object: &DynamicObject,
+    dry_run: bool,
+) -> Result<Option<serde_json::Value>, Error> {
+    let meta = &object.metadata;
+    let Some(settings) = declared_settings(meta)? else {
+        return Ok(None);
+    };
+    if data_elsewhere(meta) {
+        return Ok(None);
+    }
+
+    let secret: Secret = serde_json::from_value(serde_json::to_value(object)?)?;
+    let mut old_data = SecretData(secret.data.clone().unwrap_or_default());
+    drop_empty(meta, &settings, &mut old_data)?;
+    let opts = ctx.opts(namespace).await?;
+    let references = secret_references(&settings, namespace);
+    let secrets = fetch_references(&ctx.client, namespace, &references).await?;
+    let seed = master_seed(ctx, &settings).await?;
+    let scope = Scope {
+        meta,
+        secrets: &secrets,
+        seed: seed.as_deref().map(Vec::as_slice),
+    };
+    let mut failed = KeyErrors::new();
+    let data = blocking(|| {
+        gen_data(
+            &opts,
+            &settings,
+            &scope,
+            &old_data,
+            &BTreeSet::new(),
+            &mut failed,
+        )
+    })?;
+    if let Some((_, e)) = failed.pop_first() {
+        return Err(e);
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    let rotation = Rotation::new(&opts, meta, &settings)?;
+    let case = parse_annotation::<KeyCase>(meta, app_id!("key-case"))?;
+    let (generated_at, _) = rotation.update(|key: &str| {
+        data.contains_key(key)
+            || case.is_some_and(|c| data.contains_key(&c.apply(key)))
+    });
+
+    let mut ops = vec![];
+    if secret.data.is_none() {
+        ops.push(
+            serde_json::json!({ "op": "add", "path": "/data", "value": {} }),
+        );
+    }
+    for (key, value) in &data {
+        ops.push(serde_json::json!({
+            "op": "add",
+            "path": format!("/data/{}", escape(key)),
+            "value": value,
+        }));
+    }
+    if !generated_at.is_empty() {
+        ops.push(serde_json::json!({
+            "op": "add",
+            "path": format!(
+                "/metadata/annotations/{}",
+                escape(app_id!("generated-at"))
+            ),
+            "value": serde_json::to_string(&generated_at)?,
+        }));
+    }
+    let recorded = recorded_counters(meta)?;
+    let counters = advance_counters(&recorded, &settings, |key| {
+        data.get(key).or_else(|| data.get(&case?.apply(key)))
+    });
+    if counters != recorded {
+        ops.push(serde_json::json!({
+            "op": "add",
+            "path": format!(
+                "/metadata/annotations/{}",
+                escape(app_id!("counters"))
+            ),
+            "value": serde_json::to_string(&counters)?,
+        }));
+    }
+
+    if !dry_run {
+        let name = meta.name.as_deref().unwrap_or_default();
+        let records = audit_records(&opts, &settings, &old_data, &data);
+        publish_records(ctx, &opts, namespace, name, records).await;
+    }
+    Ok(Some(serde_json::Value::Array(ops)))
+}
+
+// Escapes a JSON pointer segment (RFC 6901).
+fn escape(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+// Checks what the reconciler would check before generating anything.
+async fn validate(
+    ctx: &Context,
+    namespace: &str,
+    object: &DynamicObject,
+) -> Result<(), Error> {
+    let Some(settings) = declared_settings(&object.metadata)? else {
+        return Ok(());
+    };
+    let opts = ctx.opts(namespace).await?;
+    match check_settings(&opts, &object.metadata, &settings)?.pop_first() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}